@@ -143,6 +143,16 @@ pub enum Event {
     ReviewerAdded(ReviewerAddedEvent),
 }
 
+impl Event {
+    /// The change the event is about.
+    pub fn change(&self) -> &Change {
+        match self {
+            Event::CommentAdded(event) => &event.change,
+            Event::ReviewerAdded(event) => &event.change,
+        }
+    }
+}
+
 fn get_pub_key_path(priv_key_path: &PathBuf) -> PathBuf {
     let mut pub_key_path = PathBuf::from(priv_key_path.to_str().unwrap());
     pub_key_path.set_extension("pub");
@@ -423,12 +433,7 @@ fn fetch_extended_info(
         query += " --patch-sets --comments";
     }
 
-    let change_id = match &event {
-        Event::CommentAdded(event) => &event.change.id,
-        Event::ReviewerAdded(event) => &event.change.id,
-    };
-
-    query += &format!(" change:{}", change_id);
+    query += &format!(" change:{}", event.change().id);
 
     future::Either::B(command_runner.run_command(query).then(
         move |result| -> Result<Event, (Event, String)> {
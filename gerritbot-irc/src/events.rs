@@ -0,0 +1,289 @@
+//! Connection to the IRC server: registration, SASL authentication and the
+//! private messages to the bot.
+
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs as _};
+use std::time::{Duration, Instant};
+
+use futures::future::{self, Future};
+use futures::sync::mpsc;
+use futures::{stream, Sink, Stream};
+use log::{error, info, warn};
+use tokio::codec::Framed;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::timer::Delay;
+
+use crate::{Client, Error, LineCodec, Message, RawMessage, SaslCredentials};
+
+/// Delay before reconnecting after the connection was closed.
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+/// Maximum length of the payload of a single `AUTHENTICATE` message.
+const MAX_AUTHENTICATE_LEN: usize = 400;
+
+type MessageSink = Box<dyn Sink<SinkItem = RawMessage, SinkError = io::Error> + Send>;
+type MessageStream = Box<dyn Stream<Item = RawMessage, Error = io::Error> + Send>;
+
+fn resolve(server: &str) -> Result<SocketAddr, Error> {
+    server.to_socket_addrs()?.next().ok_or_else(|| {
+        Error::IoError(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no address found for {}", server),
+        ))
+    })
+}
+
+fn split<S>(stream: S) -> (MessageSink, MessageStream)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (sink, messages) = Framed::new(stream, LineCodec).split();
+    (Box::new(sink), Box::new(messages))
+}
+
+/// Open a connection to the server, with TLS if configured.
+fn open(client: &Client) -> impl Future<Item = (MessageSink, MessageStream), Error = Error> {
+    let tls = client.tls;
+    let host = client
+        .server
+        .rsplitn(2, ':')
+        .last()
+        .unwrap_or_default()
+        .to_string();
+    future::result(resolve(&client.server))
+        .and_then(|address| TcpStream::connect(&address).from_err())
+        .and_then(move |stream| {
+            if tls {
+                future::Either::A(
+                    future::result(native_tls::TlsConnector::new())
+                        .and_then(move |connector| {
+                            tokio_tls::TlsConnector::from(connector).connect(&host, stream)
+                        })
+                        .map(split)
+                        .from_err(),
+                )
+            } else {
+                future::Either::B(future::ok(split(stream)))
+            }
+        })
+}
+
+/// Encode the credentials for `SASL PLAIN` and split them into the payloads of
+/// `AUTHENTICATE` messages.
+fn sasl_plain_payloads(credentials: &SaslCredentials) -> Vec<String> {
+    let encoded = base64::encode(&format!(
+        "{}\0{}\0{}",
+        credentials.account, credentials.account, credentials.password
+    ));
+    let mut payloads: Vec<String> = encoded
+        .as_bytes()
+        .chunks(MAX_AUTHENTICATE_LEN)
+        .map(|chunk| String::from_utf8_lossy(chunk).into_owned())
+        .collect();
+    // a payload of full length announces that more follows
+    if encoded.len() % MAX_AUTHENTICATE_LEN == 0 {
+        payloads.push("+".to_string());
+    }
+    payloads
+}
+
+/// State of a single connection to the server.
+struct Connection {
+    client: Client,
+    /// current nickname of the bot
+    nickname: String,
+    /// whether the server welcomed the bot, i.e. the registration is complete
+    registered: bool,
+    queue: mpsc::UnboundedSender<RawMessage>,
+}
+
+impl Connection {
+    /// Start the registration of the connection.
+    fn register(client: Client, queue: mpsc::UnboundedSender<RawMessage>) -> Self {
+        let connection = Self {
+            nickname: client.nickname.clone(),
+            registered: false,
+            client,
+            queue,
+        };
+        if connection.client.sasl.is_some() {
+            connection.send("CAP", vec!["REQ", "sasl"]);
+        }
+        if let Some(password) = &connection.client.password {
+            connection.send("PASS", vec![password.as_str()]);
+        }
+        connection.send("NICK", vec![connection.nickname.as_str()]);
+        connection.send(
+            "USER",
+            vec![connection.nickname.as_str(), "0", "*", "gerritbot"],
+        );
+        connection
+    }
+
+    fn send<S: Into<String>>(&self, command: &str, params: Vec<S>) {
+        // fails only if the connection is closed already
+        let _ = self.queue.unbounded_send(RawMessage::new(command, params));
+    }
+
+    /// Handle a message from the server. Returns private messages to the bot,
+    /// fails if the connection cannot be used.
+    fn handle(&mut self, message: RawMessage) -> Result<Option<Message>, ()> {
+        match message.command.as_str() {
+            "PING" => self.send("PONG", message.params),
+            "CAP"
+                if message.param(1) == "ACK"
+                    && message.param(2).split_whitespace().any(|cap| cap == "sasl") =>
+            {
+                self.send("AUTHENTICATE", vec!["PLAIN"]);
+            }
+            "CAP" if message.param(1) == "NAK" => {
+                error!("the irc server does not support SASL");
+                return Err(());
+            }
+            "AUTHENTICATE" if message.param(0) == "+" => {
+                if let Some(credentials) = &self.client.sasl {
+                    for payload in sasl_plain_payloads(credentials) {
+                        self.send("AUTHENTICATE", vec![payload]);
+                    }
+                }
+            }
+            // RPL_SASLSUCCESS
+            "903" => {
+                info!("authenticated with SASL");
+                self.send("CAP", vec!["END"]);
+            }
+            // ERR_NICKLOCKED, ERR_SASLFAIL, ERR_SASLTOOLONG, ERR_SASLABORTED
+            "902" | "904" | "905" | "906" => {
+                error!(
+                    "SASL authentication failed: {}",
+                    message
+                        .params
+                        .last()
+                        .map(String::as_str)
+                        .unwrap_or_default()
+                );
+                return Err(());
+            }
+            // RPL_WELCOME
+            "001" => {
+                self.nickname = message.param(0).to_string();
+                self.registered = true;
+                info!("connected to irc server as {}", self.nickname);
+                for channel in &self.client.channels {
+                    self.send("JOIN", vec![channel.as_str()]);
+                }
+                self.client.set_connection(Some(self.queue.clone()));
+            }
+            // ERR_NICKNAMEINUSE
+            "433" if !self.registered => {
+                warn!("nickname {} is taken", self.nickname);
+                self.nickname.push('_');
+                self.send("NICK", vec![self.nickname.clone()]);
+            }
+            "NICK" if message.nickname() == Some(self.nickname.as_str()) => {
+                self.nickname = message.param(0).to_string();
+            }
+            "PRIVMSG" => {
+                let text = message.param(1);
+                // messages to channels and CTCP requests are ignored
+                if message.param(0).eq_ignore_ascii_case(&self.nickname)
+                    && !text.starts_with('\u{1}')
+                {
+                    if let Some(nickname) = message.nickname() {
+                        return Ok(Some(Message {
+                            nickname: nickname.to_string(),
+                            text: text.trim().to_string(),
+                        }));
+                    }
+                }
+            }
+            "ERROR" => warn!("irc server closes the connection: {}", message.param(0)),
+            _ => (),
+        }
+        Ok(None)
+    }
+}
+
+/// Connect to the server once and stream the private messages to the bot
+/// until the connection is closed.
+fn connect(client: &Client) -> impl Stream<Item = Message, Error = ()> {
+    let client = client.clone();
+    let server = client.server.clone();
+    info!("connecting to irc server {}", server);
+    open(&client)
+        .map_err(move |e| error!("failed to connect to {}: {}", server, e))
+        .map(move |(sink, messages)| {
+            // messages are sent by a separate task
+            let (queue, queued) = mpsc::unbounded();
+            tokio::spawn(
+                queued
+                    .forward(sink.sink_map_err(|e| error!("failed to send to irc server: {}", e)))
+                    .map(|_| ()),
+            );
+
+            let mut connection = Connection::register(client, queue);
+            messages
+                .map_err(|e| error!("failed to receive from irc server: {}", e))
+                .and_then(move |message| connection.handle(message))
+                .filter_map(|message| message)
+        })
+        .flatten_stream()
+}
+
+/// Stream of the private messages to the bot. Messages can be sent with the
+/// client while the stream is connected. The connection is reestablished if it
+/// is closed.
+pub fn event_stream(client: Client) -> impl Stream<Item = Message, Error = ()> {
+    stream::iter_ok::<_, ()>(0..)
+        .map(move |attempt: u64| {
+            let delay = if attempt == 0 {
+                future::Either::A(future::ok(()))
+            } else {
+                client.set_connection(None);
+                warn!(
+                    "irc connection closed, reconnecting in {:?}",
+                    RECONNECT_DELAY
+                );
+                future::Either::B(
+                    Delay::new(Instant::now() + RECONNECT_DELAY)
+                        .map_err(|e| error!("reconnect timer failed: {}", e)),
+                )
+            };
+            let client = client.clone();
+            // errors are already logged, they only end the connection
+            delay
+                .map(move |()| connect(&client))
+                .flatten_stream()
+                .then(Ok::<_, ()>)
+                .take_while(|result| future::ok(result.is_ok()))
+                .filter_map(Result::ok)
+        })
+        .flatten()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn credentials(password: &str) -> SaslCredentials {
+        SaslCredentials {
+            account: "gerritbot".to_string(),
+            password: password.to_string(),
+        }
+    }
+
+    #[test]
+    fn encode_sasl_plain_credentials() {
+        assert_eq!(
+            sasl_plain_payloads(&credentials("secret")),
+            vec!["Z2Vycml0Ym90AGdlcnJpdGJvdABzZWNyZXQ="]
+        );
+        // 20 bytes of accounts and separators + 280 bytes of password are
+        // 400 bytes encoded
+        let payloads = sasl_plain_payloads(&credentials(&"x".repeat(280)));
+        assert_eq!(payloads.len(), 2);
+        assert_eq!(payloads[0].len(), 400);
+        assert_eq!(payloads[1], "+");
+    }
+}
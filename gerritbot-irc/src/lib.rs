@@ -0,0 +1,225 @@
+//! Client of an IRC network, optionally over TLS and authenticated with SASL.
+//!
+//! The bot only handles private messages. Messages are sent over the current
+//! connection, which is established and kept up by `event_stream`.
+
+use std::sync::{Arc, Mutex};
+use std::{error, fmt, io};
+
+use futures::sync::mpsc;
+use log::debug;
+
+mod events;
+mod message;
+
+pub use events::event_stream;
+pub use message::{LineCodec, RawMessage};
+
+/// Maximum length of the text of a single message. Servers limit lines to 512
+/// bytes, including the prefix of the sender they add.
+const MAX_TEXT_LEN: usize = 400;
+
+/// A private message to the bot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    /// nickname of the sender
+    pub nickname: String,
+    pub text: String,
+}
+
+/// Command the bot sends its messages with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// `NOTICE`, which clients and bots must never answer automatically
+    Notice,
+    /// `PRIVMSG`, i.e. ordinary messages
+    Privmsg,
+}
+
+impl Delivery {
+    fn command(self) -> &'static str {
+        match self {
+            Delivery::Notice => "NOTICE",
+            Delivery::Privmsg => "PRIVMSG",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    IoError(io::Error),
+    TlsError(native_tls::Error),
+    /// The bot is not connected to the server at the moment.
+    NotConnected,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::IoError(ref err) => fmt::Display::fmt(err, f),
+            Error::TlsError(ref err) => fmt::Display::fmt(err, f),
+            Error::NotConnected => f.write_str("not connected to the irc server"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::IoError(ref err) => err.source(),
+            Error::TlsError(ref err) => err.source(),
+            Error::NotConnected => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::IoError(err)
+    }
+}
+
+impl From<native_tls::Error> for Error {
+    fn from(err: native_tls::Error) -> Self {
+        Error::TlsError(err)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct SaslCredentials {
+    account: String,
+    password: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    server: String,
+    tls: bool,
+    nickname: String,
+    password: Option<String>,
+    sasl: Option<SaslCredentials>,
+    channels: Vec<String>,
+    delivery: Delivery,
+    /// queue of the messages to the current connection, if connected
+    connection: Arc<Mutex<Option<mpsc::UnboundedSender<RawMessage>>>>,
+}
+
+impl Client {
+    /// Create a client connecting to the server, e.g. `irc.example.org:6697`,
+    /// with TLS and the given nickname. Nothing is sent until the connection
+    /// is established by `event_stream`.
+    pub fn new(server: String, nickname: String) -> Self {
+        Self {
+            server,
+            tls: true,
+            nickname,
+            password: None,
+            sasl: None,
+            channels: Vec::new(),
+            delivery: Delivery::Notice,
+            connection: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Connect without TLS, e.g. to a local server.
+    pub fn without_tls(self) -> Self {
+        Self { tls: false, ..self }
+    }
+
+    /// Authenticate with `SASL PLAIN` before registering the nickname.
+    pub fn with_sasl(self, account: String, password: String) -> Self {
+        Self {
+            sasl: Some(SaslCredentials { account, password }),
+            ..self
+        }
+    }
+
+    /// Password of the server, sent with `PASS`.
+    pub fn with_password(self, password: String) -> Self {
+        Self {
+            password: Some(password),
+            ..self
+        }
+    }
+
+    /// Channels joined after connecting, so that messages can be sent to them.
+    pub fn with_channels(self, channels: Vec<String>) -> Self {
+        Self { channels, ..self }
+    }
+
+    pub fn with_delivery(self, delivery: Delivery) -> Self {
+        Self { delivery, ..self }
+    }
+
+    /// Configured nickname of the bot. If it is taken, the bot connects with
+    /// a different one.
+    pub fn nickname(&self) -> &str {
+        &self.nickname
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connection.lock().unwrap().is_some()
+    }
+
+    fn set_connection(&self, connection: Option<mpsc::UnboundedSender<RawMessage>>) {
+        *self.connection.lock().unwrap() = connection;
+    }
+
+    /// Send the text to a nickname or channel. Every line of the text is sent
+    /// as a separate message, long lines are split.
+    pub fn send_message(&self, target: &str, text: &str) -> Result<(), Error> {
+        debug!("send message to {}", target);
+        let connection = self.connection.lock().unwrap();
+        let connection = connection.as_ref().ok_or(Error::NotConnected)?;
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            for chunk in split_text(line, MAX_TEXT_LEN) {
+                connection
+                    .unbounded_send(RawMessage::new(
+                        self.delivery.command(),
+                        vec![target, chunk],
+                    ))
+                    .map_err(|_| Error::NotConnected)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Split the text into chunks of at most `max_len` bytes, at char boundaries.
+fn split_text(mut text: &str, max_len: usize) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    while text.len() > max_len {
+        let mut end = max_len;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        let (chunk, rest) = text.split_at(end);
+        chunks.push(chunk);
+        text = rest;
+    }
+    chunks.push(text);
+    chunks
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn split_long_text() {
+        assert_eq!(split_text("short", 10), vec!["short"]);
+        assert_eq!(split_text("0123456789ab", 5), vec!["01234", "56789", "ab"]);
+        // ü takes two bytes
+        assert_eq!(split_text("aaaüb", 4), vec!["aaa", "üb"]);
+    }
+
+    #[test]
+    fn send_without_connection_fails() {
+        let client = Client::new("localhost:6667".to_string(), "gerritbot".to_string());
+        assert!(!client.is_connected());
+        match client.send_message("jane", "hello") {
+            Err(Error::NotConnected) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,197 @@
+//! Lines of the IRC protocol (RFC 1459/2812) and their framing.
+
+use std::{fmt, io};
+
+use bytes::{BufMut, BytesMut};
+use tokio::codec::{Decoder, Encoder};
+
+/// A message of the IRC protocol, e.g. `:nick!user@host PRIVMSG bot :hi`.
+/// IRCv3 message tags are dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawMessage {
+    pub prefix: Option<String>,
+    pub command: String,
+    pub params: Vec<String>,
+}
+
+impl RawMessage {
+    pub fn new<I, S>(command: &str, params: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            prefix: None,
+            command: command.to_string(),
+            params: params.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    /// Parse a line without its line ending. Returns `None` for lines without
+    /// command.
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut rest = line.trim_end_matches(['\r', '\n']);
+        if rest.starts_with('@') {
+            rest = rest.split_once(' ')?.1;
+        }
+        rest = rest.trim_start_matches(' ');
+        let prefix = if rest.starts_with(':') {
+            let mut parts = rest[1..].splitn(2, ' ');
+            let prefix = parts.next()?.to_string();
+            rest = parts.next().unwrap_or_default().trim_start_matches(' ');
+            Some(prefix)
+        } else {
+            None
+        };
+        let (middle, trailing) = match rest.find(" :") {
+            Some(index) => (&rest[..index], Some(&rest[index + 2..])),
+            None => (rest, None),
+        };
+        let mut words = middle.split(' ').filter(|word| !word.is_empty());
+        let command = words.next()?.to_uppercase();
+        let mut params: Vec<String> = words.map(String::from).collect();
+        params.extend(trailing.map(String::from));
+        Some(Self {
+            prefix,
+            command,
+            params,
+        })
+    }
+
+    /// Nickname of the sender, i.e. the prefix up to `!`.
+    pub fn nickname(&self) -> Option<&str> {
+        self.prefix
+            .as_ref()
+            .and_then(|prefix| prefix.split('!').next())
+    }
+
+    /// Parameter at the given index, or the empty string.
+    pub fn param(&self, index: usize) -> &str {
+        self.params
+            .get(index)
+            .map(String::as_str)
+            .unwrap_or_default()
+    }
+}
+
+impl fmt::Display for RawMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(prefix) = &self.prefix {
+            write!(f, ":{} ", prefix)?;
+        }
+        f.write_str(&self.command)?;
+        if let Some((last, middle)) = self.params.split_last() {
+            for param in middle {
+                write!(f, " {}", param)?;
+            }
+            if last.is_empty() || last.contains(' ') || last.starts_with(':') {
+                write!(f, " :{}", last)?;
+            } else {
+                write!(f, " {}", last)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Frames the messages of a connection into lines. Lines which are no
+/// messages are skipped.
+#[derive(Debug, Default)]
+pub struct LineCodec;
+
+impl Decoder for LineCodec {
+    type Item = RawMessage;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<RawMessage>, io::Error> {
+        while let Some(end) = buf.iter().position(|b| *b == b'\n') {
+            let line = buf.split_to(end + 1);
+            if let Some(message) = RawMessage::parse(&String::from_utf8_lossy(&line)) {
+                return Ok(Some(message));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Encoder for LineCodec {
+    type Item = RawMessage;
+    type Error = io::Error;
+
+    fn encode(&mut self, mut message: RawMessage, buf: &mut BytesMut) -> Result<(), io::Error> {
+        // line breaks in parameters would start new commands
+        for param in &mut message.params {
+            *param = param.replace(['\r', '\n'], " ");
+        }
+        let line = message.to_string();
+        buf.reserve(line.len() + 2);
+        buf.put(line);
+        buf.put("\r\n");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_messages() {
+        let message =
+            RawMessage::parse("@account=jane :jane!~jane@host PRIVMSG gerritbot :status now\r\n")
+                .unwrap();
+        assert_eq!(message.prefix.as_ref().unwrap(), "jane!~jane@host");
+        assert_eq!(message.nickname(), Some("jane"));
+        assert_eq!(message.command, "PRIVMSG");
+        assert_eq!(message.params, vec!["gerritbot", "status now"]);
+
+        let message = RawMessage::parse("ping :irc.example.org").unwrap();
+        assert_eq!(message.prefix, None);
+        assert_eq!(message.command, "PING");
+        assert_eq!(message.param(0), "irc.example.org");
+        assert_eq!(message.param(1), "");
+
+        let message = RawMessage::parse(":irc.example.org 001 gerritbot").unwrap();
+        assert_eq!(message.command, "001");
+        assert_eq!(message.params, vec!["gerritbot"]);
+
+        assert_eq!(RawMessage::parse(""), None);
+        assert_eq!(RawMessage::parse(":prefix.only"), None);
+    }
+
+    #[test]
+    fn format_messages() {
+        assert_eq!(
+            RawMessage::new("PRIVMSG", vec!["jane", "+2 from Joe"]).to_string(),
+            "PRIVMSG jane :+2 from Joe"
+        );
+        assert_eq!(
+            RawMessage::new("NICK", vec!["gerritbot"]).to_string(),
+            "NICK gerritbot"
+        );
+        assert_eq!(
+            RawMessage::new("AUTHENTICATE", vec![":)"]).to_string(),
+            "AUTHENTICATE ::)"
+        );
+        assert_eq!(
+            RawMessage::new::<_, String>("QUIT", vec![]).to_string(),
+            "QUIT"
+        );
+    }
+
+    #[test]
+    fn frame_lines() {
+        let mut buf = BytesMut::from(&b"PING :a\r\n\r\nPING b\nPING"[..]);
+        let mut codec = LineCodec;
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap().param(0), "a");
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap().param(0), "b");
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        assert_eq!(&buf[..], b"PING");
+
+        let mut buf = BytesMut::new();
+        codec
+            .encode(RawMessage::new("PRIVMSG", vec!["jane", "a\nb"]), &mut buf)
+            .unwrap();
+        assert_eq!(&buf[..], b"PRIVMSG jane :a b\r\n");
+    }
+}
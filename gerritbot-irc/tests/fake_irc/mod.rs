@@ -0,0 +1,165 @@
+//! In-process stand-in for an IRC server.
+//!
+//! Accepts connections without TLS, negotiates `SASL PLAIN` with the account
+//! `gerritbot` and the password `PASSWORD`, rejects the nickname
+//! `TAKEN_NICKNAME` and welcomes the client once it is registered. All lines
+//! received from the client are recorded. Private messages of users are sent
+//! with `FakeIrc::send_private_message`.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const ACCOUNT: &str = "gerritbot";
+pub const PASSWORD: &str = "secret";
+pub const TAKEN_NICKNAME: &str = "gerritbot";
+
+#[derive(Debug, Default)]
+struct State {
+    lines: Vec<String>,
+    /// nickname of the connected client
+    nickname: Option<String>,
+    /// result of the SASL authentication, if any
+    authenticated: Option<bool>,
+    connection: Option<TcpStream>,
+}
+
+/// Handle to a running fake IRC server.
+#[derive(Clone)]
+pub struct FakeIrc {
+    address: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+fn write_line(stream: &mut TcpStream, line: &str) {
+    let _ = stream.write_all(format!("{}\r\n", line).as_bytes());
+}
+
+impl FakeIrc {
+    /// Start the server on a free local port. It runs as long as the test.
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(State::default()));
+        let server_state = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                serve(stream.unwrap(), &server_state);
+            }
+        });
+        Self { address, state }
+    }
+
+    pub fn address(&self) -> String {
+        self.address.to_string()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// Lines received from the client so far.
+    pub fn lines(&self) -> Vec<String> {
+        self.state().lines.clone()
+    }
+
+    pub fn nickname(&self) -> Option<String> {
+        self.state().nickname.clone()
+    }
+
+    pub fn authenticated(&self) -> Option<bool> {
+        self.state().authenticated
+    }
+
+    /// Wait until the condition holds, at most a few seconds.
+    pub fn wait_for<F>(&self, condition: F) -> bool
+    where
+        F: Fn(&Self) -> bool,
+    {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(10) {
+            if condition(self) {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    /// Send a private message of a user to the client.
+    pub fn send_private_message(&self, from: &str, text: &str) {
+        self.send_message(from, &self.nickname().expect("not registered"), text);
+    }
+
+    /// Send a message of a user to a channel or nickname.
+    pub fn send_message(&self, from: &str, target: &str, text: &str) {
+        let mut state = self.state();
+        let connection = state.connection.as_mut().expect("no connection");
+        write_line(
+            connection,
+            &format!(":{}!~{}@localhost PRIVMSG {} :{}", from, from, target, text),
+        );
+    }
+}
+
+/// Serve a single connection until it is closed.
+fn serve(stream: TcpStream, state: &Arc<Mutex<State>>) {
+    let mut writer = stream.try_clone().unwrap();
+    state.lock().unwrap().connection = Some(stream.try_clone().unwrap());
+    // registration is suspended during the capability negotiation
+    let mut negotiating = false;
+    let mut nickname: Option<String> = None;
+    let mut user = false;
+    let credentials = base64::encode(&format!("{}\0{}\0{}", ACCOUNT, ACCOUNT, PASSWORD));
+
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        state.lock().unwrap().lines.push(line.clone());
+        let words: Vec<&str> = line.split(' ').collect();
+        match words[0] {
+            "CAP" if words[1] == "REQ" => {
+                negotiating = true;
+                write_line(&mut writer, ":localhost CAP * ACK :sasl");
+            }
+            "CAP" if words[1] == "END" => negotiating = false,
+            "AUTHENTICATE" if words[1] == "PLAIN" => write_line(&mut writer, "AUTHENTICATE +"),
+            "AUTHENTICATE" => {
+                let success = words[1] == credentials;
+                state.lock().unwrap().authenticated = Some(success);
+                if success {
+                    write_line(
+                        &mut writer,
+                        ":localhost 903 * :SASL authentication successful",
+                    );
+                } else {
+                    write_line(&mut writer, ":localhost 904 * :SASL authentication failed");
+                }
+            }
+            "NICK" if words[1] == TAKEN_NICKNAME => write_line(
+                &mut writer,
+                &format!(":localhost 433 * {} :Nickname is already in use", words[1]),
+            ),
+            "NICK" => nickname = Some(words[1].to_string()),
+            "USER" => user = true,
+            "PING" => write_line(&mut writer, &format!(":localhost PONG {}", words[1])),
+            "QUIT" => break,
+            _ => (),
+        }
+        let registered = state.lock().unwrap().nickname.is_some();
+        if let (false, false, true, Some(nickname)) = (registered, negotiating, user, &nickname) {
+            state.lock().unwrap().nickname = Some(nickname.clone());
+            write_line(
+                &mut writer,
+                &format!(":localhost 001 {} :Welcome", nickname),
+            );
+        }
+    }
+    let mut state = state.lock().unwrap();
+    state.connection = None;
+    state.nickname = None;
+}
@@ -0,0 +1,98 @@
+use std::sync::mpsc;
+use std::time::Duration;
+
+use futures::{Future, Stream};
+use tokio::runtime::Runtime;
+
+use gerritbot_irc as irc;
+
+mod fake_irc;
+
+use fake_irc::{FakeIrc, ACCOUNT, PASSWORD, TAKEN_NICKNAME};
+
+/// Run the event stream of the client. Returns the received messages.
+fn run(runtime: &mut Runtime, client: &irc::Client) -> mpsc::Receiver<irc::Message> {
+    let (sender, messages) = mpsc::channel();
+    runtime.spawn(
+        irc::event_stream(client.clone())
+            .for_each(move |message| {
+                sender.send(message).unwrap();
+                Ok(())
+            })
+            .map(|_| ()),
+    );
+    messages
+}
+
+#[test]
+fn authenticate_and_receive_private_messages() {
+    let mut runtime = Runtime::new().unwrap();
+    let server = FakeIrc::start();
+    let client = irc::Client::new(server.address(), TAKEN_NICKNAME.to_string())
+        .without_tls()
+        .with_sasl(ACCOUNT.to_string(), PASSWORD.to_string());
+    let messages = run(&mut runtime, &client);
+
+    assert!(server.wait_for(|_| client.is_connected()));
+    assert_eq!(server.authenticated(), Some(true));
+    // the configured nickname is taken
+    assert_eq!(server.nickname().unwrap(), "gerritbot_");
+
+    server.send_message("jane", "#reviews", "gerritbot_: status");
+    server.send_private_message("jane", "\u{1}VERSION\u{1}");
+    server.send_private_message("jane", " status ");
+    let message = messages.recv_timeout(Duration::from_secs(10)).unwrap();
+    assert_eq!(message.nickname, "jane");
+    assert_eq!(message.text, "status");
+    assert!(messages.try_recv().is_err());
+}
+
+#[test]
+fn send_notices_and_private_messages() {
+    let mut runtime = Runtime::new().unwrap();
+    let server = FakeIrc::start();
+    let client = irc::Client::new(server.address(), "reviewbot".to_string())
+        .without_tls()
+        .with_channels(vec!["#reviews".to_string()]);
+    let _messages = run(&mut runtime, &client);
+    assert!(server.wait_for(|_| client.is_connected()));
+
+    client
+        .send_message("jane", "first line\n\nsecond line")
+        .unwrap();
+    client
+        .clone()
+        .with_delivery(irc::Delivery::Privmsg)
+        .send_message("#reviews", "hello")
+        .unwrap();
+    assert!(server.wait_for(|server| server.lines().len() >= 6));
+    assert_eq!(
+        server.lines(),
+        vec![
+            "NICK reviewbot",
+            "USER reviewbot 0 * gerritbot",
+            "JOIN #reviews",
+            "NOTICE jane :first line",
+            "NOTICE jane :second line",
+            "PRIVMSG #reviews hello",
+        ]
+    );
+}
+
+#[test]
+fn wrong_sasl_password_fails() {
+    let mut runtime = Runtime::new().unwrap();
+    let server = FakeIrc::start();
+    let client = irc::Client::new(server.address(), "reviewbot".to_string())
+        .without_tls()
+        .with_sasl(ACCOUNT.to_string(), "wrong".to_string());
+    let _messages = run(&mut runtime, &client);
+
+    assert!(server.wait_for(|server| server.authenticated().is_some()));
+    assert_eq!(server.authenticated(), Some(false));
+    assert!(!client.is_connected());
+    match client.send_message("jane", "hello") {
+        Err(irc::Error::NotConnected) => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
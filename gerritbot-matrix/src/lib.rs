@@ -0,0 +1,488 @@
+//! Client of the Matrix client-server API.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{error, fmt};
+
+use futures::future::{self, Future};
+use futures::{IntoFuture as _, Stream};
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use url::percent_encoding::{utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+
+mod sync;
+
+pub use sync::sync_stream;
+
+/// Path of the client-server API.
+const CLIENT_API: &str = "_matrix/client/r0";
+
+/// Path of the identity service API used for 3PID lookups.
+const IDENTITY_API: &str = "_matrix/identity/api/v1";
+
+/// Account data listing the direct rooms of the bot.
+const DIRECT_ROOMS_EVENT: &str = "m.direct";
+
+macro_rules! newtype_string {
+    ($type_name:ident) => {
+        #[derive(
+            Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+        )]
+        #[serde(transparent)]
+        pub struct $type_name(String);
+
+        impl $type_name {
+            pub fn new(s: String) -> Self {
+                Self(s)
+            }
+
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+
+            pub fn into_string(self) -> String {
+                self.0
+            }
+        }
+
+        impl fmt::Display for $type_name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                self.0.fmt(f)
+            }
+        }
+    };
+}
+
+// Matrix id of a user, e.g. `@gerritbot:example.org`
+newtype_string!(UserId);
+// Matrix id of a room, e.g. `!abcdef:example.org`
+newtype_string!(RoomId);
+// Matrix id of an event, e.g. `$abcdef:example.org`
+newtype_string!(EventId);
+
+impl UserId {
+    /// Name of the user without the leading `@` and the server name.
+    pub fn localpart(&self) -> &str {
+        let id = self.0.trim_start_matches('@');
+        id.split(':').next().unwrap_or(id)
+    }
+}
+
+/// A user's message to the bot, i.e. a message in a direct room or a message
+/// in a group room addressed to the bot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub sender: UserId,
+    pub room_id: RoomId,
+    pub event_id: EventId,
+    /// plain text body without the mention of the bot
+    pub text: String,
+    /// whether the message was sent in a direct room with the bot
+    pub direct: bool,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    ReqwestError(reqwest::Error),
+    JsonError(serde_json::Error),
+    /// The homeserver rejected the request with the given error code, e.g.
+    /// `M_FORBIDDEN`.
+    ApiError {
+        errcode: String,
+        error: String,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::ReqwestError(ref err) => fmt::Display::fmt(err, f),
+            Error::JsonError(ref err) => fmt::Display::fmt(err, f),
+            Error::ApiError {
+                ref errcode,
+                ref error,
+            } => write!(f, "matrix api error {}: {}", errcode, error),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::ReqwestError(ref err) => err.source(),
+            Error::JsonError(ref err) => err.source(),
+            Error::ApiError { .. } => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        Error::ReqwestError(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::JsonError(err)
+    }
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    errcode: String,
+    #[serde(default)]
+    error: String,
+}
+
+#[derive(Deserialize)]
+struct WhoamiResponse {
+    user_id: UserId,
+}
+
+#[derive(Deserialize)]
+struct EventResponse {
+    event_id: EventId,
+}
+
+#[derive(Deserialize)]
+struct RoomResponse {
+    room_id: RoomId,
+}
+
+#[derive(Deserialize)]
+struct LookupResponse {
+    #[serde(default)]
+    mxid: Option<UserId>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    client: reqwest::r#async::Client,
+    homeserver_url: String,
+    access_token: String,
+    user_id: UserId,
+    identity_server_url: Option<String>,
+    /// direct room of each user
+    direct_rooms: Arc<Mutex<HashMap<UserId, RoomId>>>,
+    /// transaction ids have to be unique for the access token, also across
+    /// restarts
+    txn_prefix: String,
+    next_txn_id: Arc<AtomicUsize>,
+}
+
+fn encode(segment: &str) -> String {
+    utf8_percent_encode(segment, PATH_SEGMENT_ENCODE_SET).to_string()
+}
+
+impl Client {
+    /// Create a client for the bot account with the given access token.
+    /// Resolves after the token was checked and the bot's user id is known.
+    pub fn new(
+        homeserver_url: String,
+        access_token: String,
+    ) -> impl Future<Item = Self, Error = Error> {
+        let txn_prefix = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis())
+            .unwrap_or_default()
+            .to_string();
+        let bootstrap_client = Client {
+            client: reqwest::r#async::Client::new(),
+            homeserver_url: homeserver_url.trim_end_matches('/').to_string(),
+            access_token,
+            user_id: UserId::default(),
+            identity_server_url: None,
+            direct_rooms: Default::default(),
+            txn_prefix,
+            next_txn_id: Default::default(),
+        };
+
+        bootstrap_client
+            .api_request(
+                bootstrap_client
+                    .client
+                    .get(&bootstrap_client.url("account/whoami")),
+            )
+            .map(|response: WhoamiResponse| response.user_id)
+            .map(|user_id| Client {
+                user_id,
+                ..bootstrap_client
+            })
+    }
+
+    /// Look up the users of emails at this identity server.
+    pub fn with_identity_server(self, identity_server_url: String) -> Self {
+        Self {
+            identity_server_url: Some(identity_server_url.trim_end_matches('/').to_string()),
+            ..self
+        }
+    }
+
+    /// User id of the bot.
+    pub fn id(&self) -> &UserId {
+        &self.user_id
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/{}/{}", self.homeserver_url, CLIENT_API, path)
+    }
+
+    fn direct_rooms(&self) -> MutexGuard<'_, HashMap<UserId, RoomId>> {
+        self.direct_rooms.lock().unwrap()
+    }
+
+    /// Whether the room is the direct room of a user with the bot.
+    pub fn is_direct_room(&self, room_id: &RoomId) -> bool {
+        self.direct_rooms().values().any(|room| room == room_id)
+    }
+
+    /// Replace the known direct rooms, e.g. with the ones of the account
+    /// data.
+    fn set_direct_rooms(&self, direct_rooms: HashMap<UserId, RoomId>) {
+        *self.direct_rooms() = direct_rooms;
+    }
+
+    fn add_direct_room(&self, user_id: UserId, room_id: RoomId) {
+        self.direct_rooms().insert(user_id, room_id);
+    }
+
+    /// Store the direct rooms in the account data, so that they are known
+    /// after a restart and to other clients of the bot account.
+    fn save_direct_rooms(&self) -> impl Future<Item = (), Error = Error> {
+        let content: HashMap<UserId, Vec<RoomId>> = self
+            .direct_rooms()
+            .iter()
+            .map(|(user_id, room_id)| (user_id.clone(), vec![room_id.clone()]))
+            .collect();
+        let request = self
+            .client
+            .put(&self.url(&format!(
+                "user/{}/account_data/{}",
+                encode(self.user_id.as_str()),
+                DIRECT_ROOMS_EVENT
+            )))
+            .json(&content);
+        self.api_request(request).map(|_: serde_json::Value| ())
+    }
+
+    fn next_txn_id(&self) -> String {
+        format!(
+            "{}.{}",
+            self.txn_prefix,
+            self.next_txn_id.fetch_add(1, Ordering::SeqCst)
+        )
+    }
+
+    /// Send an `m.room.message` event to the room.
+    fn send_event(
+        &self,
+        room_id: &RoomId,
+        content: &serde_json::Value,
+    ) -> impl Future<Item = EventId, Error = Error> {
+        let request = self
+            .client
+            .put(&self.url(&format!(
+                "rooms/{}/send/m.room.message/{}",
+                encode(room_id.as_str()),
+                encode(&self.next_txn_id())
+            )))
+            .json(content);
+        self.api_request(request)
+            .map(|response: EventResponse| response.event_id)
+    }
+
+    /// Send a message with a plain text and an HTML body, optionally as reply
+    /// to another message.
+    pub fn send_message(
+        &self,
+        room_id: &RoomId,
+        body: &str,
+        html: &str,
+        reply_to: Option<&EventId>,
+    ) -> impl Future<Item = EventId, Error = Error> {
+        debug!("send message to {}", room_id);
+        let mut content = message_content(body, html);
+        if let Some(event_id) = reply_to {
+            content["m.relates_to"] = json!({ "m.in_reply_to": { "event_id": event_id } });
+        }
+        self.send_event(room_id, &content)
+    }
+
+    /// Replace the content of a message sent before. Resolves to the id of
+    /// the event with the edit, the message itself keeps its id.
+    pub fn edit_message(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+        body: &str,
+        html: &str,
+    ) -> impl Future<Item = EventId, Error = Error> {
+        debug!("edit message {} in {}", event_id, room_id);
+        // clients without support for edits show the fallback
+        let mut content = message_content(&format!("* {}", body), &format!("* {}", html));
+        content["m.new_content"] = message_content(body, html);
+        content["m.relates_to"] = json!({ "rel_type": "m.replace", "event_id": event_id });
+        self.send_event(room_id, &content)
+    }
+
+    /// Direct room of the user with the bot. The room is created and the user
+    /// invited if there is none yet.
+    pub fn direct_room(&self, user_id: &UserId) -> impl Future<Item = RoomId, Error = Error> {
+        if let Some(room_id) = self.direct_rooms().get(user_id) {
+            return future::Either::A(future::ok(room_id.clone()));
+        }
+
+        debug!("creating direct room with {}", user_id);
+        let request = self.client.post(&self.url("createRoom")).json(&json!({
+            "is_direct": true,
+            "invite": [user_id],
+            "preset": "trusted_private_chat",
+        }));
+        let client = self.clone();
+        let user_id = user_id.clone();
+        future::Either::B(
+            self.api_request(request)
+                .and_then(move |response: RoomResponse| {
+                    let room_id = response.room_id;
+                    client.add_direct_room(user_id, room_id.clone());
+                    client.save_direct_rooms().then(move |result| {
+                        if let Err(e) = result {
+                            error!("failed to save direct rooms: {}", e);
+                        }
+                        Ok(room_id)
+                    })
+                }),
+        )
+    }
+
+    /// Join a room the bot was invited to.
+    pub fn join_room(&self, room_id: &RoomId) -> impl Future<Item = (), Error = Error> {
+        let request = self
+            .client
+            .post(&self.url(&format!("rooms/{}/join", encode(room_id.as_str()))))
+            .json(&json!({}));
+        self.api_request(request).map(|_: RoomResponse| ())
+    }
+
+    /// User the email is bound to at the identity server. Resolves to `None`
+    /// if the email is not bound or no identity server is configured.
+    pub fn lookup_email(&self, email: &str) -> impl Future<Item = Option<UserId>, Error = Error> {
+        let identity_server_url = match &self.identity_server_url {
+            Some(identity_server_url) => identity_server_url,
+            None => return future::Either::A(future::ok(None)),
+        };
+        let request = self
+            .client
+            .get(&format!("{}/{}/lookup", identity_server_url, IDENTITY_API))
+            .query(&[("medium", "email"), ("address", email)]);
+        future::Either::B(
+            request
+                .send()
+                .from_err()
+                .and_then(decode_response)
+                .map(|response: LookupResponse| response.mxid),
+        )
+    }
+
+    /// Sync with the homeserver, waiting at most `timeout` for new events.
+    fn sync(
+        &self,
+        since: Option<&str>,
+        timeout: Duration,
+    ) -> impl Future<Item = sync::SyncResponse, Error = Error> {
+        let mut query = vec![("timeout", timeout.as_millis().to_string())];
+        if let Some(since) = since {
+            query.push(("since", since.to_string()));
+        }
+        self.api_request(self.client.get(&self.url("sync")).query(&query))
+    }
+
+    fn api_request<R>(
+        &self,
+        request: reqwest::r#async::RequestBuilder,
+    ) -> impl Future<Item = R, Error = Error>
+    where
+        for<'a> R: Deserialize<'a>,
+    {
+        request
+            .bearer_auth(&self.access_token)
+            .send()
+            .from_err()
+            .and_then(decode_response)
+    }
+}
+
+fn message_content(body: &str, html: &str) -> serde_json::Value {
+    json!({
+        "msgtype": "m.text",
+        "body": body,
+        "format": "org.matrix.custom.html",
+        "formatted_body": html,
+    })
+}
+
+/// Decode the json body of a response. Failed requests are answered with an
+/// error code in the body.
+fn decode_response<T>(response: reqwest::r#async::Response) -> impl Future<Item = T, Error = Error>
+where
+    for<'a> T: Deserialize<'a>,
+{
+    let status = response.status();
+    response
+        .into_body()
+        .fold(Vec::new(), |mut v, chunk| {
+            v.extend_from_slice(&chunk);
+            future::ok::<_, reqwest::Error>(v)
+        })
+        .from_err()
+        .and_then(move |body| {
+            if status.is_success() {
+                serde_json::from_slice(&body).map_err(Error::from)
+            } else {
+                let response: ErrorResponse =
+                    serde_json::from_slice(&body).unwrap_or_else(|_| ErrorResponse {
+                        errcode: status.to_string(),
+                        error: String::from_utf8_lossy(&body).into_owned(),
+                    });
+                Err(Error::ApiError {
+                    errcode: response.errcode,
+                    error: response.error,
+                })
+            }
+            .into_future()
+        })
+}
+
+/// Render the Markdown produced by the bot's formatter as HTML for the
+/// formatted body of messages.
+pub fn markdown_to_html(markdown: &str) -> String {
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, pulldown_cmark::Parser::new(markdown));
+    html
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn render_markdown_as_html() {
+        assert_eq!(
+            markdown_to_html("[Fix the build](http://gerrit/1) 👍 +1 from **Jane**"),
+            "<p><a href=\"http://gerrit/1\">Fix the build</a> 👍 +1 from <strong>Jane</strong></p>\n"
+        );
+        assert_eq!(markdown_to_html("# Heading"), "<h1>Heading</h1>\n");
+    }
+
+    #[test]
+    fn localpart_of_user_id() {
+        assert_eq!(
+            UserId::new("@gerritbot:example.org".to_string()).localpart(),
+            "gerritbot"
+        );
+    }
+}
@@ -0,0 +1,294 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use futures::{future, stream, Future, Stream};
+use log::{debug, error, info};
+use serde::Deserialize;
+use tokio::timer::Delay;
+
+use crate::{Client, EventId, Message, RoomId, UserId, DIRECT_ROOMS_EVENT};
+
+/// Maximum time a sync waits for new events (long polling).
+const SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time to wait before syncing again after a failed sync.
+const SYNC_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub(crate) struct SyncResponse {
+    next_batch: String,
+    account_data: Events,
+    rooms: Rooms,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct Events {
+    events: Vec<Event>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct Rooms {
+    join: HashMap<RoomId, JoinedRoom>,
+    invite: HashMap<RoomId, InvitedRoom>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct JoinedRoom {
+    timeline: Events,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct InvitedRoom {
+    invite_state: Events,
+}
+
+#[derive(Deserialize, Debug)]
+struct Event {
+    #[serde(rename = "type")]
+    event_type: String,
+    #[serde(default)]
+    sender: Option<UserId>,
+    #[serde(default)]
+    event_id: Option<EventId>,
+    #[serde(default)]
+    state_key: Option<String>,
+    #[serde(default)]
+    content: serde_json::Value,
+}
+
+#[derive(Deserialize, Debug)]
+struct MessageContent {
+    msgtype: String,
+    body: String,
+    #[serde(default)]
+    formatted_body: Option<String>,
+    /// set for edits of messages
+    #[serde(default, rename = "m.new_content")]
+    new_content: Option<serde_json::Value>,
+}
+
+/// Stream of the messages to the bot.
+///
+/// Syncs with the homeserver in a loop. Messages which were sent before the
+/// stream was started are skipped. Invitations are accepted, and the rooms of
+/// invitations to direct chats are remembered as direct rooms of the
+/// inviting users.
+pub fn sync_stream(client: Client) -> impl Stream<Item = Message, Error = ()> {
+    info!("syncing with Matrix as {}", client.id());
+
+    stream::unfold(None, move |since: Option<String>| {
+        let client = client.clone();
+        // the initial sync returns right away and only establishes the state
+        let timeout = if since.is_some() {
+            SYNC_TIMEOUT
+        } else {
+            Duration::from_secs(0)
+        };
+        let sync = client
+            .sync(since.as_deref(), timeout)
+            .then(move |result| match result {
+                Ok(response) => {
+                    let initial = since.is_none();
+                    let next_batch = response.next_batch.clone();
+                    let messages = handle_sync_response(&client, response, initial);
+                    future::Either::A(future::ok((messages, Some(next_batch))))
+                }
+                // errors must not reach unfold, which would end the stream
+                Err(e) => {
+                    error!("failed to sync: {}", e);
+                    future::Either::B(
+                        Delay::new(Instant::now() + SYNC_RETRY_DELAY)
+                            .then(move |_| Ok((Vec::new(), since))),
+                    )
+                }
+            });
+        Some(sync)
+    })
+    .map(stream::iter_ok)
+    .flatten()
+}
+
+/// Update the direct rooms, accept invitations and collect the messages to
+/// the bot. The messages of the initial sync are skipped.
+fn handle_sync_response(client: &Client, response: SyncResponse, initial: bool) -> Vec<Message> {
+    let SyncResponse {
+        account_data,
+        rooms,
+        ..
+    } = response;
+
+    for event in account_data.events {
+        if event.event_type == DIRECT_ROOMS_EVENT {
+            client.set_direct_rooms(direct_rooms(event.content));
+        }
+    }
+
+    for (room_id, room) in rooms.invite {
+        accept_invitation(client, room_id, room);
+    }
+
+    if initial {
+        return Vec::new();
+    }
+    rooms
+        .join
+        .into_iter()
+        .flat_map(|(room_id, room)| {
+            room.timeline
+                .events
+                .into_iter()
+                .filter_map(move |event| message_from_event(client, &room_id, event))
+        })
+        .collect()
+}
+
+/// Direct rooms from the `m.direct` account data, which lists the rooms of
+/// each user. The last room of a user is used.
+fn direct_rooms(content: serde_json::Value) -> HashMap<UserId, RoomId> {
+    serde_json::from_value::<HashMap<UserId, Vec<RoomId>>>(content)
+        .map_err(|e| error!("failed to decode direct rooms: {}", e))
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|(user_id, mut rooms)| Some((user_id, rooms.pop()?)))
+        .collect()
+}
+
+fn accept_invitation(client: &Client, room_id: RoomId, room: InvitedRoom) {
+    let invitation = room.invite_state.events.into_iter().find(|event| {
+        event.event_type == "m.room.member"
+            && event.state_key.as_deref() == Some(client.id().as_str())
+    });
+    let (inviter, is_direct) = match invitation {
+        Some(event) => (event.sender, event.content["is_direct"] == true),
+        None => (None, false),
+    };
+    debug!("accepting invitation to {} from {:?}", room_id, inviter);
+
+    // remember the direct room right away, the user's first message might
+    // arrive before the room is joined
+    let save_direct_rooms = match inviter {
+        Some(inviter) if is_direct => {
+            client.add_direct_room(inviter, room_id.clone());
+            true
+        }
+        _ => false,
+    };
+    let joined_client = client.clone();
+    let join = client.join_room(&room_id).and_then(move |()| {
+        if save_direct_rooms {
+            future::Either::A(joined_client.save_direct_rooms())
+        } else {
+            future::Either::B(future::ok(()))
+        }
+    });
+    tokio::spawn(join.map_err(|e| error!("failed to accept invitation: {}", e)));
+}
+
+/// Convert a message event to a message to the bot. Messages in group rooms
+/// have to start with a mention of the bot, which is removed.
+fn message_from_event(client: &Client, room_id: &RoomId, event: Event) -> Option<Message> {
+    if event.event_type != "m.room.message" {
+        return None;
+    }
+    let sender = event.sender?;
+    if sender == *client.id() {
+        return None;
+    }
+    let content: MessageContent = serde_json::from_value(event.content).ok()?;
+    if content.msgtype != "m.text" || content.new_content.is_some() {
+        return None;
+    }
+
+    let direct = client.is_direct_room(room_id);
+    let text = if direct {
+        content.body.trim().to_string()
+    } else {
+        strip_mention(
+            &content.body,
+            content.formatted_body.as_deref(),
+            client.id(),
+        )?
+    };
+    Some(Message {
+        sender,
+        room_id: room_id.clone(),
+        event_id: event.event_id?,
+        text,
+        direct,
+    })
+}
+
+/// Text of a message without the leading mention of the bot, or `None` if the
+/// message does not start with a mention. Clients mention users by their id,
+/// their name or a pill with the display name, e.g. `GerritBot: status`.
+fn strip_mention(body: &str, formatted_body: Option<&str>, bot_id: &UserId) -> Option<String> {
+    let body = body.trim_start();
+    let localpart = bot_id.localpart();
+    let starts_with_localpart = body
+        .get(..localpart.len())
+        .map_or(false, |start| start.eq_ignore_ascii_case(localpart));
+    let pill = format!("https://matrix.to/#/{}", bot_id);
+
+    let rest = if body.starts_with(bot_id.as_str()) {
+        &body[bot_id.as_str().len()..]
+    } else if starts_with_localpart {
+        &body[localpart.len()..]
+    } else if formatted_body.map_or(false, |html| html.contains(&pill)) {
+        &body[body.find(':')? + 1..]
+    } else {
+        return None;
+    };
+    Some(
+        rest.trim_start_matches(|c: char| c == ':' || c == ',' || c.is_whitespace())
+            .trim_end()
+            .to_string(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn strip_mentions() {
+        let bot_id = UserId::new("@gerritbot:example.org".to_string());
+        assert_eq!(
+            strip_mention("@gerritbot:example.org status", None, &bot_id).as_deref(),
+            Some("status")
+        );
+        assert_eq!(
+            strip_mention("GerritBot: status", None, &bot_id).as_deref(),
+            Some("status")
+        );
+        assert_eq!(
+            strip_mention(
+                "Gerrit Bot: status",
+                Some(
+                    r#"<a href="https://matrix.to/#/@gerritbot:example.org">Gerrit Bot</a>: status"#
+                ),
+                &bot_id
+            )
+            .as_deref(),
+            Some("status")
+        );
+        assert_eq!(strip_mention("status", None, &bot_id), None);
+    }
+
+    #[test]
+    fn decode_direct_rooms() {
+        let rooms = direct_rooms(serde_json::json!({
+            "@alice:example.org": ["!old:example.org", "!new:example.org"],
+            "@bob:example.org": [],
+        }));
+        assert_eq!(rooms.len(), 1);
+        assert_eq!(
+            rooms[&UserId::new("@alice:example.org".to_string())].as_str(),
+            "!new:example.org"
+        );
+    }
+}
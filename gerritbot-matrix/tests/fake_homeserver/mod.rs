@@ -0,0 +1,363 @@
+//! In-process stand-in for a Matrix homeserver and identity server.
+//!
+//! Implements the parts of the client-server API used by the client:
+//! `whoami`, `sync`, sending messages, creating and joining rooms and account
+//! data, as well as the 3PID lookup of the identity service API. Messages of
+//! users and invitations are added with `FakeHomeserver::post_message` and
+//! `FakeHomeserver::invite_bot` and delivered by the next sync.
+
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use futures::{future, Future, Stream};
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde_json::{json, Value};
+use tokio::runtime::Runtime;
+use tokio::timer::Delay;
+use url::percent_encoding::percent_decode;
+
+pub const ACCESS_TOKEN: &str = "fake-access-token";
+pub const BOT_ID: &str = "@gerritbot:localhost";
+
+type ResponseFuture = Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+
+/// Time to wait before answering a sync without new events.
+const EMPTY_SYNC_DELAY: Duration = Duration::from_millis(20);
+
+#[derive(Debug, Clone)]
+enum Update {
+    Timeline {
+        room_id: String,
+        event: Value,
+    },
+    Invite {
+        room_id: String,
+        inviter: String,
+        is_direct: bool,
+    },
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// events in the order they happened, the index is the sync token
+    updates: Vec<Update>,
+    joined_rooms: HashSet<String>,
+    created_rooms: Vec<Value>,
+    account_data: HashMap<String, Value>,
+    /// bound emails of the identity server
+    emails: HashMap<String, String>,
+    syncs: usize,
+    next_id: usize,
+}
+
+impl State {
+    fn new_id(&mut self, sigil: char, prefix: &str) -> String {
+        self.next_id += 1;
+        format!("{}{}{}:localhost", sigil, prefix, self.next_id)
+    }
+
+    fn add_message(&mut self, room_id: &str, sender: &str, content: Value) -> String {
+        let event_id = self.new_id('$', "event");
+        self.updates.push(Update::Timeline {
+            room_id: room_id.to_string(),
+            event: json!({
+                "type": "m.room.message",
+                "event_id": event_id,
+                "sender": sender,
+                "content": content,
+                "origin_server_ts": 0,
+            }),
+        });
+        event_id
+    }
+
+    fn sync(&mut self, since: Option<usize>) -> Value {
+        self.syncs += 1;
+        let mut join: HashMap<String, Vec<Value>> = HashMap::new();
+        let mut invite: HashMap<String, Value> = HashMap::new();
+        let start = since.unwrap_or(0);
+        for update in self.updates.iter().skip(start) {
+            match update {
+                Update::Timeline { room_id, event } => {
+                    if since.is_some() && self.joined_rooms.contains(room_id) {
+                        join.entry(room_id.clone()).or_default().push(event.clone());
+                    }
+                }
+                Update::Invite {
+                    room_id,
+                    inviter,
+                    is_direct,
+                } => {
+                    if !self.joined_rooms.contains(room_id) {
+                        invite.insert(room_id.clone(), invite_json(inviter, *is_direct));
+                    }
+                }
+            }
+        }
+        let account_data: Vec<Value> = if since.is_none() {
+            self.account_data
+                .iter()
+                .map(|(event_type, content)| json!({ "type": event_type, "content": content }))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let join: HashMap<String, Value> = join
+            .into_iter()
+            .map(|(room_id, events)| (room_id, json!({ "timeline": { "events": events } })))
+            .collect();
+        json!({
+            "next_batch": self.updates.len().to_string(),
+            "account_data": { "events": account_data },
+            "rooms": { "join": join, "invite": invite },
+        })
+    }
+}
+
+fn invite_json(inviter: &str, is_direct: bool) -> Value {
+    json!({
+        "invite_state": {
+            "events": [{
+                "type": "m.room.member",
+                "sender": inviter,
+                "state_key": BOT_ID,
+                "content": { "membership": "invite", "is_direct": is_direct },
+            }]
+        }
+    })
+}
+
+/// Handle to a running fake homeserver.
+#[derive(Clone)]
+pub struct FakeHomeserver {
+    address: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl FakeHomeserver {
+    /// Start the server on a free local port. It runs as long as the runtime.
+    pub fn start(runtime: &mut Runtime) -> Self {
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let service_state = state.clone();
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(move || {
+            let state = service_state.clone();
+            service_fn(move |request| handle(&state, request))
+        });
+        let address = server.local_addr();
+        runtime.spawn(server.map_err(|e| panic!("fake homeserver failed: {}", e)));
+
+        Self { address, state }
+    }
+
+    /// Url of the homeserver, also serving the identity service API.
+    pub fn url(&self) -> String {
+        format!("http://{}", self.address)
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// Number of syncs answered so far.
+    pub fn syncs(&self) -> usize {
+        self.state().syncs
+    }
+
+    /// Create a room and invite the bot to it. Returns the room's id.
+    pub fn invite_bot(&self, inviter: &str, is_direct: bool) -> String {
+        let mut state = self.state();
+        let room_id = state.new_id('!', "room");
+        state.updates.push(Update::Invite {
+            room_id: room_id.clone(),
+            inviter: inviter.to_string(),
+            is_direct,
+        });
+        room_id
+    }
+
+    /// Post a message of a user to a room. Returns the event's id.
+    pub fn post_message(
+        &self,
+        room_id: &str,
+        sender: &str,
+        body: &str,
+        formatted_body: Option<&str>,
+    ) -> String {
+        let mut content = json!({ "msgtype": "m.text", "body": body });
+        if let Some(formatted_body) = formatted_body {
+            content["format"] = json!("org.matrix.custom.html");
+            content["formatted_body"] = json!(formatted_body);
+        }
+        self.state().add_message(room_id, sender, content)
+    }
+
+    /// Room id and content of the messages sent by the bot.
+    pub fn sent_messages(&self) -> Vec<(String, Value)> {
+        self.state()
+            .updates
+            .iter()
+            .filter_map(|update| match update {
+                Update::Timeline { room_id, event } if event["sender"] == BOT_ID => {
+                    Some((room_id.clone(), event["content"].clone()))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Bodies of the `createRoom` requests.
+    pub fn created_rooms(&self) -> Vec<Value> {
+        self.state().created_rooms.clone()
+    }
+
+    pub fn is_joined(&self, room_id: &str) -> bool {
+        self.state().joined_rooms.contains(room_id)
+    }
+
+    pub fn account_data(&self, event_type: &str) -> Option<Value> {
+        self.state().account_data.get(event_type).cloned()
+    }
+
+    pub fn set_account_data(&self, event_type: &str, content: Value) {
+        self.state()
+            .account_data
+            .insert(event_type.to_string(), content);
+    }
+
+    /// Mark the room as joined by the bot, e.g. a direct room of a previous
+    /// run.
+    pub fn join(&self, room_id: &str) {
+        self.state().joined_rooms.insert(room_id.to_string());
+    }
+
+    /// Bind an email to a user at the identity server.
+    pub fn bind_email(&self, email: &str, user_id: &str) {
+        self.state()
+            .emails
+            .insert(email.to_string(), user_id.to_string());
+    }
+}
+
+fn json_response(status: StatusCode, body: &Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn error_response(status: StatusCode, errcode: &str) -> Response<Body> {
+    json_response(status, &json!({ "errcode": errcode, "error": errcode }))
+}
+
+/// Value of a query parameter.
+fn query_param(query: &str, name: &str) -> Option<String> {
+    url::form_urlencoded::parse(query.as_bytes())
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+fn handle(state: &Arc<Mutex<State>>, request: Request<Body>) -> ResponseFuture {
+    let state = state.clone();
+    let method = request.method().clone();
+    let query = request.uri().query().unwrap_or_default().to_string();
+    let path: Vec<String> = request
+        .uri()
+        .path()
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| {
+            percent_decode(segment.as_bytes())
+                .decode_utf8_lossy()
+                .into_owned()
+        })
+        .collect();
+    let authorized = request
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .map(|value| value.as_bytes() == format!("Bearer {}", ACCESS_TOKEN).as_bytes())
+        .unwrap_or(false);
+
+    Box::new(request.into_body().concat2().and_then(move |body| {
+        let body: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+        let path: Vec<&str> = path.iter().map(String::as_str).collect();
+
+        if let ["_matrix", "identity", "api", "v1", "lookup"] = &path[..] {
+            let address = query_param(&query, "address").unwrap_or_default();
+            let response = match state.lock().unwrap().emails.get(&address) {
+                Some(user_id) => json!({ "address": address, "medium": "email", "mxid": user_id }),
+                None => json!({}),
+            };
+            return future::Either::A(future::ok(json_response(StatusCode::OK, &response)));
+        }
+        if !authorized {
+            return future::Either::A(future::ok(error_response(
+                StatusCode::UNAUTHORIZED,
+                "M_UNKNOWN_TOKEN",
+            )));
+        }
+        let path = match &path[..] {
+            ["_matrix", "client", "r0", path @ ..] => path,
+            _ => {
+                return future::Either::A(future::ok(error_response(
+                    StatusCode::NOT_FOUND,
+                    "M_UNRECOGNIZED",
+                )))
+            }
+        };
+
+        let mut state_guard = state.lock().unwrap();
+        let response = match (method, path) {
+            (Method::GET, ["account", "whoami"]) => {
+                json_response(StatusCode::OK, &json!({ "user_id": BOT_ID }))
+            }
+            (Method::GET, ["sync"]) => {
+                let since: Option<usize> =
+                    query_param(&query, "since").and_then(|since| since.parse().ok());
+                if since == Some(state_guard.updates.len()) {
+                    // do not let the client spin without new events
+                    drop(state_guard);
+                    return future::Either::B(Delay::new(Instant::now() + EMPTY_SYNC_DELAY).then(
+                        move |_| {
+                            Ok(json_response(
+                                StatusCode::OK,
+                                &state.lock().unwrap().sync(since),
+                            ))
+                        },
+                    ));
+                }
+                json_response(StatusCode::OK, &state_guard.sync(since))
+            }
+            (Method::PUT, ["rooms", room_id, "send", "m.room.message", _txn_id]) => {
+                if !state_guard.joined_rooms.contains(*room_id) {
+                    error_response(StatusCode::FORBIDDEN, "M_FORBIDDEN")
+                } else {
+                    let event_id = state_guard.add_message(room_id, BOT_ID, body);
+                    json_response(StatusCode::OK, &json!({ "event_id": event_id }))
+                }
+            }
+            (Method::POST, ["createRoom"]) => {
+                let room_id = state_guard.new_id('!', "room");
+                state_guard.joined_rooms.insert(room_id.clone());
+                state_guard.created_rooms.push(body);
+                json_response(StatusCode::OK, &json!({ "room_id": room_id }))
+            }
+            (Method::POST, ["rooms", room_id, "join"]) => {
+                state_guard.joined_rooms.insert(room_id.to_string());
+                json_response(StatusCode::OK, &json!({ "room_id": room_id }))
+            }
+            (Method::PUT, ["user", user_id, "account_data", event_type]) if *user_id == BOT_ID => {
+                state_guard
+                    .account_data
+                    .insert(event_type.to_string(), body);
+                json_response(StatusCode::OK, &json!({}))
+            }
+            _ => error_response(StatusCode::NOT_FOUND, "M_UNRECOGNIZED"),
+        };
+        future::Either::A(future::ok(response))
+    }))
+}
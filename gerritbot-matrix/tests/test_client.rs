@@ -0,0 +1,208 @@
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use futures::Stream;
+use serde_json::json;
+use tokio::runtime::Runtime;
+
+use gerritbot_matrix as matrix;
+
+mod fake_homeserver;
+
+use fake_homeserver::{FakeHomeserver, ACCESS_TOKEN, BOT_ID};
+
+fn setup() -> (Runtime, FakeHomeserver, matrix::Client) {
+    let mut runtime = Runtime::new().unwrap();
+    let homeserver = FakeHomeserver::start(&mut runtime);
+    let client = runtime
+        .block_on(matrix::Client::new(
+            homeserver.url(),
+            ACCESS_TOKEN.to_string(),
+        ))
+        .expect("failed to create client");
+    (runtime, homeserver, client)
+}
+
+fn wait_until(mut condition: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !condition() {
+        assert!(Instant::now() < deadline, "timed out");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn room_id(id: &str) -> matrix::RoomId {
+    matrix::RoomId::new(id.to_string())
+}
+
+fn user_id(id: &str) -> matrix::UserId {
+    matrix::UserId::new(id.to_string())
+}
+
+#[test]
+fn client_gets_bot_id() {
+    let (_runtime, _homeserver, client) = setup();
+    assert_eq!(client.id().as_str(), BOT_ID);
+}
+
+#[test]
+fn client_with_invalid_token_fails() {
+    let mut runtime = Runtime::new().unwrap();
+    let homeserver = FakeHomeserver::start(&mut runtime);
+    let client = runtime.block_on(matrix::Client::new(
+        homeserver.url(),
+        "invalid-token".to_string(),
+    ));
+    match client {
+        Err(matrix::Error::ApiError { errcode, .. }) => assert_eq!(errcode, "M_UNKNOWN_TOKEN"),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn send_and_edit_message() {
+    let (mut runtime, homeserver, client) = setup();
+    let room = homeserver.invite_bot("@alice:localhost", false);
+    homeserver.join(&room);
+    let parent = homeserver.post_message(&room, "@alice:localhost", "hi", None);
+
+    let sent = runtime
+        .block_on(client.send_message(
+            &room_id(&room),
+            "**hello**",
+            "<strong>hello</strong>",
+            Some(&matrix::EventId::new(parent.clone())),
+        ))
+        .unwrap();
+    runtime
+        .block_on(client.edit_message(&room_id(&room), &sent, "hello again", "hello again"))
+        .unwrap();
+
+    let messages = homeserver.sent_messages();
+    assert_eq!(messages.len(), 2);
+    let (message_room, content) = &messages[0];
+    assert_eq!(message_room, &room);
+    assert_eq!(content["body"], "**hello**");
+    assert_eq!(content["format"], "org.matrix.custom.html");
+    assert_eq!(content["formatted_body"], "<strong>hello</strong>");
+    assert_eq!(content["m.relates_to"]["m.in_reply_to"]["event_id"], parent);
+    let (_, edit) = &messages[1];
+    assert_eq!(edit["m.new_content"]["body"], "hello again");
+    assert_eq!(edit["m.relates_to"]["rel_type"], "m.replace");
+    assert_eq!(edit["m.relates_to"]["event_id"], sent.as_str());
+}
+
+#[test]
+fn create_direct_room_once() {
+    let (mut runtime, homeserver, client) = setup();
+    let alice = user_id("@alice:localhost");
+
+    let room = runtime.block_on(client.direct_room(&alice)).unwrap();
+    let same_room = runtime.block_on(client.direct_room(&alice)).unwrap();
+    assert_eq!(room, same_room);
+    assert!(client.is_direct_room(&room));
+
+    let created_rooms = homeserver.created_rooms();
+    assert_eq!(created_rooms.len(), 1);
+    assert_eq!(created_rooms[0]["is_direct"], true);
+    assert_eq!(created_rooms[0]["invite"], json!(["@alice:localhost"]));
+    assert_eq!(
+        homeserver.account_data("m.direct"),
+        Some(json!({ "@alice:localhost": [room.as_str()] }))
+    );
+}
+
+#[test]
+fn look_up_emails() {
+    let (mut runtime, homeserver, client) = setup();
+    homeserver.bind_email("alice@example.com", "@alice:localhost");
+
+    // without identity server
+    assert_eq!(
+        runtime
+            .block_on(client.lookup_email("alice@example.com"))
+            .unwrap(),
+        None
+    );
+
+    let client = client.with_identity_server(homeserver.url());
+    assert_eq!(
+        runtime
+            .block_on(client.lookup_email("alice@example.com"))
+            .unwrap(),
+        Some(user_id("@alice:localhost"))
+    );
+    assert_eq!(
+        runtime
+            .block_on(client.lookup_email("bob@example.com"))
+            .unwrap(),
+        None
+    );
+}
+
+#[test]
+fn receive_messages_via_sync() {
+    let (mut runtime, homeserver, client) = setup();
+    // direct room of a previous run
+    let old_room = homeserver.invite_bot("@carol:localhost", true);
+    homeserver.join(&old_room);
+    homeserver.set_account_data("m.direct", json!({ "@carol:localhost": [old_room] }));
+    homeserver.post_message(&old_room, "@carol:localhost", "sent before the start", None);
+
+    let (sender, messages) = mpsc::channel();
+    runtime.spawn(
+        matrix::sync_stream(client.clone()).for_each(move |message| {
+            sender.send(message).unwrap();
+            Ok(())
+        }),
+    );
+    wait_until(|| homeserver.syncs() > 1);
+
+    let direct_room = homeserver.invite_bot("@alice:localhost", true);
+    let group_room = homeserver.invite_bot("@bob:localhost", false);
+    wait_until(|| homeserver.is_joined(&direct_room) && homeserver.is_joined(&group_room));
+    assert!(client.is_direct_room(&room_id(&direct_room)));
+    assert!(!client.is_direct_room(&room_id(&group_room)));
+
+    let status = homeserver.post_message(&direct_room, "@alice:localhost", "status", None);
+    homeserver.post_message(&group_room, "@bob:localhost", "not for the bot", None);
+    let help = homeserver.post_message(&group_room, "@bob:localhost", "gerritbot: help", None);
+    let version = homeserver.post_message(&old_room, "@carol:localhost", " version ", None);
+
+    // the order of messages in different rooms is not defined
+    let mut received: Vec<matrix::Message> = (0..3)
+        .map(|_| messages.recv_timeout(Duration::from_secs(5)).unwrap())
+        .collect();
+    received.sort_by_key(|message| message.room_id.clone());
+    assert_eq!(
+        received,
+        vec![
+            matrix::Message {
+                sender: user_id("@carol:localhost"),
+                room_id: room_id(&old_room),
+                event_id: matrix::EventId::new(version),
+                text: "version".to_string(),
+                direct: true,
+            },
+            matrix::Message {
+                sender: user_id("@alice:localhost"),
+                room_id: room_id(&direct_room),
+                event_id: matrix::EventId::new(status),
+                text: "status".to_string(),
+                direct: true,
+            },
+            matrix::Message {
+                sender: user_id("@bob:localhost"),
+                room_id: room_id(&group_room),
+                event_id: matrix::EventId::new(help),
+                text: "help".to_string(),
+                direct: false,
+            },
+        ]
+    );
+    assert!(messages.try_recv().is_err());
+    assert_eq!(
+        homeserver.account_data("m.direct").unwrap()["@alice:localhost"],
+        json!([direct_room])
+    );
+}
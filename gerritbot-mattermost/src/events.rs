@@ -0,0 +1,243 @@
+//! Receive the messages to the bot over the websocket of the Mattermost API.
+
+use std::time::{Duration, Instant};
+
+use futures::future::{self, Future};
+use futures::sync::mpsc;
+use futures::{stream, IntoFuture as _, Sink, Stream};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::timer::Delay;
+use websocket::{ClientBuilder, OwnedMessage};
+
+use crate::{ChannelType, Client, Message, Post, User, UserId};
+
+/// Delay before reconnecting after the websocket was closed.
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Debug)]
+struct AuthenticationChallenge<'a> {
+    seq: u64,
+    action: &'a str,
+    data: AuthenticationData<'a>,
+}
+
+#[derive(Serialize, Debug)]
+struct AuthenticationData<'a> {
+    token: &'a str,
+}
+
+/// Event received over the websocket. Replies to the requests of the client
+/// have no event type.
+#[derive(Deserialize, Debug)]
+struct WebsocketEvent {
+    #[serde(default)]
+    event: String,
+    #[serde(default)]
+    data: EventData,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct EventData {
+    /// `D` for direct message channels
+    channel_type: String,
+    /// the post as json string
+    post: Option<String>,
+    /// ids of the mentioned users as json string
+    mentions: Option<String>,
+}
+
+/// Convert an event to a message to the bot. Direct messages and posts
+/// mentioning the bot are converted, everything else is ignored. The email of
+/// the sender is not known yet and left empty.
+fn message_from_event(event: WebsocketEvent, bot: &User) -> Option<Message> {
+    if event.event != "posted" {
+        return None;
+    }
+    let post: Post = serde_json::from_str(event.data.post.as_ref()?).ok()?;
+    if post.user_id == bot.id || !post.post_type.is_empty() {
+        return None;
+    }
+    let channel_type = if event.data.channel_type == "D" {
+        ChannelType::Direct
+    } else {
+        let mentions: Vec<UserId> = event
+            .data
+            .mentions
+            .and_then(|mentions| serde_json::from_str(&mentions).ok())
+            .unwrap_or_default();
+        if !mentions.contains(&bot.id) {
+            return None;
+        }
+        ChannelType::Group
+    };
+    let mention = format!("@{}", bot.username);
+    let root_id = Some(post.root_id).filter(|root_id| !root_id.as_str().is_empty());
+    Some(Message {
+        user_id: post.user_id,
+        user_email: String::new(),
+        channel_id: post.channel_id,
+        channel_type,
+        text: post.message.replace(&mention, "").trim().to_string(),
+        post_id: post.id,
+        root_id,
+    })
+}
+
+/// Connect to the websocket once and stream the messages to the bot until
+/// the connection is closed.
+fn connect(client: &Client) -> impl Stream<Item = Message, Error = ()> {
+    let authentication = serde_json::to_string(&AuthenticationChallenge {
+        seq: 1,
+        action: "authentication_challenge",
+        data: AuthenticationData {
+            token: &client.access_token,
+        },
+    })
+    .expect("failed to serialize authentication challenge");
+    let bot = client.bot.clone();
+
+    info!("connecting to websocket {}", client.websocket_url);
+    ClientBuilder::new(&client.websocket_url)
+        .map_err(|e| error!("invalid websocket url: {}", e))
+        .into_future()
+        .and_then(|builder| {
+            builder
+                .async_connect(None)
+                .and_then(move |(client, _headers)| client.send(OwnedMessage::Text(authentication)))
+                .map_err(|e| error!("failed to connect to websocket: {}", e))
+        })
+        .map(move |client| {
+            info!("connected to websocket");
+            let (sink, messages) = client.split();
+            // pongs are sent by a separate task
+            let (reply_sink, replies) = mpsc::unbounded();
+            tokio::spawn(
+                replies
+                    .forward(sink.sink_map_err(|e| error!("failed to reply on websocket: {}", e)))
+                    .map(|_| ()),
+            );
+
+            messages
+                .map_err(|e| error!("failed to receive from websocket: {}", e))
+                .take_while(|message| future::ok(!message.is_close()))
+                .filter_map(move |message| match message {
+                    OwnedMessage::Text(text) => match serde_json::from_str(&text) {
+                        Ok(event) => message_from_event(event, &bot),
+                        Err(e) => {
+                            warn!("failed to parse websocket message: {}", e);
+                            None
+                        }
+                    },
+                    OwnedMessage::Ping(data) => {
+                        let _ = reply_sink.unbounded_send(OwnedMessage::Pong(data));
+                        None
+                    }
+                    _ => None,
+                })
+        })
+        .flatten_stream()
+}
+
+/// Stream of the messages to the bot received over the websocket. The
+/// websocket is reconnected if the connection is closed. Messages of users
+/// whose email cannot be fetched are dropped.
+pub fn event_stream(client: Client) -> impl Stream<Item = Message, Error = ()> {
+    let email_client = client.clone();
+    stream::iter_ok::<_, ()>(0..)
+        .map(move |attempt: u64| {
+            let delay = if attempt == 0 {
+                future::Either::A(future::ok(()))
+            } else {
+                warn!("websocket closed, reconnecting in {:?}", RECONNECT_DELAY);
+                future::Either::B(
+                    Delay::new(Instant::now() + RECONNECT_DELAY)
+                        .map_err(|e| error!("reconnect timer failed: {}", e)),
+                )
+            };
+            let client = client.clone();
+            // errors are already logged, they only end the connection
+            delay
+                .map(move |()| connect(&client))
+                .flatten_stream()
+                .then(Ok::<_, ()>)
+                .take_while(|result| future::ok(result.is_ok()))
+                .filter_map(Result::ok)
+        })
+        .flatten()
+        .and_then(move |message| {
+            email_client
+                .get_user_email(&message.user_id)
+                .then(move |result| match result {
+                    Ok(user_email) => Ok(Some(Message {
+                        user_email,
+                        ..message
+                    })),
+                    Err(e) => {
+                        error!("failed to get email of {}: {}", message.user_id, e);
+                        Ok(None)
+                    }
+                })
+        })
+        .filter_map(|message| message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn bot() -> User {
+        User {
+            id: UserId::new("bot".to_string()),
+            username: "gerritbot".to_string(),
+            email: String::new(),
+        }
+    }
+
+    fn posted(channel_type: &str, user_id: &str, message: &str, mentions: &str) -> WebsocketEvent {
+        let post = serde_json::json!({
+            "id": "p2",
+            "user_id": user_id,
+            "channel_id": "c1",
+            "root_id": "p1",
+            "message": message,
+            "type": "",
+        });
+        serde_json::from_value(serde_json::json!({
+            "event": "posted",
+            "data": {
+                "channel_type": channel_type,
+                "post": post.to_string(),
+                "mentions": mentions,
+            },
+            "broadcast": { "channel_id": "c1" },
+            "seq": 2,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn convert_events_to_messages() {
+        let message = message_from_event(posted("D", "u1", "status", "[]"), &bot()).unwrap();
+        assert_eq!(message.channel_type, ChannelType::Direct);
+        assert_eq!(message.user_id.as_str(), "u1");
+        assert_eq!(message.channel_id.as_str(), "c1");
+        assert_eq!(message.post_id.as_str(), "p2");
+        assert_eq!(message.root_id.unwrap().as_str(), "p1");
+        assert_eq!(message.text, "status");
+
+        let message =
+            message_from_event(posted("O", "u1", "@gerritbot help", r#"["bot"]"#), &bot()).unwrap();
+        assert_eq!(message.channel_type, ChannelType::Group);
+        assert_eq!(message.text, "help");
+
+        // posts in channels without mention
+        assert!(message_from_event(posted("O", "u1", "hi", "[]"), &bot()).is_none());
+        // own posts
+        assert!(message_from_event(posted("D", "bot", "hi", "[]"), &bot()).is_none());
+        // replies to requests of the client
+        let reply = serde_json::from_str(r#"{"status":"OK","seq_reply":1}"#).unwrap();
+        assert!(message_from_event(reply, &bot()).is_none());
+    }
+}
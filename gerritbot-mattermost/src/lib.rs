@@ -0,0 +1,409 @@
+//! Client of the Mattermost REST API (v4) and receiver of the events of its
+//! websocket.
+
+use std::{error, fmt};
+
+use futures::future::{self, Future};
+use futures::{IntoFuture as _, Stream};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use url::percent_encoding::{utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+
+mod events;
+
+pub use events::event_stream;
+
+/// Path of the REST API.
+const API_PATH: &str = "api/v4";
+
+macro_rules! newtype_string {
+    ($type_name:ident) => {
+        #[derive(
+            Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+        )]
+        #[serde(transparent)]
+        pub struct $type_name(String);
+
+        impl $type_name {
+            pub fn new(s: String) -> Self {
+                Self(s)
+            }
+
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+
+            pub fn into_string(self) -> String {
+                self.0
+            }
+        }
+
+        impl fmt::Display for $type_name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                self.0.fmt(f)
+            }
+        }
+    };
+}
+
+// Mattermost id of a user, e.g. `4xp9fdt77pncbef59f4k1qe83o`
+newtype_string!(UserId);
+// Mattermost id of a channel
+newtype_string!(ChannelId);
+// Mattermost id of a post, i.e. a message
+newtype_string!(PostId);
+
+/// Kind of the channel a message was sent in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelType {
+    /// direct message channel between a user and the bot
+    Direct,
+    /// public or private channel, or group message
+    Group,
+}
+
+/// A user's message to the bot, i.e. a direct message or a message in a
+/// channel mentioning the bot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub user_id: UserId,
+    /// email of the sender
+    pub user_email: String,
+    pub channel_id: ChannelId,
+    pub channel_type: ChannelType,
+    /// text without the mention of the bot
+    pub text: String,
+    pub post_id: PostId,
+    /// root post of the thread if sent in a thread
+    pub root_id: Option<PostId>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct User {
+    pub id: UserId,
+    #[serde(default)]
+    pub username: String,
+    /// Empty unless the server shows emails or the bot may see them.
+    #[serde(default)]
+    pub email: String,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Post {
+    pub id: PostId,
+    pub channel_id: ChannelId,
+    #[serde(default)]
+    pub user_id: UserId,
+    /// empty if the post is not part of a thread
+    #[serde(default)]
+    pub root_id: PostId,
+    #[serde(default)]
+    pub message: String,
+    /// empty for posts of users, set for system messages, e.g. joins
+    #[serde(default, rename = "type")]
+    pub post_type: String,
+}
+
+#[derive(Deserialize)]
+struct ChannelResponse {
+    id: ChannelId,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    #[serde(default)]
+    id: String,
+    #[serde(default)]
+    message: String,
+}
+
+#[derive(Serialize, Debug)]
+struct CreatePostParameters<'a> {
+    channel_id: &'a ChannelId,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    root_id: Option<&'a PostId>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    ReqwestError(reqwest::Error),
+    JsonError(serde_json::Error),
+    /// The server rejected the request, e.g. `api.context.session_expired.app_error`.
+    ApiError {
+        status: u16,
+        id: String,
+        message: String,
+    },
+    /// The email of the user is hidden from the bot.
+    MissingEmail(UserId),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::ReqwestError(ref err) => fmt::Display::fmt(err, f),
+            Error::JsonError(ref err) => fmt::Display::fmt(err, f),
+            Error::ApiError {
+                status,
+                ref id,
+                ref message,
+            } => write!(f, "mattermost api error {} ({}): {}", status, id, message),
+            Error::MissingEmail(ref user_id) => write!(f, "email of user {} is hidden", user_id),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::ReqwestError(ref err) => err.source(),
+            Error::JsonError(ref err) => err.source(),
+            Error::ApiError { .. } | Error::MissingEmail(_) => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        Error::ReqwestError(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::JsonError(err)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    client: reqwest::r#async::Client,
+    server_url: String,
+    websocket_url: String,
+    access_token: String,
+    bot: User,
+}
+
+fn encode(segment: &str) -> String {
+    utf8_percent_encode(segment, PATH_SEGMENT_ENCODE_SET).to_string()
+}
+
+/// Url of the websocket of the server, i.e. the server url with a websocket
+/// scheme.
+fn default_websocket_url(server_url: &str) -> String {
+    let url = if server_url.starts_with("https://") {
+        server_url.replacen("https://", "wss://", 1)
+    } else {
+        server_url.replacen("http://", "ws://", 1)
+    };
+    format!("{}/{}/websocket", url, API_PATH)
+}
+
+impl Client {
+    /// Create a client for the bot account with the given access token, e.g.
+    /// the token of a bot account or a personal access token. Resolves after
+    /// the token was checked and the bot's user is known.
+    pub fn new(
+        server_url: String,
+        access_token: String,
+    ) -> impl Future<Item = Self, Error = Error> {
+        let server_url = server_url.trim_end_matches('/').to_string();
+        let bootstrap_client = Client {
+            client: reqwest::r#async::Client::new(),
+            websocket_url: default_websocket_url(&server_url),
+            server_url,
+            access_token,
+            bot: User {
+                id: UserId::default(),
+                username: String::new(),
+                email: String::new(),
+            },
+        };
+
+        bootstrap_client
+            .api_request(
+                bootstrap_client
+                    .client
+                    .get(&bootstrap_client.url("users/me")),
+            )
+            .map(|bot| Client {
+                bot,
+                ..bootstrap_client
+            })
+    }
+
+    /// Receive events from this websocket url instead of the one of the
+    /// server, e.g. if a proxy forwards websockets elsewhere.
+    pub fn with_websocket_url(self, websocket_url: String) -> Self {
+        Self {
+            websocket_url,
+            ..self
+        }
+    }
+
+    /// User id of the bot.
+    pub fn id(&self) -> &UserId {
+        &self.bot.id
+    }
+
+    /// User name of the bot, which is used to mention it.
+    pub fn username(&self) -> &str {
+        &self.bot.username
+    }
+
+    pub fn websocket_url(&self) -> &str {
+        &self.websocket_url
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/{}/{}", self.server_url, API_PATH, path)
+    }
+
+    /// Create a post in a channel, optionally as reply in the thread of
+    /// another post. The message is rendered as Markdown by the clients.
+    pub fn create_post(
+        &self,
+        channel_id: &ChannelId,
+        message: &str,
+        root_id: Option<&PostId>,
+    ) -> impl Future<Item = Post, Error = Error> {
+        debug!("create post in {}", channel_id);
+        let request = self
+            .client
+            .post(&self.url("posts"))
+            .json(&CreatePostParameters {
+                channel_id,
+                message,
+                root_id,
+            });
+        self.api_request(request)
+    }
+
+    /// Replace the message of a post of the bot.
+    pub fn update_post(
+        &self,
+        post_id: &PostId,
+        message: &str,
+    ) -> impl Future<Item = Post, Error = Error> {
+        debug!("update post {}", post_id);
+        let request = self
+            .client
+            .put(&self.url(&format!("posts/{}/patch", encode(post_id.as_str()))))
+            .json(&json!({ "message": message }));
+        self.api_request(request)
+    }
+
+    /// Direct message channel of the user with the bot. The channel is
+    /// created if there is none yet.
+    pub fn direct_channel(&self, user_id: &UserId) -> impl Future<Item = ChannelId, Error = Error> {
+        let request = self
+            .client
+            .post(&self.url("channels/direct"))
+            .json(&[&self.bot.id, user_id]);
+        self.api_request(request)
+            .map(|channel: ChannelResponse| channel.id)
+    }
+
+    pub fn get_user(&self, user_id: &UserId) -> impl Future<Item = User, Error = Error> {
+        let request = self
+            .client
+            .get(&self.url(&format!("users/{}", encode(user_id.as_str()))));
+        self.api_request(request)
+    }
+
+    /// Find the user with the given email.
+    pub fn get_user_by_email(&self, email: &str) -> impl Future<Item = User, Error = Error> {
+        let request = self
+            .client
+            .get(&self.url(&format!("users/email/{}", encode(email))));
+        self.api_request(request)
+    }
+
+    /// Email of the user.
+    pub fn get_user_email(&self, user_id: &UserId) -> impl Future<Item = String, Error = Error> {
+        self.get_user(user_id).and_then(|user| {
+            if user.email.is_empty() {
+                Err(Error::MissingEmail(user.id))
+            } else {
+                Ok(user.email)
+            }
+        })
+    }
+
+    fn api_request<R>(
+        &self,
+        request: reqwest::r#async::RequestBuilder,
+    ) -> impl Future<Item = R, Error = Error>
+    where
+        for<'a> R: Deserialize<'a>,
+    {
+        request
+            .bearer_auth(&self.access_token)
+            .send()
+            .from_err()
+            .and_then(decode_response)
+    }
+}
+
+/// Decode the json body of a response. Failed requests are answered with an
+/// error id and message in the body.
+fn decode_response<T>(response: reqwest::r#async::Response) -> impl Future<Item = T, Error = Error>
+where
+    for<'a> T: Deserialize<'a>,
+{
+    let status = response.status();
+    response
+        .into_body()
+        .fold(Vec::new(), |mut v, chunk| {
+            v.extend_from_slice(&chunk);
+            future::ok::<_, reqwest::Error>(v)
+        })
+        .from_err()
+        .and_then(move |body| {
+            if status.is_success() {
+                serde_json::from_slice(&body).map_err(Error::from)
+            } else {
+                let response: ErrorResponse =
+                    serde_json::from_slice(&body).unwrap_or_else(|_| ErrorResponse {
+                        id: String::new(),
+                        message: String::from_utf8_lossy(&body).into_owned(),
+                    });
+                Err(Error::ApiError {
+                    status: status.as_u16(),
+                    id: response.id,
+                    message: response.message,
+                })
+            }
+            .into_future()
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn websocket_url_of_server() {
+        assert_eq!(
+            default_websocket_url("https://chat.example.org"),
+            "wss://chat.example.org/api/v4/websocket"
+        );
+        assert_eq!(
+            default_websocket_url("http://localhost:8065"),
+            "ws://localhost:8065/api/v4/websocket"
+        );
+    }
+
+    #[test]
+    fn deserialize_post() {
+        let post: Post = serde_json::from_str(
+            r#"{"id":"p1","create_at":1,"user_id":"u1","channel_id":"c1","root_id":"","message":"hello","type":"","props":{}}"#,
+        )
+        .unwrap();
+        assert_eq!(post.id, PostId::new("p1".to_string()));
+        assert_eq!(post.root_id, PostId::default());
+        assert_eq!(post.message, "hello");
+    }
+}
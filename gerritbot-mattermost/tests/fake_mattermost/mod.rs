@@ -0,0 +1,347 @@
+//! In-process stand-in for a Mattermost server.
+//!
+//! Implements the parts of the REST API used by the client: the bot's and
+//! other users, direct channels, creating and patching posts. Posts of users
+//! are added with `FakeMattermost::post_direct_message` and
+//! `FakeMattermost::post_mention` and delivered as `posted` events over the
+//! websocket, which is served on a separate port. Events posted before the
+//! bot connected are delivered once it has authenticated.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use futures::sync::mpsc;
+use futures::{future, Future, Sink, Stream};
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde_json::{json, Value};
+use tokio::runtime::Runtime;
+use url::percent_encoding::percent_decode;
+use websocket::r#async::Server;
+use websocket::OwnedMessage;
+
+pub const ACCESS_TOKEN: &str = "fake-access-token";
+pub const BOT_ID: &str = "botuserid";
+pub const BOT_USERNAME: &str = "gerritbot";
+
+type ResponseFuture = Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+
+#[derive(Debug, Default)]
+struct State {
+    users: Vec<Value>,
+    posts: Vec<Value>,
+    /// direct channels by the sorted ids of their members
+    direct_channels: HashMap<(String, String), String>,
+    authenticated: bool,
+    next_id: usize,
+}
+
+impl State {
+    fn new_id(&mut self, prefix: &str) -> String {
+        self.next_id += 1;
+        format!("{}{}", prefix, self.next_id)
+    }
+
+    fn user_by_email(&mut self, email: &str) -> Value {
+        if let Some(user) = self.users.iter().find(|user| user["email"] == email) {
+            return user.clone();
+        }
+        let id = self.new_id("user");
+        let user = user_json(&id, email.split('@').next().unwrap_or(email), email);
+        self.users.push(user.clone());
+        user
+    }
+
+    fn direct_channel(&mut self, user_id: &str, other_user_id: &str) -> String {
+        let mut members = [user_id.to_string(), other_user_id.to_string()];
+        members.sort();
+        let [first, second] = members;
+        if let Some(channel_id) = self.direct_channels.get(&(first.clone(), second.clone())) {
+            return channel_id.clone();
+        }
+        let channel_id = self.new_id("channel");
+        self.direct_channels
+            .insert((first, second), channel_id.clone());
+        channel_id
+    }
+
+    fn add_post(&mut self, channel_id: &str, user_id: &str, message: &str, root_id: &str) -> Value {
+        let id = self.new_id("post");
+        let post = json!({
+            "id": id,
+            "channel_id": channel_id,
+            "user_id": user_id,
+            "root_id": root_id,
+            "message": message,
+            "type": "",
+        });
+        self.posts.push(post.clone());
+        post
+    }
+}
+
+fn user_json(id: &str, username: &str, email: &str) -> Value {
+    json!({ "id": id, "username": username, "email": email })
+}
+
+fn posted_event(post: &Value, channel_type: &str, mentions: &[&str]) -> String {
+    json!({
+        "event": "posted",
+        "data": {
+            "channel_type": channel_type,
+            "post": post.to_string(),
+            "mentions": json!(mentions).to_string(),
+        },
+        "broadcast": { "channel_id": post["channel_id"] },
+    })
+    .to_string()
+}
+
+/// Handle to a running fake Mattermost server.
+#[derive(Clone)]
+pub struct FakeMattermost {
+    address: SocketAddr,
+    websocket_address: SocketAddr,
+    state: Arc<Mutex<State>>,
+    events: mpsc::UnboundedSender<String>,
+}
+
+impl FakeMattermost {
+    /// Start the server on free local ports. It runs as long as the runtime.
+    pub fn start(runtime: &mut Runtime) -> Self {
+        let state = Arc::new(Mutex::new(State::default()));
+        state
+            .lock()
+            .unwrap()
+            .users
+            .push(user_json(BOT_ID, BOT_USERNAME, "gerritbot@localhost"));
+
+        let service_state = state.clone();
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(move || {
+            let state = service_state.clone();
+            service_fn(move |request| handle(&state, request))
+        });
+        let address = server.local_addr();
+        runtime.spawn(server.map_err(|e| panic!("fake mattermost server failed: {}", e)));
+
+        let (events, event_receiver) = mpsc::unbounded();
+        let websocket_server = Server::bind("127.0.0.1:0", &Default::default()).unwrap();
+        let websocket_address = websocket_server.local_addr().unwrap();
+        runtime.spawn(serve_websocket(
+            websocket_server,
+            state.clone(),
+            event_receiver,
+        ));
+
+        Self {
+            address,
+            websocket_address,
+            state,
+            events,
+        }
+    }
+
+    /// Url of the server.
+    pub fn url(&self) -> String {
+        format!("http://{}", self.address)
+    }
+
+    pub fn websocket_url(&self) -> String {
+        format!("ws://{}/api/v4/websocket", self.websocket_address)
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// Whether the bot authenticated on the websocket.
+    pub fn is_authenticated(&self) -> bool {
+        self.state().authenticated
+    }
+
+    /// Add a user and return its id.
+    pub fn add_user(&self, email: &str) -> String {
+        let user = self.state().user_by_email(email);
+        user["id"].as_str().unwrap().to_string()
+    }
+
+    /// Posts so far, including the ones of the bot.
+    pub fn posts(&self) -> Vec<Value> {
+        self.state().posts.clone()
+    }
+
+    /// Post a direct message from a user to the bot. Returns the post.
+    pub fn post_direct_message(&self, email: &str, message: &str) -> Value {
+        let post = {
+            let mut state = self.state();
+            let user = state.user_by_email(email);
+            let user_id = user["id"].as_str().unwrap();
+            let channel_id = state.direct_channel(user_id, BOT_ID);
+            state.add_post(&channel_id, user_id, message, "")
+        };
+        self.events
+            .unbounded_send(posted_event(&post, "D", &[]))
+            .unwrap();
+        post
+    }
+
+    /// Post a message mentioning the bot in a channel. Returns the post.
+    pub fn post_mention(&self, email: &str, channel_id: &str, message: &str) -> Value {
+        let post = {
+            let mut state = self.state();
+            let user = state.user_by_email(email);
+            let user_id = user["id"].as_str().unwrap();
+            state.add_post(channel_id, user_id, message, "")
+        };
+        self.events
+            .unbounded_send(posted_event(&post, "O", &[BOT_ID]))
+            .unwrap();
+        post
+    }
+}
+
+/// Accept a single websocket connection, check its authentication challenge
+/// and forward the events to it.
+fn serve_websocket(
+    server: Server<websocket::server::NoTlsAcceptor>,
+    state: Arc<Mutex<State>>,
+    events: mpsc::UnboundedReceiver<String>,
+) -> impl Future<Item = (), Error = ()> {
+    server
+        .incoming()
+        .take(1)
+        .map_err(|e| panic!("failed to accept connection: {:?}", e.error))
+        .and_then(|(upgrade, _addr)| upgrade.accept().map_err(|e| panic!("{}", e)))
+        .and_then(|(client, _headers)| client.into_future().map_err(|(e, _)| panic!("{}", e)))
+        .into_future()
+        .map_err(|_| ())
+        .and_then(move |(connection, _)| {
+            let (challenge, client) = connection.expect("no websocket connection");
+            let challenge: Value = match challenge {
+                Some(OwnedMessage::Text(text)) => serde_json::from_str(&text).unwrap(),
+                message => panic!("unexpected message: {:?}", message),
+            };
+            assert_eq!(challenge["action"], "authentication_challenge");
+            if challenge["data"]["token"] != ACCESS_TOKEN {
+                return future::Either::A(future::ok(()));
+            }
+            state.lock().unwrap().authenticated = true;
+
+            let reply = json!({ "status": "OK", "seq_reply": challenge["seq"] }).to_string();
+            future::Either::B(
+                client
+                    .send(OwnedMessage::Text(reply))
+                    .map_err(|e| panic!("{}", e))
+                    .and_then(|client| {
+                        let (sink, _messages) = client.split();
+                        events
+                            .map(OwnedMessage::Text)
+                            .forward(sink.sink_map_err(|e| panic!("{}", e)))
+                            .map(|_| ())
+                    }),
+            )
+        })
+}
+
+fn json_response(status: StatusCode, body: &Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn error_response(status: StatusCode, id: &str) -> Response<Body> {
+    json_response(
+        status,
+        &json!({ "id": id, "message": id, "status_code": status.as_u16() }),
+    )
+}
+
+fn handle(state: &Arc<Mutex<State>>, request: Request<Body>) -> ResponseFuture {
+    let state = state.clone();
+    let method = request.method().clone();
+    let path: Vec<String> = request
+        .uri()
+        .path()
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| {
+            percent_decode(segment.as_bytes())
+                .decode_utf8_lossy()
+                .into_owned()
+        })
+        .collect();
+    let authorized = request
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .map(|value| value.as_bytes() == format!("Bearer {}", ACCESS_TOKEN).as_bytes())
+        .unwrap_or(false);
+
+    Box::new(request.into_body().concat2().map(move |body| {
+        if !authorized {
+            return error_response(
+                StatusCode::UNAUTHORIZED,
+                "api.context.session_expired.app_error",
+            );
+        }
+        let body: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+        let path: Vec<&str> = path.iter().map(String::as_str).collect();
+        let mut state = state.lock().unwrap();
+        let find_user = |state: &State, key: &str, value: &str| {
+            state.users.iter().find(|user| user[key] == value).cloned()
+        };
+
+        match (method, &path[..]) {
+            (Method::GET, ["api", "v4", "users", "me"]) => {
+                json_response(StatusCode::OK, &find_user(&state, "id", BOT_ID).unwrap())
+            }
+            (Method::GET, ["api", "v4", "users", "email", email]) => {
+                match find_user(&state, "email", email) {
+                    Some(user) => json_response(StatusCode::OK, &user),
+                    None => error_response(
+                        StatusCode::NOT_FOUND,
+                        "store.sql_user.missing_account.const",
+                    ),
+                }
+            }
+            (Method::GET, ["api", "v4", "users", user_id]) => {
+                match find_user(&state, "id", user_id) {
+                    Some(user) => json_response(StatusCode::OK, &user),
+                    None => error_response(
+                        StatusCode::NOT_FOUND,
+                        "store.sql_user.missing_account.const",
+                    ),
+                }
+            }
+            (Method::POST, ["api", "v4", "channels", "direct"]) => {
+                let members: Vec<String> = serde_json::from_value(body).unwrap();
+                let channel_id = state.direct_channel(&members[0], &members[1]);
+                json_response(
+                    StatusCode::CREATED,
+                    &json!({ "id": channel_id, "type": "D" }),
+                )
+            }
+            (Method::POST, ["api", "v4", "posts"]) => {
+                let post = state.add_post(
+                    body["channel_id"].as_str().unwrap(),
+                    BOT_ID,
+                    body["message"].as_str().unwrap(),
+                    body["root_id"].as_str().unwrap_or_default(),
+                );
+                json_response(StatusCode::CREATED, &post)
+            }
+            (Method::PUT, ["api", "v4", "posts", post_id, "patch"]) => {
+                match state.posts.iter_mut().find(|post| post["id"] == *post_id) {
+                    Some(post) => {
+                        post["message"] = body["message"].clone();
+                        json_response(StatusCode::OK, post)
+                    }
+                    None => error_response(StatusCode::NOT_FOUND, "app.post.get.app_error"),
+                }
+            }
+            _ => error_response(StatusCode::NOT_FOUND, "api.context.404.app_error"),
+        }
+    }))
+}
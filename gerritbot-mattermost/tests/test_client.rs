@@ -0,0 +1,124 @@
+use std::time::Duration;
+
+use futures::Stream;
+use tokio::prelude::FutureExt as _;
+use tokio::runtime::Runtime;
+
+use gerritbot_mattermost as mattermost;
+
+mod fake_mattermost;
+
+use fake_mattermost::{FakeMattermost, ACCESS_TOKEN, BOT_ID, BOT_USERNAME};
+
+fn setup() -> (Runtime, FakeMattermost, mattermost::Client) {
+    let mut runtime = Runtime::new().unwrap();
+    let mattermost = FakeMattermost::start(&mut runtime);
+    let client = runtime
+        .block_on(mattermost::Client::new(
+            mattermost.url(),
+            ACCESS_TOKEN.to_string(),
+        ))
+        .expect("failed to create client")
+        .with_websocket_url(mattermost.websocket_url());
+    (runtime, mattermost, client)
+}
+
+#[test]
+fn client_gets_bot_user() {
+    let (_runtime, _mattermost, client) = setup();
+    assert_eq!(client.id().as_str(), BOT_ID);
+    assert_eq!(client.username(), BOT_USERNAME);
+}
+
+#[test]
+fn client_with_invalid_token_fails() {
+    let mut runtime = Runtime::new().unwrap();
+    let mattermost = FakeMattermost::start(&mut runtime);
+    let client = runtime.block_on(mattermost::Client::new(
+        mattermost.url(),
+        "invalid-token".to_string(),
+    ));
+    match client {
+        Err(mattermost::Error::ApiError { status, id, .. }) => {
+            assert_eq!(status, 401);
+            assert_eq!(id, "api.context.session_expired.app_error");
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn create_and_update_posts() {
+    let (mut runtime, mattermost, client) = setup();
+    let user_id = mattermost::UserId::new(mattermost.add_user("author@example.com"));
+
+    let channel_id = runtime.block_on(client.direct_channel(&user_id)).unwrap();
+    assert_eq!(
+        runtime.block_on(client.direct_channel(&user_id)).unwrap(),
+        channel_id
+    );
+
+    let post = runtime
+        .block_on(client.create_post(&channel_id, "**hello**", None))
+        .unwrap();
+    let reply = runtime
+        .block_on(client.create_post(&channel_id, "reply", Some(&post.id)))
+        .unwrap();
+    assert_eq!(reply.root_id, post.id);
+    let updated = runtime
+        .block_on(client.update_post(&post.id, "hello again"))
+        .unwrap();
+    assert_eq!(updated.id, post.id);
+
+    let posts = mattermost.posts();
+    assert_eq!(posts.len(), 2);
+    assert_eq!(posts[0]["message"], "hello again");
+    assert_eq!(posts[0]["user_id"], BOT_ID);
+    assert_eq!(posts[1]["root_id"], post.id.as_str());
+}
+
+#[test]
+fn resolve_users_by_email() {
+    let (mut runtime, mattermost, client) = setup();
+    let user_id = mattermost.add_user("author@example.com");
+
+    let user = runtime
+        .block_on(client.get_user_by_email("author@example.com"))
+        .unwrap();
+    assert_eq!(user.id.as_str(), user_id);
+    let email = runtime.block_on(client.get_user_email(&user.id)).unwrap();
+    assert_eq!(email, "author@example.com");
+
+    match runtime.block_on(client.get_user_by_email("unknown@example.com")) {
+        Err(mattermost::Error::ApiError { status: 404, .. }) => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn receive_messages_over_websocket() {
+    let (mut runtime, mattermost, client) = setup();
+    let direct = mattermost.post_direct_message("author@example.com", "status");
+    let mention = mattermost.post_mention("other@example.com", "town-square", "@gerritbot help");
+
+    let messages = runtime
+        .block_on(
+            mattermost::event_stream(client)
+                .take(2)
+                .collect()
+                .timeout(Duration::from_secs(10)),
+        )
+        .expect("failed to receive messages");
+    assert!(mattermost.is_authenticated());
+
+    assert_eq!(messages[0].post_id.as_str(), direct["id"]);
+    assert_eq!(messages[0].channel_type, mattermost::ChannelType::Direct);
+    assert_eq!(messages[0].user_email, "author@example.com");
+    assert_eq!(messages[0].text, "status");
+
+    assert_eq!(messages[1].post_id.as_str(), mention["id"]);
+    assert_eq!(messages[1].channel_type, mattermost::ChannelType::Group);
+    assert_eq!(messages[1].channel_id.as_str(), "town-square");
+    assert_eq!(messages[1].user_email, "other@example.com");
+    assert_eq!(messages[1].text, "help");
+}
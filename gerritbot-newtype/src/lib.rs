@@ -0,0 +1,104 @@
+//! Newtypes of strings shared by the bot and the chat service clients, e.g.
+//! ids of users and rooms, so that ids of different kinds cannot be mixed up.
+
+/// Define a newtype String and its borrowed counterpart.
+///
+/// The crate using it needs `serde` as dependency.
+#[macro_export]
+macro_rules! newtype_string {
+    ($type_name:ident, $type_ref_name:ident) => {
+        #[derive(
+            serde::Deserialize,
+            serde::Serialize,
+            Clone,
+            Debug,
+            Default,
+            PartialEq,
+            Eq,
+            PartialOrd,
+            Ord,
+            Hash,
+        )]
+        #[serde(transparent)]
+        pub struct $type_name(String);
+
+        impl $type_name {
+            pub fn new(s: String) -> Self {
+                Self(s)
+            }
+
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+
+            pub fn into_string(self) -> String {
+                self.0
+            }
+        }
+
+        impl std::fmt::Display for $type_name {
+            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                self.0.fmt(f)
+            }
+        }
+
+        #[derive(serde::Serialize, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        #[serde(transparent)]
+        pub struct $type_ref_name(str);
+
+        impl $type_ref_name {
+            pub fn new(s: &str) -> &Self {
+                unsafe { &*(s as *const str as *const Self) }
+            }
+
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl std::fmt::Display for $type_ref_name {
+            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                self.0.fmt(f)
+            }
+        }
+
+        impl std::borrow::Borrow<$type_ref_name> for $type_name {
+            fn borrow(&self) -> &$type_ref_name {
+                &*self
+            }
+        }
+
+        impl std::borrow::ToOwned for $type_ref_name {
+            type Owned = $type_name;
+            fn to_owned(&self) -> Self::Owned {
+                Self::Owned::new(self.0.to_string())
+            }
+        }
+
+        impl std::ops::Deref for $type_name {
+            type Target = $type_ref_name;
+
+            fn deref(&self) -> &$type_ref_name {
+                Self::Target::new(&self.0)
+            }
+        }
+
+        impl<'a> std::cmp::PartialEq<&'a $type_ref_name> for $type_name {
+            fn eq(&self, other: &&$type_ref_name) -> bool {
+                self.0 == other.0
+            }
+        }
+
+        impl<'a, 'b> std::cmp::PartialEq<&'a $type_ref_name> for &'b $type_name {
+            fn eq(&self, other: &&$type_ref_name) -> bool {
+                self.0 == other.0
+            }
+        }
+
+        impl<'a> std::cmp::PartialEq<$type_name> for &'a $type_ref_name {
+            fn eq(&self, other: &$type_name) -> bool {
+                self.0 == other.0
+            }
+        }
+    };
+}
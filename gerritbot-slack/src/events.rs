@@ -0,0 +1,344 @@
+//! Receiver of the Slack Events API.
+
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::sync::mpsc::channel;
+use futures::{Future, Sink, Stream};
+use hmac::{Hmac, Mac};
+use hyper::service::service_fn;
+use hyper::{Body, Request, Response, StatusCode};
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use sha2::Sha256;
+
+use crate::{ChannelId, ChannelType, Client, Message, MessageTs, UserId};
+
+/// Version of the signature scheme, used as prefix of the signed string and
+/// the signature.
+const SIGNATURE_VERSION: &str = "v0";
+
+/// Seconds after which a signed request is rejected to prevent replays.
+const MAX_REQUEST_AGE: u64 = 5 * 60;
+
+type ResponseFuture = Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+
+pub struct EventsServer<M, S> {
+    /// Messages to the bot. Must be polled in order for the server to
+    /// proceed.
+    pub messages: M,
+    /// Future of the server. Must be run in order for messages to produce
+    /// anything.
+    pub server: S,
+}
+
+/// Request posted by the Events API.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum EventRequest {
+    /// Sent once when the request url is configured.
+    UrlVerification {
+        challenge: String,
+    },
+    EventCallback {
+        event: Event,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize, Debug)]
+struct Event {
+    #[serde(rename = "type")]
+    event_type: String,
+    #[serde(default)]
+    subtype: Option<String>,
+    /// set for messages of bots, including the own ones
+    #[serde(default)]
+    bot_id: Option<String>,
+    #[serde(default)]
+    user: Option<UserId>,
+    #[serde(default)]
+    text: String,
+    #[serde(default)]
+    channel: Option<ChannelId>,
+    #[serde(default)]
+    channel_type: Option<String>,
+    #[serde(default)]
+    ts: Option<MessageTs>,
+    #[serde(default)]
+    thread_ts: Option<MessageTs>,
+}
+
+/// Check the signature of a request of the Events API, cf.
+/// https://api.slack.com/authentication/verifying-requests-from-slack
+///
+/// `now` is the current unix time in seconds.
+pub fn verify_signature(
+    signing_secret: &str,
+    timestamp: &str,
+    body: &[u8],
+    signature: &str,
+    now: u64,
+) -> bool {
+    let request_time: u64 = match timestamp.parse() {
+        Ok(request_time) => request_time,
+        Err(_) => return false,
+    };
+    if request_time + MAX_REQUEST_AGE < now || now + MAX_REQUEST_AGE < request_time {
+        return false;
+    }
+    let code = match signature
+        .strip_prefix(SIGNATURE_VERSION)
+        .and_then(|signature| signature.strip_prefix('='))
+        .and_then(|signature| hex::decode(signature).ok())
+    {
+        Some(code) => code,
+        None => return false,
+    };
+    let mut mac = match Hmac::<Sha256>::new(signing_secret.as_bytes()) {
+        Ok(mac) => mac,
+        Err(_) => return false,
+    };
+    mac.input(format!("{}:{}:", SIGNATURE_VERSION, timestamp).as_bytes());
+    mac.input(body);
+    mac.verify(&code).is_ok()
+}
+
+/// Convert an event to a message to the bot. Direct messages and mentions of
+/// the bot are converted, everything else is ignored. The email of the sender
+/// is not known yet and left empty.
+fn message_from_event(event: Event, bot_id: &UserId) -> Option<Message> {
+    let channel_type = match (event.event_type.as_str(), event.channel_type.as_deref()) {
+        ("message", Some("im")) => ChannelType::Direct,
+        ("app_mention", _) => ChannelType::Group,
+        _ => return None,
+    };
+    // ignore edits, joins etc. as well as messages of bots
+    if event.subtype.is_some() || event.bot_id.is_some() {
+        return None;
+    }
+    let user = event.user?;
+    if user == *bot_id {
+        return None;
+    }
+    let mention = format!("<@{}>", bot_id);
+    Some(Message {
+        user,
+        user_email: String::new(),
+        channel: event.channel?,
+        channel_type,
+        text: event.text.replace(&mention, "").trim().to_string(),
+        ts: event.ts?,
+        thread_ts: event.thread_ts,
+    })
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+fn header<'a>(request: &'a http::request::Parts, name: &str) -> Option<&'a str> {
+    request
+        .headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Start a server receiving the requests of the Events API. Requests are
+/// rejected unless they are signed with the signing secret of the app.
+pub fn start_events_server(
+    listen_address: &SocketAddr,
+    signing_secret: String,
+    client: Client,
+) -> EventsServer<
+    impl Stream<Item = Message, Error = ()>,
+    impl Future<Item = (), Error = hyper::Error>,
+> {
+    let (message_sink, messages) = channel(1);
+
+    info!("listening to Slack on {}", listen_address);
+
+    let server = hyper::Server::bind(listen_address).serve(move || {
+        let message_sink = message_sink.clone();
+        let signing_secret = signing_secret.clone();
+        let client = client.clone();
+
+        service_fn(move |request: Request<Body>| -> ResponseFuture {
+            debug!("events request: {:?}", request);
+
+            let message_sink = message_sink.clone();
+            let signing_secret = signing_secret.clone();
+            let client = client.clone();
+            let (parts, body) = request.into_parts();
+
+            Box::new(body.concat2().map(move |body| {
+                let timestamp = header(&parts, "X-Slack-Request-Timestamp").unwrap_or_default();
+                let signature = header(&parts, "X-Slack-Signature").unwrap_or_default();
+                if !verify_signature(&signing_secret, timestamp, &body, signature, unix_time()) {
+                    warn!("rejecting events request with invalid signature");
+                    return status(StatusCode::UNAUTHORIZED);
+                }
+
+                let event = match serde_json::from_slice(&body) {
+                    Ok(EventRequest::UrlVerification { challenge }) => {
+                        return Response::new(Body::from(challenge));
+                    }
+                    Ok(EventRequest::EventCallback { event }) => event,
+                    Ok(EventRequest::Other) => return status(StatusCode::OK),
+                    Err(e) => {
+                        error!("failed to decode events request: {}", e);
+                        return status(StatusCode::BAD_REQUEST);
+                    }
+                };
+
+                if let Some(message) = message_from_event(event, client.id()) {
+                    // answer right away, Slack retries requests which take
+                    // longer than 3 seconds
+                    tokio::spawn(
+                        client
+                            .get_user_email(&message.user)
+                            .map_err(|e| error!("failed to get email of sender: {}", e))
+                            .map(|user_email| Message {
+                                user_email,
+                                ..message
+                            })
+                            .and_then(|message| {
+                                message_sink
+                                    .send(message)
+                                    .map_err(|e| error!("failed to send message: {}", e))
+                                    .map(|_| ())
+                            }),
+                    );
+                }
+                status(StatusCode::OK)
+            }))
+        })
+    });
+
+    EventsServer { messages, server }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SECRET: &str = "8f742231b10e8888abcd99yyyzzz85a5";
+    const TIMESTAMP: &str = "1531420618";
+    const BODY: &[u8] = b"token=xyzz0WbapA4vBCDEFasx0q6G&team_id=T1DC2JH3J&team_domain=testteamnow&channel_id=G8PSS9T3V&channel_name=foobar&user_id=U2CERLKJA&user_name=roadrunner&command=%2Fwebhook-collect&text=&response_url=https%3A%2F%2Fhooks.slack.com%2Fcommands%2FT1DC2JH3J%2F397700885554%2F96rGlfmibIGlgcZRskXaIFfN&trigger_id=398738663015.47445629121.803a0bc887a14d10d2c447fce8b6703c";
+    const SIGNATURE: &str = "v0=a2114d57b48eac39b9ad189dd8316235a7b4a8d21a10bd27519666489c69b503";
+
+    #[test]
+    fn verify_valid_signature() {
+        assert!(verify_signature(
+            SECRET,
+            TIMESTAMP,
+            BODY,
+            SIGNATURE,
+            1_531_420_618
+        ));
+        assert!(verify_signature(
+            SECRET,
+            TIMESTAMP,
+            BODY,
+            SIGNATURE,
+            1_531_420_618 + 60
+        ));
+    }
+
+    #[test]
+    fn reject_invalid_signature() {
+        assert!(!verify_signature(
+            "other secret",
+            TIMESTAMP,
+            BODY,
+            SIGNATURE,
+            1_531_420_618
+        ));
+        assert!(!verify_signature(
+            SECRET,
+            TIMESTAMP,
+            b"other body",
+            SIGNATURE,
+            1_531_420_618
+        ));
+        assert!(!verify_signature(
+            SECRET,
+            TIMESTAMP,
+            BODY,
+            "v0=00",
+            1_531_420_618
+        ));
+        assert!(!verify_signature(
+            SECRET,
+            TIMESTAMP,
+            BODY,
+            &SIGNATURE[3..],
+            1_531_420_618
+        ));
+        // replayed request
+        assert!(!verify_signature(
+            SECRET,
+            TIMESTAMP,
+            BODY,
+            SIGNATURE,
+            1_531_420_618 + 10 * 60
+        ));
+    }
+
+    fn event(json: &str) -> Event {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn convert_events_to_messages() {
+        let bot_id = UserId::new("UBOT".to_string());
+
+        let message = message_from_event(
+            event(r#"{"type":"message","channel_type":"im","user":"U1","text":"status","channel":"D1","ts":"1.1"}"#),
+            &bot_id,
+        )
+        .unwrap();
+        assert_eq!(message.channel_type, ChannelType::Direct);
+        assert_eq!(message.channel, ChannelId::new("D1".to_string()));
+        assert_eq!(message.text, "status");
+
+        let message = message_from_event(
+            event(r#"{"type":"app_mention","user":"U1","text":"<@UBOT> help","channel":"C1","ts":"2.2","thread_ts":"1.1"}"#),
+            &bot_id,
+        )
+        .unwrap();
+        assert_eq!(message.channel_type, ChannelType::Group);
+        assert_eq!(message.text, "help");
+        assert_eq!(message.thread_ts, Some(MessageTs::new("1.1".to_string())));
+
+        // messages in channels arrive as mentions
+        assert!(message_from_event(
+            event(r#"{"type":"message","channel_type":"channel","user":"U1","text":"hi","channel":"C1","ts":"1.1"}"#),
+            &bot_id,
+        )
+        .is_none());
+        // own messages
+        assert!(message_from_event(
+            event(r#"{"type":"message","channel_type":"im","user":"UBOT","bot_id":"B1","text":"hi","channel":"D1","ts":"1.1"}"#),
+            &bot_id,
+        )
+        .is_none());
+        // edits
+        assert!(message_from_event(
+            event(r#"{"type":"message","subtype":"message_changed","channel_type":"im","channel":"D1","ts":"1.1"}"#),
+            &bot_id,
+        )
+        .is_none());
+    }
+}
@@ -0,0 +1,371 @@
+//! Client of the Slack Web API and receiver of the Slack Events API.
+
+use std::{error, fmt};
+
+use futures::future::{self, Future};
+use futures::{IntoFuture as _, Stream};
+use lazy_static::lazy_static;
+use log::debug;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+mod events;
+
+pub use events::{start_events_server, verify_signature, EventsServer};
+
+/// Url of the Slack Web API.
+pub const DEFAULT_API_URL: &str = "https://slack.com/api";
+
+macro_rules! newtype_string {
+    ($type_name:ident) => {
+        #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        #[serde(transparent)]
+        pub struct $type_name(String);
+
+        impl $type_name {
+            pub fn new(s: String) -> Self {
+                Self(s)
+            }
+
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+
+            pub fn into_string(self) -> String {
+                self.0
+            }
+        }
+
+        impl fmt::Display for $type_name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                self.0.fmt(f)
+            }
+        }
+    };
+}
+
+// Slack id of a user, e.g. `U0123ABCD`
+newtype_string!(UserId);
+// Slack id of a channel, e.g. `D0123ABCD` for a direct message channel
+newtype_string!(ChannelId);
+// Timestamp of a message, which identifies the message in its channel
+newtype_string!(MessageTs);
+
+/// Kind of the channel a message was sent in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelType {
+    /// direct message channel between a user and the bot
+    Direct,
+    /// public or private channel, or group direct message
+    Group,
+}
+
+/// A user's message to the bot, i.e. a direct message or a message in a
+/// channel mentioning the bot.
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub user: UserId,
+    /// email of the sender from the user's profile
+    pub user_email: String,
+    pub channel: ChannelId,
+    pub channel_type: ChannelType,
+    /// text without the mention of the bot
+    pub text: String,
+    pub ts: MessageTs,
+    /// timestamp of the thread's parent message if sent in a thread
+    pub thread_ts: Option<MessageTs>,
+}
+
+/// A message created or updated by the bot.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SentMessage {
+    pub channel: ChannelId,
+    pub ts: MessageTs,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct User {
+    pub id: UserId,
+    #[serde(default)]
+    pub name: String,
+    pub profile: Profile,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Profile {
+    /// Only available with the `users:read.email` scope.
+    #[serde(default)]
+    pub email: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct UserResponse {
+    user: User,
+}
+
+#[derive(Deserialize)]
+struct AuthTestResponse {
+    user_id: UserId,
+}
+
+#[derive(Serialize, Debug)]
+struct PostMessageParameters {
+    channel: String,
+    text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thread_ts: Option<MessageTs>,
+}
+
+#[derive(Serialize, Debug)]
+struct UpdateMessageParameters {
+    channel: ChannelId,
+    ts: MessageTs,
+    text: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    client: reqwest::r#async::Client,
+    url: String,
+    bot_token: String,
+    bot_id: UserId,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    ReqwestError(reqwest::Error),
+    JsonError(serde_json::Error),
+    /// The Web API rejected the call with the given error code.
+    ApiError(String),
+    /// The user's profile has no email, e.g. the scope to read it is missing.
+    MissingEmail(UserId),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::ReqwestError(ref err) => fmt::Display::fmt(err, f),
+            Error::JsonError(ref err) => fmt::Display::fmt(err, f),
+            Error::ApiError(ref code) => write!(f, "slack api error: {}", code),
+            Error::MissingEmail(ref user_id) => write!(f, "user {} has no email", user_id),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::ReqwestError(ref err) => err.source(),
+            Error::JsonError(ref err) => err.source(),
+            Error::ApiError(_) | Error::MissingEmail(_) => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        Error::ReqwestError(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::JsonError(err)
+    }
+}
+
+impl Client {
+    /// Create a client for the bot with the given token. Resolves after the
+    /// token was checked and the bot's user id is known.
+    pub fn new(api_url: String, bot_token: String) -> impl Future<Item = Self, Error = Error> {
+        let bootstrap_client = Client {
+            client: reqwest::r#async::Client::new(),
+            url: api_url,
+            bot_token,
+            bot_id: UserId(String::new()),
+        };
+
+        bootstrap_client
+            .api_post_json("auth.test", &serde_json::json!({}))
+            .map(|response: AuthTestResponse| response.user_id)
+            .map(|bot_id| Client {
+                bot_id,
+                ..bootstrap_client
+            })
+    }
+
+    /// User id of the bot.
+    pub fn id(&self) -> &UserId {
+        &self.bot_id
+    }
+
+    /// Post a message in mrkdwn format to a channel, or to the direct message
+    /// channel with a user if `channel` is a user id.
+    pub fn post_message(
+        &self,
+        channel: &str,
+        text: &str,
+        thread_ts: Option<&MessageTs>,
+    ) -> impl Future<Item = SentMessage, Error = Error> {
+        debug!("post message to {}", channel);
+        self.api_post_json(
+            "chat.postMessage",
+            &PostMessageParameters {
+                channel: channel.to_string(),
+                text: text.to_string(),
+                thread_ts: thread_ts.cloned(),
+            },
+        )
+    }
+
+    /// Replace the text of a message posted by the bot.
+    pub fn update_message(
+        &self,
+        channel: &ChannelId,
+        ts: &MessageTs,
+        text: &str,
+    ) -> impl Future<Item = SentMessage, Error = Error> {
+        debug!("update message {} in {}", ts, channel);
+        self.api_post_json(
+            "chat.update",
+            &UpdateMessageParameters {
+                channel: channel.clone(),
+                ts: ts.clone(),
+                text: text.to_string(),
+            },
+        )
+    }
+
+    pub fn get_user(&self, user_id: &UserId) -> impl Future<Item = User, Error = Error> {
+        self.api_get_json("users.info", &[("user", user_id.as_str())])
+            .map(|response: UserResponse| response.user)
+    }
+
+    /// Find the user with the given email in the workspace.
+    pub fn lookup_user_by_email(&self, email: &str) -> impl Future<Item = User, Error = Error> {
+        self.api_get_json("users.lookupByEmail", &[("email", email)])
+            .map(|response: UserResponse| response.user)
+    }
+
+    /// Email of the user from the user's profile.
+    pub fn get_user_email(&self, user_id: &UserId) -> impl Future<Item = String, Error = Error> {
+        let user_id = user_id.clone();
+        self.get_user(&user_id).and_then(move |user| {
+            user.profile
+                .email
+                .ok_or_else(|| Error::MissingEmail(user_id))
+        })
+    }
+
+    /// Call a method of the Web API with a json body.
+    fn api_post_json<T, R>(&self, method: &str, data: &T) -> impl Future<Item = R, Error = Error>
+    where
+        T: Serialize,
+        for<'a> R: Deserialize<'a>,
+    {
+        self.client
+            .post(&format!("{}/{}", self.url, method))
+            .bearer_auth(&self.bot_token)
+            .json(data)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .from_err()
+            .and_then(|response| decode_api_response(response.into_body()))
+    }
+
+    /// Call a method of the Web API with url parameters.
+    fn api_get_json<R>(
+        &self,
+        method: &str,
+        query: &[(&str, &str)],
+    ) -> impl Future<Item = R, Error = Error>
+    where
+        for<'a> R: Deserialize<'a>,
+    {
+        self.client
+            .get(&format!("{}/{}", self.url, method))
+            .query(query)
+            .bearer_auth(&self.bot_token)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .from_err()
+            .and_then(|response| decode_api_response(response.into_body()))
+    }
+}
+
+/// Decode the response of a Web API call. Failed calls are answered with
+/// status 200 too, but have `ok` set to false and an `error` code.
+fn decode_api_response<T, B, C>(body: B) -> impl Future<Item = T, Error = Error>
+where
+    for<'a> T: Deserialize<'a>,
+    B: Stream<Item = C, Error = reqwest::Error>,
+    C: AsRef<[u8]>,
+{
+    body.fold(Vec::new(), |mut v, chunk| {
+        v.extend_from_slice(chunk.as_ref());
+        future::ok::<_, reqwest::Error>(v)
+    })
+    .from_err()
+    .and_then(|v| {
+        serde_json::from_slice::<serde_json::Value>(&v)
+            .map_err(Error::from)
+            .and_then(|response| {
+                if response["ok"] == true {
+                    serde_json::from_value(response).map_err(Error::from)
+                } else {
+                    let code = response["error"].as_str().unwrap_or("unknown_error");
+                    Err(Error::ApiError(code.to_string()))
+                }
+            })
+            .into_future()
+    })
+}
+
+/// Convert the Markdown produced by the bot's formatter to Slack's mrkdwn:
+/// emphasis, links and headings are written differently.
+pub fn markdown_to_mrkdwn(markdown: &str) -> String {
+    lazy_static! {
+        static ref EMPHASIS: Regex = Regex::new(r"\*\*(.+?)\*\*|\*([^*\s][^*]*?)\*").unwrap();
+        static ref LINK: Regex = Regex::new(r"\[([^\]]+)\]\(([^)\s]+)\)").unwrap();
+        static ref HEADING: Regex = Regex::new(r"(?m)^#{1,6}\s+(.+)$").unwrap();
+    }
+    let text = EMPHASIS.replace_all(markdown, |cap: &regex::Captures| match cap.get(1) {
+        Some(bold) => format!("*{}*", bold.as_str()),
+        None => format!("_{}_", &cap[2]),
+    });
+    let text = LINK.replace_all(&text, "<$2|$1>");
+    HEADING.replace_all(&text, "*$1*").into_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn convert_markdown_to_mrkdwn() {
+        assert_eq!(
+            markdown_to_mrkdwn(
+                "[Fix the build](http://gerrit/1) (project) 👍 +1 (Code-Review) from **Jane**"
+            ),
+            "<http://gerrit/1|Fix the build> (project) 👍 +1 (Code-Review) from *Jane*"
+        );
+        assert_eq!(markdown_to_mrkdwn("# Heading\ntext"), "*Heading*\ntext");
+        assert_eq!(
+            markdown_to_mrkdwn("`code` and *italic*"),
+            "`code` and _italic_"
+        );
+    }
+
+    #[test]
+    fn deserialize_user() {
+        let response: UserResponse = serde_json::from_str(
+            r#"{"ok":true,"user":{"id":"U123","name":"jane","profile":{"email":"jane@example.com","display_name":"Jane"}}}"#,
+        )
+        .unwrap();
+        assert_eq!(response.user.id, UserId::new("U123".to_string()));
+        assert_eq!(
+            response.user.profile.email.as_deref(),
+            Some("jane@example.com")
+        );
+    }
+}
@@ -0,0 +1,161 @@
+use std::time::Duration;
+
+use futures::{Future, Stream};
+use hyper::StatusCode;
+use serde_json::json;
+use tokio::prelude::FutureExt as _;
+use tokio::runtime::Runtime;
+
+use gerritbot_slack as slack;
+
+mod fake_slack;
+
+use fake_slack::{FakeSlack, BOT_ID, BOT_TOKEN, SIGNING_SECRET};
+
+fn setup() -> (Runtime, FakeSlack, slack::Client) {
+    let mut runtime = Runtime::new().unwrap();
+    let slack = FakeSlack::start(&mut runtime);
+    let client = runtime
+        .block_on(slack::Client::new(slack.api_uri(), BOT_TOKEN.to_string()))
+        .expect("failed to create client");
+    (runtime, slack, client)
+}
+
+/// Address of a free local port.
+fn free_address() -> std::net::SocketAddr {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .unwrap()
+}
+
+/// Start the events server in the runtime. Returns its url and the stream of
+/// received messages.
+fn start_events_server(
+    runtime: &mut Runtime,
+    client: slack::Client,
+) -> (String, impl Stream<Item = slack::Message, Error = ()>) {
+    let address = free_address();
+    let slack::EventsServer { server, messages } =
+        slack::start_events_server(&address, SIGNING_SECRET.to_string(), client);
+    runtime.spawn(server.map_err(|e| panic!("events server failed: {}", e)));
+    (format!("http://{}/", address), messages)
+}
+
+#[test]
+fn client_gets_bot_id() {
+    let (_runtime, _slack, client) = setup();
+    assert_eq!(client.id().as_str(), BOT_ID);
+}
+
+#[test]
+fn client_with_invalid_token_fails() {
+    let mut runtime = Runtime::new().unwrap();
+    let slack = FakeSlack::start(&mut runtime);
+    let client = runtime.block_on(slack::Client::new(
+        slack.api_uri(),
+        "invalid-token".to_string(),
+    ));
+    match client {
+        Err(slack::Error::ApiError(code)) => assert_eq!(code, "invalid_auth"),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn post_and_update_message() {
+    let (mut runtime, slack, client) = setup();
+    let user_id = slack.add_user("author@example.com");
+
+    let sent = runtime
+        .block_on(client.post_message(&user_id, "hello", None))
+        .unwrap();
+    assert_eq!(sent.channel.as_str(), format!("D{}", user_id));
+    let reply = runtime
+        .block_on(client.post_message(sent.channel.as_str(), "reply", Some(&sent.ts)))
+        .unwrap();
+    let updated = runtime
+        .block_on(client.update_message(&sent.channel, &sent.ts, "hello again"))
+        .unwrap();
+    assert_eq!(sent, updated);
+
+    let messages = slack.messages();
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[0]["text"], "hello again");
+    assert_eq!(messages[1]["ts"], reply.ts.as_str());
+    assert_eq!(messages[1]["thread_ts"], sent.ts.as_str());
+}
+
+#[test]
+fn look_up_users() {
+    let (mut runtime, slack, client) = setup();
+    let user_id = slack.add_user("author@example.com");
+
+    let user = runtime
+        .block_on(client.lookup_user_by_email("author@example.com"))
+        .unwrap();
+    assert_eq!(user.id.as_str(), user_id);
+    let email = runtime.block_on(client.get_user_email(&user.id)).unwrap();
+    assert_eq!(email, "author@example.com");
+
+    match runtime.block_on(client.lookup_user_by_email("unknown@example.com")) {
+        Err(slack::Error::ApiError(code)) => assert_eq!(code, "users_not_found"),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn receive_messages_via_events() {
+    let (mut runtime, slack, client) = setup();
+    let (events_url, messages) = start_events_server(&mut runtime, client);
+
+    let status = runtime
+        .block_on(slack.post_direct_message(&events_url, "author@example.com", "status"))
+        .unwrap();
+    assert_eq!(status, StatusCode::OK);
+    let status = runtime
+        .block_on(slack.post_mention(&events_url, "C1", "reviewer@example.com", "help"))
+        .unwrap();
+    assert_eq!(status, StatusCode::OK);
+
+    let messages = runtime
+        .block_on(messages.take(2).collect().timeout(Duration::from_secs(5)))
+        .expect("messages not received");
+    assert_eq!(messages[0].user_email, "author@example.com");
+    assert_eq!(messages[0].channel_type, slack::ChannelType::Direct);
+    assert_eq!(messages[0].text, "status");
+    assert_eq!(messages[1].user_email, "reviewer@example.com");
+    assert_eq!(messages[1].channel_type, slack::ChannelType::Group);
+    assert_eq!(messages[1].channel.as_str(), "C1");
+    assert_eq!(messages[1].text, "help");
+}
+
+#[test]
+fn reject_events_with_invalid_signature() {
+    let (mut runtime, _slack, client) = setup();
+    let (events_url, _messages) = start_events_server(&mut runtime, client);
+
+    let status = runtime
+        .block_on(fake_slack::send_event(
+            &events_url,
+            &json!({ "type": "url_verification", "challenge": "abc" }),
+            "wrong-secret",
+        ))
+        .unwrap();
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[test]
+fn answer_url_verification() {
+    let (mut runtime, _slack, client) = setup();
+    let (events_url, _messages) = start_events_server(&mut runtime, client);
+
+    let (status, body) = runtime
+        .block_on(fake_slack::send_event_with_response(
+            &events_url,
+            &json!({ "type": "url_verification", "challenge": "abc" }),
+            SIGNING_SECRET,
+        ))
+        .unwrap();
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "abc");
+}
@@ -2,7 +2,7 @@
 #![recursion_limit = "128"]
 
 use futures::future::lazy;
-use futures::{future::Either, Future as _, Stream as _};
+use futures::{future::Either, Future as _, IntoFuture as _, Stream as _};
 use log::{debug, error, info};
 use serde::Deserialize;
 use std::path::PathBuf;
@@ -64,12 +64,17 @@ fn main() {
                 let next_client = client.clone();
 
                 client
-                    .register_webhook(&webhook_url)
+                    .register_webhook("gerritbot-echo", &webhook_url)
                     .map_err(|e| error!("failed to register webhook: {}", e))
                     .map(move |()| next_client)
             })
             .and_then(move |client| {
-                spark::sqs_event_stream(spark_config.sqs_url.clone(), sqs_region, client.clone())
+                let queue = spark::SqsQueue::new(spark_config.sqs_url.clone(), sqs_region);
+                spark::sqs_event_stream(queue, client.clone())
+                    .map_err(|e| error!("failed to create sqs client: {}", e))
+                    .into_future()
+                    .flatten_stream()
+                    .filter_map(spark::Event::into_message)
                     .for_each(move |message| {
                         debug!("got a message: {:?}", message);
 
@@ -84,8 +89,11 @@ fn main() {
                                 markdown: message.markdown.as_ref().map(String::as_str),
                                 html: message.html.as_ref().map(String::as_str),
                                 text: Some(&message.text),
+                                parent_id: None,
+                                attachments: &[],
                             }))
                         }
+                        .map(|_| ())
                         .map_err(|e| error!("failed to send message: {}", e))
                     })
             })
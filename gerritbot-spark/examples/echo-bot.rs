@@ -61,7 +61,7 @@ fn main() {
                 let next_client = client.clone();
 
                 client
-                    .register_webhook(&webhook_url)
+                    .register_webhook("gerritbot-echo", &webhook_url)
                     .map_err(|e| error!("failed to register webhook: {}", e))
                     .map(move |()| next_client)
             })
@@ -69,7 +69,8 @@ fn main() {
                 let spark::WebhookServer { messages, server } =
                     spark::start_webhook_server(&endpoint_address, client.clone());
 
-                // consume messages
+                // consume messages, ignore card actions
+                let messages = messages.filter_map(spark::Event::into_message);
                 let messages_future = messages.for_each(move |message| {
                     debug!("got a message: {:?}", message);
 
@@ -84,8 +85,11 @@ fn main() {
                             markdown: message.markdown.as_ref().map(String::as_str),
                             html: message.html.as_ref().map(String::as_str),
                             text: Some(&message.text),
+                            parent_id: None,
+                            attachments: &[],
                         }))
                     }
+                    .map(|_| ())
                     .map_err(|e| error!("failed to send message: {}", e))
                 });
 
@@ -0,0 +1,150 @@
+//! Drop duplicate deliveries of the same resource. Webex retries webhook
+//! deliveries and SQS delivers messages at least once.
+
+use std::time::Duration;
+
+use log::info;
+use lru_time_cache::LruCache;
+
+use super::WebhookData;
+
+/// Maximum number of remembered deliveries.
+pub const DEFAULT_CAPACITY: usize = 10_000;
+
+/// Time after which a delivery is forgotten.
+pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Ids of the recently delivered resources.
+pub struct Deduplicator {
+    seen: LruCache<String, ()>,
+    duplicates: u64,
+}
+
+impl Default for Deduplicator {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_AGE, DEFAULT_CAPACITY)
+    }
+}
+
+impl Deduplicator {
+    pub fn new(max_age: Duration, capacity: usize) -> Self {
+        Self {
+            seen: LruCache::with_expiry_duration_and_capacity(max_age, capacity),
+            duplicates: 0,
+        }
+    }
+
+    /// Remember the delivery. Returns false if the resource was delivered
+    /// before, in which case it is counted as duplicate.
+    pub fn is_new(&mut self, data: &WebhookData) -> bool {
+        if self.is_duplicate(data) {
+            false
+        } else {
+            self.remember(data);
+            true
+        }
+    }
+
+    /// Check whether the resource was delivered before without remembering
+    /// the delivery. Duplicates are counted.
+    pub fn is_duplicate(&mut self, data: &WebhookData) -> bool {
+        let key = key(data);
+        if self.seen.get(&key).is_some() {
+            self.duplicates += 1;
+            info!(
+                "dropping duplicate delivery of {} ({} duplicates so far)",
+                key, self.duplicates
+            );
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Remember the delivery of the resource, e.g. after it was handled.
+    pub fn remember(&mut self, data: &WebhookData) {
+        self.seen.insert(key(data), ());
+    }
+
+    /// Number of dropped duplicates.
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+}
+
+/// Key identifying the delivered resource.
+fn key(data: &WebhookData) -> String {
+    match data {
+        WebhookData::Message(message) => format!("message:{}", message.id),
+        WebhookData::AttachmentAction(action) => format!("action:{}", action.id),
+        // the same membership is created and deleted
+        WebhookData::Membership(event, membership) => {
+            format!("membership:{:?}:{}", event, membership.id)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{EventType, Membership, Message, MessageId};
+
+    fn message(id: &str) -> WebhookData {
+        WebhookData::Message(Message {
+            id: MessageId::new(id.to_string()),
+            ..Default::default()
+        })
+    }
+
+    fn membership(event: EventType) -> WebhookData {
+        WebhookData::Membership(
+            event,
+            Membership {
+                id: "membership-id".to_string(),
+                room_id: Default::default(),
+                person_id: Default::default(),
+                person_email: Default::default(),
+                is_moderator: false,
+                room_type: None,
+            },
+        )
+    }
+
+    #[test]
+    fn drop_duplicates() {
+        let mut deduplicator = Deduplicator::default();
+        assert!(deduplicator.is_new(&message("first")));
+        assert!(deduplicator.is_new(&message("second")));
+        assert!(!deduplicator.is_new(&message("first")));
+        assert!(!deduplicator.is_new(&message("first")));
+        assert_eq!(deduplicator.duplicates(), 2);
+    }
+
+    #[test]
+    fn membership_events_are_distinct() {
+        let mut deduplicator = Deduplicator::default();
+        assert!(deduplicator.is_new(&membership(EventType::Created)));
+        assert!(deduplicator.is_new(&membership(EventType::Deleted)));
+        assert!(!deduplicator.is_new(&membership(EventType::Deleted)));
+    }
+
+    #[test]
+    fn check_without_remembering() {
+        let mut deduplicator = Deduplicator::default();
+        assert!(!deduplicator.is_duplicate(&message("first")));
+        assert!(!deduplicator.is_duplicate(&message("first")));
+        deduplicator.remember(&message("first"));
+        assert!(deduplicator.is_duplicate(&message("first")));
+        assert_eq!(deduplicator.duplicates(), 1);
+    }
+
+    #[test]
+    fn forget_old_deliveries() {
+        let mut deduplicator = Deduplicator::new(Duration::from_secs(60), 1);
+        assert!(deduplicator.is_new(&message("first")));
+        assert!(deduplicator.is_new(&message("second")));
+        // forgotten due to capacity
+        assert!(deduplicator.is_new(&message("first")));
+        assert_eq!(deduplicator.duplicates(), 0);
+    }
+}
@@ -2,106 +2,29 @@
 
 use std::convert::identity;
 use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{error, fmt, io};
 
 use futures::future::{self, Future};
 use futures::sync::mpsc::channel;
 use futures::{IntoFuture as _, Sink, Stream};
+use gerritbot_newtype::newtype_string;
 use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
 
+mod dedup;
+mod mercury;
 mod sqs;
 
+pub use dedup::Deduplicator;
+pub use mercury::DEFAULT_DEVICES_URL;
+pub use sqs::{SqsCredentials, SqsQueue};
+
 //
 // Spark data model
 //
 
-/// Define a newtype String.
-macro_rules! newtype_string {
-    ($type_name:ident, $type_ref_name:ident) => {
-        #[derive(
-            Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
-        )]
-        #[serde(transparent)]
-        pub struct $type_name(String);
-
-        impl $type_name {
-            pub fn new(s: String) -> Self {
-                Self(s)
-            }
-
-            pub fn as_str(&self) -> &str {
-                &self.0
-            }
-
-            pub fn into_string(self) -> String {
-                self.0
-            }
-        }
-
-        impl std::fmt::Display for $type_name {
-            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-                self.0.fmt(f)
-            }
-        }
-
-        #[derive(Serialize, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-        #[serde(transparent)]
-        pub struct $type_ref_name(str);
-
-        impl $type_ref_name {
-            pub fn new(s: &str) -> &Self {
-                unsafe { &*(s as *const str as *const Self) }
-            }
-        }
-
-        impl std::fmt::Display for $type_ref_name {
-            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-                self.0.fmt(f)
-            }
-        }
-
-        impl std::borrow::Borrow<$type_ref_name> for $type_name {
-            fn borrow(&self) -> &$type_ref_name {
-                &*self
-            }
-        }
-
-        impl std::borrow::ToOwned for $type_ref_name {
-            type Owned = $type_name;
-            fn to_owned(&self) -> Self::Owned {
-                Self::Owned::new(self.0.to_string())
-            }
-        }
-
-        impl std::ops::Deref for $type_name {
-            type Target = $type_ref_name;
-
-            fn deref(&self) -> &$type_ref_name {
-                Self::Target::new(&self.0)
-            }
-        }
-
-        impl<'a> std::cmp::PartialEq<&'a $type_ref_name> for $type_name {
-            fn eq(&self, other: &&$type_ref_name) -> bool {
-                self.0 == other.0
-            }
-        }
-
-        impl<'a, 'b> std::cmp::PartialEq<&'a $type_ref_name> for &'b $type_name {
-            fn eq(&self, other: &&$type_ref_name) -> bool {
-                self.0 == other.0
-            }
-        }
-
-        impl<'a> std::cmp::PartialEq<$type_name> for &'a $type_ref_name {
-            fn eq(&self, other: &$type_name) -> bool {
-                self.0 == other.0
-            }
-        }
-    };
-}
-
 /// Spark id of the user
 newtype_string!(PersonId, PersonIdRef);
 newtype_string!(ResourceId, ResourceIdRef);
@@ -109,6 +32,10 @@ newtype_string!(Email, EmailRef);
 newtype_string!(WebhookId, WebhookIdRef);
 newtype_string!(MessageId, MessageIdRef);
 newtype_string!(RoomId, RoomIdRef);
+newtype_string!(AttachmentActionId, AttachmentActionIdRef);
+
+/// Content type of Adaptive Card attachments.
+pub const ADAPTIVE_CARD_CONTENT_TYPE: &str = "application/vnd.microsoft.card.adaptive";
 
 #[derive(Deserialize, Serialize, Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[serde(rename_all = "lowercase")]
@@ -123,6 +50,8 @@ pub enum ResourceType {
     Memberships,
     Messages,
     Rooms,
+    #[serde(rename = "attachmentActions")]
+    AttachmentActions,
 }
 
 #[derive(Deserialize, Serialize, Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -131,6 +60,8 @@ pub enum EventType {
     Created,
     Updated,
     Deleted,
+    /// any of the above, only used to register webhooks
+    All,
 }
 
 fn deserialize_timestamp<'de, D>(deserializer: D) -> Result<chrono::DateTime<chrono::Utc>, D::Error>
@@ -170,16 +101,137 @@ pub struct WebhookMessage {
     app_id: String,
     created: Timestamp,
     created_by: PersonId,
-    pub data: Message,
+    data: serde_json::Value,
     event: EventType,
     name: String,
     org_id: String,
     owned_by: String,
-    resource: ResourceId,
+    resource: ResourceType,
     status: String,
     target_url: String,
 }
 
+impl WebhookMessage {
+    /// Data of the resource the webhook was triggered by.
+    pub fn data(&self) -> Result<WebhookData, serde_json::Error> {
+        match self.resource {
+            ResourceType::Memberships => serde_json::from_value(self.data.clone())
+                .map(|membership| WebhookData::Membership(self.event, membership)),
+            _ => serde_json::from_value(self.data.clone()),
+        }
+    }
+}
+
+/// Resource a webhook was triggered by. Only the ids of the resource are
+/// included, the full resource has to be fetched.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum WebhookData {
+    Message(Message),
+    AttachmentAction(AttachmentAction),
+    /// Membership which was created, updated or deleted; cannot be told apart
+    /// from a message by its fields.
+    #[serde(skip_deserializing)]
+    Membership(EventType, Membership),
+}
+
+impl WebhookData {
+    /// Person who created the resource, or the member of a membership.
+    pub fn person_id(&self) -> &PersonIdRef {
+        match self {
+            WebhookData::Message(message) => &message.person_id,
+            WebhookData::AttachmentAction(action) => &action.person_id,
+            WebhookData::Membership(_, membership) => &membership.person_id,
+        }
+    }
+}
+
+/// Get the data of a webhook post, logging errors.
+fn webhook_data(post: WebhookMessage) -> Option<WebhookData> {
+    post.data()
+        .map_err(|e| error!("failed to decode webhook data: {}", e))
+        .ok()
+}
+
+/// Message or card action received by the bot, or a change of the bot's
+/// memberships.
+#[derive(Debug, Clone)]
+pub enum Event {
+    Message(Message),
+    CardAction(CardAction),
+    /// the bot was added to a room
+    BotAdded(Membership),
+    /// the bot was removed from a room
+    BotRemoved(Membership),
+}
+
+impl Event {
+    /// The message, if the event is one.
+    pub fn into_message(self) -> Option<Message> {
+        match self {
+            Event::Message(message) => Some(message),
+            _ => None,
+        }
+    }
+}
+
+impl From<Message> for Event {
+    fn from(message: Message) -> Self {
+        Event::Message(message)
+    }
+}
+
+impl From<CardAction> for Event {
+    fn from(action: CardAction) -> Self {
+        Event::CardAction(action)
+    }
+}
+
+/// Attachment of a message, e.g. an Adaptive Card.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Attachment {
+    pub content_type: String,
+    pub content: serde_json::Value,
+}
+
+impl Attachment {
+    /// Attachment with the given Adaptive Card.
+    pub fn adaptive_card(card: serde_json::Value) -> Self {
+        Self {
+            content_type: ADAPTIVE_CARD_CONTENT_TYPE.to_string(),
+            content: card,
+        }
+    }
+}
+
+/// Submission of a card, e.g. by a click on an `Action.Submit` button.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AttachmentAction {
+    pub id: AttachmentActionId,
+    #[serde(rename = "type")]
+    pub action_type: String,
+    /// message containing the card
+    pub message_id: MessageId,
+    pub person_id: PersonId,
+    pub room_id: RoomId,
+    pub created: Option<Timestamp>,
+    /// values of the card's inputs and the data of the submit button; not
+    /// included in webhook posts
+    #[serde(default)]
+    pub inputs: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Card action together with the details of the person and the room, which
+/// are not part of the action itself.
+#[derive(Debug, Clone)]
+pub struct CardAction {
+    pub action: AttachmentAction,
+    pub person_email: Email,
+    pub room_type: RoomType,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Message {
@@ -196,6 +248,48 @@ pub struct Message {
     pub markdown: Option<String>,
     pub html: Option<String>,
     pub files: Option<Vec<String>>,
+    #[serde(default)]
+    pub mentioned_people: Vec<PersonId>,
+}
+
+impl Message {
+    /// Check if the given person was @mentioned in the message.
+    pub fn mentions(&self, person_id: &PersonIdRef) -> bool {
+        self.mentioned_people.iter().any(|p| p == person_id)
+    }
+
+    /// Text of the message with leading @mentions removed.
+    ///
+    /// In group rooms the text of a message addressed to the bot starts with
+    /// the bot's display name, e.g. `GerritBot status`. The names of mentioned
+    /// persons are taken from the `<spark-mention>` elements in the message's
+    /// html.
+    pub fn text_without_mentions(&self) -> &str {
+        const MENTION_START: &str = "<spark-mention";
+        const MENTION_END: &str = "</spark-mention>";
+
+        let mut text = self.text.trim_start();
+        let mut html = self.html.as_ref().map_or("", |html| html.as_str());
+
+        while let Some(start) = html.find(MENTION_START) {
+            let rest = &html[start..];
+            let (name_start, name_end) = match (rest.find('>'), rest.find(MENTION_END)) {
+                (Some(name_start), Some(name_end)) if name_start < name_end => {
+                    (name_start + 1, name_end)
+                }
+                _ => break,
+            };
+            let name = &rest[name_start..name_end];
+            if !name.is_empty() && text.starts_with(name) {
+                text = text[name.len()..].trim_start();
+            } else {
+                break;
+            }
+            html = &rest[name_end + MENTION_END.len()..];
+        }
+
+        text
+    }
 }
 
 impl Default for Message {
@@ -211,6 +305,7 @@ impl Default for Message {
             markdown: Default::default(),
             html: Default::default(),
             files: Default::default(),
+            mentioned_people: Default::default(),
         }
     }
 }
@@ -261,6 +356,52 @@ impl<'a> From<&'a EmailRef> for CreateMessageTarget<'a> {
     }
 }
 
+/// Owned version of `CreateMessageTarget`.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum MessageTarget {
+    RoomId(RoomId),
+    PersonId(PersonId),
+    PersonEmail(Email),
+}
+
+impl std::fmt::Display for MessageTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MessageTarget::RoomId(room_id) => write!(f, "room {}", room_id),
+            MessageTarget::PersonId(person_id) => person_id.fmt(f),
+            MessageTarget::PersonEmail(email) => email.fmt(f),
+        }
+    }
+}
+
+impl From<RoomId> for MessageTarget {
+    fn from(room_id: RoomId) -> MessageTarget {
+        MessageTarget::RoomId(room_id)
+    }
+}
+
+impl From<PersonId> for MessageTarget {
+    fn from(person_id: PersonId) -> MessageTarget {
+        MessageTarget::PersonId(person_id)
+    }
+}
+
+impl From<Email> for MessageTarget {
+    fn from(email: Email) -> MessageTarget {
+        MessageTarget::PersonEmail(email)
+    }
+}
+
+impl<'a> From<&'a MessageTarget> for CreateMessageTarget<'a> {
+    fn from(target: &'a MessageTarget) -> CreateMessageTarget<'a> {
+        match target {
+            MessageTarget::RoomId(room_id) => CreateMessageTarget::RoomId(room_id),
+            MessageTarget::PersonId(person_id) => CreateMessageTarget::PersonId(person_id),
+            MessageTarget::PersonEmail(email) => CreateMessageTarget::PersonEmail(email),
+        }
+    }
+}
+
 #[derive(Serialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct CreateMessageParameters<'a> {
@@ -270,6 +411,31 @@ pub struct CreateMessageParameters<'a> {
     pub markdown: Option<&'a str>,
     /// Note: This parameter is not in the documented API.
     pub html: Option<&'a str>,
+    /// Message to reply to in a thread
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<&'a MessageIdRef>,
+    /// Cards shown with the message; clients without card support show the
+    /// text or markdown instead.
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    pub attachments: &'a [Attachment],
+}
+
+/// Parameters to edit a message. The room is required even for messages sent
+/// directly to a person.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct EditMessageParameters<'a> {
+    pub room_id: &'a RoomIdRef,
+    pub text: Option<&'a str>,
+    pub markdown: Option<&'a str>,
+}
+
+/// A message created or edited by the bot.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct SentMessage {
+    pub id: MessageId,
+    pub room_id: RoomId,
 }
 
 #[derive(Deserialize, Debug)]
@@ -318,6 +484,63 @@ struct Webhooks {
     items: Vec<Webhook>,
 }
 
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct WebhookUpdate {
+    name: String,
+    target_url: String,
+    status: &'static str,
+}
+
+/// Status of a webhook Webex delivers events to.
+const WEBHOOK_STATUS_ACTIVE: &str = "active";
+
+/// Number of webhooks to request per page.
+const WEBHOOKS_PAGE_SIZE: usize = 100;
+
+/// Url of the next page from the `Link` headers of a paginated response.
+fn next_page_url(headers: &http::HeaderMap) -> Option<String> {
+    headers
+        .get_all(http::header::LINK)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|link| {
+            let mut parts = link.split(';').map(str::trim);
+            let url = parts.next()?;
+            if parts.any(|param| param == r#"rel="next""#) {
+                Some(
+                    url.trim_start_matches('<')
+                        .trim_end_matches('>')
+                        .to_string(),
+                )
+            } else {
+                None
+            }
+        })
+}
+
+/// Membership of a person in a room
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Membership {
+    pub id: String,
+    pub room_id: RoomId,
+    pub person_id: PersonId,
+    pub person_email: Email,
+    #[serde(default)]
+    pub is_moderator: bool,
+    /// not included in all responses
+    #[serde(default)]
+    pub room_type: Option<RoomType>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Memberships {
+    items: Vec<Membership>,
+}
+
 //
 // Client
 //
@@ -339,6 +562,12 @@ pub enum Error {
     RegisterWebhook(String),
     DeleteWebhook(String),
     IoError(io::Error),
+    TlsError(rusoto_core::request::TlsError),
+    CredentialsError(rusoto_core::CredentialsError),
+    /// A person without email, e.g. a bot, sent a card action.
+    MissingEmail(String),
+    /// A setting of the SQS queue is outside of the limits of SQS.
+    InvalidQueueConfig(String),
 }
 
 impl fmt::Display for Error {
@@ -352,6 +581,11 @@ impl fmt::Display for Error {
                 fmt::Display::fmt(msg, f)
             }
             Error::IoError(ref err) => fmt::Display::fmt(err, f),
+            Error::TlsError(ref err) => fmt::Display::fmt(err, f),
+            Error::CredentialsError(ref err) => fmt::Display::fmt(err, f),
+            Error::MissingEmail(ref msg) | Error::InvalidQueueConfig(ref msg) => {
+                fmt::Display::fmt(msg, f)
+            }
         }
     }
 }
@@ -363,8 +597,13 @@ impl error::Error for Error {
             Error::HyperError(ref err) => err.description(),
             // Error::SqsError(ref err) => err.description(),
             Error::JsonError(ref err) => err.description(),
-            Error::RegisterWebhook(ref msg) | Error::DeleteWebhook(ref msg) => msg,
+            Error::RegisterWebhook(ref msg)
+            | Error::DeleteWebhook(ref msg)
+            | Error::MissingEmail(ref msg)
+            | Error::InvalidQueueConfig(ref msg) => msg,
             Error::IoError(ref err) => err.description(),
+            Error::TlsError(ref err) => err.description(),
+            Error::CredentialsError(ref err) => err.description(),
         }
     }
 
@@ -374,8 +613,13 @@ impl error::Error for Error {
             Error::HyperError(ref err) => err.source(),
             // Error::SqsError(ref err) => err.source(),
             Error::JsonError(ref err) => err.source(),
-            Error::RegisterWebhook(_) | Error::DeleteWebhook(_) => None,
+            Error::RegisterWebhook(_)
+            | Error::DeleteWebhook(_)
+            | Error::MissingEmail(_)
+            | Error::InvalidQueueConfig(_) => None,
             Error::IoError(ref err) => err.source(),
+            Error::TlsError(ref err) => err.source(),
+            Error::CredentialsError(ref err) => err.source(),
         }
     }
 }
@@ -398,6 +642,18 @@ impl From<serde_json::Error> for Error {
     }
 }
 
+impl From<rusoto_core::request::TlsError> for Error {
+    fn from(err: rusoto_core::request::TlsError) -> Self {
+        Error::TlsError(err)
+    }
+}
+
+impl From<rusoto_core::CredentialsError> for Error {
+    fn from(err: rusoto_core::CredentialsError) -> Self {
+        Error::CredentialsError(err)
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(err: io::Error) -> Self {
         Error::IoError(err)
@@ -436,6 +692,27 @@ impl Client {
             .and_then(|response| decode_json_body(response.into_body()))
     }
 
+    /// Try to get json from the given url and query with basic token
+    /// authorization.
+    fn api_get_json_with_query<T>(
+        &self,
+        resource: &str,
+        query: &[(&str, &str)],
+    ) -> impl Future<Item = T, Error = Error>
+    where
+        for<'a> T: Deserialize<'a>,
+    {
+        self.client
+            .get(&format!("{}/{}", self.url, resource))
+            .query(query)
+            .bearer_auth(&self.bot_token)
+            .header(http::header::ACCEPT, "application/json")
+            .send()
+            .and_then(|response| response.error_for_status())
+            .from_err()
+            .and_then(|response| decode_json_body(response.into_body()))
+    }
+
     /// Try to post json to the given url with basic token authorization.
     fn api_post_json<T>(&self, resource: &str, data: &T) -> impl Future<Item = (), Error = Error>
     where
@@ -451,6 +728,50 @@ impl Client {
             .map(|_| ())
     }
 
+    /// Try to post json to the given url with basic token authorization and
+    /// decode the json response.
+    fn api_post_json_with_response<T, R>(
+        &self,
+        resource: &str,
+        data: &T,
+    ) -> impl Future<Item = R, Error = Error>
+    where
+        T: Serialize,
+        for<'a> R: Deserialize<'a>,
+    {
+        self.client
+            .post(&format!("{}/{}", self.url, resource))
+            .bearer_auth(&self.bot_token)
+            .header(http::header::ACCEPT, "application/json")
+            .json(data)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .from_err()
+            .and_then(|response| decode_json_body(response.into_body()))
+    }
+
+    /// Try to put json to the given url with basic token authorization and
+    /// decode the json response.
+    fn api_put_json_with_response<T, R>(
+        &self,
+        resource: &str,
+        data: &T,
+    ) -> impl Future<Item = R, Error = Error>
+    where
+        T: Serialize,
+        for<'a> R: Deserialize<'a>,
+    {
+        self.client
+            .put(&format!("{}/{}", self.url, resource))
+            .bearer_auth(&self.bot_token)
+            .header(http::header::ACCEPT, "application/json")
+            .json(data)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .from_err()
+            .and_then(|response| decode_json_body(response.into_body()))
+    }
+
     /// Try to post json to the given url with basic token authorization.
     fn api_delete(&self, resource: &str) -> impl Future<Item = (), Error = Error> {
         self.client
@@ -462,17 +783,30 @@ impl Client {
             .map(|_| ())
     }
 
+    fn get_person_details(
+        &self,
+        person_id: &PersonIdRef,
+    ) -> impl Future<Item = PersonDetails, Error = Error> {
+        self.api_get_json(&format!("people/{}", person_id))
+    }
+
     fn get_bot_id(&self) -> impl Future<Item = PersonId, Error = Error> {
         self.api_get_json("people/me")
             .map(|details: PersonDetails| details.id)
     }
 
-    fn add_webhook(&self, url: &str) -> impl Future<Item = (), Error = Error> {
+    fn add_webhook(
+        &self,
+        name: &str,
+        url: &str,
+        resource: ResourceType,
+        event: EventType,
+    ) -> impl Future<Item = (), Error = Error> {
         let webhook = WebhookRegistration {
-            name: "gerritbot".to_string(),
+            name: name.to_string(),
             target_url: url.to_string(),
-            resource: ResourceType::Messages,
-            event: EventType::Created,
+            resource,
+            event,
         };
 
         debug!("adding webhook: {:?}", webhook);
@@ -481,8 +815,45 @@ impl Client {
             .map(|()| debug!("added webhook"))
     }
 
-    fn list_webhooks(&self) -> impl Future<Item = Webhooks, Error = Error> {
-        self.api_get_json("webhooks")
+    /// List all webhooks of the bot, following the pagination links.
+    fn list_webhooks(&self) -> impl Future<Item = Vec<Webhook>, Error = Error> {
+        let client = self.clone();
+        let first_page = format!("{}/webhooks?max={}", self.url, WEBHOOKS_PAGE_SIZE);
+        futures::stream::unfold(Some(first_page), move |page_url| {
+            page_url.map(|page_url| client.get_webhooks_page(&page_url))
+        })
+        .concat2()
+    }
+
+    /// Get a page of webhooks and the url of the next page, if any.
+    fn get_webhooks_page(
+        &self,
+        page_url: &str,
+    ) -> impl Future<Item = (Vec<Webhook>, Option<String>), Error = Error> {
+        self.client
+            .get(page_url)
+            .bearer_auth(&self.bot_token)
+            .header(http::header::ACCEPT, "application/json")
+            .send()
+            .and_then(|response| response.error_for_status())
+            .from_err()
+            .and_then(|response| {
+                let next_page = next_page_url(response.headers());
+                decode_json_body(response.into_body())
+                    .map(move |webhooks: Webhooks| (webhooks.items, next_page))
+            })
+    }
+
+    /// Set the status of a webhook to active again.
+    fn enable_webhook(&self, webhook: &Webhook) -> impl Future<Item = (), Error = Error> {
+        let update = WebhookUpdate {
+            name: webhook.name.clone(),
+            target_url: webhook.target_url.clone(),
+            status: WEBHOOK_STATUS_ACTIVE,
+        };
+        info!("re-enabling {} webhook {}", webhook.status, webhook.id);
+        self.api_put_json_with_response(&format!("webhooks/{}", webhook.id), &update)
+            .map(|_: Webhook| debug!("enabled webhook"))
     }
 
     fn delete_webhook(&self, id: &WebhookId) -> impl Future<Item = (), Error = Error> {
@@ -502,19 +873,75 @@ impl Client {
             .map(|()| debug!("deleted webhook"))
     }
 
-    pub fn register_webhook(self, url: &str) -> impl Future<Item = (), Error = Error> {
+    /// Register the url for created messages, card actions and all changes of
+    /// memberships under the given name. Webhooks with other names are left
+    /// alone. Webhooks with the name but a different url, resource or event
+    /// are deleted, disabled ones are enabled again and missing ones are
+    /// added.
+    pub fn register_webhook(self, name: &str, url: &str) -> impl Future<Item = (), Error = Error> {
+        const RESOURCES: &[(ResourceType, EventType)] = &[
+            (ResourceType::Messages, EventType::Created),
+            (ResourceType::AttachmentActions, EventType::Created),
+            (ResourceType::Memberships, EventType::All),
+        ];
+
+        let name = name.to_string();
         let url = url.to_string();
-        let delete_client = self.clone();
-        let add_client = self.clone();
-        self.list_webhooks()
-            .map(|webhooks| futures::stream::iter_ok(webhooks.items))
-            .flatten_stream()
-            .filter(|webhook| {
-                webhook.resource == ResourceType::Messages && webhook.event == EventType::Created
+        let client = self.clone();
+        self.list_webhooks().and_then(move |webhooks| {
+            let mut missing = RESOURCES.to_vec();
+            let mut obsolete = Vec::new();
+            let mut disabled = Vec::new();
+            for webhook in webhooks.into_iter().filter(|webhook| webhook.name == name) {
+                let position = missing
+                    .iter()
+                    .position(|resource| *resource == (webhook.resource, webhook.event));
+                match position {
+                    Some(index) if webhook.target_url == url => {
+                        missing.remove(index);
+                        if webhook.status != WEBHOOK_STATUS_ACTIVE {
+                            disabled.push(webhook);
+                        }
+                    }
+                    _ => obsolete.push(webhook),
+                }
+            }
+
+            let delete_client = client.clone();
+            let enable_client = client.clone();
+            futures::stream::iter_ok(obsolete)
+                .inspect(|webhook| debug!("removing webhook: {}", webhook.target_url))
+                .for_each(move |webhook| delete_client.delete_webhook(&webhook.id))
+                .and_then(move |()| {
+                    futures::stream::iter_ok(disabled)
+                        .for_each(move |webhook| enable_client.enable_webhook(&webhook))
+                })
+                .and_then(move |()| {
+                    futures::stream::iter_ok(missing).for_each(move |(resource, event)| {
+                        client.add_webhook(&name, &url, resource, event)
+                    })
+                })
+        })
+    }
+
+    /// Register the webhook like `register_webhook` every `interval`, e.g. to
+    /// enable it again after Webex disabled it due to failed deliveries.
+    /// Errors are logged.
+    pub fn watch_webhook(
+        self,
+        name: String,
+        url: String,
+        interval: Duration,
+    ) -> impl Future<Item = (), Error = ()> {
+        tokio::timer::Interval::new(Instant::now() + interval, interval)
+            .map_err(|e| error!("webhook timer failed: {}", e))
+            .for_each(move |_| {
+                debug!("checking webhooks");
+                self.clone().register_webhook(&name, &url).or_else(|e| {
+                    error!("failed to check webhooks: {}", e);
+                    Ok(())
+                })
             })
-            .inspect(|webhook| debug!("Removing webhook from Spark: {}", webhook.target_url))
-            .for_each(move |webhook| delete_client.delete_webhook(&webhook.id))
-            .and_then(move |()| add_client.add_webhook(&url))
     }
 
     pub fn id(&self) -> &PersonId {
@@ -525,7 +952,7 @@ impl Client {
         &self,
         target: &'a T,
         markdown: &'a str,
-    ) -> impl Future<Item = (), Error = Error>
+    ) -> impl Future<Item = SentMessage, Error = Error>
     where
         &'a T: Into<CreateMessageTarget<'a>>,
     {
@@ -534,20 +961,99 @@ impl Client {
             markdown: Some(markdown),
             text: None,
             html: None,
+            parent_id: None,
+            attachments: &[],
+        })
+    }
+
+    /// Send a message as reply in the thread of the parent message.
+    pub fn send_thread_reply<'a, T: ?Sized>(
+        &self,
+        target: &'a T,
+        parent_id: &'a MessageIdRef,
+        markdown: &'a str,
+    ) -> impl Future<Item = SentMessage, Error = Error>
+    where
+        &'a T: Into<CreateMessageTarget<'a>>,
+    {
+        self.create_message(CreateMessageParameters {
+            target: target.into(),
+            markdown: Some(markdown),
+            text: None,
+            html: None,
+            parent_id: Some(parent_id),
+            attachments: &[],
+        })
+    }
+
+    /// Send a message with an Adaptive Card, optionally as reply in the thread
+    /// of the parent message. The markdown is shown by clients which cannot
+    /// render the card.
+    pub fn send_card<'a, T: ?Sized>(
+        &self,
+        target: &'a T,
+        markdown: &'a str,
+        card: &serde_json::Value,
+        parent_id: Option<&'a MessageIdRef>,
+    ) -> impl Future<Item = SentMessage, Error = Error>
+    where
+        &'a T: Into<CreateMessageTarget<'a>>,
+    {
+        self.create_message(CreateMessageParameters {
+            target: target.into(),
+            markdown: Some(markdown),
+            text: None,
+            html: None,
+            parent_id,
+            attachments: &[Attachment::adaptive_card(card.clone())],
         })
     }
 
     pub fn create_message<'a>(
         &self,
         parameters: CreateMessageParameters<'a>,
-    ) -> impl Future<Item = (), Error = Error> {
+    ) -> impl Future<Item = SentMessage, Error = Error> {
         debug!("send message to {:?}", parameters.target);
         let json = match serde_json::to_value(&parameters) {
             Ok(json) => json,
             Err(e) => return future::Either::A(future::err(e).from_err()),
         };
 
-        future::Either::B(self.api_post_json("messages", &json))
+        future::Either::B(self.api_post_json_with_response("messages", &json))
+    }
+
+    /// Replace the text of a message sent by the bot.
+    pub fn edit_message<'a>(
+        &self,
+        message_id: &MessageIdRef,
+        room_id: &'a RoomIdRef,
+        markdown: &'a str,
+    ) -> impl Future<Item = SentMessage, Error = Error> {
+        self.update_message(
+            message_id,
+            EditMessageParameters {
+                room_id,
+                markdown: Some(markdown),
+                text: None,
+            },
+        )
+    }
+
+    /// Edit a message with the given parameters and return the edited message.
+    pub fn update_message<'a>(
+        &self,
+        message_id: &MessageIdRef,
+        parameters: EditMessageParameters<'a>,
+    ) -> impl Future<Item = SentMessage, Error = Error> {
+        debug!("edit message {}", message_id);
+        let json = match serde_json::to_value(&parameters) {
+            Ok(json) => json,
+            Err(e) => return future::Either::A(future::err(e).from_err()),
+        };
+
+        future::Either::B(
+            self.api_put_json_with_response(&format!("messages/{}", message_id), &json),
+        )
     }
 
     pub fn get_message(
@@ -556,6 +1062,67 @@ impl Client {
     ) -> impl Future<Item = Message, Error = Error> {
         self.api_get_json(&format!("messages/{}", message_id))
     }
+
+    /// Get a card action including the values of the card's inputs.
+    pub fn get_attachment_action(
+        &self,
+        action_id: &AttachmentActionIdRef,
+    ) -> impl Future<Item = AttachmentAction, Error = Error> {
+        self.api_get_json(&format!("attachment/actions/{}", action_id))
+    }
+
+    /// Get a card action together with the email of the person and the type
+    /// of the room the card was posted to.
+    pub fn get_card_action(
+        &self,
+        action_id: &AttachmentActionIdRef,
+    ) -> impl Future<Item = CardAction, Error = Error> {
+        let client = self.clone();
+        self.get_attachment_action(action_id)
+            .and_then(move |action| {
+                let message = client.get_message(&action.message_id);
+                let person = client.get_person_details(&action.person_id);
+                message
+                    .join(person)
+                    .map(|(message, person)| (action, message, person))
+            })
+            .and_then(|(action, message, person)| {
+                let PersonDetails { id, emails, .. } = person;
+                let person_email = emails
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| Error::MissingEmail(format!("person {} has no email", id)))?;
+                Ok(CardAction {
+                    action,
+                    person_email,
+                    room_type: message.room_type,
+                })
+            })
+    }
+
+    /// Get the memberships of the person in the room. The result is empty if
+    /// the person is not a member of the room.
+    pub fn get_memberships(
+        &self,
+        room_id: &RoomIdRef,
+        person_id: &PersonIdRef,
+    ) -> impl Future<Item = Vec<Membership>, Error = Error> {
+        self.api_get_json_with_query(
+            "memberships",
+            &[("roomId", &room_id.0), ("personId", &person_id.0)],
+        )
+        .map(|memberships: Memberships| memberships.items)
+    }
+
+    /// Check if the person is a moderator of the room.
+    pub fn is_moderator(
+        &self,
+        room_id: &RoomIdRef,
+        person_id: &PersonIdRef,
+    ) -> impl Future<Item = bool, Error = Error> {
+        self.get_memberships(room_id, person_id)
+            .map(|memberships| memberships.iter().any(|m| m.is_moderator))
+    }
 }
 
 fn reject_webhook_request(
@@ -672,20 +1239,53 @@ pub fn start_raw_webhook_server(
     RawWebhookServer { messages, server }
 }
 
-/// Fetch messages from webhook message stream using client. Skip messages from
-/// own id, log and then ignore errors.
-fn fetch_messages<M>(client: Client, raw_messages: M) -> impl Stream<Item = Message, Error = ()>
+/// Whether the resource has to be handled by the bot.
+fn is_relevant(data: &WebhookData, own_id: &PersonId) -> bool {
+    match data {
+        // only the bot's own memberships are of interest
+        WebhookData::Membership(event, membership) => {
+            membership.person_id == *own_id && *event != EventType::Updated
+        }
+        // ignore own messages
+        _ => data.person_id() != *own_id,
+    }
+}
+
+/// Fetch the full resource announced by a webhook.
+fn fetch_event(client: &Client, data: WebhookData) -> impl Future<Item = Event, Error = Error> {
+    match data {
+        WebhookData::Message(message) => future::Either::A(future::Either::A(
+            client.get_message(&message.id).map(Event::Message),
+        )),
+        WebhookData::AttachmentAction(action) => future::Either::A(future::Either::B(
+            client.get_card_action(&action.id).map(Event::CardAction),
+        )),
+        WebhookData::Membership(EventType::Deleted, membership) => {
+            future::Either::B(future::ok(Event::BotRemoved(membership)))
+        }
+        WebhookData::Membership(_, membership) => {
+            future::Either::B(future::ok(Event::BotAdded(membership)))
+        }
+    }
+}
+
+/// Fetch messages and card actions from webhook message stream using client.
+/// Skip messages from own id and repeated deliveries, log and then ignore
+/// errors.
+fn fetch_messages<M>(client: Client, raw_messages: M) -> impl Stream<Item = Event, Error = ()>
 where
-    M: Stream<Item = WebhookMessage, Error = ()>,
+    M: Stream<Item = WebhookData, Error = ()>,
 {
     let own_id = client.id().clone();
+    let mut deduplicator = Deduplicator::default();
     raw_messages
-        // ignore own messages
-        .filter(move |post| post.data.person_id != own_id)
-        .and_then(move |post| {
-            client.get_message(&post.data.id).then(|message_result| {
+        // drop repeated deliveries of the same resource
+        .filter(move |data| deduplicator.is_new(data))
+        .filter(move |data| is_relevant(data, &own_id))
+        .and_then(move |data| {
+            fetch_event(&client, data).then(|event_result| {
                 future::ok(
-                    message_result
+                    event_result
                         .map_err(|e| error!("failed to fetch message: {}", e))
                         .map(Some)
                         .unwrap_or(None),
@@ -697,7 +1297,7 @@ where
 
 pub struct WebhookServer<M, S>
 where
-    M: Stream<Item = Message, Error = ()>,
+    M: Stream<Item = Event, Error = ()>,
     S: Future<Item = (), Error = hyper::Error>,
 {
     /// Stream of webhook posts.
@@ -711,7 +1311,7 @@ pub fn start_webhook_server(
     listen_address: &SocketAddr,
     client: Client,
 ) -> WebhookServer<
-    impl Stream<Item = Message, Error = ()>,
+    impl Stream<Item = Event, Error = ()>,
     impl Future<Item = (), Error = hyper::Error>,
 > {
     let RawWebhookServer {
@@ -719,36 +1319,125 @@ pub fn start_webhook_server(
         server,
     } = start_raw_webhook_server(listen_address);
 
-    let messages = fetch_messages(client, raw_messages);
+    let messages = fetch_messages(client, raw_messages.filter_map(webhook_data));
 
     WebhookServer { messages, server }
 }
 
+/// Decode the body of a message received from SQS.
+fn parse_sqs_message(sqs_message: &rusoto_sqs::Message) -> Option<WebhookMessage> {
+    let body = sqs_message.body.as_ref()?;
+    serde_json::from_str(body)
+        .map_err(|e| error!("failed to parse sqs message body: {}", e))
+        .ok()
+}
+
+/// Stream of the webhook posts received from SQS. Each message is deleted from
+/// the queue when the next one is requested. Messages which cannot be parsed
+/// are left in the queue.
 pub fn raw_sqs_event_stream(
-    sqs_url: String,
-    sqs_region: rusoto_core::Region,
-) -> impl Stream<Item = WebhookMessage, Error = ()> {
-    sqs::sqs_receiver(sqs_url, sqs_region)
-        // skip messages with an empty body
-        .filter_map(|sqs_message| sqs_message.body)
-        // decode body
-        .and_then(|data| {
-            future::ok(
-                serde_json::from_str(&data)
-                    // log and ignore errors
-                    .map_err(|e| error!("failed to parse sqs message body: {}", e))
-                    .ok(),
-            )
-        })
-        .filter_map(identity)
+    queue: SqsQueue,
+) -> Result<impl Stream<Item = WebhookMessage, Error = ()>, Error> {
+    let (queue_client, sqs_messages) = sqs::sqs_receiver(queue)?;
+    let failed_client = queue_client.clone();
+    let raw_messages = sqs_messages.filter_map(move |sqs_message| {
+        let receipt_handle = sqs_message.receipt_handle.clone()?;
+        match parse_sqs_message(&sqs_message) {
+            Some(raw_message) => Some((raw_message, receipt_handle)),
+            None => {
+                failed_client.failed(&sqs_message);
+                None
+            }
+        }
+    });
+    Ok(sqs::DeleteProcessed::new(
+        raw_messages,
+        move |receipt_handle| queue_client.delete(receipt_handle),
+    ))
 }
 
+/// Stream of the events received from SQS.
+///
+/// Every message is deleted from the queue only after the bot requested the
+/// next event, i.e. after the event was handled. Messages which cannot be
+/// parsed or whose resource cannot be fetched are left in the queue and are
+/// received again after its visibility timeout. Messages which are not
+/// relevant for the bot or which were delivered before are deleted right away.
 pub fn sqs_event_stream(
-    sqs_url: String,
-    sqs_region: rusoto_core::Region,
+    queue: SqsQueue,
+    client: Client,
+) -> Result<impl Stream<Item = Event, Error = ()>, Error> {
+    let (queue_client, sqs_messages) = sqs::sqs_receiver(queue)?;
+    let skipped_client = queue_client.clone();
+    let processed_client = queue_client.clone();
+    let failed_client = queue_client.clone();
+    let own_id = client.id().clone();
+    let deduplicator = Arc::new(Mutex::new(Deduplicator::default()));
+    let delivered = deduplicator.clone();
+
+    let events = sqs_messages
+        .filter_map(move |sqs_message| {
+            let receipt_handle = sqs_message.receipt_handle.clone()?;
+            match parse_sqs_message(&sqs_message).and_then(webhook_data) {
+                Some(data) => Some((sqs_message, receipt_handle, data)),
+                None => {
+                    skipped_client.failed(&sqs_message);
+                    None
+                }
+            }
+        })
+        .filter(move |(_, receipt_handle, data)| {
+            // deliveries are only remembered after the resource was fetched
+            let relevant =
+                is_relevant(data, &own_id) && !delivered.lock().unwrap().is_duplicate(data);
+            if !relevant {
+                processed_client.delete(receipt_handle.clone());
+            }
+            relevant
+        })
+        .and_then(move |(sqs_message, receipt_handle, data)| {
+            let failed_client = failed_client.clone();
+            let deduplicator = deduplicator.clone();
+            fetch_event(&client, data.clone()).then(move |event_result| {
+                future::ok(match event_result {
+                    Ok(event) => {
+                        deduplicator.lock().unwrap().remember(&data);
+                        Some((event, receipt_handle))
+                    }
+                    Err(e) => {
+                        error!("failed to fetch message: {}", e);
+                        failed_client.failed(&sqs_message);
+                        None
+                    }
+                })
+            })
+        })
+        .filter_map(identity);
+    Ok(sqs::DeleteProcessed::new(events, move |receipt_handle| {
+        queue_client.delete(receipt_handle)
+    }))
+}
+
+/// Stream of the messages received over the websocket of a Webex device. If
+/// no websocket url is given, a device is registered at `devices_url` to get
+/// one.
+pub fn websocket_event_stream(
+    devices_url: String,
+    websocket_url: Option<String>,
     client: Client,
-) -> impl Stream<Item = Message, Error = ()> {
-    let raw_messages = raw_sqs_event_stream(sqs_url, sqs_region);
+) -> impl Stream<Item = Event, Error = ()> {
+    let websocket_url = match websocket_url {
+        Some(websocket_url) => future::Either::A(future::ok(websocket_url)),
+        None => future::Either::B(
+            client
+                .register_device(&devices_url)
+                .map_err(|e| error!("failed to register device: {}", e)),
+        ),
+    };
+    let bot_token = client.bot_token.clone();
+    let raw_messages = websocket_url
+        .map(move |websocket_url| mercury::websocket_receiver(websocket_url, bot_token))
+        .flatten_stream();
     fetch_messages(client, raw_messages)
 }
 
@@ -756,10 +1445,159 @@ pub fn sqs_event_stream(
 mod test {
     use super::*;
 
+    #[test]
+    fn next_page_url_from_link_header() {
+        let mut headers = http::HeaderMap::new();
+        assert_eq!(next_page_url(&headers), None);
+
+        headers.insert(
+            http::header::LINK,
+            r#"<https://api.example.org/v1/webhooks?max=2&cursor=abc>; rel="next""#
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(
+            next_page_url(&headers),
+            Some("https://api.example.org/v1/webhooks?max=2&cursor=abc".to_string())
+        );
+
+        headers.insert(
+            http::header::LINK,
+            r#"<https://api.example.org/v1/webhooks?max=2>; rel="first""#
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(next_page_url(&headers), None);
+    }
+
     #[test]
     fn person_id_ref() {
         let p = PersonId("person-id".to_string());
         let ref_p: &PersonIdRef = &p;
         assert_eq!(p, ref_p);
     }
+
+    #[test]
+    fn text_without_mentions() {
+        let message = Message {
+            text: "GerritBot filter enable".to_string(),
+            html: Some(
+                r#"<p><spark-mention data-object-type="person" data-object-id="bot-id">GerritBot</spark-mention> filter enable</p>"#
+                    .to_string(),
+            ),
+            mentioned_people: vec![PersonId("bot-id".to_string())],
+            ..Default::default()
+        };
+        assert!(message.mentions(PersonIdRef::new("bot-id")));
+        assert!(!message.mentions(PersonIdRef::new("other-id")));
+        assert_eq!(message.text_without_mentions(), "filter enable");
+    }
+
+    #[test]
+    fn deserialize_memberships() {
+        let memberships: Memberships = serde_json::from_str(
+            r#"{"items":[{"id":"membership-id","roomId":"room-id","personId":"person-id","personEmail":"person@example.com","personDisplayName":"Person","personOrgId":"org-id","isModerator":true,"isMonitor":false,"created":"2019-03-01T12:00:00.000Z"}]}"#,
+        )
+        .expect("failed to deserialize memberships");
+        assert_eq!(memberships.items.len(), 1);
+        assert_eq!(
+            memberships.items[0].person_id,
+            PersonIdRef::new("person-id")
+        );
+        assert!(memberships.items[0].is_moderator);
+    }
+
+    #[test]
+    fn serialize_create_message_parameters() {
+        let room_id = RoomId("room-id".to_string());
+        let parameters = CreateMessageParameters {
+            target: (&room_id).into(),
+            markdown: Some("reply"),
+            text: None,
+            html: None,
+            parent_id: Some(MessageIdRef::new("parent-id")),
+            attachments: &[],
+        };
+        let json = serde_json::to_value(&parameters).unwrap();
+        assert_eq!(json["roomId"], "room-id");
+        assert_eq!(json["parentId"], "parent-id");
+        assert!(json.get("attachments").is_none());
+
+        let parameters = CreateMessageParameters {
+            parent_id: None,
+            ..parameters
+        };
+        let json = serde_json::to_value(&parameters).unwrap();
+        assert!(json.get("parentId").is_none());
+    }
+
+    #[test]
+    fn serialize_card_attachment() {
+        let room_id = RoomId("room-id".to_string());
+        let attachments = [Attachment::adaptive_card(
+            serde_json::json!({"type": "AdaptiveCard", "version": "1.0"}),
+        )];
+        let parameters = CreateMessageParameters {
+            target: (&room_id).into(),
+            markdown: Some("fallback"),
+            text: None,
+            html: None,
+            parent_id: None,
+            attachments: &attachments,
+        };
+        let json = serde_json::to_value(&parameters).unwrap();
+        assert_eq!(
+            json["attachments"][0]["contentType"],
+            ADAPTIVE_CARD_CONTENT_TYPE
+        );
+        assert_eq!(json["attachments"][0]["content"]["type"], "AdaptiveCard");
+    }
+
+    #[test]
+    fn deserialize_webhook_data() {
+        let message: WebhookData = serde_json::from_str(
+            r#"{"id":"message-id","roomId":"room-id","roomType":"group","personId":"person-id","personEmail":"person@example.com","created":"2019-03-01T12:00:00.000Z"}"#,
+        )
+        .expect("failed to deserialize message");
+        match message {
+            WebhookData::Message(message) => {
+                assert_eq!(message.id, MessageIdRef::new("message-id"))
+            }
+            data => panic!("wrong webhook data: {:?}", data),
+        }
+
+        let action: WebhookData = serde_json::from_str(
+            r#"{"id":"action-id","type":"submit","messageId":"message-id","personId":"person-id","roomId":"room-id","created":"2019-03-01T12:00:00.000Z"}"#,
+        )
+        .expect("failed to deserialize action");
+        match action {
+            WebhookData::AttachmentAction(action) => {
+                assert_eq!(action.id, AttachmentActionIdRef::new("action-id"));
+                assert_eq!(action.person_id, PersonIdRef::new("person-id"));
+                assert!(action.inputs.is_empty());
+            }
+            data => panic!("wrong webhook data: {:?}", data),
+        }
+    }
+
+    #[test]
+    fn serialize_edit_message_parameters() {
+        let parameters = EditMessageParameters {
+            room_id: RoomIdRef::new("room-id"),
+            markdown: Some("edited"),
+            text: None,
+        };
+        let json = serde_json::to_value(&parameters).unwrap();
+        assert_eq!(json["roomId"], "room-id");
+        assert_eq!(json["markdown"], "edited");
+    }
+
+    #[test]
+    fn text_without_mentions_plain_message() {
+        let message = Message {
+            text: "status".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(message.text_without_mentions(), "status");
+    }
 }
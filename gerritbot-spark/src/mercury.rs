@@ -0,0 +1,371 @@
+//! Receive events over the websocket of a Webex device ("Mercury"), like the
+//! official SDKs do. This does not need a public endpoint for webhooks.
+
+use std::time::{Duration, Instant};
+
+use futures::future::{self, Future};
+use futures::sync::mpsc;
+use futures::{stream, IntoFuture as _, Sink, Stream};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::timer::Delay;
+use websocket::{ClientBuilder, OwnedMessage};
+
+use super::{
+    decode_json_body, AttachmentAction, AttachmentActionId, Client, Email, Error, Message,
+    MessageId, PersonId, RoomId, WebhookData,
+};
+
+/// Url to register devices at.
+pub const DEFAULT_DEVICES_URL: &str = "https://wdm-a.wbx2.com/wdm/api/v1/devices";
+
+/// Delay before reconnecting after the websocket was closed.
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct DeviceRegistration {
+    device_name: &'static str,
+    device_type: &'static str,
+    localized_model: &'static str,
+    model: &'static str,
+    name: &'static str,
+    system_name: &'static str,
+    system_version: &'static str,
+}
+
+const DEVICE_REGISTRATION: DeviceRegistration = DeviceRegistration {
+    device_name: "gerritbot",
+    device_type: "DESKTOP",
+    localized_model: "rust",
+    model: "rust",
+    name: "gerritbot",
+    system_name: "gerritbot",
+    system_version: env!("CARGO_PKG_VERSION"),
+};
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Device {
+    web_socket_url: String,
+}
+
+#[derive(Serialize, Debug)]
+struct Authorization<'a> {
+    id: &'a str,
+    #[serde(rename = "type")]
+    message_type: &'a str,
+    data: AuthorizationData,
+}
+
+#[derive(Serialize, Debug)]
+struct AuthorizationData {
+    token: String,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Ack<'a> {
+    #[serde(rename = "type")]
+    message_type: &'a str,
+    message_id: &'a str,
+}
+
+/// Event received over the websocket.
+#[derive(Deserialize, Debug)]
+struct MercuryMessage {
+    id: String,
+    #[serde(default)]
+    data: Option<MercuryData>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct MercuryData {
+    event_type: String,
+    activity: Option<Activity>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Activity {
+    id: String,
+    verb: String,
+    actor: Actor,
+    target: Option<ActivityTarget>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Actor {
+    id: String,
+    email_address: Option<String>,
+    #[serde(rename = "entryUUID")]
+    entry_uuid: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ActivityTarget {
+    id: String,
+}
+
+/// Build the id used by the REST API from the uuid of a resource.
+fn hydra_id(resource: &str, uuid: &str) -> String {
+    base64::encode_config(
+        &format!("ciscospark://us/{}/{}", resource, uuid),
+        base64::URL_SAFE_NO_PAD,
+    )
+}
+
+/// Translate a conversation activity into the data of the corresponding
+/// webhook. Only the ids are filled in, the rest has to be fetched.
+fn activity_to_webhook_data(activity: Activity) -> Option<WebhookData> {
+    let person_id = PersonId::new(hydra_id(
+        "PEOPLE",
+        activity
+            .actor
+            .entry_uuid
+            .as_ref()
+            .unwrap_or(&activity.actor.id),
+    ));
+    let room_id = activity
+        .target
+        .map(|target| RoomId::new(hydra_id("ROOM", &target.id)))
+        .unwrap_or_default();
+
+    match &activity.verb[..] {
+        "post" | "share" => Some(WebhookData::Message(Message {
+            id: MessageId::new(hydra_id("MESSAGE", &activity.id)),
+            person_email: Email::new(activity.actor.email_address.unwrap_or_default()),
+            person_id,
+            room_id,
+            ..Default::default()
+        })),
+        "cardAction" => Some(WebhookData::AttachmentAction(AttachmentAction {
+            id: AttachmentActionId::new(hydra_id("ATTACHMENT_ACTION", &activity.id)),
+            action_type: "submit".to_string(),
+            message_id: Default::default(),
+            person_id,
+            room_id,
+            created: None,
+            inputs: Default::default(),
+        })),
+        _ => None,
+    }
+}
+
+/// Parse a text message received over the websocket. Returns the id to
+/// acknowledge and the webhook data of the event, if it is of interest.
+fn parse_message(text: &str) -> Result<(String, Option<WebhookData>), serde_json::Error> {
+    let message: MercuryMessage = serde_json::from_str(text)?;
+    let data = message
+        .data
+        .filter(|data| data.event_type == "conversation.activity")
+        .and_then(|data| data.activity)
+        .and_then(activity_to_webhook_data);
+    Ok((message.id, data))
+}
+
+impl Client {
+    /// Register a device for the bot and return the url of its websocket.
+    pub fn register_device(&self, devices_url: &str) -> impl Future<Item = String, Error = Error> {
+        debug!("registering device at {}", devices_url);
+        self.client
+            .post(devices_url)
+            .bearer_auth(&self.bot_token)
+            .header(http::header::ACCEPT, "application/json")
+            .json(&DEVICE_REGISTRATION)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .from_err()
+            .and_then(|response| decode_json_body(response.into_body()))
+            .map(|device: Device| device.web_socket_url)
+    }
+}
+
+/// Connect to the websocket once and stream the received events until the
+/// connection is closed. Every event is acknowledged.
+fn connect(websocket_url: &str, bot_token: &str) -> impl Stream<Item = WebhookData, Error = ()> {
+    let authorization = serde_json::to_string(&Authorization {
+        id: "gerritbot-authorization",
+        message_type: "authorization",
+        data: AuthorizationData {
+            token: format!("Bearer {}", bot_token),
+        },
+    })
+    .expect("failed to serialize authorization");
+
+    info!("connecting to websocket {}", websocket_url);
+    ClientBuilder::new(websocket_url)
+        .map_err(|e| error!("invalid websocket url: {}", e))
+        .into_future()
+        .and_then(|builder| {
+            builder
+                .async_connect(None)
+                .and_then(move |(client, _headers)| client.send(OwnedMessage::Text(authorization)))
+                .map_err(|e| error!("failed to connect to websocket: {}", e))
+        })
+        .map(|client| {
+            info!("connected to websocket");
+            let (sink, messages) = client.split();
+            // replies, i.e. acks and pongs, are sent by a separate task
+            let (reply_sink, replies) = mpsc::unbounded();
+            tokio::spawn(
+                replies
+                    .forward(sink.sink_map_err(|e| error!("failed to reply on websocket: {}", e)))
+                    .map(|_| ()),
+            );
+
+            messages
+                .map_err(|e| error!("failed to receive from websocket: {}", e))
+                .take_while(|message| future::ok(!message.is_close()))
+                .filter_map(move |message| match message {
+                    OwnedMessage::Text(text) => match parse_message(&text) {
+                        Ok((id, data)) => {
+                            let ack = serde_json::to_string(&Ack {
+                                message_type: "ack",
+                                message_id: &id,
+                            })
+                            .expect("failed to serialize ack");
+                            let _ = reply_sink.unbounded_send(OwnedMessage::Text(ack));
+                            data
+                        }
+                        Err(e) => {
+                            warn!("failed to parse websocket message: {}", e);
+                            None
+                        }
+                    },
+                    OwnedMessage::Ping(data) => {
+                        let _ = reply_sink.unbounded_send(OwnedMessage::Pong(data));
+                        None
+                    }
+                    _ => None,
+                })
+        })
+        .flatten_stream()
+}
+
+/// Stream of the events received over the websocket at the given url. The
+/// websocket is reconnected if the connection is closed.
+pub fn websocket_receiver(
+    websocket_url: String,
+    bot_token: String,
+) -> impl Stream<Item = WebhookData, Error = ()> {
+    stream::iter_ok::<_, ()>(0..)
+        .map(move |attempt: u64| {
+            let delay = if attempt == 0 {
+                future::Either::A(future::ok(()))
+            } else {
+                warn!("websocket closed, reconnecting in {:?}", RECONNECT_DELAY);
+                future::Either::B(
+                    Delay::new(Instant::now() + RECONNECT_DELAY)
+                        .map_err(|e| error!("reconnect timer failed: {}", e)),
+                )
+            };
+            let websocket_url = websocket_url.clone();
+            let bot_token = bot_token.clone();
+            // errors are already logged, they only end the connection
+            delay
+                .map(move |()| connect(&websocket_url, &bot_token))
+                .flatten_stream()
+                .then(Ok::<_, ()>)
+                .take_while(|result| future::ok(result.is_ok()))
+                .filter_map(Result::ok)
+        })
+        .flatten()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ACTIVITY_JSON: &str = r#"{"id":"mercury-id","data":{"eventType":"conversation.activity","activity":{"id":"activity-uuid","verb":"post","actor":{"id":"actor-uuid","emailAddress":"person@example.com","entryUUID":"entry-uuid"},"target":{"id":"conversation-uuid"}}},"timestamp":1553631812}"#;
+
+    #[test]
+    fn parse_post_activity() {
+        let (id, data) = parse_message(ACTIVITY_JSON).expect("failed to parse message");
+        assert_eq!(id, "mercury-id");
+        match data {
+            Some(WebhookData::Message(message)) => {
+                assert_eq!(message.id.as_str(), hydra_id("MESSAGE", "activity-uuid"));
+                assert_eq!(message.person_id.as_str(), hydra_id("PEOPLE", "entry-uuid"));
+                assert_eq!(
+                    message.room_id.as_str(),
+                    hydra_id("ROOM", "conversation-uuid")
+                );
+                assert_eq!(message.person_email.as_str(), "person@example.com");
+            }
+            data => panic!("unexpected data: {:?}", data),
+        }
+    }
+
+    #[test]
+    fn ignore_other_events() {
+        let (id, data) =
+            parse_message(r#"{"id":"mercury-id","data":{"eventType":"status.start_typing"}}"#)
+                .expect("failed to parse message");
+        assert_eq!(id, "mercury-id");
+        assert!(data.is_none());
+    }
+
+    #[test]
+    fn hydra_ids() {
+        assert_eq!(
+            hydra_id("PEOPLE", "f5b36187-c8dd-4727-8b2f-f9c447f29046"),
+            "Y2lzY29zcGFyazovL3VzL1BFT1BMRS9mNWIzNjE4Ny1jOGRkLTQ3MjctOGIyZi1mOWM0NDdmMjkwNDY"
+        );
+    }
+
+    #[test]
+    fn receive_from_local_websocket() {
+        use websocket::r#async::Server;
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let server = Server::bind("127.0.0.1:0", &tokio::reactor::Handle::default()).unwrap();
+        let url = format!("ws://{}", server.local_addr().unwrap());
+
+        // accept a single connection, check the authorization, send an
+        // activity and wait for its ack
+        let (received_sink, received) = mpsc::unbounded();
+        let stand_in = server
+            .incoming()
+            .take(1)
+            .map_err(|e| panic!("failed to accept connection: {:?}", e.error))
+            .and_then(|(upgrade, _addr)| upgrade.accept().map_err(|e| panic!("{}", e)))
+            .and_then(|(client, _headers)| client.into_future().map_err(|(e, _)| panic!("{}", e)))
+            .and_then(move |(authorization, client)| {
+                let received_sink = received_sink.clone();
+                received_sink.unbounded_send(authorization).unwrap();
+                client
+                    .send(OwnedMessage::Text(ACTIVITY_JSON.to_string()))
+                    .and_then(|client| client.into_future().map_err(|(e, _)| e))
+                    .map(move |(ack, _client)| received_sink.unbounded_send(ack).unwrap())
+                    .map_err(|e| panic!("{}", e))
+            })
+            .for_each(|()| Ok(()));
+        runtime.spawn(stand_in);
+
+        let events = runtime
+            .block_on(
+                websocket_receiver(url, "token".to_string())
+                    .take(1)
+                    .collect(),
+            )
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        match &events[0] {
+            WebhookData::Message(message) => {
+                assert_eq!(message.id.as_str(), hydra_id("MESSAGE", "activity-uuid"))
+            }
+            data => panic!("unexpected data: {:?}", data),
+        }
+
+        let received: Vec<_> = runtime.block_on(received.take(2).collect()).unwrap();
+        match &received[..] {
+            [Some(OwnedMessage::Text(authorization)), Some(OwnedMessage::Text(ack))] => {
+                assert!(authorization.contains(r#""token":"Bearer token""#));
+                assert!(ack.contains(r#""messageId":"mercury-id""#));
+            }
+            received => panic!("unexpected messages: {:?}", received),
+        }
+    }
+}
@@ -1,87 +1,385 @@
 use std::convert::identity;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use futures::{future, stream, Future, Stream};
-use log::{error, warn};
-use rusoto_core::Region;
+use futures::{future, stream, Async, Future, Poll, Stream};
+use log::{debug, error, warn};
+use rusoto_core::credential::{ProfileProvider, StaticProvider};
+use rusoto_core::{DefaultCredentialsProvider, HttpClient, Region};
 use rusoto_sqs::{
-    DeleteMessageBatchRequest, DeleteMessageBatchRequestEntry, Message, ReceiveMessageRequest,
-    Sqs as _, SqsClient,
+    DeleteMessageRequest, Message, ReceiveMessageRequest, SendMessageRequest, Sqs as _, SqsClient,
 };
+use serde::Deserialize;
+use tokio::timer::Delay;
 
+use crate::Error;
+
+/// Attribute of a message counting how often it was received.
+const RECEIVE_COUNT_ATTRIBUTE: &str = "ApproximateReceiveCount";
+
+/// Maximum time to wait for messages in a single poll (long polling).
+const DEFAULT_WAIT_TIME: Duration = Duration::from_secs(10);
+
+/// Maximum number of messages received in a single poll.
+const DEFAULT_BATCH_SIZE: usize = 10;
+
+/// Longest wait time of a poll accepted by SQS.
+const MAX_WAIT_TIME: Duration = Duration::from_secs(20);
+
+/// Largest number of messages per poll accepted by SQS.
+const MAX_BATCH_SIZE: usize = 10;
+
+/// Time to wait before polling again after a failed poll.
+const RECEIVE_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Credentials used to access the queue.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub enum SqsCredentials {
+    /// Credentials from the environment, the credentials file, the container
+    /// or the instance metadata, in this order.
+    #[default]
+    Default,
+    /// Profile of a credentials file. Without name, `AWS_PROFILE` or
+    /// `default` is used. Without file, `~/.aws/credentials` is used.
+    Profile {
+        #[serde(default)]
+        name: Option<String>,
+        #[serde(default)]
+        file: Option<PathBuf>,
+    },
+    /// Fixed credentials, e.g. for a local stand-in of SQS.
+    Static {
+        access_key_id: String,
+        secret_access_key: String,
+        #[serde(default)]
+        session_token: Option<String>,
+    },
+}
+
+/// SQS queue the Webex messages are forwarded to.
+///
+/// Messages are only deleted from the queue after they were processed. Failed
+/// messages are received again after the visibility timeout of the queue. If a
+/// dead-letter queue is set, messages which failed too often are moved there.
+/// Otherwise, the redrive policy of the queue applies.
+#[derive(Debug, Clone)]
+pub struct SqsQueue {
+    url: String,
+    region: Region,
+    credentials: SqsCredentials,
+    wait_time: Duration,
+    batch_size: usize,
+    dead_letter_queue: Option<DeadLetterQueue>,
+}
+
+#[derive(Debug, Clone)]
+struct DeadLetterQueue {
+    url: String,
+    max_receive_count: u32,
+}
+
+impl SqsQueue {
+    pub fn new(url: String, region: Region) -> Self {
+        Self {
+            url,
+            region,
+            credentials: SqsCredentials::default(),
+            wait_time: DEFAULT_WAIT_TIME,
+            batch_size: DEFAULT_BATCH_SIZE,
+            dead_letter_queue: None,
+        }
+    }
+
+    /// Send the requests to this endpoint instead of the one of the region,
+    /// e.g. to an SQS compatible service like ElasticMQ.
+    pub fn with_endpoint(self, endpoint: String) -> Self {
+        let region = Region::Custom {
+            name: self.region.name().to_string(),
+            endpoint,
+        };
+        Self { region, ..self }
+    }
+
+    pub fn with_credentials(self, credentials: SqsCredentials) -> Self {
+        Self {
+            credentials,
+            ..self
+        }
+    }
+
+    /// Maximum time a poll waits for messages. SQS allows at most 20 seconds.
+    pub fn with_wait_time(self, wait_time: Duration) -> Result<Self, Error> {
+        if wait_time > MAX_WAIT_TIME {
+            return Err(Error::InvalidQueueConfig(format!(
+                "wait time of {} seconds exceeds the maximum of {} seconds",
+                wait_time.as_secs(),
+                MAX_WAIT_TIME.as_secs()
+            )));
+        }
+        Ok(Self { wait_time, ..self })
+    }
+
+    /// Maximum number of messages received by a poll. SQS allows 1 to 10
+    /// messages.
+    pub fn with_batch_size(self, batch_size: usize) -> Result<Self, Error> {
+        if !(1..=MAX_BATCH_SIZE).contains(&batch_size) {
+            return Err(Error::InvalidQueueConfig(format!(
+                "batch size of {} is not between 1 and {}",
+                batch_size, MAX_BATCH_SIZE
+            )));
+        }
+        Ok(Self { batch_size, ..self })
+    }
+
+    /// Move messages which failed to be processed `max_receive_count` times to
+    /// the queue with the given url.
+    pub fn with_dead_letter_queue(self, url: String, max_receive_count: u32) -> Self {
+        Self {
+            dead_letter_queue: Some(DeadLetterQueue {
+                url,
+                max_receive_count,
+            }),
+            ..self
+        }
+    }
+
+    fn client(&self) -> Result<SqsClient, Error> {
+        let dispatcher = HttpClient::new()?;
+        let region = self.region.clone();
+        let client = match &self.credentials {
+            SqsCredentials::Default => {
+                SqsClient::new_with(dispatcher, DefaultCredentialsProvider::new()?, region)
+            }
+            SqsCredentials::Profile { name, file } => {
+                let mut provider = match file {
+                    Some(file) => ProfileProvider::with_default_configuration(file),
+                    None => ProfileProvider::new()?,
+                };
+                if let Some(name) = name {
+                    provider.set_profile(name.as_str());
+                }
+                SqsClient::new_with(dispatcher, provider, region)
+            }
+            SqsCredentials::Static {
+                access_key_id,
+                secret_access_key,
+                session_token,
+            } => {
+                let provider = StaticProvider::new(
+                    access_key_id.clone(),
+                    secret_access_key.clone(),
+                    session_token.clone(),
+                    None,
+                );
+                SqsClient::new_with(dispatcher, provider, region)
+            }
+        };
+        Ok(client)
+    }
+}
+
+/// Client of a queue, used to delete messages and move them to the
+/// dead-letter queue.
+#[derive(Clone)]
+pub struct QueueClient {
+    client: Arc<SqsClient>,
+    queue: SqsQueue,
+}
+
+impl QueueClient {
+    fn new(client: Arc<SqsClient>, queue: SqsQueue) -> Self {
+        Self { client, queue }
+    }
+
+    /// Delete a processed message from the queue in the background.
+    pub fn delete(&self, receipt_handle: String) {
+        let request = DeleteMessageRequest {
+            queue_url: self.queue.url.clone(),
+            receipt_handle,
+        };
+        tokio::spawn(
+            self.client
+                .delete_message(request)
+                .map(|()| debug!("deleted message from queue"))
+                .map_err(|e| error!("failed to delete message from queue: {}", e)),
+        );
+    }
+
+    /// Handle a message which could not be processed. It is moved to the
+    /// dead-letter queue if it failed too often, otherwise it is left in the
+    /// queue to be received again.
+    pub fn failed(&self, message: &Message) {
+        let dead_letter_queue = match &self.queue.dead_letter_queue {
+            Some(dead_letter_queue) => dead_letter_queue,
+            None => return,
+        };
+        let receive_count: u32 = message
+            .attributes
+            .as_ref()
+            .and_then(|attributes| attributes.get(RECEIVE_COUNT_ATTRIBUTE))
+            .and_then(|count| count.parse().ok())
+            .unwrap_or(1);
+        let receipt_handle = match &message.receipt_handle {
+            Some(receipt_handle) if receive_count >= dead_letter_queue.max_receive_count => {
+                receipt_handle.clone()
+            }
+            _ => return,
+        };
+
+        warn!(
+            "moving message {:?} to dead-letter queue after {} attempts",
+            message.message_id, receive_count
+        );
+        let request = SendMessageRequest {
+            queue_url: dead_letter_queue.url.clone(),
+            message_body: message.body.clone().unwrap_or_default(),
+            ..Default::default()
+        };
+        let queue = self.clone();
+        tokio::spawn(
+            self.client
+                .send_message(request)
+                .map_err(|e| error!("failed to move message to dead-letter queue: {}", e))
+                .map(move |_| queue.delete(receipt_handle)),
+        );
+    }
+}
+
+/// Receive messages from the queue. The messages are not deleted, cf.
+/// `QueueClient`.
 pub fn sqs_receiver(
-    queue_url: String,
-    queue_region: Region,
-) -> impl Stream<Item = Message, Error = ()> {
+    queue: SqsQueue,
+) -> Result<(QueueClient, impl Stream<Item = Message, Error = ()>), Error> {
     // set up receiver client and receive request template
-    let receive_client = SqsClient::new(queue_region.clone());
+    let receive_client = Arc::new(queue.client()?);
+    let queue_client = QueueClient::new(receive_client.clone(), queue.clone());
     let receive_request = ReceiveMessageRequest {
-        queue_url: queue_url.clone(),
-        wait_time_seconds: Some(10),
-        max_number_of_messages: Some(10),
-        ..Default::default()
-    };
-    // set up deleter client and delete request template
-    let delete_client = SqsClient::new(queue_region.clone());
-    let delete_request = DeleteMessageBatchRequest {
-        queue_url: queue_url.clone(),
+        queue_url: queue.url,
+        wait_time_seconds: Some(queue.wait_time.as_secs() as i64),
+        max_number_of_messages: Some(queue.batch_size as i64),
+        attribute_names: Some(vec![RECEIVE_COUNT_ATTRIBUTE.to_string()]),
         ..Default::default()
     };
 
     // repeatedly poll for messages
-    stream::unfold((), move |()| {
-        Some(
-            receive_client
-                .receive_message(receive_request.clone())
-                .map(|receive_result| (receive_result, ())),
-        )
+    let messages = stream::unfold((), move |()| {
+        // errors must not reach unfold, which would end the stream
+        let receive_result = receive_client
+            .receive_message(receive_request.clone())
+            .then(|receive_result| match receive_result {
+                Ok(receive_result) => future::Either::A(future::ok(Some(receive_result))),
+                // log the errors and skip the errors after a delay
+                Err(e) => {
+                    error!("failed to receive message: {}", e);
+                    future::Either::B(
+                        Delay::new(Instant::now() + RECEIVE_RETRY_DELAY).then(|_| Ok(None)),
+                    )
+                }
+            });
+        Some(receive_result.map(|receive_result| (receive_result, ())))
     })
-    // log the errors and skip the errors
-    .map_err(|e| error!("failed to receive message: {}", e))
-    .then(|result| future::ok(result.ok()))
     .filter_map(identity)
-    // delete messages from the queue
-    .and_then(move |receive_result| {
-        let messages = receive_result.messages.unwrap_or_else(Vec::new);
-
-        if !messages.is_empty() {
-            // prepare delete request
-            let delete_request = DeleteMessageBatchRequest {
-                entries: messages
-                    .iter()
-                    .filter_map(|message| message.receipt_handle.clone())
-                    .enumerate()
-                    .map(|(index, receipt_handle)| DeleteMessageBatchRequestEntry {
-                        id: index.to_string(),
-                        receipt_handle,
-                    })
-                    .collect(),
-                ..delete_request.clone()
-            };
-
-            // send delete request
-            future::Either::A(delete_client.delete_message_batch(delete_request).then(
-                |delete_request_result| {
-                    // log errors, if any
-                    match delete_request_result {
-                        Ok(ref delete_result) if !delete_result.failed.is_empty() => {
-                            warn!("failed to delete some messages: {:?}", delete_result.failed);
-                        }
-                        Ok(_) => (),
-                        Err(e) => {
-                            error!("message delete request failed: {}", e);
-                        }
-                    }
-
-                    // forward messages
-                    future::ok(messages)
-                },
-            ))
-        } else {
-            // timeout, no messages received
-            future::Either::B(future::ok(messages))
-        }
-    })
     // flatten messages to return one by one
-    .map(stream::iter_ok)
-    .flatten()
+    .map(|receive_result| stream::iter_ok(receive_result.messages.unwrap_or_else(Vec::new)))
+    .flatten();
+
+    Ok((queue_client, messages))
+}
+
+/// Stream of items, each with the receipt handle of the message it was created
+/// from. The message of an item is deleted when the next item is requested,
+/// i.e. after the item was processed.
+///
+/// Processed only means that the consumer asked for the next item, not that
+/// the effects of the item are durable. E.g. the replies of the bot may still
+/// be waiting to be written to the outbox file, and are lost if the bot
+/// crashes before. The replies to a message are therefore delivered at most
+/// once.
+pub struct DeleteProcessed<S, D> {
+    items: S,
+    delete: D,
+    processed: Option<String>,
+}
+
+impl<S, D> DeleteProcessed<S, D> {
+    pub fn new(items: S, delete: D) -> Self {
+        Self {
+            items,
+            delete,
+            processed: None,
+        }
+    }
+}
+
+impl<S, D, T> Stream for DeleteProcessed<S, D>
+where
+    S: Stream<Item = (T, String)>,
+    D: FnMut(String),
+{
+    type Item = T;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<T>, S::Error> {
+        if let Some(receipt_handle) = self.processed.take() {
+            (self.delete)(receipt_handle);
+        }
+        match self.items.poll()? {
+            Async::Ready(Some((item, receipt_handle))) => {
+                self.processed = Some(receipt_handle);
+                Ok(Async::Ready(Some(item)))
+            }
+            Async::Ready(None) => Ok(Async::Ready(None)),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn delete_after_processing() {
+        let deleted = Rc::new(RefCell::new(Vec::new()));
+        let delete_log = deleted.clone();
+        let mut items = DeleteProcessed::new(
+            stream::iter_ok::<_, ()>(vec![
+                ("first", "handle-1".to_string()),
+                ("second", "handle-2".to_string()),
+            ]),
+            move |receipt_handle| delete_log.borrow_mut().push(receipt_handle),
+        );
+
+        assert_eq!(items.poll(), Ok(Async::Ready(Some("first"))));
+        assert!(deleted.borrow().is_empty());
+        assert_eq!(items.poll(), Ok(Async::Ready(Some("second"))));
+        assert_eq!(*deleted.borrow(), vec!["handle-1"]);
+        assert_eq!(items.poll(), Ok(Async::Ready(None)));
+        assert_eq!(*deleted.borrow(), vec!["handle-1", "handle-2"]);
+    }
+
+    fn queue() -> SqsQueue {
+        SqsQueue::new(
+            "http://localhost:9324/queue/gerritbot".to_string(),
+            Region::UsEast1,
+        )
+    }
+
+    #[test]
+    fn limit_wait_time() {
+        assert!(queue().with_wait_time(Duration::from_secs(0)).is_ok());
+        assert!(queue().with_wait_time(Duration::from_secs(20)).is_ok());
+        assert!(queue().with_wait_time(Duration::from_secs(21)).is_err());
+    }
+
+    #[test]
+    fn limit_batch_size() {
+        assert!(queue().with_batch_size(0).is_err());
+        assert!(queue().with_batch_size(1).is_ok());
+        assert!(queue().with_batch_size(10).is_ok());
+        assert!(queue().with_batch_size(11).is_err());
+    }
 }
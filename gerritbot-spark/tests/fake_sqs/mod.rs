@@ -0,0 +1,257 @@
+//! In-process stand-in for SQS, like ElasticMQ.
+//!
+//! Implements the actions used by the client: `ReceiveMessage`,
+//! `DeleteMessage` and `SendMessage`. Received messages become visible again
+//! after the visibility timeout unless they are deleted. Plain posts to a
+//! queue's url are added to the queue, like the API gateway forwarding Webex
+//! webhooks does.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use futures::{future, Future, Stream};
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use tokio::runtime::Runtime;
+use tokio::timer::Delay;
+
+type ResponseFuture = Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+
+/// Time to wait before answering a poll of an empty queue.
+const EMPTY_POLL_DELAY: Duration = Duration::from_millis(20);
+
+/// Metadata SQS adds to every response.
+const RESPONSE_METADATA: &str =
+    "<ResponseMetadata><RequestId>fake-request</RequestId></ResponseMetadata>";
+
+#[derive(Debug, Clone)]
+struct QueuedMessage {
+    id: String,
+    body: String,
+    receipt_handle: Option<String>,
+    receive_count: u32,
+    visible_at: Instant,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// messages by queue url
+    queues: HashMap<String, Vec<QueuedMessage>>,
+    visibility_timeout: Duration,
+    next_id: usize,
+}
+
+impl State {
+    fn new_id(&mut self, prefix: &str) -> String {
+        self.next_id += 1;
+        format!("{}-{}", prefix, self.next_id)
+    }
+
+    fn send(&mut self, queue_url: &str, body: String) -> String {
+        let id = self.new_id("message");
+        let message = QueuedMessage {
+            id: id.clone(),
+            body,
+            receipt_handle: None,
+            receive_count: 0,
+            visible_at: Instant::now(),
+        };
+        self.queues
+            .entry(queue_url.to_string())
+            .or_default()
+            .push(message);
+        id
+    }
+
+    fn receive(&mut self, queue_url: &str, max_messages: usize) -> Vec<QueuedMessage> {
+        let now = Instant::now();
+        let visible_at = now + self.visibility_timeout;
+        let mut receipt_handles: Vec<_> =
+            (0..max_messages).map(|_| self.new_id("receipt")).collect();
+        self.queues
+            .entry(queue_url.to_string())
+            .or_default()
+            .iter_mut()
+            .filter(|message| message.visible_at <= now)
+            .take(max_messages)
+            .map(|message| {
+                message.receipt_handle = receipt_handles.pop();
+                message.receive_count += 1;
+                message.visible_at = visible_at;
+                message.clone()
+            })
+            .collect()
+    }
+
+    fn delete(&mut self, queue_url: &str, receipt_handle: &str) {
+        if let Some(messages) = self.queues.get_mut(queue_url) {
+            messages.retain(|message| message.receipt_handle.as_deref() != Some(receipt_handle));
+        }
+    }
+}
+
+/// Handle to a running fake SQS server.
+#[derive(Clone)]
+pub struct FakeSqs {
+    address: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl FakeSqs {
+    /// Start the server on a free local port. It runs as long as the runtime.
+    pub fn start(runtime: &mut Runtime) -> Self {
+        let state = Arc::new(Mutex::new(State {
+            visibility_timeout: Duration::from_secs(30),
+            ..Default::default()
+        }));
+
+        let service_state = state.clone();
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(move || {
+            let state = service_state.clone();
+            service_fn(move |request| handle(&state, request))
+        });
+        let address = server.local_addr();
+        runtime.spawn(server.map_err(|e| panic!("fake sqs server failed: {}", e)));
+
+        Self { address, state }
+    }
+
+    /// Url to use as endpoint of the client.
+    pub fn endpoint(&self) -> String {
+        format!("http://{}", self.address)
+    }
+
+    /// Url of the queue with the given name.
+    pub fn queue_url(&self, name: &str) -> String {
+        format!("http://{}/queue/{}", self.address, name)
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// Time after which received messages become visible again.
+    pub fn set_visibility_timeout(&self, visibility_timeout: Duration) {
+        self.state().visibility_timeout = visibility_timeout;
+    }
+
+    /// Add a message to the queue.
+    pub fn send(&self, queue_url: &str, body: &str) -> String {
+        self.state().send(queue_url, body.to_string())
+    }
+
+    /// Bodies of the messages in the queue, including the received ones.
+    pub fn bodies(&self, queue_url: &str) -> Vec<String> {
+        self.state()
+            .queues
+            .get(queue_url)
+            .map(|messages| {
+                messages
+                    .iter()
+                    .map(|message| message.body.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+fn handle(state: &Arc<Mutex<State>>, request: Request<Body>) -> ResponseFuture {
+    let state = state.clone();
+    let (parts, body) = request.into_parts();
+    Box::new(body.concat2().and_then(move |body| {
+        if parts.method != Method::POST {
+            return future::Either::A(future::ok(status(StatusCode::METHOD_NOT_ALLOWED)));
+        }
+        // forwarded webhook
+        if parts.uri.path().starts_with("/queue/") {
+            let queue_url = format!("http://{}{}", host(&parts), parts.uri.path());
+            let body = String::from_utf8_lossy(&body).into_owned();
+            state.lock().unwrap().send(&queue_url, body);
+            return future::Either::A(future::ok(status(StatusCode::OK)));
+        }
+
+        let params: HashMap<String, String> = url::form_urlencoded::parse(&body)
+            .into_owned()
+            .collect();
+        let param = |name: &str| params.get(name).cloned().unwrap_or_default();
+        let queue_url = param("QueueUrl");
+        let mut state_guard = state.lock().unwrap();
+        match param("Action").as_str() {
+            "ReceiveMessage" => {
+                let max_messages = param("MaxNumberOfMessages").parse().unwrap_or(1);
+                let messages = state_guard.receive(&queue_url, max_messages);
+                let response = xml(&format!(
+                    "<ReceiveMessageResponse><ReceiveMessageResult>{}</ReceiveMessageResult>{}</ReceiveMessageResponse>",
+                    messages.iter().map(message_xml).collect::<String>(),
+                    RESPONSE_METADATA
+                ));
+                if messages.is_empty() {
+                    // do not let the client spin on an empty queue
+                    future::Either::B(
+                        Delay::new(Instant::now() + EMPTY_POLL_DELAY)
+                            .then(move |_| Ok(response)),
+                    )
+                } else {
+                    future::Either::A(future::ok(response))
+                }
+            }
+            "DeleteMessage" => {
+                state_guard.delete(&queue_url, &param("ReceiptHandle"));
+                future::Either::A(future::ok(xml(&format!(
+                    "<DeleteMessageResponse>{}</DeleteMessageResponse>",
+                    RESPONSE_METADATA
+                ))))
+            }
+            "SendMessage" => {
+                let id = state_guard.send(&queue_url, param("MessageBody"));
+                future::Either::A(future::ok(xml(&format!(
+                    "<SendMessageResponse><SendMessageResult><MessageId>{}</MessageId></SendMessageResult>{}</SendMessageResponse>",
+                    id, RESPONSE_METADATA
+                ))))
+            }
+            _ => future::Either::A(future::ok(status(StatusCode::BAD_REQUEST))),
+        }
+    }))
+}
+
+fn host(parts: &http::request::Parts) -> String {
+    parts
+        .headers
+        .get(hyper::header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn message_xml(message: &QueuedMessage) -> String {
+    format!(
+        "<Message><MessageId>{}</MessageId><ReceiptHandle>{}</ReceiptHandle><Body>{}</Body><Attribute><Name>ApproximateReceiveCount</Name><Value>{}</Value></Attribute></Message>",
+        message.id,
+        message.receipt_handle.as_deref().unwrap_or_default(),
+        escape(&message.body),
+        message.receive_count
+    )
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn xml(body: &str) -> Response<Body> {
+    Response::builder()
+        .header(hyper::header::CONTENT_TYPE, "text/xml")
+        .body(Body::from(format!(r#"<?xml version="1.0"?>{}"#, body)))
+        .unwrap()
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
@@ -0,0 +1,436 @@
+//! In-process stand-in for the Webex Teams REST API.
+//!
+//! Implements the parts of the API used by the client: `people`, `messages`
+//! and `webhooks`. Messages sent by users via `FakeWebex::post_message` and
+//! changes of the bot's memberships are delivered to the registered webhooks
+//! like Webex does.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use futures::{future, Future, Stream};
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde_json::{json, Value};
+use tokio::runtime::Runtime;
+
+pub const BOT_TOKEN: &str = "fake-bot-token";
+pub const BOT_ID: &str = "bot-person-id";
+pub const BOT_EMAIL: &str = "bot@webex.bot";
+
+type ResponseFuture = Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+
+#[derive(Debug, Default)]
+struct State {
+    people: Vec<Value>,
+    messages: Vec<Value>,
+    webhooks: Vec<Value>,
+    /// maximum number of items per page
+    page_size: usize,
+    next_id: usize,
+}
+
+impl State {
+    fn new_id(&mut self, prefix: &str) -> String {
+        self.next_id += 1;
+        format!("{}-{}", prefix, self.next_id)
+    }
+
+    fn person_by_email(&mut self, email: &str) -> Value {
+        if let Some(person) = self
+            .people
+            .iter()
+            .find(|person| person["emails"][0] == email)
+        {
+            return person.clone();
+        }
+        let id = self.new_id("person");
+        let person = person_json(&id, email);
+        self.people.push(person.clone());
+        person
+    }
+}
+
+fn person_json(id: &str, email: &str) -> Value {
+    json!({
+        "id": id,
+        "emails": [email],
+        "displayName": email.split('@').next().unwrap_or(email),
+        "orgId": "fake-org",
+        "created": now(),
+        "type": "person",
+    })
+}
+
+fn now() -> String {
+    chrono::Utc::now().to_rfc3339()
+}
+
+/// Handle to a running fake Webex server.
+#[derive(Clone)]
+pub struct FakeWebex {
+    address: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl FakeWebex {
+    /// Start the server on a free local port. It runs as long as the runtime.
+    pub fn start(runtime: &mut Runtime) -> Self {
+        let mut state = State {
+            page_size: 100,
+            ..Default::default()
+        };
+        state.people.push(person_json(BOT_ID, BOT_EMAIL));
+        let state = Arc::new(Mutex::new(state));
+
+        let service_state = state.clone();
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(move || {
+            let state = service_state.clone();
+            service_fn(move |request| handle(&state, request))
+        });
+        let address = server.local_addr();
+        runtime.spawn(server.map_err(|e| panic!("fake webex server failed: {}", e)));
+
+        Self { address, state }
+    }
+
+    /// Url to use as `api_uri` of the client.
+    pub fn api_uri(&self) -> String {
+        format!("http://{}", self.address)
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// Messages created so far, including the ones sent by the bot.
+    pub fn messages(&self) -> Vec<Value> {
+        self.state().messages.clone()
+    }
+
+    /// Currently registered webhooks.
+    pub fn webhooks(&self) -> Vec<Value> {
+        self.state().webhooks.clone()
+    }
+
+    /// Limit the number of items per page of lists.
+    pub fn set_page_size(&self, page_size: usize) {
+        self.state().page_size = page_size;
+    }
+
+    /// Change the status of a webhook, e.g. to `disabled` like Webex does
+    /// after failed deliveries.
+    pub fn set_webhook_status(&self, id: &str, status: &str) {
+        let mut state = self.state();
+        let webhook = state
+            .webhooks
+            .iter_mut()
+            .find(|webhook| webhook["id"] == id)
+            .expect("no such webhook");
+        webhook["status"] = json!(status);
+    }
+
+    /// Register a webhook like another integration sharing the bot account.
+    pub fn add_webhook(&self, name: &str, target_url: &str, resource: &str, event: &str) -> String {
+        let mut state = self.state();
+        let id = state.new_id("webhook");
+        let webhook = webhook_json(&id, name, target_url, resource, event);
+        state.webhooks.push(webhook);
+        id
+    }
+
+    /// Post a message from a user to the bot in a direct room and deliver it
+    /// to all webhooks of created messages. Resolves to the message's id once
+    /// all webhooks accepted it.
+    pub fn post_message(
+        &self,
+        person_email: &str,
+        text: &str,
+    ) -> impl Future<Item = String, Error = ()> {
+        let message = {
+            let mut state = self.state();
+            let person = state.person_by_email(person_email);
+            let id = state.new_id("message");
+            let message = json!({
+                "id": id,
+                "roomId": format!("direct-room-{}", person["id"].as_str().unwrap()),
+                "roomType": "direct",
+                "text": text,
+                "personId": person["id"],
+                "personEmail": person_email,
+                "created": now(),
+            });
+            state.messages.push(message.clone());
+            message
+        };
+
+        let message_id = message["id"].as_str().unwrap().to_string();
+        self.redeliver_message(&message_id)
+            .map(move |()| message_id)
+    }
+
+    /// Deliver a message to the webhooks again, like Webex does when the
+    /// delivery seemingly failed.
+    pub fn redeliver_message(&self, message_id: &str) -> impl Future<Item = (), Error = ()> {
+        let mut data = self
+            .messages()
+            .into_iter()
+            .find(|message| message["id"] == message_id)
+            .expect("no such message");
+        // webhooks only contain the ids, the text has to be fetched
+        data.as_object_mut().unwrap().remove("text");
+        self.deliver("messages", "created", data)
+    }
+
+    /// Add the bot to a room or remove it from the room, and deliver the
+    /// change of the membership to the webhooks of memberships.
+    pub fn change_bot_membership(
+        &self,
+        room_id: &str,
+        room_type: &str,
+        event: &str,
+    ) -> impl Future<Item = (), Error = ()> {
+        let id = self.state().new_id("membership");
+        let membership = json!({
+            "id": id,
+            "roomId": room_id,
+            "roomType": room_type,
+            "personId": BOT_ID,
+            "personEmail": BOT_EMAIL,
+            "isModerator": false,
+            "created": now(),
+        });
+        self.deliver("memberships", event, membership)
+    }
+
+    /// Post the data to all webhooks registered for the resource and event.
+    fn deliver(
+        &self,
+        resource: &str,
+        event: &str,
+        data: Value,
+    ) -> impl Future<Item = (), Error = ()> {
+        let webhooks: Vec<Value> = self
+            .state()
+            .webhooks
+            .iter()
+            .filter(|webhook| {
+                webhook["resource"] == resource
+                    && (webhook["event"] == event || webhook["event"] == "all")
+            })
+            .cloned()
+            .collect();
+
+        let event = event.to_string();
+        let client = reqwest::r#async::Client::new();
+        let deliveries = webhooks.into_iter().map(move |webhook| {
+            let mut callback = webhook.clone();
+            callback["actorId"] = data["personId"].clone();
+            callback["event"] = json!(event);
+            callback["data"] = data.clone();
+            client
+                .post(webhook["targetUrl"].as_str().unwrap())
+                .json(&callback)
+                .send()
+                .and_then(|response| response.error_for_status())
+                .map(|_| ())
+                .map_err(|e| panic!("failed to deliver webhook: {}", e))
+        });
+        future::join_all(deliveries).map(|_| ())
+    }
+}
+
+fn webhook_json(id: &str, name: &str, target_url: &str, resource: &str, event: &str) -> Value {
+    json!({
+        "id": id,
+        "name": name,
+        "targetUrl": target_url,
+        "resource": resource,
+        "event": event,
+        "orgId": "fake-org",
+        "createdBy": BOT_ID,
+        "appId": "fake-app",
+        "ownedBy": "creator",
+        "status": "active",
+        "created": now(),
+    })
+}
+
+fn json_response(status: StatusCode, body: &Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn found(value: Option<&Value>) -> Response<Body> {
+    match value {
+        Some(value) => json_response(StatusCode::OK, value),
+        None => status_response(StatusCode::NOT_FOUND),
+    }
+}
+
+fn handle(state: &Arc<Mutex<State>>, request: Request<Body>) -> ResponseFuture {
+    let authorized = request
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .map(|value| value.as_bytes() == format!("Bearer {}", BOT_TOKEN).as_bytes())
+        .unwrap_or(false);
+    if !authorized {
+        return Box::new(future::ok(status_response(StatusCode::UNAUTHORIZED)));
+    }
+
+    let state = state.clone();
+    let method = request.method().clone();
+    let query = request.uri().query().unwrap_or_default().to_string();
+    let host = request
+        .headers()
+        .get(http::header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let path: Vec<String> = request
+        .uri()
+        .path()
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(String::from)
+        .collect();
+
+    Box::new(request.into_body().concat2().map(move |body| {
+        let body: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+        let mut state = state.lock().unwrap();
+        let path: Vec<&str> = path.iter().map(String::as_str).collect();
+        match (method, &path[..]) {
+            (Method::GET, ["people", "me"]) => found(state.people.first()),
+            (Method::GET, ["people", id]) => found(state.people.iter().find(|p| p["id"] == *id)),
+            (Method::POST, ["messages"]) => create_message(&mut state, body),
+            (Method::GET, ["messages", id]) => {
+                found(state.messages.iter().find(|m| m["id"] == *id))
+            }
+            (Method::PUT, ["messages", id]) => {
+                match state.messages.iter_mut().find(|m| m["id"] == *id) {
+                    Some(message) => {
+                        message["text"] = body["text"].clone();
+                        message["markdown"] = body["markdown"].clone();
+                        json_response(StatusCode::OK, message)
+                    }
+                    None => status_response(StatusCode::NOT_FOUND),
+                }
+            }
+            (Method::GET, ["webhooks"]) => list_webhooks(&state, &host, &query),
+            (Method::POST, ["webhooks"]) => {
+                let id = state.new_id("webhook");
+                let webhook = webhook_json(
+                    &id,
+                    body["name"].as_str().unwrap_or_default(),
+                    body["targetUrl"].as_str().unwrap_or_default(),
+                    body["resource"].as_str().unwrap_or_default(),
+                    body["event"].as_str().unwrap_or_default(),
+                );
+                state.webhooks.push(webhook.clone());
+                json_response(StatusCode::OK, &webhook)
+            }
+            (Method::PUT, ["webhooks", id]) => {
+                match state.webhooks.iter_mut().find(|w| w["id"] == *id) {
+                    Some(webhook) => {
+                        for field in &["name", "targetUrl", "status"] {
+                            if !body[field].is_null() {
+                                webhook[field] = body[field].clone();
+                            }
+                        }
+                        json_response(StatusCode::OK, webhook)
+                    }
+                    None => status_response(StatusCode::NOT_FOUND),
+                }
+            }
+            (Method::DELETE, ["webhooks", id]) => {
+                let count = state.webhooks.len();
+                state.webhooks.retain(|webhook| webhook["id"] != *id);
+                if state.webhooks.len() < count {
+                    status_response(StatusCode::NO_CONTENT)
+                } else {
+                    status_response(StatusCode::NOT_FOUND)
+                }
+            }
+            _ => status_response(StatusCode::NOT_FOUND),
+        }
+    }))
+}
+
+/// Value of a query parameter.
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            Some((parts.next()?, parts.next()?))
+        })
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// A page of webhooks. The `Link` header points to the next page like in the
+/// Webex API.
+fn list_webhooks(state: &State, host: &str, query: &str) -> Response<Body> {
+    let max = query_param(query, "max")
+        .and_then(|max| max.parse().ok())
+        .unwrap_or(state.page_size)
+        .min(state.page_size);
+    let cursor: usize = query_param(query, "cursor")
+        .and_then(|cursor| cursor.parse().ok())
+        .unwrap_or(0);
+    let items: Vec<&Value> = state.webhooks.iter().skip(cursor).take(max).collect();
+
+    let mut response = json_response(StatusCode::OK, &json!({ "items": items }));
+    if cursor + max < state.webhooks.len() {
+        let link = format!(
+            r#"<http://{}/webhooks?max={}&cursor={}>; rel="next""#,
+            host,
+            max,
+            cursor + max
+        );
+        response
+            .headers_mut()
+            .insert(http::header::LINK, link.parse().unwrap());
+    }
+    response
+}
+
+/// Create a message sent by the bot.
+fn create_message(state: &mut State, body: Value) -> Response<Body> {
+    let room_id = if let Some(room_id) = body["roomId"].as_str() {
+        room_id.to_string()
+    } else if let Some(email) = body["toPersonEmail"].as_str() {
+        let person = state.person_by_email(email);
+        format!("direct-room-{}", person["id"].as_str().unwrap())
+    } else if let Some(person_id) = body["toPersonId"].as_str() {
+        format!("direct-room-{}", person_id)
+    } else {
+        return status_response(StatusCode::BAD_REQUEST);
+    };
+
+    let room_type = if room_id.starts_with("direct-room-") {
+        "direct"
+    } else {
+        "group"
+    };
+    let id = state.new_id("message");
+    let mut message = body;
+    message["id"] = json!(id);
+    message["roomId"] = json!(room_id);
+    message["roomType"] = json!(room_type);
+    message["personId"] = json!(BOT_ID);
+    message["personEmail"] = json!(BOT_EMAIL);
+    message["created"] = json!(now());
+    state.messages.push(message.clone());
+    json_response(StatusCode::OK, &message)
+}
@@ -0,0 +1,396 @@
+use std::time::{Duration, Instant};
+
+use futures::{Future, Stream};
+use tokio::runtime::Runtime;
+
+use gerritbot_spark as spark;
+
+mod fake_sqs;
+mod fake_webex;
+
+use fake_sqs::FakeSqs;
+use fake_webex::{FakeWebex, BOT_EMAIL, BOT_ID, BOT_TOKEN};
+
+fn setup() -> (Runtime, FakeWebex, spark::Client) {
+    let mut runtime = Runtime::new().unwrap();
+    let webex = FakeWebex::start(&mut runtime);
+    let client = runtime
+        .block_on(spark::Client::new(webex.api_uri(), BOT_TOKEN.to_string()))
+        .expect("failed to create client");
+    (runtime, webex, client)
+}
+
+/// Address of a free local port.
+fn free_address() -> std::net::SocketAddr {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .unwrap()
+}
+
+#[test]
+fn client_gets_bot_id() {
+    let (_runtime, _webex, client) = setup();
+    assert_eq!(client.id().as_str(), BOT_ID);
+}
+
+#[test]
+fn client_with_invalid_token_fails() {
+    let mut runtime = Runtime::new().unwrap();
+    let webex = FakeWebex::start(&mut runtime);
+    let client = runtime.block_on(spark::Client::new(
+        webex.api_uri(),
+        "invalid-token".to_string(),
+    ));
+    assert!(client.is_err());
+}
+
+#[test]
+fn send_and_edit_message() {
+    let (mut runtime, webex, client) = setup();
+    let email = spark::Email::new("author@example.com".to_string());
+
+    let sent = runtime
+        .block_on(client.send_message(&email, "hello"))
+        .unwrap();
+    let edited = runtime
+        .block_on(client.edit_message(&sent.id, &sent.room_id, "hello again"))
+        .unwrap();
+    assert_eq!(sent, edited);
+
+    let messages = webex.messages();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0]["id"], sent.id.as_str());
+    assert_eq!(messages[0]["toPersonEmail"], "author@example.com");
+    assert_eq!(messages[0]["markdown"], "hello again");
+    assert_eq!(messages[0]["personEmail"], BOT_EMAIL);
+}
+
+/// Name, target url, resource and status of the registered webhooks.
+fn webhooks(webex: &FakeWebex) -> Vec<(String, String, String, String)> {
+    let mut webhooks: Vec<_> = webex
+        .webhooks()
+        .into_iter()
+        .map(|webhook| {
+            let field = |name: &str| webhook[name].as_str().unwrap().to_string();
+            (
+                field("name"),
+                field("targetUrl"),
+                field("resource"),
+                field("status"),
+            )
+        })
+        .collect();
+    webhooks.sort();
+    webhooks
+}
+
+fn webhook(
+    name: &str,
+    url: &str,
+    resource: &str,
+    status: &str,
+) -> (String, String, String, String) {
+    (
+        name.to_string(),
+        url.to_string(),
+        resource.to_string(),
+        status.to_string(),
+    )
+}
+
+#[test]
+fn register_webhook_keeps_other_webhooks() {
+    let (mut runtime, webex, client) = setup();
+    webex.add_webhook("gerritbot", "http://old.example.org", "messages", "created");
+    webex.add_webhook("other", "http://other.example.org", "messages", "created");
+
+    runtime
+        .block_on(client.register_webhook("gerritbot", "http://new.example.org"))
+        .unwrap();
+
+    assert_eq!(
+        webhooks(&webex),
+        vec![
+            webhook(
+                "gerritbot",
+                "http://new.example.org",
+                "attachmentActions",
+                "active"
+            ),
+            webhook(
+                "gerritbot",
+                "http://new.example.org",
+                "memberships",
+                "active"
+            ),
+            webhook("gerritbot", "http://new.example.org", "messages", "active"),
+            webhook("other", "http://other.example.org", "messages", "active"),
+        ]
+    );
+}
+
+#[test]
+fn register_webhook_keeps_registered_webhooks() {
+    let (mut runtime, webex, client) = setup();
+
+    runtime
+        .block_on(
+            client
+                .clone()
+                .register_webhook("gerritbot", "http://bot.example.org"),
+        )
+        .unwrap();
+    let registered = webex.webhooks();
+    runtime
+        .block_on(client.register_webhook("gerritbot", "http://bot.example.org"))
+        .unwrap();
+
+    assert_eq!(webex.webhooks(), registered);
+}
+
+#[test]
+fn register_webhook_enables_disabled_webhooks() {
+    let (mut runtime, webex, client) = setup();
+    let id = webex.add_webhook("gerritbot", "http://bot.example.org", "messages", "created");
+    webex.set_webhook_status(&id, "disabled");
+
+    runtime
+        .block_on(client.register_webhook("gerritbot", "http://bot.example.org"))
+        .unwrap();
+
+    assert_eq!(
+        webhooks(&webex),
+        vec![
+            webhook(
+                "gerritbot",
+                "http://bot.example.org",
+                "attachmentActions",
+                "active"
+            ),
+            webhook(
+                "gerritbot",
+                "http://bot.example.org",
+                "memberships",
+                "active"
+            ),
+            webhook("gerritbot", "http://bot.example.org", "messages", "active"),
+        ]
+    );
+    assert!(webex.webhooks().iter().any(|webhook| webhook["id"] == id));
+}
+
+#[test]
+fn register_webhook_follows_pagination() {
+    let (mut runtime, webex, client) = setup();
+    webex.set_page_size(2);
+    for i in 0..5 {
+        webex.add_webhook(
+            "other",
+            &format!("http://other-{}.example.org", i),
+            "messages",
+            "created",
+        );
+    }
+    // on the last pages
+    webex.add_webhook("gerritbot", "http://bot.example.org", "messages", "created");
+    webex.add_webhook(
+        "gerritbot",
+        "http://bot.example.org",
+        "attachmentActions",
+        "created",
+    );
+    webex.add_webhook("gerritbot", "http://bot.example.org", "memberships", "all");
+    let registered = webex.webhooks();
+
+    runtime
+        .block_on(client.register_webhook("gerritbot", "http://bot.example.org"))
+        .unwrap();
+
+    assert_eq!(webex.webhooks(), registered);
+}
+
+#[test]
+fn receive_messages_via_webhook() {
+    let (mut runtime, webex, client) = setup();
+    let address = free_address();
+
+    let spark::WebhookServer { server, messages } =
+        spark::start_webhook_server(&address, client.clone());
+    runtime.spawn(server.map_err(|e| panic!("webhook server failed: {}", e)));
+    runtime
+        .block_on(client.register_webhook("gerritbot", &format!("http://{}/", address)))
+        .unwrap();
+
+    let message_id = runtime
+        .block_on(webex.post_message("author@example.com", "status"))
+        .unwrap();
+
+    let events = runtime.block_on(messages.take(1).collect()).unwrap();
+    let message = events
+        .into_iter()
+        .next()
+        .and_then(spark::Event::into_message)
+        .expect("no message received");
+    assert_eq!(message.id.as_str(), message_id);
+    assert_eq!(message.text, "status");
+    assert_eq!(message.person_email.as_str(), "author@example.com");
+    assert_eq!(message.room_type, spark::RoomType::Direct);
+}
+
+#[test]
+fn receive_membership_changes_via_webhook() {
+    let (mut runtime, webex, client) = setup();
+    let address = free_address();
+
+    let spark::WebhookServer { server, messages } =
+        spark::start_webhook_server(&address, client.clone());
+    runtime.spawn(server.map_err(|e| panic!("webhook server failed: {}", e)));
+    runtime
+        .block_on(client.register_webhook("gerritbot", &format!("http://{}/", address)))
+        .unwrap();
+
+    runtime
+        .block_on(webex.change_bot_membership("group-room", "group", "created"))
+        .unwrap();
+    runtime
+        .block_on(webex.change_bot_membership("group-room", "group", "updated"))
+        .unwrap();
+    runtime
+        .block_on(webex.change_bot_membership("group-room", "group", "deleted"))
+        .unwrap();
+
+    let events = runtime.block_on(messages.take(2).collect()).unwrap();
+    match &events[..] {
+        [spark::Event::BotAdded(added), spark::Event::BotRemoved(removed)] => {
+            assert_eq!(added.room_id.as_str(), "group-room");
+            assert_eq!(added.room_type, Some(spark::RoomType::Group));
+            assert_eq!(removed.room_id.as_str(), "group-room");
+        }
+        events => panic!("unexpected events: {:?}", events),
+    }
+}
+
+#[test]
+fn ignore_repeated_webhook_deliveries() {
+    let (mut runtime, webex, client) = setup();
+    let address = free_address();
+
+    let spark::WebhookServer { server, messages } =
+        spark::start_webhook_server(&address, client.clone());
+    runtime.spawn(server.map_err(|e| panic!("webhook server failed: {}", e)));
+    runtime
+        .block_on(client.register_webhook("gerritbot", &format!("http://{}/", address)))
+        .unwrap();
+
+    let first_id = runtime
+        .block_on(webex.post_message("author@example.com", "status"))
+        .unwrap();
+    runtime
+        .block_on(webex.redeliver_message(&first_id))
+        .unwrap();
+    let second_id = runtime
+        .block_on(webex.post_message("author@example.com", "help"))
+        .unwrap();
+
+    let events = runtime.block_on(messages.take(2).collect()).unwrap();
+    let ids: Vec<_> = events
+        .into_iter()
+        .filter_map(spark::Event::into_message)
+        .map(|message| message.id.into_string())
+        .collect();
+    assert_eq!(ids, vec![first_id, second_id]);
+}
+
+/// Queue of the fake SQS server with the given name.
+fn sqs_queue(sqs: &FakeSqs, name: &str) -> spark::SqsQueue {
+    spark::SqsQueue::new(sqs.queue_url(name), rusoto_core::Region::UsEast1)
+        .with_endpoint(sqs.endpoint())
+        .with_credentials(spark::SqsCredentials::Static {
+            access_key_id: "fake-key".to_string(),
+            secret_access_key: "fake-secret".to_string(),
+            session_token: None,
+        })
+        .with_wait_time(Duration::from_secs(1))
+        .and_then(|queue| queue.with_batch_size(5))
+        .unwrap()
+}
+
+/// Wait until the condition holds, e.g. until a background request is done.
+fn wait_until(mut condition: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !condition() {
+        assert!(Instant::now() < deadline, "timed out");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn receive_messages_via_sqs() {
+    let (mut runtime, webex, client) = setup();
+    let sqs = FakeSqs::start(&mut runtime);
+    let queue_url = sqs.queue_url("webhooks");
+    runtime
+        .block_on(client.clone().register_webhook("gerritbot", &queue_url))
+        .unwrap();
+    let events = spark::sqs_event_stream(sqs_queue(&sqs, "webhooks"), client).unwrap();
+
+    let first_id = runtime
+        .block_on(webex.post_message("author@example.com", "status"))
+        .unwrap();
+    let (event, events) = runtime
+        .block_on(events.into_future())
+        .map_err(|(e, _)| e)
+        .unwrap();
+    let message = event.and_then(spark::Event::into_message).unwrap();
+    assert_eq!(message.id.as_str(), first_id);
+    assert_eq!(message.text, "status");
+    // not deleted before the next message is requested
+    assert_eq!(sqs.bodies(&queue_url).len(), 1);
+
+    let second_id = runtime
+        .block_on(webex.post_message("author@example.com", "help"))
+        .unwrap();
+    let (event, _events) = runtime
+        .block_on(events.into_future())
+        .map_err(|(e, _)| e)
+        .unwrap();
+    let message = event.and_then(spark::Event::into_message).unwrap();
+    assert_eq!(message.id.as_str(), second_id);
+    wait_until(|| sqs.bodies(&queue_url).len() == 1);
+}
+
+#[test]
+fn failed_sqs_messages_move_to_dead_letter_queue() {
+    let (mut runtime, webex, client) = setup();
+    let sqs = FakeSqs::start(&mut runtime);
+    sqs.set_visibility_timeout(Duration::from_millis(200));
+
+    // webhook post of a message which cannot be fetched
+    runtime
+        .block_on(
+            client
+                .clone()
+                .register_webhook("gerritbot", &sqs.queue_url("scratch")),
+        )
+        .unwrap();
+    runtime
+        .block_on(webex.post_message("author@example.com", "status"))
+        .unwrap();
+    let mut body: serde_json::Value =
+        serde_json::from_str(&sqs.bodies(&sqs.queue_url("scratch"))[0]).unwrap();
+    body["data"]["id"] = "missing-message".into();
+    let body = body.to_string();
+
+    let queue_url = sqs.queue_url("webhooks");
+    let dead_letter_queue_url = sqs.queue_url("dead-letters");
+    sqs.send(&queue_url, &body);
+    let queue =
+        sqs_queue(&sqs, "webhooks").with_dead_letter_queue(dead_letter_queue_url.clone(), 3);
+    let events = spark::sqs_event_stream(queue, client).unwrap();
+    runtime.spawn(
+        events.for_each(|event| -> Result<(), ()> { panic!("unexpected event: {:?}", event) }),
+    );
+
+    wait_until(|| sqs.bodies(&dead_letter_queue_url) == vec![body.clone()]);
+    wait_until(|| sqs.bodies(&queue_url).is_empty());
+}
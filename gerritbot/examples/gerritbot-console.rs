@@ -4,6 +4,7 @@
 
 use std::io::{BufRead as _, BufReader, Write as _};
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use lazy_static::lazy_static;
 use regex::Regex;
@@ -15,12 +16,15 @@ use futures::{future, stream, sync::mpsc::channel, Future as _, Sink as _, Strea
 use log::{error, info, warn};
 
 use gerritbot as bot;
+use gerritbot::chat::{
+    ChatError, ChatEvent, ChatMessage, ChatUserId, ChatUserIdRef, Email, MessageId, MessageIdRef,
+    MessageTarget, RoomId, RoomIdRef, SentMessage,
+};
 use gerritbot_gerrit as gerrit;
-use gerritbot_spark as spark;
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "gerritbot-console", rename_all = "kebab-case")]
-/// Run the gerritbot without actually connecting to Spark. Instead the
+/// Run the gerritbot without actually connecting to a chat service. Instead the
 /// program's stdin can be used to simulate sending messages. Replies will be
 /// sent to stdout. Log messages will only appear on stderr. If the --email
 /// option (see below) is given each input line will be treated as message from
@@ -76,26 +80,26 @@ struct Args {
 #[derive(Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 struct SimpleInputMessage {
-    person_email: spark::Email,
-    person_id: Option<spark::PersonId>,
+    person_email: Email,
+    person_id: Option<ChatUserId>,
     text: String,
 }
 
-fn email_to_person_id(email: &spark::Email) -> spark::PersonId {
-    spark::PersonId::new(email.to_string())
+fn email_to_person_id(email: &Email) -> ChatUserId {
+    ChatUserId::new(email.to_string())
 }
 
-impl Into<spark::Message> for SimpleInputMessage {
-    fn into(self) -> spark::Message {
+impl Into<ChatMessage> for SimpleInputMessage {
+    fn into(self) -> ChatMessage {
         let SimpleInputMessage {
             person_email,
             person_id,
             text,
         } = self;
         let person_id = person_id.unwrap_or_else(|| email_to_person_id(&person_email));
-        spark::Message {
-            person_email,
-            person_id,
+        ChatMessage {
+            user_email: person_email,
+            user_id: person_id,
             text,
             ..Default::default()
         }
@@ -105,29 +109,91 @@ impl Into<spark::Message> for SimpleInputMessage {
 #[derive(Serialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 struct SimpleOutputMessage {
-    person_id: spark::PersonId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    person_id: Option<ChatUserId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    person_email: Option<Email>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    room_id: Option<RoomId>,
     text: String,
 }
 
+impl SimpleOutputMessage {
+    fn new(target: &MessageTarget, text: String) -> Self {
+        let mut message = Self {
+            person_id: None,
+            person_email: None,
+            room_id: None,
+            text,
+        };
+        match target.clone() {
+            MessageTarget::UserId(person_id) => message.person_id = Some(person_id),
+            MessageTarget::UserEmail(email) => message.person_email = Some(email),
+            MessageTarget::RoomId(room_id) => message.room_id = Some(room_id),
+        }
+        message
+    }
+}
+
+/// Counter for the ids of sent messages.
+static MESSAGE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
 #[derive(Clone)]
-enum ConsoleSparkClient {
+enum ConsoleChatBackend {
     Plain,
     Json,
 }
 
-impl bot::SparkClient for ConsoleSparkClient {
-    type ReplyFuture = future::FutureResult<(), spark::Error>;
-    fn send_message(&self, person_id: &spark::PersonId, msg: &str) -> Self::ReplyFuture {
+impl bot::ChatBackend for ConsoleChatBackend {
+    type Event = ChatMessage;
+    type ReplyFuture = future::FutureResult<SentMessage, ChatError>;
+    fn id(&self) -> &ChatUserIdRef {
+        ChatUserIdRef::new("gerritbot-console")
+    }
+    fn to_chat_event(&self, message: ChatMessage) -> ChatEvent {
+        ChatEvent::Message(message)
+    }
+    fn send_message(
+        &self,
+        target: &MessageTarget,
+        msg: &str,
+        _parent_id: Option<&MessageIdRef>,
+    ) -> Self::ReplyFuture {
         // Write synchronously and crash if writing fails. There's no point in
         // error handling here.
         match self {
-            ConsoleSparkClient::Plain => write!(std::io::stdout(), "{}: {}\n", person_id, msg)
+            ConsoleChatBackend::Plain => write!(std::io::stdout(), "{}: {}\n", target, msg)
                 .expect("writing to stdout failed"),
-            ConsoleSparkClient::Json => {
-                let message = SimpleOutputMessage {
-                    person_id: person_id.clone(),
-                    text: msg.to_string(),
-                };
+            ConsoleChatBackend::Json => {
+                let message = SimpleOutputMessage::new(target, msg.to_string());
+                serde_json::to_writer(std::io::stdout(), &message)
+                    .expect("writing JSON to stdout failed");
+                std::io::stdout()
+                    .write(b"\n")
+                    .expect("writing to stdout failed");
+            }
+        }
+        let message_number = MESSAGE_COUNTER.fetch_add(1, Ordering::SeqCst);
+        future::ok(SentMessage {
+            id: MessageId::new(format!("message-{}", message_number)),
+            room_id: RoomId::new(format!("room-{}", target)),
+        })
+    }
+    fn edit_message(
+        &self,
+        message_id: &MessageIdRef,
+        room_id: &RoomIdRef,
+        msg: &str,
+    ) -> Self::ReplyFuture {
+        // Edits are printed like new messages to the room.
+        let target = MessageTarget::RoomId(room_id.to_owned());
+        match self {
+            ConsoleChatBackend::Plain => {
+                write!(std::io::stdout(), "{} (edited): {}\n", target, msg)
+                    .expect("writing to stdout failed")
+            }
+            ConsoleChatBackend::Json => {
+                let message = SimpleOutputMessage::new(&target, msg.to_string());
                 serde_json::to_writer(std::io::stdout(), &message)
                     .expect("writing JSON to stdout failed");
                 std::io::stdout()
@@ -135,7 +201,10 @@ impl bot::SparkClient for ConsoleSparkClient {
                     .expect("writing to stdout failed");
             }
         }
-        future::ok(())
+        future::ok(SentMessage {
+            id: message_id.to_owned(),
+            room_id: room_id.to_owned(),
+        })
     }
 }
 
@@ -219,9 +288,9 @@ fn main() {
                 .ok()
         } else if let Some(email) = &email {
             // If we have an email, send each line from this email.
-            Some(spark::Message {
-                person_email: spark::Email::new(email.clone()),
-                person_id: spark::PersonId::new(email.clone()),
+            Some(ChatMessage {
+                user_email: Email::new(email.clone()),
+                user_id: ChatUserId::new(email.clone()),
                 text: line,
                 ..Default::default()
             })
@@ -237,9 +306,9 @@ fn main() {
                 .map(|captures| {
                     let email = captures.name("email").unwrap().as_str();
                     let message = captures.name("message").unwrap().as_str();
-                    spark::Message {
-                        person_email: spark::Email::new(email.to_string()),
-                        person_id: spark::PersonId::new(email.to_string()),
+                    ChatMessage {
+                        user_email: Email::new(email.to_string()),
+                        user_id: ChatUserId::new(email.to_string()),
                         text: message.to_string(),
                         ..Default::default()
                     }
@@ -251,15 +320,15 @@ fn main() {
         }
     };
 
-    let spark_messages = stdin_lines
+    let chat_messages = stdin_lines
         .filter(|line| !line.is_empty())
         .filter_map(message_from_line);
-    let spark_client = if use_json {
-        ConsoleSparkClient::Json
+    let chat_client = if use_json {
+        ConsoleChatBackend::Json
     } else {
-        ConsoleSparkClient::Plain
+        ConsoleChatBackend::Plain
     };
 
-    let bot = bot_builder.build(gerrit_command_runner, spark_client);
-    tokio::run(bot.run(gerrit_event_stream, spark_messages));
+    let bot = bot_builder.build(gerrit_command_runner, chat_client);
+    tokio::run(bot.run(gerrit_event_stream, chat_messages));
 }
@@ -1,18 +1,71 @@
+use std::collections::HashMap;
 use std::fs::File;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use log::debug;
-use rusoto_core::Region;
 use serde::Deserialize;
 use structopt::StructOpt;
 
+use crate::SparkModeConfig;
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
     pub gerrit: GerritConfig,
-    pub spark: SparkConfig,
+    /// Webex Teams as chat service. Exactly one chat service has to be set.
+    #[serde(default)]
+    pub spark: Option<SparkConfig>,
+    /// Slack as chat service.
+    #[serde(default)]
+    pub slack: Option<SlackConfig>,
+    /// Matrix as chat service.
+    #[serde(default)]
+    pub matrix: Option<MatrixConfig>,
+    /// Mattermost as chat service.
+    #[serde(default)]
+    pub mattermost: Option<MattermostConfig>,
+    /// IRC as chat service.
+    #[serde(default)]
+    pub irc: Option<IrcConfig>,
     pub bot: BotConfig,
 }
 
+/// The chat service the bot is connected to.
+#[derive(Debug, Clone)]
+pub enum ChatConfig {
+    Spark(Box<SparkConfig>),
+    Slack(SlackConfig),
+    Matrix(MatrixConfig),
+    Mattermost(MattermostConfig),
+    Irc(IrcConfig),
+}
+
+impl Config {
+    /// The configured chat service. Exactly one has to be configured.
+    pub fn chat(&self) -> Result<ChatConfig, &'static str> {
+        let mut configured = Vec::new();
+        if let Some(spark) = &self.spark {
+            configured.push(ChatConfig::Spark(Box::new(spark.clone())));
+        }
+        if let Some(slack) = &self.slack {
+            configured.push(ChatConfig::Slack(slack.clone()));
+        }
+        if let Some(matrix) = &self.matrix {
+            configured.push(ChatConfig::Matrix(matrix.clone()));
+        }
+        if let Some(mattermost) = &self.mattermost {
+            configured.push(ChatConfig::Mattermost(mattermost.clone()));
+        }
+        if let Some(irc) = &self.irc {
+            configured.push(ChatConfig::Irc(irc.clone()));
+        }
+        match configured.len() {
+            0 => Err("one of spark, slack, matrix, mattermost and irc has to be configured"),
+            1 => Ok(configured.remove(0)),
+            _ => Err("only one of spark, slack, matrix, mattermost and irc can be configured"),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct GerritConfig {
     pub host: String,
@@ -24,14 +77,139 @@ pub struct GerritConfig {
 pub struct SparkConfig {
     pub bot_token: String,
     pub api_uri: String,
-    pub webhook_url: String,
-    pub mode: ModeConfig,
+    /// Url the webhooks are registered for. If not set, the webhooks have to
+    /// be registered manually. Not needed in websocket mode.
+    #[serde(default)]
+    pub webhook_url: Option<String>,
+    /// Name of the webhooks managed by the bot. Webhooks with other names are
+    /// left alone, so that several bots can share an account.
+    #[serde(default = "default_webhook_name")]
+    pub webhook_name: String,
+    /// Seconds between checks whether the webhooks are still registered and
+    /// enabled. 0 disables the checks.
+    #[serde(default = "default_webhook_check_interval")]
+    pub webhook_check_interval: u64,
+    pub mode: SparkModeConfig,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SlackConfig {
+    /// Token of the bot user, starting with `xoxb-`. The bot needs the scopes
+    /// `chat:write`, `im:history`, `app_mentions:read`, `users:read` and
+    /// `users:read.email`.
+    pub bot_token: String,
+    #[serde(default = "default_slack_api_uri")]
+    pub api_uri: String,
+    /// Signing secret of the app, used to verify the requests of the Events
+    /// API.
+    pub signing_secret: String,
+    /// Address the server receiving the requests of the Events API listens
+    /// on. The request url of the app has to point to it.
+    pub listen_address: std::net::SocketAddr,
+}
+
+fn default_slack_api_uri() -> String {
+    gerritbot_slack::DEFAULT_API_URL.to_string()
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MatrixConfig {
+    /// Url of the homeserver, e.g. `https://matrix.example.org`.
+    pub homeserver_url: String,
+    /// Access token of the bot account.
+    pub access_token: String,
+    /// Identity server used to find users by email and to check the emails
+    /// users link to their accounts with `link <email>`.
+    #[serde(default)]
+    pub identity_server_url: Option<String>,
+    /// Emails of users by Matrix user id, e.g. `"@jane:example.org":
+    /// jane@example.org`. Other users have to link their email themselves.
+    #[serde(default)]
+    pub emails: HashMap<String, String>,
+    /// File the emails linked by the users are stored in. Defaults to
+    /// `matrix-emails.json` next to the state.
+    #[serde(default)]
+    pub email_links_path: Option<PathBuf>,
+}
+
+impl MatrixConfig {
+    /// File the emails linked by the users are stored in.
+    pub fn email_links_path(&self, state_path: &Path) -> PathBuf {
+        self.email_links_path
+            .clone()
+            .unwrap_or_else(|| state_path.with_file_name("matrix-emails.json"))
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MattermostConfig {
+    /// Url of the server, e.g. `https://mattermost.example.org`.
+    pub server_url: String,
+    /// Access token of the bot account, or a personal access token.
+    pub access_token: String,
+    /// Websocket to receive events from. Derived from `server_url` if not set.
+    #[serde(default)]
+    pub websocket_url: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct IrcConfig {
+    /// Address of the server, e.g. `irc.example.org:6697`.
+    pub server: String,
+    /// Connect with TLS.
+    #[serde(default = "default_irc_tls")]
+    pub tls: bool,
+    pub nickname: String,
+    /// Password of the server.
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Account to authenticate with `SASL PLAIN`.
+    #[serde(default)]
+    pub sasl: Option<IrcSaslConfig>,
+    /// Channels to join, e.g. for routes.
+    #[serde(default)]
+    pub channels: Vec<String>,
+    #[serde(default)]
+    pub delivery: IrcDelivery,
+    /// File the emails registered by the users are stored in. Defaults to
+    /// `irc-registrations.json` next to the state.
+    #[serde(default)]
+    pub registrations_path: Option<PathBuf>,
+}
+
+impl IrcConfig {
+    /// File the emails registered by the users are stored in.
+    pub fn registrations_path(&self, state_path: &Path) -> PathBuf {
+        self.registrations_path
+            .clone()
+            .unwrap_or_else(|| state_path.with_file_name("irc-registrations.json"))
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
-pub enum ModeConfig {
-    Direct { endpoint: std::net::SocketAddr },
-    Sqs { uri: String, region: Region },
+pub struct IrcSaslConfig {
+    pub account: String,
+    pub password: String,
+}
+
+/// Command the messages of the bot are sent with.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IrcDelivery {
+    #[default]
+    Notice,
+    Privmsg,
+}
+
+fn default_irc_tls() -> bool {
+    true
+}
+
+fn default_webhook_name() -> String {
+    String::from("gerritbot")
+}
+
+fn default_webhook_check_interval() -> u64 {
+    5 * 60
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -39,6 +217,203 @@ pub struct BotConfig {
     pub msg_expiration: u64,
     pub msg_capacity: usize,
     pub format_script: Option<String>,
+    /// Storage of the users and subscriptions.
+    #[serde(default)]
+    pub state: StateConfig,
+    #[serde(default)]
+    pub outbox: OutboxConfig,
+    #[serde(default)]
+    pub threads: ThreadsConfig,
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+    /// Only allow moderators of a group room to change its subscriptions.
+    #[serde(default)]
+    pub subscriptions_moderators_only: bool,
+    /// Also send the notifications of some users by email.
+    #[serde(default)]
+    pub email: Option<EmailConfig>,
+    /// Also post the notifications to a webhook.
+    #[serde(default)]
+    pub outgoing_webhook: Option<OutgoingWebhookConfig>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct OutgoingWebhookConfig {
+    /// Url the notifications are posted to as JSON.
+    pub url: String,
+    /// Gerrit emails of the users whose notifications are posted. The
+    /// notifications of all users are posted if empty.
+    #[serde(default)]
+    pub recipients: Vec<String>,
+}
+
+/// SMTP server the notifications by email are sent with.
+#[derive(Debug, Deserialize, Clone)]
+pub struct EmailConfig {
+    /// Address of the SMTP server, e.g. `smtp.example.com:587`.
+    pub smtp_server: String,
+    #[serde(default)]
+    pub security: SmtpSecurity,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Sender of the mails.
+    pub from: String,
+    /// Gerrit emails of the users who get their notifications by email.
+    #[serde(default)]
+    pub recipients: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SmtpSecurity {
+    /// Plain text, only for local servers.
+    None,
+    /// Upgrade the connection with STARTTLS.
+    #[default]
+    StartTls,
+    /// Connect with TLS, usually on port 465.
+    Tls,
+}
+
+/// Storage of the users and subscriptions.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct StateConfig {
+    pub backend: StateBackend,
+    /// File the state is stored in. Defaults to `state.json`, or `state.db`
+    /// for the SQLite backend.
+    pub path: Option<PathBuf>,
+    /// Number of previous versions of the JSON file kept as `<path>.1`,
+    /// `<path>.2`, ...
+    pub backups: usize,
+}
+
+impl StateConfig {
+    /// File the state is stored in.
+    pub fn path(&self) -> PathBuf {
+        self.path.clone().unwrap_or_else(|| match self.backend {
+            StateBackend::Json => PathBuf::from("state.json"),
+            StateBackend::Sqlite => PathBuf::from("state.db"),
+        })
+    }
+}
+
+impl Default for StateConfig {
+    fn default() -> Self {
+        Self {
+            backend: StateBackend::default(),
+            path: None,
+            backups: 3,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StateBackend {
+    /// JSON file, rewritten whenever the state is saved.
+    #[default]
+    Json,
+    /// SQLite database, changed in a transaction per command.
+    Sqlite,
+}
+
+/// Threads of notifications about the same change.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ThreadsConfig {
+    /// Post notifications about a change as replies to the first notification.
+    pub enabled: bool,
+    /// File the first notifications are stored in. Defaults to
+    /// `threads.json` next to the state.
+    pub path: Option<PathBuf>,
+    /// Seconds after which a new thread is started for the change.
+    pub max_age: u64,
+}
+
+impl Default for ThreadsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: None,
+            max_age: 7 * 24 * 60 * 60,
+        }
+    }
+}
+
+impl ThreadsConfig {
+    /// File the first notifications are stored in.
+    pub fn path(&self, state_path: &Path) -> PathBuf {
+        self.path
+            .clone()
+            .unwrap_or_else(|| state_path.with_file_name("threads.json"))
+    }
+}
+
+/// Route of Gerrit events to a group room. The patterns are globs, where `*`
+/// matches any sequence of characters and `?` a single character.
+#[derive(Debug, Deserialize, Clone)]
+pub struct RouteConfig {
+    /// Room the events are posted to.
+    pub room_id: String,
+    /// Pattern of the project name.
+    #[serde(default = "match_all")]
+    pub project: String,
+    /// Pattern of the branch name.
+    #[serde(default = "match_all")]
+    pub branch: String,
+    /// Pattern of the event type, i.e. `comment-added` or `reviewer-added`.
+    #[serde(default = "match_all")]
+    pub event: String,
+}
+
+fn match_all() -> String {
+    String::from("*")
+}
+
+/// Queue of messages which could not be delivered yet.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct OutboxConfig {
+    /// File the queue is stored in. Defaults to `outbox.json` next to the
+    /// state.
+    pub path: Option<PathBuf>,
+    /// Seconds after which an undelivered message is dropped.
+    pub max_age: u64,
+    /// Seconds to wait before retrying a failed delivery. The delay is doubled
+    /// after each further failure.
+    pub retry_delay: u64,
+    /// Upper limit of the retry delay in seconds.
+    pub max_retry_delay: u64,
+    /// Maximum number of messages sent at the same time. Messages to the same
+    /// recipient are always sent one after another.
+    pub parallelism: usize,
+    /// Seconds during which a notification about a change is edited to
+    /// include later notifications about the same change, instead of sending
+    /// a new message. 0 disables editing.
+    pub edit_window: u64,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            max_age: 24 * 60 * 60,
+            retry_delay: 5,
+            max_retry_delay: 10 * 60,
+            parallelism: 10,
+            edit_window: 0,
+        }
+    }
+}
+
+impl OutboxConfig {
+    /// File the queue is stored in.
+    pub fn path(&self, state_path: &Path) -> PathBuf {
+        self.path
+            .clone()
+            .unwrap_or_else(|| state_path.with_file_name("outbox.json"))
+    }
 }
 
 /// Cisco Webex Teams <> Gerrit Bot
@@ -57,6 +432,13 @@ pub struct Args {
     /// Dump default format script and exit
     #[structopt(long)]
     pub dump_format_script: bool,
+    /// State file, overriding `bot.state.path` of the configuration
+    #[structopt(long)]
+    pub state: Option<PathBuf>,
+    /// Start with an empty state if the state file is corrupt. A state file of
+    /// a newer version of the bot is never discarded.
+    #[structopt(long)]
+    pub ignore_corrupt_state: bool,
 }
 
 pub fn parse_args() -> Args {
@@ -80,3 +462,181 @@ pub fn parse_config(path: PathBuf) -> Config {
     debug!("{:#?}", config);
     config
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const GERRIT_AND_BOT: &str = r#"
+gerrit:
+  host: localhost:29418
+  username: gerritbot
+  priv_key_path: id_rsa
+bot:
+  msg_expiration: 4
+  msg_capacity: 100
+"#;
+
+    const SPARK: &str = r#"
+spark:
+  api_uri: https://api.ciscospark.com/v1
+  bot_token: token
+  mode:
+    Direct:
+      endpoint: 0.0.0.0:8888
+"#;
+
+    const SLACK: &str = r#"
+slack:
+  bot_token: xoxb-token
+  signing_secret: secret
+  listen_address: 0.0.0.0:8888
+"#;
+
+    const MATRIX: &str = r#"
+matrix:
+  homeserver_url: https://matrix.example.org
+  access_token: token
+  emails:
+    "@jane:example.org": jane@example.org
+"#;
+
+    const MATTERMOST: &str = r#"
+mattermost:
+  server_url: https://mattermost.example.org
+  access_token: token
+"#;
+
+    const IRC: &str = r#"
+irc:
+  server: irc.example.org:6697
+  nickname: gerritbot
+  sasl:
+    account: gerritbot
+    password: secret
+"#;
+
+    fn config(chat: &str) -> Config {
+        serde_yaml::from_str(&format!("{}{}", GERRIT_AND_BOT, chat)).unwrap()
+    }
+
+    #[test]
+    fn select_chat_service() {
+        match config(SPARK).chat() {
+            Ok(ChatConfig::Spark(spark)) => assert_eq!(spark.bot_token, "token"),
+            other => panic!("unexpected chat config: {:?}", other),
+        }
+        match config(SLACK).chat() {
+            Ok(ChatConfig::Slack(slack)) => {
+                assert_eq!(slack.bot_token, "xoxb-token");
+                assert_eq!(slack.api_uri, gerritbot_slack::DEFAULT_API_URL);
+            }
+            other => panic!("unexpected chat config: {:?}", other),
+        }
+        match config(MATRIX).chat() {
+            Ok(ChatConfig::Matrix(matrix)) => {
+                assert_eq!(matrix.emails["@jane:example.org"], "jane@example.org");
+                assert_eq!(
+                    matrix.email_links_path(Path::new("state.json")),
+                    PathBuf::from("matrix-emails.json")
+                );
+            }
+            other => panic!("unexpected chat config: {:?}", other),
+        }
+        match config(MATTERMOST).chat() {
+            Ok(ChatConfig::Mattermost(mattermost)) => {
+                assert_eq!(mattermost.server_url, "https://mattermost.example.org");
+                assert_eq!(mattermost.websocket_url, None);
+            }
+            other => panic!("unexpected chat config: {:?}", other),
+        }
+        match config(IRC).chat() {
+            Ok(ChatConfig::Irc(irc)) => {
+                assert!(irc.tls);
+                assert_eq!(irc.sasl.as_ref().unwrap().account, "gerritbot");
+                assert_eq!(irc.delivery, IrcDelivery::Notice);
+                assert_eq!(
+                    irc.registrations_path(Path::new("/var/lib/gerritbot/state.json")),
+                    PathBuf::from("/var/lib/gerritbot/irc-registrations.json")
+                );
+            }
+            other => panic!("unexpected chat config: {:?}", other),
+        }
+        assert!(config("").chat().is_err());
+        assert!(config(&format!("{}{}", SLACK, MATRIX)).chat().is_err());
+        assert!(config(&format!("{}{}", SPARK, SLACK)).chat().is_err());
+    }
+
+    #[test]
+    fn parse_delivery_config() {
+        assert!(config(MATTERMOST).bot.email.is_none());
+        assert!(config(MATTERMOST).bot.outgoing_webhook.is_none());
+        let config: Config = serde_yaml::from_str(&format!(
+            "{}{}{}",
+            GERRIT_AND_BOT,
+            r#"  email:
+    smtp_server: smtp.example.com:587
+    from: gerritbot@example.com
+    recipients:
+      - jane@example.com
+  outgoing_webhook:
+    url: https://hooks.example.com/gerritbot
+"#,
+            MATTERMOST
+        ))
+        .unwrap();
+        let outgoing_webhook = config.bot.outgoing_webhook.unwrap();
+        assert_eq!(outgoing_webhook.url, "https://hooks.example.com/gerritbot");
+        assert!(outgoing_webhook.recipients.is_empty());
+        let email = config.bot.email.unwrap();
+        assert_eq!(email.smtp_server, "smtp.example.com:587");
+        assert_eq!(email.security, SmtpSecurity::StartTls);
+        assert_eq!(email.username, None);
+        assert_eq!(email.recipients, vec!["jane@example.com".to_string()]);
+    }
+
+    #[test]
+    fn parse_state_config() {
+        assert_eq!(config(SPARK).bot.state.backend, StateBackend::Json);
+        assert_eq!(config(SPARK).bot.state.backups, 3);
+        assert_eq!(config(SPARK).bot.state.path(), PathBuf::from("state.json"));
+        let config: Config = serde_yaml::from_str(&format!(
+            "{}{}{}",
+            GERRIT_AND_BOT,
+            r#"  state:
+    backend: Sqlite
+"#,
+            SPARK
+        ))
+        .unwrap();
+        assert_eq!(config.bot.state.backend, StateBackend::Sqlite);
+        assert_eq!(config.bot.state.path(), PathBuf::from("state.db"));
+    }
+
+    #[test]
+    fn derive_paths_from_state_path() {
+        let state_path = Path::new("/var/lib/gerritbot/state.db");
+        let config = config(SPARK);
+        assert_eq!(
+            config.bot.outbox.path(state_path),
+            PathBuf::from("/var/lib/gerritbot/outbox.json")
+        );
+        assert_eq!(
+            config.bot.threads.path(state_path),
+            PathBuf::from("/var/lib/gerritbot/threads.json")
+        );
+        let config: Config = serde_yaml::from_str(&format!(
+            "{}{}{}",
+            GERRIT_AND_BOT,
+            r#"  outbox:
+    path: /tmp/outbox.json
+"#,
+            SPARK
+        ))
+        .unwrap();
+        assert_eq!(
+            config.bot.outbox.path(state_path),
+            PathBuf::from("/tmp/outbox.json")
+        );
+    }
+}
@@ -2,16 +2,57 @@
 #![recursion_limit = "128"]
 #![deny(bare_trait_objects)]
 
+use std::path::Path;
 use std::time::Duration;
 
 use futures::{future, future::lazy, Future, Stream};
+use lettre::smtp::authentication::Credentials;
+use lettre::{ClientSecurity, ClientTlsParameters, SmtpClient};
 use log::{debug, error, info, warn};
 
 use gerritbot as bot;
 use gerritbot::args;
 use gerritbot_gerrit as gerrit;
+use gerritbot_irc as irc;
+use gerritbot_matrix as matrix;
+use gerritbot_mattermost as mattermost;
+use gerritbot_slack as slack;
 use gerritbot_spark as spark;
 
+/// Create the mailer sending notifications with the configured SMTP server.
+fn create_mailer(email_config: args::EmailConfig) -> bot::Mailer {
+    let domain = email_config
+        .smtp_server
+        .rsplitn(2, ':')
+        .last()
+        .unwrap_or_default()
+        .to_string();
+    let tls_parameters = || {
+        native_tls::TlsConnector::new()
+            .map(|connector| ClientTlsParameters::new(domain.clone(), connector))
+            .unwrap_or_else(|e| {
+                error!("failed to create TLS connector: {}", e);
+                std::process::exit(1);
+            })
+    };
+    let security = match email_config.security {
+        args::SmtpSecurity::None => ClientSecurity::None,
+        args::SmtpSecurity::StartTls => ClientSecurity::Required(tls_parameters()),
+        args::SmtpSecurity::Tls => ClientSecurity::Wrapper(tls_parameters()),
+    };
+    let client = SmtpClient::new(email_config.smtp_server.as_str(), security).unwrap_or_else(|e| {
+        error!("invalid SMTP server {}: {}", email_config.smtp_server, e);
+        std::process::exit(1);
+    });
+    let client = match (email_config.username, email_config.password) {
+        (Some(username), Some(password)) => {
+            client.credentials(Credentials::new(username, password))
+        }
+        _ => client,
+    };
+    bot::Mailer::new(client.transport(), email_config.from).with_recipients(email_config.recipients)
+}
+
 /// Create spark message stream. Returns a future representing a webhook server
 /// and a stream of messages.
 fn create_spark_message_stream(
@@ -19,10 +60,10 @@ fn create_spark_message_stream(
     spark_client: spark::Client,
 ) -> (
     impl Future<Item = (), Error = ()>,
-    Box<dyn Stream<Item = spark::Message, Error = ()> + Send>,
+    Box<dyn Stream<Item = spark::Event, Error = ()> + Send>,
 ) {
     match spark_config.mode {
-        args::ModeConfig::Direct {
+        bot::SparkModeConfig::Direct {
             endpoint: listen_address,
         } => {
             let spark::WebhookServer { server, messages } =
@@ -32,13 +73,287 @@ fn create_spark_message_stream(
                 Box::new(messages),
             )
         }
-        args::ModeConfig::Sqs { uri, region } => (
+        bot::SparkModeConfig::Sqs {
+            uri,
+            region,
+            endpoint,
+            credentials,
+            wait_time,
+            batch_size,
+            dead_letter_queue,
+            max_receive_count,
+        } => {
+            let mut queue = spark::SqsQueue::new(uri, region).with_credentials(credentials);
+            if let Some(endpoint) = endpoint {
+                queue = queue.with_endpoint(endpoint);
+            }
+            let invalid_queue_config = |e: spark::Error| {
+                error!("invalid sqs configuration: {}", e);
+                std::process::exit(2);
+            };
+            if let Some(wait_time) = wait_time {
+                queue = queue
+                    .with_wait_time(Duration::from_secs(wait_time))
+                    .unwrap_or_else(invalid_queue_config);
+            }
+            if let Some(batch_size) = batch_size {
+                queue = queue
+                    .with_batch_size(batch_size)
+                    .unwrap_or_else(invalid_queue_config);
+            }
+            if let Some(dead_letter_queue) = dead_letter_queue {
+                queue = queue.with_dead_letter_queue(dead_letter_queue, max_receive_count);
+            }
+            let messages = spark::sqs_event_stream(queue, spark_client).unwrap_or_else(|e| {
+                error!("failed to create sqs client: {}", e);
+                std::process::exit(1);
+            });
+            (future::Either::B(future::empty()), Box::new(messages))
+        }
+        bot::SparkModeConfig::Websocket {
+            devices_url,
+            websocket_url,
+        } => (
             future::Either::B(future::empty()),
-            Box::new(spark::sqs_event_stream(uri, region, spark_client)),
+            Box::new(spark::websocket_event_stream(
+                devices_url,
+                websocket_url,
+                spark_client,
+            )),
         ),
     }
 }
 
+/// Connect the bot to Webex Teams and run it.
+fn run_spark(
+    spark_config: args::SparkConfig,
+    bot_builder: bot::Builder,
+    gerrit_command_runner: gerrit::CommandRunner,
+    gerrit_event_stream: impl Stream<Item = gerrit::Event, Error = ()> + Send + 'static,
+) -> impl Future<Item = (), Error = ()> {
+    let webhook_url = match spark_config.mode {
+        // events are received over the websocket
+        bot::SparkModeConfig::Websocket { .. } => None,
+        _ => spark_config.webhook_url.clone(),
+    };
+    let webhook_name = spark_config.webhook_name.clone();
+    let webhook_check_interval = Duration::from_secs(spark_config.webhook_check_interval);
+    let watched_webhook_url = webhook_url.clone();
+    let watched_webhook_name = webhook_name.clone();
+
+    spark::Client::new(spark_config.api_uri.clone(), spark_config.bot_token.clone())
+        .map_err(|e| error!("failed to create spark client: {}", e))
+        .and_then(move |client| {
+            info!("created spark client: {}", client.id());
+
+            let next_client = client.clone();
+
+            match webhook_url {
+                Some(webhook_url) => future::Either::A(
+                    next_client
+                        .register_webhook(&webhook_name, &webhook_url)
+                        .map_err(|e| error!("failed to register webhook: {}", e))
+                        .map(move |()| client),
+                ),
+                None => future::Either::B(future::ok(client)),
+            }
+        })
+        .and_then(move |spark_client| {
+            let (spark_webhook_server, spark_messages) =
+                create_spark_message_stream(spark_config.clone(), spark_client.clone());
+
+            // re-register or re-enable the webhooks if they went missing
+            let webhook_watch = match watched_webhook_url {
+                Some(url) if webhook_check_interval > Duration::from_secs(0) => {
+                    future::Either::A(spark_client.clone().watch_webhook(
+                        watched_webhook_name,
+                        url,
+                        webhook_check_interval,
+                    ))
+                }
+                _ => future::Either::B(future::empty()),
+            };
+
+            let bot = bot_builder.build(gerrit_command_runner, spark_client);
+
+            fn ignore<T>(_: T) {}
+
+            // run webhook server, webhook watch or bot to completion - they
+            // should never exit unless there's an error, in which case they
+            // should print that
+            spark_webhook_server
+                .select(webhook_watch)
+                .map(ignore)
+                .map_err(ignore)
+                .select(bot.run(gerrit_event_stream, spark_messages))
+                .map(ignore)
+                .map_err(ignore)
+        })
+}
+
+/// Connect the bot to Slack and run it.
+fn run_slack(
+    slack_config: args::SlackConfig,
+    bot_builder: bot::Builder,
+    gerrit_command_runner: gerrit::CommandRunner,
+    gerrit_event_stream: impl Stream<Item = gerrit::Event, Error = ()> + Send + 'static,
+) -> impl Future<Item = (), Error = ()> {
+    let args::SlackConfig {
+        bot_token,
+        api_uri,
+        signing_secret,
+        listen_address,
+    } = slack_config;
+
+    slack::Client::new(api_uri, bot_token)
+        .map_err(|e| error!("failed to create slack client: {}", e))
+        .and_then(move |slack_client| {
+            info!("created slack client: {}", slack_client.id());
+
+            let slack::EventsServer { server, messages } =
+                slack::start_events_server(&listen_address, signing_secret, slack_client.clone());
+            let bot = bot_builder.build(gerrit_command_runner, slack_client);
+
+            fn ignore<T>(_: T) {}
+
+            // run events server or bot to completion - they should never exit
+            // unless there's an error, in which case they should print that
+            server
+                .map_err(|e| error!("events server error: {}", e))
+                .select(bot.run(gerrit_event_stream, messages))
+                .map(ignore)
+                .map_err(ignore)
+        })
+}
+
+/// Connect the bot to Matrix and run it.
+fn run_matrix(
+    matrix_config: args::MatrixConfig,
+    state_path: &Path,
+    bot_builder: bot::Builder,
+    gerrit_command_runner: gerrit::CommandRunner,
+    gerrit_event_stream: impl Stream<Item = gerrit::Event, Error = ()> + Send + 'static,
+) -> impl Future<Item = (), Error = ()> {
+    let email_links_path = matrix_config.email_links_path(state_path);
+    let args::MatrixConfig {
+        homeserver_url,
+        access_token,
+        identity_server_url,
+        emails,
+        ..
+    } = matrix_config;
+
+    let email_links = bot::EmailLinks::load(&email_links_path)
+        .unwrap_or_else(|err| {
+            error!(
+                "Could not load email links from {:?}: {:?}",
+                email_links_path, err
+            );
+            std::process::exit(1);
+        })
+        .with_configured(
+            emails
+                .into_iter()
+                .map(|(user_id, email)| (matrix::UserId::new(user_id), email)),
+        );
+    info!("Loaded {} email(s) of Matrix users.", email_links.len());
+
+    matrix::Client::new(homeserver_url, access_token)
+        .map_err(|e| error!("failed to create matrix client: {}", e))
+        .and_then(move |matrix_client| {
+            info!("created matrix client: {}", matrix_client.id());
+
+            let matrix_client = match identity_server_url {
+                Some(url) => matrix_client.with_identity_server(url),
+                None => matrix_client,
+            };
+            let matrix_messages = bot::matrix_event_stream(matrix_client.clone(), email_links);
+            let bot = bot_builder.build(gerrit_command_runner, matrix_client);
+            bot.run(gerrit_event_stream, matrix_messages)
+        })
+}
+
+/// Connect the bot to Mattermost and run it.
+fn run_mattermost(
+    mattermost_config: args::MattermostConfig,
+    bot_builder: bot::Builder,
+    gerrit_command_runner: gerrit::CommandRunner,
+    gerrit_event_stream: impl Stream<Item = gerrit::Event, Error = ()> + Send + 'static,
+) -> impl Future<Item = (), Error = ()> {
+    let args::MattermostConfig {
+        server_url,
+        access_token,
+        websocket_url,
+    } = mattermost_config;
+
+    mattermost::Client::new(server_url, access_token)
+        .map_err(|e| error!("failed to create mattermost client: {}", e))
+        .and_then(move |mattermost_client| {
+            info!("created mattermost client: {}", mattermost_client.id());
+
+            let mattermost_client = match websocket_url {
+                Some(url) => mattermost_client.with_websocket_url(url),
+                None => mattermost_client,
+            };
+            let mattermost_messages = mattermost::event_stream(mattermost_client.clone());
+            let bot = bot_builder.build(gerrit_command_runner, mattermost_client);
+            bot.run(gerrit_event_stream, mattermost_messages)
+        })
+}
+
+/// Connect the bot to IRC and run it.
+fn run_irc(
+    irc_config: args::IrcConfig,
+    state_path: &Path,
+    bot_builder: bot::Builder,
+    gerrit_command_runner: gerrit::CommandRunner,
+    gerrit_event_stream: impl Stream<Item = gerrit::Event, Error = ()> + Send + 'static,
+) -> impl Future<Item = (), Error = ()> {
+    let registrations_path = irc_config.registrations_path(state_path);
+    let args::IrcConfig {
+        server,
+        tls,
+        nickname,
+        password,
+        sasl,
+        channels,
+        delivery,
+        ..
+    } = irc_config;
+
+    let registrations = bot::Registrations::load(&registrations_path).unwrap_or_else(|err| {
+        error!(
+            "Could not load irc registrations from {:?}: {:?}",
+            registrations_path, err
+        );
+        std::process::exit(1);
+    });
+    info!("Loaded {} email(s) of IRC users.", registrations.len());
+
+    let irc_client = irc::Client::new(server, nickname)
+        .with_channels(channels)
+        .with_delivery(match delivery {
+            args::IrcDelivery::Notice => irc::Delivery::Notice,
+            args::IrcDelivery::Privmsg => irc::Delivery::Privmsg,
+        });
+    let irc_client = if tls {
+        irc_client
+    } else {
+        irc_client.without_tls()
+    };
+    let irc_client = match password {
+        Some(password) => irc_client.with_password(password),
+        None => irc_client,
+    };
+    let irc_client = match sasl {
+        Some(sasl) => irc_client.with_sasl(sasl.account, sasl.password),
+        None => irc_client,
+    };
+    let irc_messages = bot::irc_event_stream(irc_client.clone(), registrations);
+    let bot = bot_builder.build(gerrit_command_runner, irc_client);
+    bot.run(gerrit_event_stream, irc_messages)
+}
+
 fn main() {
     let args = args::parse_args();
 
@@ -50,33 +365,123 @@ fn main() {
     stderrlog::new()
         .module(module_path!())
         .module("gerritbot_gerrit")
+        .module("gerritbot_irc")
+        .module("gerritbot_matrix")
+        .module("gerritbot_mattermost")
+        .module("gerritbot_slack")
         .module("gerritbot_spark")
         .quiet(args.quiet)
         .timestamp(stderrlog::Timestamp::Second)
         .verbosity(if args.verbose { 5 } else { 2 })
         .init()
         .unwrap();
+    let ignore_corrupt_state = args.ignore_corrupt_state;
+    let state_path = args.state;
+    let config = args::parse_config(args.config);
+    let chat_config = config.chat().unwrap_or_else(|e| {
+        error!("invalid configuration: {}", e);
+        std::process::exit(2);
+    });
     let args::Config {
         gerrit: gerrit_config,
         bot: bot_config,
-        spark: spark_config,
-    } = args::parse_config(args.config);
+        ..
+    } = config;
 
     // load or create a new bot
-    let bot_state = bot::State::load("state.json")
-        .map(|state| {
-            info!(
-                "Loaded bot from 'state.json' with {} user(s).",
-                state.num_users()
+    let state_path = state_path.unwrap_or_else(|| bot_config.state.path());
+    let bot_state = match bot_config.state.backend {
+        args::StateBackend::Json => {
+            let store = bot::JsonStateStore::load(&state_path).unwrap_or_else(|err| {
+                if let bot::BotError::StateVersion(version) = err {
+                    // not corrupt, so never discard it
+                    error!(
+                        "{:?} has version {} and was written by a newer version of the bot. \
+                         Upgrade the bot to load it.",
+                        state_path, version
+                    );
+                    std::process::exit(1);
+                }
+                if !ignore_corrupt_state {
+                    error!(
+                        "Could not load bot from {:?}: {:?}. \
+                         Restore it from a backup, or start with an empty state \
+                         with --ignore-corrupt-state.",
+                        state_path, err
+                    );
+                    std::process::exit(1);
+                }
+                warn!("Could not load bot from {:?}: {:?}", state_path, err);
+                bot::JsonStateStore::new(&state_path)
+            });
+            bot::State::with_store(store.with_backups(bot_config.state.backups))
+        }
+        args::StateBackend::Sqlite => bot::SqliteStateStore::open(&state_path)
+            .map(bot::State::with_store)
+            .unwrap_or_else(|err| {
+                error!("Could not open {:?}: {:?}", state_path, err);
+                std::process::exit(1);
+            }),
+    };
+    let num_users = bot_state.num_users().unwrap_or_else(|err| {
+        error!("Could not read {:?}: {:?}", state_path, err);
+        std::process::exit(1);
+    });
+    info!(
+        "Loaded bot from {:?} with {} user(s).",
+        state_path, num_users
+    );
+
+    let outbox_path = bot_config.outbox.path(&state_path);
+    let outbox = bot::Outbox::load(&outbox_path).unwrap_or_else(|err| {
+        error!("Could not load outbox from {:?}: {:?}", outbox_path, err);
+        std::process::exit(1);
+    });
+    if !outbox.is_empty() {
+        info!(
+            "Loaded {} undelivered message(s) from {:?}.",
+            outbox.len(),
+            outbox_path
+        );
+    }
+    let outbox = outbox
+        .with_max_age(Duration::from_secs(bot_config.outbox.max_age))
+        .with_retry_delay(
+            Duration::from_secs(bot_config.outbox.retry_delay),
+            Duration::from_secs(bot_config.outbox.max_retry_delay),
+        )
+        .with_parallelism(bot_config.outbox.parallelism)
+        .with_edit_window(Duration::from_secs(bot_config.outbox.edit_window));
+    let outbox = if bot_config.threads.enabled {
+        let threads_path = bot_config.threads.path(&state_path);
+        let threads = bot::Threads::load(&threads_path).unwrap_or_else(|err| {
+            error!("Could not load threads from {:?}: {:?}", threads_path, err);
+            std::process::exit(1);
+        });
+        outbox.with_threads(threads.with_max_age(Duration::from_secs(bot_config.threads.max_age)))
+    } else {
+        outbox
+    };
+
+    let routes = bot_config
+        .routes
+        .iter()
+        .map(|route| {
+            debug!(
+                "Routing {} events of {} on branch {} to room {}",
+                route.event, route.project, route.branch, route.room_id
             );
-            state
+            bot::Route::new(bot::chat::RoomId::new(route.room_id.clone()))
+                .with_project(&route.project)
+                .with_branch(&route.branch)
+                .with_event_type(&route.event)
         })
-        .unwrap_or_else(|err| {
-            warn!("Could not load bot from 'state.json': {:?}", err);
-            bot::State::new()
-        });
+        .collect();
 
-    let bot_builder = bot::Builder::new(bot_state);
+    let bot_builder = bot::Builder::new(bot_state)
+        .with_outbox(outbox)
+        .with_routes(routes)
+        .with_subscriptions_moderators_only(bot_config.subscriptions_moderators_only);
     let bot_builder = {
         if bot_config.msg_expiration != 0 && bot_config.msg_capacity != 0 {
             debug!(
@@ -103,6 +508,28 @@ fn main() {
             bot_builder
         }
     };
+    let bot_builder = {
+        if let Some(email_config) = bot_config.email {
+            info!(
+                "Sending notifications by email with {}",
+                email_config.smtp_server
+            );
+            bot_builder.with_mailer(create_mailer(email_config))
+        } else {
+            bot_builder
+        }
+    };
+    let bot_builder = {
+        if let Some(webhook_config) = bot_config.outgoing_webhook {
+            info!("Posting notifications to {}", webhook_config.url);
+            bot_builder.with_outgoing_webhook(
+                bot::OutgoingWebhook::new(webhook_config.url)
+                    .with_recipients(webhook_config.recipients),
+            )
+        } else {
+            bot_builder
+        }
+    };
     let connect_to_gerrit = || {
         info!(
             "Connecting to gerrit with username {} at {}",
@@ -126,36 +553,42 @@ fn main() {
     let gerrit_command_runner = gerrit::CommandRunner::new(connect_to_gerrit());
 
     // run rest of the logic while the tokio runtime is running
-    tokio::run(lazy(move || {
-        let webhook_url = spark_config.webhook_url.clone();
-
-        spark::Client::new(spark_config.api_uri.clone(), spark_config.bot_token.clone())
-            .map_err(|e| error!("failed to create spark client: {}", e))
-            .and_then(move |client| {
-                info!("created spark client: {}", client.id());
-
-                let next_client = client.clone();
-
-                client
-                    .register_webhook(&webhook_url)
-                    .map_err(|e| error!("failed to register webhook: {}", e))
-                    .map(move |()| next_client)
-            })
-            .and_then(move |spark_client| {
-                let (spark_webhook_server, spark_messages) =
-                    create_spark_message_stream(spark_config.clone(), spark_client.clone());
-
-                let bot = bot_builder.build(gerrit_command_runner, spark_client);
-
-                fn ignore<T>(_: T) {}
-
-                // run webhook server or bot to completion - they should never
-                // exit unless there's an error, in which case they should print
-                // that
-                spark_webhook_server
-                    .select(bot.run(gerrit_event_stream, spark_messages))
-                    .map(ignore)
-                    .map_err(ignore)
-            })
-    }))
+    tokio::run(lazy(
+        move || -> Box<dyn Future<Item = (), Error = ()> + Send> {
+            match chat_config {
+                args::ChatConfig::Spark(spark_config) => Box::new(run_spark(
+                    *spark_config,
+                    bot_builder,
+                    gerrit_command_runner,
+                    gerrit_event_stream,
+                )),
+                args::ChatConfig::Slack(slack_config) => Box::new(run_slack(
+                    slack_config,
+                    bot_builder,
+                    gerrit_command_runner,
+                    gerrit_event_stream,
+                )),
+                args::ChatConfig::Matrix(matrix_config) => Box::new(run_matrix(
+                    matrix_config,
+                    &state_path,
+                    bot_builder,
+                    gerrit_command_runner,
+                    gerrit_event_stream,
+                )),
+                args::ChatConfig::Mattermost(mattermost_config) => Box::new(run_mattermost(
+                    mattermost_config,
+                    bot_builder,
+                    gerrit_command_runner,
+                    gerrit_event_stream,
+                )),
+                args::ChatConfig::Irc(irc_config) => Box::new(run_irc(
+                    irc_config,
+                    &state_path,
+                    bot_builder,
+                    gerrit_command_runner,
+                    gerrit_event_stream,
+                )),
+            }
+        },
+    ))
 }
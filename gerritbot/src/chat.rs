@@ -0,0 +1,187 @@
+//! Model of the chat service the bot is connected to.
+//!
+//! The bot core only knows these types. Each chat service implements
+//! `ChatBackend` and converts between its own types and the types here, e.g.
+//! user ids of the service into `ChatUserId`.
+
+use std::{error, fmt};
+
+use gerritbot_newtype::newtype_string;
+use serde::{Deserialize, Serialize};
+
+// id of a user of the chat service
+newtype_string!(ChatUserId, ChatUserIdRef);
+// email of a user; assumed to be the same in the chat service and Gerrit
+newtype_string!(Email, EmailRef);
+newtype_string!(RoomId, RoomIdRef);
+newtype_string!(MessageId, MessageIdRef);
+
+#[derive(Deserialize, Serialize, Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum RoomType {
+    /// room of the bot and a single user
+    Direct,
+    Group,
+}
+
+/// Message received by the bot.
+#[derive(Debug, Clone)]
+pub struct ChatMessage {
+    pub id: MessageId,
+    pub user_id: ChatUserId,
+    pub user_email: Email,
+    pub room_id: RoomId,
+    pub room_type: RoomType,
+    /// text of the message with leading @mentions removed
+    pub text: String,
+    pub mentioned_users: Vec<ChatUserId>,
+}
+
+impl ChatMessage {
+    /// Check if the given user was @mentioned in the message.
+    pub fn mentions(&self, user_id: &ChatUserIdRef) -> bool {
+        self.mentioned_users.iter().any(|u| u == user_id)
+    }
+}
+
+impl Default for ChatMessage {
+    fn default() -> Self {
+        Self {
+            id: Default::default(),
+            user_id: Default::default(),
+            user_email: Default::default(),
+            room_id: Default::default(),
+            room_type: RoomType::Direct,
+            text: Default::default(),
+            mentioned_users: Default::default(),
+        }
+    }
+}
+
+/// Submission of a card sent by the bot, e.g. by a click on a button.
+#[derive(Debug, Clone)]
+pub struct CardAction {
+    pub user_id: ChatUserId,
+    pub user_email: Email,
+    /// room of the card
+    pub room_id: RoomId,
+    pub room_type: RoomType,
+    /// values of the card's inputs and the data of the submit button
+    pub inputs: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Message or card action received by the bot, or a change of the bot's
+/// rooms.
+#[derive(Debug, Clone)]
+pub enum ChatEvent {
+    Message(ChatMessage),
+    CardAction(CardAction),
+    /// the bot was added to a room
+    BotAdded(RoomId, RoomType),
+    /// the bot was removed from a room
+    BotRemoved(RoomId),
+}
+
+impl ChatEvent {
+    /// The message, if the event is one.
+    pub fn into_message(self) -> Option<ChatMessage> {
+        match self {
+            ChatEvent::Message(message) => Some(message),
+            _ => None,
+        }
+    }
+}
+
+impl From<ChatMessage> for ChatEvent {
+    fn from(message: ChatMessage) -> Self {
+        ChatEvent::Message(message)
+    }
+}
+
+impl From<CardAction> for ChatEvent {
+    fn from(action: CardAction) -> Self {
+        ChatEvent::CardAction(action)
+    }
+}
+
+/// Recipient of a message sent by the bot.
+///
+/// The variant names of users are the ones of Webex Teams, so that queued
+/// messages written by older versions can still be loaded.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum MessageTarget {
+    RoomId(RoomId),
+    #[serde(rename = "PersonId")]
+    UserId(ChatUserId),
+    #[serde(rename = "PersonEmail")]
+    UserEmail(Email),
+}
+
+impl fmt::Display for MessageTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MessageTarget::RoomId(room_id) => write!(f, "room {}", room_id),
+            MessageTarget::UserId(user_id) => user_id.fmt(f),
+            MessageTarget::UserEmail(email) => email.fmt(f),
+        }
+    }
+}
+
+impl From<RoomId> for MessageTarget {
+    fn from(room_id: RoomId) -> MessageTarget {
+        MessageTarget::RoomId(room_id)
+    }
+}
+
+impl From<ChatUserId> for MessageTarget {
+    fn from(user_id: ChatUserId) -> MessageTarget {
+        MessageTarget::UserId(user_id)
+    }
+}
+
+impl From<Email> for MessageTarget {
+    fn from(email: Email) -> MessageTarget {
+        MessageTarget::UserEmail(email)
+    }
+}
+
+/// A message sent or edited by the bot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SentMessage {
+    pub id: MessageId,
+    pub room_id: RoomId,
+}
+
+/// Error of the chat service, e.g. a failure to send a message.
+#[derive(Debug)]
+pub struct ChatError(String);
+
+impl ChatError {
+    pub fn new<E: fmt::Display>(err: E) -> Self {
+        ChatError(err.to_string())
+    }
+}
+
+impl fmt::Display for ChatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl error::Error for ChatError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn message_targets_keep_their_serialization() {
+        let target = MessageTarget::UserId(ChatUserId::new("person".to_string()));
+        let json = serde_json::to_string(&target).unwrap();
+        assert_eq!(json, r#"{"PersonId":"person"}"#);
+        assert_eq!(
+            serde_json::from_str::<MessageTarget>(r#"{"PersonEmail":"a@example.com"}"#).unwrap(),
+            MessageTarget::UserEmail(Email::new("a@example.com".to_string()))
+        );
+    }
+}
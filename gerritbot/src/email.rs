@@ -0,0 +1,199 @@
+//! Notifications by email, for users who do not want to get them in chat.
+//!
+//! Mails about the same change have the same subject and refer to the same
+//! thread in `In-Reply-To` and `References`, so that mail clients group them.
+
+use std::collections::HashSet;
+use std::sync::mpsc;
+use std::thread;
+
+use lettre::{SmtpTransport, Transport as _};
+use lettre_email::{Email, EmailBuilder};
+use log::{debug, error};
+
+use crate::markdown;
+
+/// A notification about a change to be sent by email.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mail {
+    pub to: String,
+    pub change_number: u32,
+    pub change_subject: String,
+    /// the notification as formatted by the format script
+    pub markdown: String,
+}
+
+/// Sends notifications by email to the configured recipients.
+pub struct Mailer {
+    from: String,
+    recipients: HashSet<String>,
+    queue: mpsc::Sender<Email>,
+}
+
+impl Mailer {
+    /// Create a mailer sending from the given address. SMTP is blocking, so
+    /// the mails are sent by a separate thread.
+    pub fn new(mut transport: SmtpTransport, from: String) -> Self {
+        let (queue, mails) = mpsc::channel::<Email>();
+        thread::spawn(move || {
+            for mail in mails {
+                if let Err(e) = transport.send(mail.into()) {
+                    error!("failed to send mail: {}", e);
+                }
+            }
+        });
+        Self {
+            from,
+            recipients: HashSet::new(),
+            queue,
+        }
+    }
+
+    /// Gerrit emails of the users who get their notifications by email.
+    pub fn with_recipients<I>(self, recipients: I) -> Self
+    where
+        I: IntoIterator<Item = String>,
+    {
+        Self {
+            recipients: recipients.into_iter().collect(),
+            ..self
+        }
+    }
+
+    pub fn is_recipient(&self, email: &str) -> bool {
+        self.recipients.contains(email)
+    }
+
+    /// Queue the mail for sending.
+    pub fn send(&self, mail: Mail) {
+        debug!("mail about change {} to {}", mail.change_number, mail.to);
+        match build_email(&self.from, &mail) {
+            Ok(email) => {
+                if self.queue.send(email).is_err() {
+                    error!("failed to queue mail, the mail thread is gone");
+                }
+            }
+            Err(e) => error!("failed to build mail to {}: {}", mail.to, e),
+        }
+    }
+}
+
+/// Id of the thread of the mails about the change.
+fn thread_id(from: &str, change_number: u32) -> String {
+    let domain = from.rsplit('@').next().unwrap_or("localhost");
+    format!("<change-{}@{}>", change_number, domain)
+}
+
+fn build_email(from: &str, mail: &Mail) -> Result<Email, lettre_email::error::Error> {
+    let thread = thread_id(from, mail.change_number);
+    EmailBuilder::new()
+        .from(from)
+        .to(mail.to.as_str())
+        .subject(format!(
+            "Change {}: {}",
+            mail.change_number, mail.change_subject
+        ))
+        .in_reply_to(thread.clone())
+        .references(thread)
+        .alternative(
+            markdown::to_html(&mail.markdown),
+            markdown::to_text(&mail.markdown),
+        )
+        .build()
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::mpsc::Receiver;
+    use std::time::Duration;
+
+    use lettre::{ClientSecurity, SmtpClient};
+
+    use super::*;
+
+    /// Start an SMTP server on a free local port, which accepts all mails.
+    /// Returns its address and the received messages.
+    pub fn start_smtp_sink() -> (SocketAddr, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (received, messages) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut data: Option<String> = None;
+                stream.write_all(b"220 localhost ESMTP sink\r\n").unwrap();
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                    let reply: &[u8] = match data.as_mut() {
+                        Some(message) if line == ".\r\n" => {
+                            received.send(message.clone()).unwrap();
+                            data = None;
+                            b"250 OK\r\n"
+                        }
+                        Some(message) => {
+                            message.push_str(&line);
+                            b""
+                        }
+                        None if line.starts_with("DATA") => {
+                            data = Some(String::new());
+                            b"354 go ahead\r\n"
+                        }
+                        None if line.starts_with("QUIT") => {
+                            stream.write_all(b"221 bye\r\n").unwrap();
+                            break;
+                        }
+                        None => b"250 OK\r\n",
+                    };
+                    stream.write_all(reply).unwrap();
+                    line.clear();
+                }
+            }
+        });
+        (address, messages)
+    }
+
+    pub fn sink_mailer(address: SocketAddr) -> Mailer {
+        let transport = SmtpClient::new(address, ClientSecurity::None)
+            .unwrap()
+            .transport();
+        Mailer::new(transport, "gerritbot@example.com".to_string())
+            .with_recipients(vec!["author@example.com".to_string()])
+    }
+
+    fn mail(body: &str) -> Mail {
+        Mail {
+            to: "author@example.com".to_string(),
+            change_number: 42,
+            change_subject: "Fix the build".to_string(),
+            markdown: body.to_string(),
+        }
+    }
+
+    #[test]
+    fn send_mails_with_html_and_text() {
+        let (address, messages) = start_smtp_sink();
+        let mailer = sink_mailer(address);
+        assert!(mailer.is_recipient("author@example.com"));
+        assert!(!mailer.is_recipient("other@example.com"));
+
+        mailer.send(mail("+1 (Code-Review) from **Jane**"));
+        mailer.send(mail("+2 (Code-Review) from **Joe**"));
+
+        let timeout = Duration::from_secs(10);
+        let first = messages.recv_timeout(timeout).unwrap();
+        let second = messages.recv_timeout(timeout).unwrap();
+        for message in &[&first, &second] {
+            assert!(message.contains("To: <author@example.com>"));
+            assert!(message.contains("Subject: Change 42: Fix the build"));
+            assert!(message.contains("In-Reply-To: <change-42@example.com>"));
+            assert!(message.contains("References: <change-42@example.com>"));
+            assert!(message.contains("multipart/alternative"));
+        }
+        assert!(first.contains("+1 (Code-Review) from <strong>Jane</strong>"));
+        assert!(first.contains("+1 (Code-Review) from Jane\r\n"));
+        assert!(second.contains("+2 (Code-Review) from <strong>Joe</strong>"));
+    }
+}
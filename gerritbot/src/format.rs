@@ -1,17 +1,28 @@
 use std::convert::identity;
 
 use rlua::{
-    FromLua, Function as LuaFunction, Lua, StdLib as LuaStdLib, ToLuaMulti, Value as LuaValue,
+    FromLua, Function as LuaFunction, Lua, StdLib as LuaStdLib, Table as LuaTable, ToLuaMulti,
+    Value as LuaValue,
 };
 use serde::Serialize;
 use serde_json::Value as JsonValue;
 
 use gerritbot_gerrit as gerrit;
 
+use crate::outgoing_webhook::WebhookPayload;
+
 pub const DEFAULT_FORMAT_SCRIPT: &str = include_str!("../../scripts/format.lua");
 const LUA_FORMAT_COMMENT_ADDED: &str = "format_comment_added";
 const LUA_FORMAT_REVIEWER_ADDED: &str = "format_reviewer_added";
 const LUA_FORMAT_FUNCTIONS: &[&str] = &[LUA_FORMAT_COMMENT_ADDED, LUA_FORMAT_REVIEWER_ADDED];
+// optional, the functions above are used if missing
+const LUA_FORMAT_ROOM_COMMENT_ADDED: &str = "format_room_comment_added";
+const LUA_FORMAT_ROOM_REVIEWER_ADDED: &str = "format_room_reviewer_added";
+// optional, Adaptive Cards sent together with the formatted messages
+const LUA_FORMAT_COMMENT_ADDED_CARD: &str = "format_comment_added_card";
+const LUA_FORMAT_REVIEWER_ADDED_CARD: &str = "format_reviewer_added_card";
+// optional, payload posted to the outgoing webhook
+const LUA_FORMAT_WEBHOOK_PAYLOAD: &str = "format_webhook_payload";
 
 pub struct Formatter {
     lua: Lua,
@@ -88,6 +99,61 @@ fn json_to_lua<'lua>(json: &JsonValue, lua: rlua::Context<'lua>) -> rlua::Result
     })
 }
 
+fn lua_to_json(value: LuaValue) -> rlua::Result<JsonValue> {
+    Ok(match value {
+        LuaValue::Nil => JsonValue::Null,
+        LuaValue::Boolean(b) => JsonValue::Bool(b),
+        LuaValue::Integer(n) => JsonValue::from(n),
+        LuaValue::Number(n) => serde_json::Number::from_f64(n)
+            .map(JsonValue::Number)
+            .unwrap_or(JsonValue::Null),
+        LuaValue::String(s) => JsonValue::String(s.to_str()?.to_string()),
+        LuaValue::Table(table) => lua_table_to_json(table)?,
+        value => Err(rlua::Error::FromLuaConversionError {
+            from: match value {
+                LuaValue::Function(_) => "function",
+                LuaValue::Thread(_) => "thread",
+                LuaValue::UserData(_) | LuaValue::LightUserData(_) => "userdata",
+                _ => "value",
+            },
+            to: "serde_json::Value",
+            message: None,
+        })?,
+    })
+}
+
+/// Convert a table into a JSON array if its keys are exactly `1..n`, and into
+/// a JSON object otherwise. Empty tables are converted into empty arrays.
+fn lua_table_to_json(table: LuaTable) -> rlua::Result<JsonValue> {
+    let len = table.raw_len();
+    let pairs = table
+        .clone()
+        .pairs::<LuaValue, LuaValue>()
+        .collect::<rlua::Result<Vec<_>>>()?;
+
+    if pairs.len() as i64 == len {
+        let values = (1..=len)
+            .map(|i| table.raw_get(i).and_then(lua_to_json))
+            .collect::<rlua::Result<_>>()?;
+        return Ok(JsonValue::Array(values));
+    }
+
+    let mut object = serde_json::Map::new();
+    for (key, value) in pairs {
+        let key = match key {
+            LuaValue::String(s) => s.to_str()?.to_string(),
+            LuaValue::Integer(n) => n.to_string(),
+            _ => Err(rlua::Error::FromLuaConversionError {
+                from: "table key",
+                to: "String",
+                message: Some("only string and integer keys are supported".to_string()),
+            })?,
+        };
+        object.insert(key, lua_to_json(value)?);
+    }
+    Ok(JsonValue::Object(object))
+}
+
 fn to_lua_via_json<'lua, T: Serialize>(
     value: T,
     lua: rlua::Context<'lua>,
@@ -151,6 +217,110 @@ impl Formatter {
             Formatter::format_lua(context, LUA_FORMAT_REVIEWER_ADDED, event, identity)
         })
     }
+
+    /// Convert the result of a formatting function returning JSON, which is a
+    /// table or nil, e.g. describing an Adaptive Card.
+    fn json_from_lua(function_name: &str, value: LuaValue) -> Result<Option<JsonValue>, String> {
+        match value {
+            LuaValue::Nil => Ok(None),
+            LuaValue::Table(table) => lua_table_to_json(table)
+                .map(Some)
+                .map_err(|e| format!("failed to convert result of {}: {}", function_name, e)),
+            _ => Err(format!("{} did not return a table", function_name)),
+        }
+    }
+
+    /// Format the Adaptive Card sent with the messages about a comment-added
+    /// event, if the format script defines one.
+    pub fn format_comment_added_card(
+        &self,
+        event: &gerrit::CommentAddedEvent,
+        is_human: bool,
+    ) -> Result<Option<JsonValue>, String> {
+        if !self.has_function(LUA_FORMAT_COMMENT_ADDED_CARD) {
+            return Ok(None);
+        }
+        self.lua.context(|context| {
+            let card =
+                Formatter::format_lua(context, LUA_FORMAT_COMMENT_ADDED_CARD, event, |event| {
+                    (event, is_human)
+                })?;
+            Formatter::json_from_lua(LUA_FORMAT_COMMENT_ADDED_CARD, card)
+        })
+    }
+
+    /// Format the Adaptive Card sent with the messages about a reviewer-added
+    /// event, if the format script defines one.
+    pub fn format_reviewer_added_card(
+        &self,
+        event: &gerrit::ReviewerAddedEvent,
+    ) -> Result<Option<JsonValue>, String> {
+        if !self.has_function(LUA_FORMAT_REVIEWER_ADDED_CARD) {
+            return Ok(None);
+        }
+        self.lua.context(|context| {
+            let card =
+                Formatter::format_lua(context, LUA_FORMAT_REVIEWER_ADDED_CARD, event, identity)?;
+            Formatter::json_from_lua(LUA_FORMAT_REVIEWER_ADDED_CARD, card)
+        })
+    }
+
+    /// Format the payload posted to the outgoing webhook. The default payload
+    /// is posted if the format script does not reshape it; returning `nil`
+    /// posts nothing.
+    pub fn format_webhook_payload(
+        &self,
+        payload: &WebhookPayload,
+    ) -> Result<Option<JsonValue>, String> {
+        if !self.has_function(LUA_FORMAT_WEBHOOK_PAYLOAD) {
+            return serde_json::to_value(payload)
+                .map(Some)
+                .map_err(|e| format!("failed to serialize payload: {}", e));
+        }
+        self.lua.context(|context| {
+            let payload =
+                Formatter::format_lua(context, LUA_FORMAT_WEBHOOK_PAYLOAD, payload, identity)?;
+            Formatter::json_from_lua(LUA_FORMAT_WEBHOOK_PAYLOAD, payload)
+        })
+    }
+
+    fn has_function(&self, function_name: &str) -> bool {
+        self.lua.context(|context| {
+            context
+                .globals()
+                .get::<_, LuaFunction>(function_name)
+                .is_ok()
+        })
+    }
+
+    /// Format a comment-added event posted to a group room.
+    pub fn format_room_comment_added(
+        &self,
+        event: &gerrit::CommentAddedEvent,
+        is_human: bool,
+    ) -> Result<Option<String>, String> {
+        if !self.has_function(LUA_FORMAT_ROOM_COMMENT_ADDED) {
+            return self.format_comment_added(event, is_human);
+        }
+        self.lua.context(|context| {
+            Formatter::format_lua(context, LUA_FORMAT_ROOM_COMMENT_ADDED, event, |event| {
+                (event, is_human)
+            })
+        })
+    }
+
+    /// Format a reviewer-added event posted to a group room.
+    pub fn format_room_reviewer_added(
+        &self,
+        event: &gerrit::ReviewerAddedEvent,
+    ) -> Result<Option<String>, String> {
+        if !self.has_function(LUA_FORMAT_ROOM_REVIEWER_ADDED) {
+            return self.format_reviewer_added(event).map(Some);
+        }
+        self.lua.context(|context| {
+            Formatter::format_lua(context, LUA_FORMAT_ROOM_REVIEWER_ADDED, event, identity)
+        })
+    }
 }
 
 #[cfg(test)]
@@ -235,6 +405,123 @@ mod test {
         assert_eq!(res, Ok(None));
     }
 
+    #[test]
+    fn format_room_reviewer_added() {
+        let event = gerrit::ReviewerAddedEvent {
+            change: get_event().change,
+            patchset: get_event().patchset,
+            reviewer: gerrit::User {
+                name: Some("Reviewer".to_string()),
+                username: Some("reviewer".to_string()),
+                email: "reviewer@example.com".to_string(),
+            },
+            created_on: 0,
+        };
+        let res = Formatter::default().format_room_reviewer_added(&event);
+        let res = res.as_ref().map(|o| o.as_ref().map(String::as_str));
+        assert_eq!(
+            res,
+            Ok(Some("[Some review.](http://localhost/42) ([demo-project](http://localhost/q/project:demo-project+status:open)) by [Author](http://localhost/q/owner:author@example.com+status:open) 👓 [Reviewer](http://localhost/q/reviewer:reviewer@example.com+status:open) added as reviewer"))
+        );
+    }
+
+    #[test]
+    fn format_room_falls_back_to_direct_functions() {
+        let script = r#"
+            function format_comment_added(event, is_human) return "comment" end
+            function format_reviewer_added(event) return "reviewer" end
+        "#;
+        let formatter = Formatter::new(script).expect("failed to load script");
+        let res = formatter.format_room_comment_added(&get_event(), true);
+        assert_eq!(res, Ok(Some("comment".to_string())));
+    }
+
+    #[test]
+    fn format_card() {
+        let script = r#"
+            function format_comment_added(event, is_human) return "comment" end
+            function format_reviewer_added(event) return "reviewer" end
+            function format_comment_added_card(event, is_human)
+                return {
+                    type = "AdaptiveCard",
+                    version = "1.0",
+                    body = {{ type = "TextBlock", text = event.change.subject }},
+                    actions = {{
+                        type = "Action.Submit",
+                        title = "Mute this change",
+                        data = { command = "mute " .. event.change.number },
+                    }},
+                }
+            end
+        "#;
+        let formatter = Formatter::new(script).expect("failed to load script");
+        let card = formatter
+            .format_comment_added_card(&get_event(), true)
+            .expect("card formatting failed");
+        assert_eq!(
+            card,
+            Some(serde_json::json!({
+                "type": "AdaptiveCard",
+                "version": "1.0",
+                "body": [{"type": "TextBlock", "text": "Some review."}],
+                "actions": [{
+                    "type": "Action.Submit",
+                    "title": "Mute this change",
+                    "data": {"command": "mute 49"},
+                }],
+            }))
+        );
+        assert_eq!(
+            Formatter::default().format_comment_added_card(&get_event(), true),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn format_webhook_payload() {
+        let payload = WebhookPayload {
+            recipient: "author@example.com",
+            change_number: 49,
+            event_type: "comment-added",
+            markdown: "+2 from **Approver**",
+            event: serde_json::to_value(get_event()).unwrap(),
+        };
+        let default = Formatter::default()
+            .format_webhook_payload(&payload)
+            .expect("payload formatting failed")
+            .expect("no payload");
+        assert_eq!(default["recipient"], "author@example.com");
+        assert_eq!(default["change_number"], 49);
+        assert_eq!(default["event"]["change"]["subject"], "Some review.");
+
+        let script = r#"
+            function format_comment_added(event, is_human) return "comment" end
+            function format_reviewer_added(event) return "reviewer" end
+            function format_webhook_payload(payload)
+                if payload.event_type ~= "comment-added" then return nil end
+                return {
+                    title = payload.event.change.subject,
+                    text = payload.markdown,
+                    to = { payload.recipient },
+                }
+            end
+        "#;
+        let formatter = Formatter::new(script).expect("failed to load script");
+        assert_eq!(
+            formatter.format_webhook_payload(&payload),
+            Ok(Some(serde_json::json!({
+                "title": "Some review.",
+                "text": "+2 from **Approver**",
+                "to": ["author@example.com"],
+            })))
+        );
+        let payload = WebhookPayload {
+            event_type: "reviewer-added",
+            ..payload
+        };
+        assert_eq!(formatter.format_webhook_payload(&payload), Ok(None));
+    }
+
     #[test]
     fn test_format_comments() {
         let mut event = get_event();
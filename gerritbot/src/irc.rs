@@ -0,0 +1,286 @@
+//! IRC as chat service of the bot.
+//!
+//! Only private messages are handled. Nicknames are used as chat user ids and as
+//! rooms of the direct messages, channels as rooms. The Markdown of the
+//! messages is stripped, since IRC clients show it verbatim.
+//!
+//! IRC has no email identity, so users register the email they use in Gerrit
+//! with `register <email>`. Nicknames are not verified, so the network should
+//! enforce the registration of nicknames with its services.
+
+use std::collections::HashMap;
+use std::convert::identity;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use futures::{future, Stream};
+use lazy_static::lazy_static;
+use log::{error, info};
+use regex::Regex;
+
+use gerritbot_irc as irc;
+
+use super::BotError;
+use crate::chat::{
+    ChatError, ChatEvent, ChatMessage, ChatUserId, ChatUserIdRef, Email, MessageId, MessageIdRef,
+    MessageTarget, RoomId, RoomIdRef, RoomType, SentMessage,
+};
+use crate::markdown;
+use crate::ChatBackend;
+
+const NOT_REGISTERED_MSG: &str = "Your nickname is not registered yet. Please send me `register <email>` with the email you use in Gerrit.";
+
+/// Counter for the ids of sent messages, which IRC does not have.
+static NEXT_MESSAGE_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Send the message with the Markdown stripped.
+fn send(client: &irc::Client, target: &str, msg: &str) -> Result<SentMessage, ChatError> {
+    client
+        .send_message(target, &markdown::to_text(msg))
+        .map(|()| SentMessage {
+            id: MessageId::new(format!(
+                "irc-{}",
+                NEXT_MESSAGE_ID.fetch_add(1, Ordering::Relaxed)
+            )),
+            room_id: RoomId::new(target.to_string()),
+        })
+        .map_err(ChatError::new)
+}
+
+impl ChatBackend for irc::Client {
+    /// Events are converted by `irc_event_stream`, which needs the
+    /// registrations of the users.
+    type Event = ChatEvent;
+    type ReplyFuture = future::FutureResult<SentMessage, ChatError>;
+    fn id(&self) -> &ChatUserIdRef {
+        ChatUserIdRef::new(self.nickname())
+    }
+    fn to_chat_event(&self, event: ChatEvent) -> ChatEvent {
+        event
+    }
+    fn send_message(
+        &self,
+        target: &MessageTarget,
+        msg: &str,
+        _parent_id: Option<&MessageIdRef>,
+    ) -> Self::ReplyFuture {
+        let target = match target {
+            MessageTarget::RoomId(room_id) => room_id.as_str(),
+            MessageTarget::UserId(user_id) => user_id.as_str(),
+            MessageTarget::UserEmail(email) => {
+                return future::err(ChatError::new(format!(
+                    "cannot send irc messages to email {}",
+                    email
+                )));
+            }
+        };
+        future::result(send(self, target, msg))
+    }
+    fn edit_message(
+        &self,
+        _message_id: &MessageIdRef,
+        room_id: &RoomIdRef,
+        msg: &str,
+    ) -> Self::ReplyFuture {
+        // messages cannot be edited, so the new text is sent again
+        future::result(send(self, &room_id.to_string(), msg))
+    }
+}
+
+/// Emails registered by IRC users, by lowercase nickname.
+///
+/// If created with a filename, the registrations are written to disk on every
+/// change.
+#[derive(Debug, Default)]
+pub struct Registrations {
+    emails: HashMap<String, String>,
+    filename: Option<PathBuf>,
+}
+
+impl Registrations {
+    /// Create registrations which are kept in memory only.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Load the registrations from the given file. A missing file results in
+    /// no registrations. All later changes are written back to the same file.
+    pub fn load<P>(filename: P) -> Result<Self, BotError>
+    where
+        P: AsRef<Path>,
+    {
+        let filename = filename.as_ref();
+        let emails = match File::open(filename) {
+            Ok(f) => serde_json::from_reader(f)?,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Self {
+            emails,
+            filename: Some(filename.to_path_buf()),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.emails.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.emails.is_empty()
+    }
+
+    fn save(&self) {
+        if let Some(filename) = self.filename.as_ref() {
+            let result = File::create(filename)
+                .map_err(BotError::from)
+                .and_then(|f| serde_json::to_writer(f, &self.emails).map_err(BotError::from));
+            if let Err(err) = result {
+                error!("Could not save irc registrations: {:?}", err);
+            }
+        }
+    }
+
+    /// Email of the user.
+    pub fn get(&self, nickname: &str) -> Option<&str> {
+        self.emails
+            .get(&nickname.to_lowercase())
+            .map(String::as_str)
+    }
+
+    /// Register the email of the user.
+    pub fn register(&mut self, nickname: &str, email: String) {
+        info!("Registering {} as {}", nickname, email);
+        self.emails.insert(nickname.to_lowercase(), email);
+        self.save();
+    }
+}
+
+/// Convert a private message to the bot into the event the bot handles.
+pub fn irc_message_to_event(message: irc::Message, email: String) -> ChatEvent {
+    ChatEvent::Message(ChatMessage {
+        id: MessageId::new(format!(
+            "irc-{}",
+            NEXT_MESSAGE_ID.fetch_add(1, Ordering::Relaxed)
+        )),
+        user_email: Email::new(email),
+        user_id: ChatUserId::new(message.nickname.clone()),
+        room_id: RoomId::new(message.nickname),
+        room_type: RoomType::Direct,
+        text: message.text,
+        ..Default::default()
+    })
+}
+
+fn reply(client: &irc::Client, message: &irc::Message, text: &str) {
+    if let Err(e) = client.send_message(&message.nickname, text) {
+        error!("failed to reply: {}", e);
+    }
+}
+
+/// Handle `register <email>`. Returns the message if it is something else.
+fn register(
+    client: &irc::Client,
+    registrations: &Mutex<Registrations>,
+    message: irc::Message,
+) -> Option<irc::Message> {
+    lazy_static! {
+        static ref REGISTER_REGEX: Regex = Regex::new(r"(?i)^register (\S+@\S+)$").unwrap();
+    }
+    let email = match REGISTER_REGEX.captures(&message.text) {
+        Some(captures) => captures[1].to_string(),
+        None => return Some(message),
+    };
+    registrations
+        .lock()
+        .unwrap()
+        .register(&message.nickname, email.clone());
+    reply(
+        client,
+        &message,
+        &format!("Your nickname is registered as {} now.", email),
+    );
+    None
+}
+
+/// Stream of the events for the bot. Registrations and messages of users who
+/// did not register yet are answered directly and not passed on.
+pub fn irc_event_stream(
+    client: irc::Client,
+    registrations: Registrations,
+) -> impl Stream<Item = ChatEvent, Error = ()> {
+    let registrations = Arc::new(Mutex::new(registrations));
+    irc::event_stream(client.clone())
+        .map(move |message| {
+            let message = register(&client, &registrations, message)?;
+            let email = registrations
+                .lock()
+                .unwrap()
+                .get(&message.nickname)
+                .map(String::from);
+            match email {
+                Some(email) => Some(irc_message_to_event(message, email)),
+                None => {
+                    reply(&client, &message, NOT_REGISTERED_MSG);
+                    None
+                }
+            }
+        })
+        .filter_map(identity)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn convert_messages_to_events() {
+        let message = irc::Message {
+            nickname: "jane".to_string(),
+            text: "status".to_string(),
+        };
+        let message = irc_message_to_event(message, "jane@example.com".to_string())
+            .into_message()
+            .unwrap();
+        assert_eq!(message.room_type, RoomType::Direct);
+        assert_eq!(message.user_id.as_str(), "jane");
+        assert_eq!(message.user_email.as_str(), "jane@example.com");
+        assert_eq!(message.room_id.as_str(), "jane");
+        assert_eq!(message.text, "status");
+    }
+
+    #[test]
+    fn register_emails() {
+        let dir = tempfile::tempdir().unwrap();
+        let filename = dir.path().join("registrations.json");
+        let client = irc::Client::new("localhost:6667".to_string(), "gerritbot".to_string());
+        let registrations = Mutex::new(Registrations::load(&filename).unwrap());
+        let message = |text: &str| irc::Message {
+            nickname: "Jane".to_string(),
+            text: text.to_string(),
+        };
+
+        assert_eq!(
+            register(&client, &registrations, message("status")),
+            Some(message("status"))
+        );
+        assert_eq!(
+            register(
+                &client,
+                &registrations,
+                message("register jane@example.com")
+            ),
+            None
+        );
+        assert_eq!(
+            registrations.lock().unwrap().get("jane"),
+            Some("jane@example.com")
+        );
+
+        let registrations = Registrations::load(&filename).unwrap();
+        assert_eq!(registrations.get("JANE"), Some("jane@example.com"));
+        assert_eq!(registrations.get("joe"), None);
+    }
+}
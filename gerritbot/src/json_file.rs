@@ -0,0 +1,97 @@
+//! Crash-safe writing of JSON files.
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use super::BotError;
+
+/// Path of the file with the suffix appended, e.g. `state.json.1`.
+pub(crate) fn with_suffix(filename: &Path, suffix: &str) -> PathBuf {
+    let mut filename = OsString::from(filename);
+    filename.push(suffix);
+    filename.into()
+}
+
+/// Shift the backups by one, and keep the current file as first backup.
+fn rotate_backups(filename: &Path, backups: usize) -> io::Result<()> {
+    if backups == 0 || !filename.exists() {
+        return Ok(());
+    }
+    for i in (1..backups).rev() {
+        let backup = with_suffix(filename, &format!(".{}", i));
+        if backup.exists() {
+            fs::rename(&backup, with_suffix(filename, &format!(".{}", i + 1)))?;
+        }
+    }
+    // link instead of rename, so that the file exists at any time
+    let backup = with_suffix(filename, ".1");
+    if backup.exists() {
+        fs::remove_file(&backup)?;
+    }
+    fs::hard_link(filename, &backup).or_else(|_| fs::copy(filename, &backup).map(|_| ()))
+}
+
+/// Write the value to the file and make sure it is on disk.
+fn write_synced<T>(filename: &Path, value: &T) -> Result<(), BotError>
+where
+    T: Serialize + ?Sized,
+{
+    let mut writer = io::BufWriter::new(File::create(filename)?);
+    serde_json::to_writer(&mut writer, value)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    Ok(())
+}
+
+/// Write the value to a temporary file, and replace the file with it once it
+/// is completely on disk. A crash leaves either the old or the new file. The
+/// given number of previous versions is kept as `<file>.1`, `<file>.2`, ...
+pub(crate) fn write<T>(filename: &Path, value: &T, backups: usize) -> Result<(), BotError>
+where
+    T: Serialize + ?Sized,
+{
+    let tmp_filename = with_suffix(filename, ".tmp");
+    let replaced = write_synced(&tmp_filename, value)
+        .and_then(|()| rotate_backups(filename, backups).map_err(BotError::from))
+        .and_then(|()| fs::rename(&tmp_filename, filename).map_err(BotError::from));
+    if let Err(err) = replaced {
+        // the file may not even have been created
+        let _ = fs::remove_file(&tmp_filename);
+        return Err(err);
+    }
+    // persist the rename
+    if let Some(dir) = filename.parent() {
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn keep_file_and_remove_temporary_file_on_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let filename = dir.path().join("state.json");
+        write(&filename, &vec![1, 2], 0).unwrap();
+
+        // maps with non-string keys cannot be serialized to JSON
+        let mut value = HashMap::new();
+        value.insert((1, 2), 3);
+        assert!(write(&filename, &value, 0).is_err());
+        assert!(!with_suffix(&filename, ".tmp").exists());
+        assert_eq!(fs::read_to_string(&filename).unwrap(), "[1,2]");
+    }
+}
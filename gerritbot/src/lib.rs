@@ -1,27 +1,56 @@
 use std::borrow::Cow;
-use std::collections::HashMap;
 use std::convert;
-use std::fs::File;
+use std::fmt;
 use std::io;
 use std::path::Path;
 use std::time::Duration;
 
-use futures::{future::Future, stream::Stream};
+use futures::{future, future::Future, stream, stream::Stream};
 use lazy_static::lazy_static;
-use log::{debug, error, warn};
+use log::{debug, error};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 
 use gerritbot_gerrit as gerrit;
-use gerritbot_spark as spark;
 
 pub mod args;
+pub mod chat;
+mod email;
 mod format;
+mod irc;
+mod json_file;
+mod markdown;
+mod matrix;
+mod mattermost;
+mod outbox;
+mod outgoing_webhook;
 mod rate_limit;
-
+mod routes;
+mod slack;
+mod spark;
+mod sqlite_store;
+mod store;
+mod threads;
+
+use chat::{
+    CardAction, ChatError, ChatEvent, ChatMessage, ChatUserId, ChatUserIdRef, Email, EmailRef,
+    MessageIdRef, MessageTarget, RoomId, RoomIdRef, RoomType, SentMessage,
+};
+pub use email::{Mail, Mailer};
 use format::Formatter;
 pub use format::DEFAULT_FORMAT_SCRIPT;
+pub use irc::{irc_event_stream, irc_message_to_event, Registrations};
+pub use matrix::{matrix_event_stream, matrix_message_to_event, EmailLinks};
+pub use mattermost::mattermost_message_to_event;
+pub use outbox::Outbox;
+pub use outgoing_webhook::{OutgoingWebhook, WebhookPayload};
 use rate_limit::RateLimiter;
+pub use routes::Route;
+pub use slack::slack_message_to_event;
+pub use spark::SparkModeConfig;
+pub use sqlite_store::SqliteStateStore;
+pub use store::{JsonStateStore, StateStore, User};
+pub use threads::Threads;
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Filter {
@@ -38,23 +67,37 @@ impl Filter {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct User {
-    spark_person_id: spark::PersonId,
-    /// email of the user; assumed to be the same in Spark and Gerrit
-    email: spark::Email,
-    enabled: bool,
-    filter: Option<Filter>,
+/// Subscription of a group room to the review events of a project.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Subscription {
+    /// name or glob pattern of the project
+    pub project: String,
+    /// glob pattern of the branches
+    pub branch: String,
 }
 
-impl User {
-    fn new(person_id: spark::PersonId, email: spark::Email) -> Self {
+impl Subscription {
+    pub fn new<A: Into<String>, B: Into<String>>(project: A, branch: B) -> Self {
         Self {
-            spark_person_id: person_id,
-            email: email,
-            filter: None,
-            enabled: true,
+            project: project.into(),
+            branch: branch.into(),
+        }
+    }
+
+    fn route(&self, room_id: &RoomId) -> Route {
+        Route::new(room_id.clone())
+            .with_project(&self.project)
+            .with_branch(&self.branch)
+    }
+}
+
+impl fmt::Display for Subscription {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "project `{}`", self.project)?;
+        if self.branch != "*" {
+            write!(f, " on branch `{}`", self.branch)?;
         }
+        Ok(())
     }
 }
 
@@ -62,33 +105,76 @@ pub trait GerritCommandRunner {}
 
 impl GerritCommandRunner for gerrit::CommandRunner {}
 
-pub trait SparkClient: Clone {
-    type ReplyFuture: Future<Item = (), Error = spark::Error> + Send;
-    fn send_message(&self, person_id: &spark::PersonId, msg: &str) -> Self::ReplyFuture;
-}
-
-impl SparkClient for spark::Client {
-    type ReplyFuture = Box<dyn Future<Item = (), Error = spark::Error> + Send>;
-    fn send_message(&self, person_id: &spark::PersonId, msg: &str) -> Self::ReplyFuture {
-        Box::new(self.send_message(person_id, msg))
+/// Chat service the bot receives commands from and sends notifications to.
+pub trait ChatBackend: Clone {
+    /// Event as received from the chat service.
+    type Event;
+    /// Future resolving to the sent or edited message.
+    type ReplyFuture: Future<Item = SentMessage, Error = ChatError> + Send;
+    /// Id of the bot itself.
+    fn id(&self) -> &ChatUserIdRef;
+    /// Convert a received event into the event handled by the bot.
+    fn to_chat_event(&self, event: Self::Event) -> ChatEvent;
+    /// Send a message, optionally as reply in the thread of the parent message.
+    fn send_message(
+        &self,
+        target: &MessageTarget,
+        msg: &str,
+        parent_id: Option<&MessageIdRef>,
+    ) -> Self::ReplyFuture;
+    /// Replace the text of a message sent before.
+    fn edit_message(
+        &self,
+        message_id: &MessageIdRef,
+        room_id: &RoomIdRef,
+        msg: &str,
+    ) -> Self::ReplyFuture;
+    /// Send a message with an Adaptive Card. Clients without support for
+    /// cards send the message only.
+    fn send_card(
+        &self,
+        target: &MessageTarget,
+        msg: &str,
+        _card: &serde_json::Value,
+        parent_id: Option<&MessageIdRef>,
+    ) -> Self::ReplyFuture {
+        self.send_message(target, msg, parent_id)
+    }
+    /// Check if the user is a moderator of the room. Clients without
+    /// support for memberships treat everybody as moderator.
+    fn is_moderator(
+        &self,
+        _room_id: &RoomIdRef,
+        _user_id: &ChatUserIdRef,
+    ) -> Box<dyn Future<Item = bool, Error = ChatError> + Send> {
+        Box::new(future::ok(true))
     }
 }
 
-pub struct Bot<G = gerrit::CommandRunner, S = spark::Client> {
+pub struct Bot<G, S> {
     state: State,
     rate_limiter: RateLimiter,
     formatter: format::Formatter,
+    outbox: Outbox,
+    routes: Vec<Route>,
+    /// routes of the subscriptions in `state`, updated when they change
+    subscription_routes: Vec<Route>,
+    subscriptions_moderators_only: bool,
+    mailer: Option<Mailer>,
+    outgoing_webhook: Option<OutgoingWebhook>,
     gerrit_command_runner: G,
-    spark_client: S,
+    chat_client: S,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+/// Users and subscriptions of the bot, kept in a `StateStore`.
 pub struct State {
-    users: Vec<User>,
-    #[serde(skip_serializing, skip_deserializing)]
-    person_id_index: HashMap<spark::PersonId, usize>,
-    #[serde(skip_serializing, skip_deserializing)]
-    email_index: HashMap<spark::Email, usize>,
+    store: Box<dyn StateStore>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::with_store(JsonStateStore::default())
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -102,12 +188,20 @@ pub struct Builder {
     state: State,
     rate_limiter: RateLimiter,
     formatter: Formatter,
+    outbox: Outbox,
+    routes: Vec<Route>,
+    subscriptions_moderators_only: bool,
+    mailer: Option<Mailer>,
+    outgoing_webhook: Option<OutgoingWebhook>,
 }
 
 #[derive(Debug)]
 pub enum BotError {
     Io(io::Error),
     Serialization(serde_json::Error),
+    Sqlite(rusqlite::Error),
+    /// state file of a newer version of the bot
+    StateVersion(u64),
 }
 
 impl convert::From<io::Error> for BotError {
@@ -122,6 +216,12 @@ impl convert::From<serde_json::Error> for BotError {
     }
 }
 
+impl convert::From<rusqlite::Error> for BotError {
+    fn from(err: rusqlite::Error) -> BotError {
+        BotError::Sqlite(err)
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum AddFilterResult {
     UserNotFound,
@@ -131,179 +231,204 @@ pub enum AddFilterResult {
 }
 
 impl State {
+    /// State which is kept in memory only.
     pub fn new() -> Self {
         Default::default()
     }
 
+    pub fn with_store<S: StateStore + 'static>(store: S) -> Self {
+        Self {
+            store: Box::new(store),
+        }
+    }
+
+    /// Load the state from a JSON file.
     pub fn load<P>(filename: P) -> Result<Self, BotError>
     where
         P: AsRef<Path>,
     {
-        let f = File::open(filename)?;
-
-        serde_json::from_reader(f)
-            .map(|mut state: Self| {
-                state.index_users();
-                state
-            })
-            .map_err(BotError::from)
+        JsonStateStore::load(filename).map(Self::with_store)
     }
 
-    fn index_users(&mut self) {
-        for (user_pos, user) in self.users.iter().enumerate() {
-            self.person_id_index
-                .insert(user.spark_person_id.clone(), user_pos);
-            self.email_index.insert(user.email.clone(), user_pos);
-        }
-    }
-
-    pub fn num_users(&self) -> usize {
-        self.users.len()
+    pub fn num_users(&self) -> Result<usize, BotError> {
+        self.store.num_users()
     }
 
-    // Note: This method is not idempotent, and in particular, when adding the same user twice,
-    // it will completely mess up the indexes.
-    fn add_user<'a>(
-        &'a mut self,
-        person_id: &spark::PersonIdRef,
-        email: &spark::EmailRef,
-    ) -> &'a mut User {
-        let user_pos = self.users.len();
-        self.users
-            .push(User::new(person_id.to_owned(), email.to_owned()));
-        self.person_id_index.insert(person_id.to_owned(), user_pos);
-        self.email_index.insert(email.to_owned(), user_pos);
-        self.users.last_mut().unwrap()
+    fn num_enabled_users(&self) -> Result<usize, BotError> {
+        self.store.num_enabled_users()
     }
 
-    fn find_or_add_user_by_person_id<'a>(
-        &'a mut self,
-        person_id: &spark::PersonIdRef,
-        email: &spark::EmailRef,
-    ) -> &'a mut User {
-        let pos = self
-            .users
-            .iter()
-            .position(|u| u.spark_person_id == person_id);
-        let user: &'a mut User = match pos {
-            Some(pos) => &mut self.users[pos],
-            None => self.add_user(person_id, email),
-        };
-        user
+    fn save_user(&mut self, user: &User) -> Result<(), BotError> {
+        self.store.save_user(user)
     }
 
-    fn find_user_mut<'a, P: ?Sized>(&'a mut self, person_id: &P) -> Option<&'a mut User>
-    where
-        spark::PersonId: std::borrow::Borrow<P>,
-        P: std::hash::Hash + Eq,
-    {
-        self.person_id_index
-            .get(person_id)
-            .cloned()
-            .map(move |pos| &mut self.users[pos])
+    fn find_user(&self, person_id: &ChatUserIdRef) -> Result<Option<User>, BotError> {
+        self.store.find_user(person_id)
     }
 
-    fn find_user<'a, P: ?Sized>(&'a self, person_id: &P) -> Option<&'a User>
-    where
-        spark::PersonId: std::borrow::Borrow<P>,
-        P: std::hash::Hash + Eq,
-    {
-        self.person_id_index
-            .get(person_id)
-            .cloned()
-            .map(|pos| &self.users[pos])
+    fn find_user_by_email(&self, email: &EmailRef) -> Result<Option<User>, BotError> {
+        self.store.find_user_by_email(email)
     }
 
-    fn enable<'a>(
-        &'a mut self,
-        person_id: &spark::PersonIdRef,
-        email: &spark::EmailRef,
+    fn enable(
+        &mut self,
+        person_id: &ChatUserIdRef,
+        email: &EmailRef,
         enabled: bool,
-    ) -> &'a User {
-        let user: &'a mut User = self.find_or_add_user_by_person_id(person_id, email);
+    ) -> Result<User, BotError> {
+        let mut user = self
+            .find_user(person_id)?
+            .unwrap_or_else(|| User::new(person_id.to_owned(), email.to_owned()));
         user.enabled = enabled;
-        user
+        self.save_user(&user)?;
+        Ok(user)
     }
 
+    /// Add a filter for the user. The outer error is a failure of the store.
     pub fn add_filter<A>(
         &mut self,
-        person_id: &spark::PersonIdRef,
+        person_id: &ChatUserIdRef,
         filter: A,
-    ) -> Result<(), AddFilterResult>
+    ) -> Result<Result<(), AddFilterResult>, BotError>
     where
         A: Into<String>,
     {
-        let user = self.find_user_mut(person_id);
-        match user {
-            Some(user) => {
-                if !user.enabled {
-                    Err(AddFilterResult::UserDisabled)
-                } else {
-                    let filter: String = filter.into();
-                    if Regex::new(&filter).is_err() {
-                        return Err(AddFilterResult::InvalidFilter);
-                    }
-                    user.filter = Some(Filter::new(filter));
-                    Ok(())
-                }
-            }
-            None => Err(AddFilterResult::UserNotFound),
+        let mut user = match self.find_user(person_id)? {
+            Some(user) => user,
+            None => return Ok(Err(AddFilterResult::UserNotFound)),
+        };
+        if !user.enabled {
+            return Ok(Err(AddFilterResult::UserDisabled));
+        }
+        let filter: String = filter.into();
+        if Regex::new(&filter).is_err() {
+            return Ok(Err(AddFilterResult::InvalidFilter));
         }
+        user.filter = Some(Filter::new(filter));
+        self.save_user(&user)?;
+        Ok(Ok(()))
     }
 
-    pub fn get_filter<'a>(
-        &'a self,
-        person_id: &spark::PersonIdRef,
-    ) -> Result<Option<&'a Filter>, AddFilterResult> {
-        let user = self.find_user(person_id);
-        match user {
-            Some(user) => Ok(user.filter.as_ref()),
+    pub fn get_filter(
+        &self,
+        person_id: &ChatUserIdRef,
+    ) -> Result<Result<Option<Filter>, AddFilterResult>, BotError> {
+        Ok(match self.find_user(person_id)? {
+            Some(user) => Ok(user.filter),
             None => Err(AddFilterResult::UserNotFound),
-        }
+        })
     }
 
     pub fn enable_filter(
         &mut self,
-        person_id: &spark::PersonIdRef,
+        person_id: &ChatUserIdRef,
         enabled: bool,
-    ) -> Result<String /* filter */, AddFilterResult> {
-        let user = self.find_user_mut(person_id);
-        match user {
-            Some(user) => {
-                if !user.enabled {
-                    Err(AddFilterResult::UserDisabled)
-                } else {
-                    match user.filter.as_mut() {
-                        Some(filter) => {
-                            if Regex::new(&filter.regex).is_err() {
-                                return Err(AddFilterResult::InvalidFilter);
-                            }
-                            filter.enabled = enabled;
-                            Ok(filter.regex.clone())
-                        }
-                        None => Err(AddFilterResult::FilterNotConfigured),
-                    }
+    ) -> Result<Result<String /* filter */, AddFilterResult>, BotError> {
+        let mut user = match self.find_user(person_id)? {
+            Some(user) => user,
+            None => return Ok(Err(AddFilterResult::UserNotFound)),
+        };
+        if !user.enabled {
+            return Ok(Err(AddFilterResult::UserDisabled));
+        }
+        let regex = match user.filter.as_mut() {
+            Some(filter) => {
+                if Regex::new(&filter.regex).is_err() {
+                    return Ok(Err(AddFilterResult::InvalidFilter));
                 }
+                filter.enabled = enabled;
+                filter.regex.clone()
             }
-            None => Err(AddFilterResult::UserNotFound),
+            None => return Ok(Err(AddFilterResult::FilterNotConfigured)),
+        };
+        self.save_user(&user)?;
+        Ok(Ok(regex))
+    }
+
+    /// Subscribe the room to the events of a project. Returns false if the
+    /// room is already subscribed.
+    pub fn subscribe(
+        &mut self,
+        room_id: &RoomIdRef,
+        subscription: Subscription,
+    ) -> Result<bool, BotError> {
+        let mut subscriptions = self.subscriptions(room_id)?;
+        if subscriptions.contains(&subscription) {
+            return Ok(false);
         }
+        subscriptions.push(subscription);
+        self.store.set_subscriptions(room_id, &subscriptions)?;
+        Ok(true)
     }
 
-    fn is_filtered(&self, user_pos: usize, msg: &str) -> bool {
-        let user = &self.users[user_pos];
-        if let Some(filter) = user.filter.as_ref() {
-            if filter.enabled {
-                if let Ok(re) = Regex::new(&filter.regex) {
-                    return re.is_match(msg);
-                } else {
-                    warn!(
-                        "User {} has configured invalid filter regex: {}",
-                        user.spark_person_id, filter.regex
-                    );
-                }
-            }
+    /// Remove a subscription of the room. Returns false if the room is not
+    /// subscribed.
+    pub fn unsubscribe(
+        &mut self,
+        room_id: &RoomIdRef,
+        subscription: &Subscription,
+    ) -> Result<bool, BotError> {
+        let mut subscriptions = self.subscriptions(room_id)?;
+        let len = subscriptions.len();
+        subscriptions.retain(|s| s != subscription);
+        if subscriptions.len() == len {
+            return Ok(false);
+        }
+        self.store.set_subscriptions(room_id, &subscriptions)?;
+        Ok(true)
+    }
+
+    /// Remove all subscriptions of the room. Returns the number of removed
+    /// subscriptions.
+    pub fn unsubscribe_all(&mut self, room_id: &RoomIdRef) -> Result<usize, BotError> {
+        let len = self.subscriptions(room_id)?.len();
+        if len > 0 {
+            self.store.set_subscriptions(room_id, &[])?;
+        }
+        Ok(len)
+    }
+
+    pub fn subscriptions(&self, room_id: &RoomIdRef) -> Result<Vec<Subscription>, BotError> {
+        self.store.subscriptions(room_id)
+    }
+
+    /// Mute or unmute the notifications about a change for the user. Returns
+    /// false if the change is already (un)muted. The outer error is a failure
+    /// of the store.
+    pub fn mute_change(
+        &mut self,
+        person_id: &ChatUserIdRef,
+        change: u32,
+        muted: bool,
+    ) -> Result<Result<bool, AddFilterResult>, BotError> {
+        let mut user = match self.find_user(person_id)? {
+            Some(user) => user,
+            None => return Ok(Err(AddFilterResult::UserNotFound)),
+        };
+        if user.is_muted(change) == muted {
+            return Ok(Ok(false));
+        }
+        if muted {
+            user.muted_changes.push(change);
+        } else {
+            user.muted_changes.retain(|c| *c != change);
         }
-        false
+        self.save_user(&user)?;
+        Ok(Ok(true))
+    }
+
+    fn subscription_routes(&self) -> Result<Vec<Route>, BotError> {
+        Ok(self
+            .store
+            .all_subscriptions()?
+            .into_iter()
+            .map(|(room_id, subscription)| subscription.route(&room_id))
+            .collect())
+    }
+
+    /// Persist the changes, if the store does not do it immediately.
+    fn flush(&mut self) -> Result<(), BotError> {
+        self.store.flush()
     }
 }
 
@@ -329,47 +454,208 @@ impl Builder {
         })
     }
 
-    pub fn build<G, S>(self, gerrit_command_runner: G, spark_client: S) -> Bot<G, S> {
+    pub fn with_outbox(self, outbox: Outbox) -> Self {
+        Self { outbox, ..self }
+    }
+
+    /// Post Gerrit events matching the routes to group rooms.
+    pub fn with_routes(self, routes: Vec<Route>) -> Self {
+        Self { routes, ..self }
+    }
+
+    /// Only allow moderators of a group room to change its subscriptions.
+    pub fn with_subscriptions_moderators_only(self, subscriptions_moderators_only: bool) -> Self {
+        Self {
+            subscriptions_moderators_only,
+            ..self
+        }
+    }
+
+    /// Also send notifications by email to the recipients of the mailer.
+    pub fn with_mailer(self, mailer: Mailer) -> Self {
+        Self {
+            mailer: Some(mailer),
+            ..self
+        }
+    }
+
+    /// Also post notifications to the outgoing webhook.
+    pub fn with_outgoing_webhook(self, outgoing_webhook: OutgoingWebhook) -> Self {
+        Self {
+            outgoing_webhook: Some(outgoing_webhook),
+            ..self
+        }
+    }
+
+    pub fn build<G, S>(self, gerrit_command_runner: G, chat_client: S) -> Bot<G, S> {
         let Self {
             formatter,
             rate_limiter,
             state,
+            outbox,
+            routes,
+            subscriptions_moderators_only,
+            mailer,
+            outgoing_webhook,
         } = self;
 
         Bot {
             gerrit_command_runner,
-            spark_client,
+            chat_client,
             rate_limiter,
             formatter,
+            subscription_routes: state.subscription_routes().unwrap_or_else(|err| {
+                error!("Could not load the subscriptions: {:?}", err);
+                Vec::new()
+            }),
             state,
+            outbox,
+            routes,
+            subscriptions_moderators_only,
+            mailer,
+            outgoing_webhook,
         }
     }
 }
 
-fn spark_message_to_action(message: spark::Message) -> Action {
+/// Transform a chat message or card action into a bot action.
+fn chat_event_to_action(event: ChatEvent, bot_id: &ChatUserIdRef) -> Option<Action> {
+    match event {
+        ChatEvent::Message(message) => chat_message_to_action(message, bot_id),
+        ChatEvent::CardAction(action) => card_action_to_action(action),
+        ChatEvent::BotAdded(room_id, room_type) => Some(Action::Joined(room_id, room_type)),
+        ChatEvent::BotRemoved(room_id) => Some(Action::Left(room_id)),
+    }
+}
+
+/// Where to send the reply to a command sent in a room of the given type.
+fn reply_to(room_type: RoomType, user_id: &ChatUserIdRef, room_id: RoomId) -> MessageTarget {
+    match room_type {
+        RoomType::Direct => MessageTarget::UserId(user_id.to_owned()),
+        RoomType::Group => MessageTarget::RoomId(room_id),
+    }
+}
+
+/// Transform a chat message into a bot action. In group rooms only messages
+/// @mentioning the bot are considered and answered in the room.
+fn chat_message_to_action(message: ChatMessage, bot_id: &ChatUserIdRef) -> Option<Action> {
+    if message.room_type == RoomType::Group && !message.mentions(bot_id) {
+        debug!("Ignoring group message not mentioning the bot");
+        return None;
+    }
+    let text = message.text.trim().to_string();
+    let sender = Sender {
+        reply_to: reply_to(message.room_type, &message.user_id, message.room_id),
+        person_id: message.user_id,
+        email: message.user_email,
+    };
+
+    Some(command_to_action(&text, sender))
+}
+
+/// Transform a card action into a bot action. The data of the card's submit
+/// button is expected to contain the command to run, e.g.
+/// `{"command": "mute 42"}`. The reply is sent to the room of the card.
+fn card_action_to_action(action: CardAction) -> Option<Action> {
+    let command = match action.inputs.get("command").and_then(|c| c.as_str()) {
+        Some(command) => command.trim().to_string(),
+        None => {
+            debug!("Ignoring card action without command: {:?}", action.inputs);
+            return None;
+        }
+    };
+    let sender = Sender {
+        reply_to: reply_to(action.room_type, &action.user_id, action.room_id),
+        person_id: action.user_id,
+        email: action.user_email,
+    };
+
+    Some(command_to_action(&command, sender))
+}
+
+/// Parse the text of a command sent by the sender.
+fn command_to_action(text: &str, sender: Sender) -> Action {
     lazy_static! {
         static ref FILTER_REGEX: Regex = Regex::new(r"(?i)^filter (.*)$").unwrap();
+        static ref SUBSCRIBE_REGEX: Regex =
+            Regex::new(r"(?i)^subscribe project (\S+)(?: branch (\S+))?$").unwrap();
+        static ref UNSUBSCRIBE_REGEX: Regex =
+            Regex::new(r"(?i)^unsubscribe project (\S+)(?: branch (\S+))?$").unwrap();
+        static ref MUTE_REGEX: Regex = Regex::new(r"(?i)^(un)?mute (\d+)$").unwrap();
     };
 
-    let sender_email = message.person_email;
-    let sender_id = message.person_id;
-    match &message.text.trim().to_lowercase()[..] {
-        "enable" => Action::Enable(sender_id, sender_email),
-        "disable" => Action::Disable(sender_id, sender_email),
-        "status" => Action::Status(sender_id),
-        "help" => Action::Help(sender_id),
-        "version" => Action::Version(sender_id),
-        "filter" => Action::FilterStatus(sender_id),
-        "filter enable" => Action::FilterEnable(sender_id),
-        "filter disable" => Action::FilterDisable(sender_id),
-        _ => FILTER_REGEX
-            .captures(&message.text.trim()[..])
-            .and_then(|cap| cap.get(1))
-            .map(|m| Action::FilterAdd(sender_id.clone(), m.as_str().to_string()))
-            .unwrap_or_else(|| Action::Unknown(sender_id.clone())),
+    match &text.to_lowercase()[..] {
+        "enable" => Action::Enable(sender),
+        "disable" => Action::Disable(sender),
+        "status" => Action::Status(sender),
+        "help" => Action::Help(sender),
+        "version" => Action::Version(sender),
+        "filter" => Action::FilterStatus(sender),
+        "filter enable" => Action::FilterEnable(sender),
+        "filter disable" => Action::FilterDisable(sender),
+        "subscriptions" => Action::Subscriptions(sender),
+        "unsubscribe" => Action::Unsubscribe(sender, None),
+        _ => {
+            if let Some(m) = FILTER_REGEX.captures(text).and_then(|cap| cap.get(1)) {
+                Action::FilterAdd(sender, m.as_str().to_string())
+            } else if let Some(cap) = SUBSCRIBE_REGEX.captures(text) {
+                Action::Subscribe(sender, subscription_from_captures(&cap))
+            } else if let Some(cap) = UNSUBSCRIBE_REGEX.captures(text) {
+                Action::Unsubscribe(sender, Some(subscription_from_captures(&cap)))
+            } else if let Some(change) = MUTE_REGEX.captures(text).and_then(|cap| {
+                cap[2]
+                    .parse()
+                    .ok()
+                    .map(|change| (cap.get(1).is_none(), change))
+            }) {
+                match change {
+                    (true, change) => Action::Mute(sender, change),
+                    (false, change) => Action::Unmute(sender, change),
+                }
+            } else {
+                Action::Unknown(sender)
+            }
+        }
     }
 }
 
+/// Create a subscription from the project and optional branch captured by a
+/// (un)subscribe command.
+fn subscription_from_captures(cap: &regex::Captures) -> Subscription {
+    Subscription::new(&cap[1], cap.get(2).map(|m| m.as_str()).unwrap_or("*"))
+}
+
+/// Replace commands changing the subscriptions of a room by
+/// `Action::NotModerator` if the sender is not a moderator of the room.
+fn check_moderator<S: ChatBackend>(
+    action: Action,
+    chat_client: &S,
+) -> impl Future<Item = Action, Error = ()> {
+    let is_moderator = match &action {
+        Action::Subscribe(sender, _) | Action::Unsubscribe(sender, _) => sender
+            .room_id()
+            .map(|room_id| chat_client.is_moderator(room_id, &sender.person_id)),
+        _ => None,
+    };
+    let is_moderator = match is_moderator {
+        Some(is_moderator) => is_moderator,
+        None => return future::Either::A(future::ok(action)),
+    };
+
+    future::Either::B(is_moderator.then(move |result| {
+        let is_moderator = result.unwrap_or_else(|e| {
+            error!("failed to check moderator of room: {}", e);
+            false
+        });
+        Ok(match action {
+            Action::Subscribe(sender, _) | Action::Unsubscribe(sender, _) if !is_moderator => {
+                Action::NotModerator(sender)
+            }
+            action => action,
+        })
+    }))
+}
+
 /// Transform a gerrit event into a bot action.
 pub fn gerrit_event_to_action(event: gerrit::Event) -> Option<Action> {
     match event {
@@ -378,6 +664,16 @@ pub fn gerrit_event_to_action(event: gerrit::Event) -> Option<Action> {
     }
 }
 
+/// Whether the account belongs to a human, judging by its user name. Accounts
+/// without user name are not considered human.
+fn is_human(account: &gerrit::User) -> bool {
+    account
+        .username
+        .as_ref()
+        .map(|name| !name.to_lowercase().contains("bot"))
+        .unwrap_or(false)
+}
+
 pub fn request_extended_gerrit_info(event: &gerrit::Event) -> Cow<'static, [gerrit::ExtendedInfo]> {
     let mut extended_info = Vec::new();
 
@@ -385,11 +681,10 @@ pub fn request_extended_gerrit_info(event: &gerrit::Event) -> Cow<'static, [gerr
         gerrit::Event::CommentAdded(event) => {
             let owner_name = event.change.owner.username.as_ref();
             let approver_name = event.author.username.as_ref();
-            let is_human = approver_name
-                .map(|name| !name.to_lowercase().contains("bot"))
-                .unwrap_or(false);
-
-            if owner_name != approver_name && is_human && maybe_has_inline_comments(event) {
+            if owner_name != approver_name
+                && is_human(&event.author)
+                && maybe_has_inline_comments(event)
+            {
                 extended_info.push(gerrit::ExtendedInfo::InlineComments);
             }
 
@@ -406,60 +701,87 @@ pub fn request_extended_gerrit_info(event: &gerrit::Event) -> Cow<'static, [gerr
 impl<G, S> Bot<G, S>
 where
     G: GerritCommandRunner,
-    S: SparkClient,
+    S: ChatBackend,
 {
     pub fn run(
-        self,
+        mut self,
         // TODO: gerrit event stream probably shouldn't produce errors
         gerrit_events: impl Stream<Item = gerrit::Event, Error = ()> + Send,
-        spark_messages: impl Stream<Item = spark::Message, Error = ()> + Send,
+        chat_events: impl Stream<Item = S::Event, Error = ()> + Send,
     ) -> impl Future<Item = (), Error = ()> {
         let _ = &self.gerrit_command_runner;
-        let spark_client = self.spark_client.clone();
+        let chat_client = self.chat_client.clone();
+        let outbox = std::mem::replace(&mut self.outbox, Outbox::new());
+        let bot_id = chat_client.id().to_owned();
+        let moderator_client = chat_client.clone();
+        let moderators_only = self.subscriptions_moderators_only;
         let gerrit_actions = gerrit_events.filter_map(gerrit_event_to_action);
-        let spark_actions = spark_messages.map(spark_message_to_action);
+        let event_client = chat_client.clone();
+        let chat_actions = chat_events
+            .filter_map(move |event| {
+                chat_event_to_action(event_client.to_chat_event(event), &bot_id)
+            })
+            .and_then(move |action| {
+                if moderators_only {
+                    future::Either::A(check_moderator(action, &moderator_client))
+                } else {
+                    future::Either::B(future::ok(action))
+                }
+            });
         let bot_for_action = std::sync::Arc::new(std::sync::Mutex::new(self));
         let bot_for_task = bot_for_action.clone();
 
-        gerrit_actions
-            .select(spark_actions)
+        let responses = gerrit_actions
+            .select(chat_actions)
             .filter_map(move |action| bot_for_action.lock().unwrap().update(action))
-            .filter_map(move |task| bot_for_task.lock().unwrap().handle_task(task))
-            .for_each(move |response| {
-                debug!("Replying with: {}", response.message);
-                spark_client
-                    .send_message(&response.person_id, &response.message)
-                    .map_err(|e| error!("failed to send spark message: {}", e))
-            })
+            .map(move |task| stream::iter_ok(bot_for_task.lock().unwrap().handle_task(task)))
+            .flatten();
+
+        outbox.deliver(responses, chat_client)
     }
 
     /// Action controller
     /// Return an optional message to send to the user
     fn update(&mut self, action: Action) -> Option<Task> {
-        match action {
-        Action::Enable(person_id, email) => {
-            self.state.enable(&person_id, &email, true);
-            let task = Task::ReplyAndSave(Response::new(person_id, "Got it! Happy reviewing!"));
+        let reply_to = action.sender().map(|sender| sender.reply_to.clone());
+        self.handle_action(action).unwrap_or_else(|err| {
+            error!("Could not handle action: {:?}", err);
+            reply_to.map(|reply_to| Task::Reply(Response::new(reply_to, STATE_ERROR_MSG)))
+        })
+    }
+
+    fn handle_action(&mut self, action: Action) -> Result<Option<Task>, BotError> {
+        Ok(match action {
+        Action::Enable(sender) => {
+            self.state.enable(&sender.person_id, &sender.email, true)?;
+            let task = Task::ReplyAndSave(Response::new(sender.reply_to, "Got it! Happy reviewing!"));
             Some(task)
         }
-        Action::Disable(person_id, email) => {
-            self.state.enable(&person_id, &email, false);
-            let task = Task::ReplyAndSave(Response::new(person_id, "Got it! I will stay silent."));
+        Action::Disable(sender) => {
+            self.state.enable(&sender.person_id, &sender.email, false)?;
+            let task = Task::ReplyAndSave(Response::new(sender.reply_to, "Got it! I will stay silent."));
             Some(task)
         }
         Action::UpdateApprovals(event) => {
-            self.get_approvals_msg(event).map(|(user, message, _is_human)|
-                    Task::Reply(Response::new(user.spark_person_id.clone(), message)))
-        }
-        Action::Help(person_id) => Some(Task::Reply(Response::new(person_id, HELP_MSG))),
-            Action::Version(person_id) => Some(Task::Reply(Response::new(person_id, VERSION_MSG))),
-        Action::Unknown(person_id) => Some(Task::Reply(Response::new(person_id, GREETINGS_MSG))),
-        Action::Status(person_id) => {
-            let status = self.status_for(&person_id);
-            Some(Task::Reply(Response::new(person_id, status)))
-        }
-        Action::FilterStatus(person_id) => {
-            let resp: String = match self.state.get_filter(&person_id) {
+            let thread = event.change.number.to_string();
+            let card = self.get_comment_added_card(&event);
+            let mut responses = self.get_room_approvals_msgs(&event);
+            if let Some((user, message, _is_human)) = self.get_approvals_msg(&event)? {
+                self.deliver(&user, &event.change, "comment-added", &*event, &message);
+                responses.push(Response::new(user.user_id.clone(), message));
+            }
+            let responses = responses.into_iter().map(|r| r.in_thread(thread.clone()).with_card(card.clone()));
+            Some(Task::Notify(responses.collect()))
+        }
+        Action::Help(sender) => Some(Task::Reply(Response::new(sender.reply_to, HELP_MSG))),
+            Action::Version(sender) => Some(Task::Reply(Response::new(sender.reply_to, VERSION_MSG))),
+        Action::Unknown(sender) => Some(Task::Reply(Response::new(sender.reply_to, GREETINGS_MSG))),
+        Action::Status(sender) => {
+            let status = self.status_for(&sender.person_id)?;
+            Some(Task::Reply(Response::new(sender.reply_to, status)))
+        }
+        Action::FilterStatus(sender) => {
+            let resp: String = match self.state.get_filter(&sender.person_id)? {
                 Ok(Some(filter)) => {
                     format!(
                         "The following filter is configured for you: `{}`. It is **{}**.",
@@ -485,19 +807,19 @@ where
                 }
             };
             if !resp.is_empty() {
-                Some(Task::Reply(Response::new(person_id, resp)))
+                Some(Task::Reply(Response::new(sender.reply_to, resp)))
             } else {
                 None
             }
         }
-        Action::FilterAdd(person_id, filter) => {
-            Some(match self.state.add_filter(&person_id, filter) {
+        Action::FilterAdd(sender, filter) => {
+            Some(match self.state.add_filter(&sender.person_id, filter)? {
                 Ok(()) => Task::ReplyAndSave(Response::new(
-                    person_id,
+                    sender.reply_to,
                     "Filter successfully added and enabled.")),
                 Err(err) => {
                     Task::Reply(Response::new(
-                        person_id,
+                        sender.reply_to,
                         match err {
                             AddFilterResult::UserDisabled |
                             AddFilterResult::UserNotFound => {
@@ -515,11 +837,11 @@ where
                 }
             })
         }
-        Action::FilterEnable(person_id) => {
-            Some(match self.state.enable_filter(&person_id, true) {
+        Action::FilterEnable(sender) => {
+            Some(match self.state.enable_filter(&sender.person_id, true)? {
                 Ok(filter) => {
                     Task::ReplyAndSave(Response::new(
-                        person_id,
+                        sender.reply_to,
                         format!(
                             "Filter successfully enabled. The following filter is configured: {}",
                             filter
@@ -528,7 +850,7 @@ where
                 }
                 Err(err) => {
                     Task::Reply(Response::new(
-                        person_id,
+                        sender.reply_to,
                         match err {
                             AddFilterResult::UserDisabled |
                             AddFilterResult::UserNotFound => {
@@ -545,14 +867,14 @@ where
                 }
             })
         }
-        Action::FilterDisable(person_id) => {
-            Some(match self.state.enable_filter(&person_id, false) {
+        Action::FilterDisable(sender) => {
+            Some(match self.state.enable_filter(&sender.person_id, false)? {
                 Ok(_) => Task::ReplyAndSave(
-                    Response::new(person_id, "Filter successfully disabled."),
+                    Response::new(sender.reply_to, "Filter successfully disabled."),
                 ),
                 Err(err) => {
                     Task::Reply(Response::new(
-                        person_id,
+                        sender.reply_to,
                         match err {
                             AddFilterResult::UserDisabled |
                             AddFilterResult::UserNotFound => {
@@ -569,110 +891,369 @@ where
                 }
             })
         }
-        Action::ReviewerAdded(event) => {
-            self.get_reviewer_added_msg(&event).map(|(user, message)| {
-                Task::Reply(Response::new(user.spark_person_id.clone(), message))
+        Action::Subscribe(sender, subscription) => {
+            let room_id = match sender.room_id() {
+                Some(room_id) => room_id.clone(),
+                None => return Ok(Some(Task::Reply(Response::new(sender.reply_to, SUBSCRIPTIONS_IN_SPACES_ONLY_MSG)))),
+            };
+            let message = format!("{}", subscription);
+            if self.state.subscribe(&room_id, subscription)? {
+                self.update_subscription_routes()?;
+                Some(Task::ReplyAndSave(Response::new(
+                    sender.reply_to,
+                    format!("Got it! I will post review events of {} to this space.", message),
+                )))
+            } else {
+                Some(Task::Reply(Response::new(
+                    sender.reply_to,
+                    format!("This space is already subscribed to {}.", message),
+                )))
+            }
+        }
+        Action::Unsubscribe(sender, subscription) => {
+            let room_id = match sender.room_id() {
+                Some(room_id) => room_id.clone(),
+                None => return Ok(Some(Task::Reply(Response::new(sender.reply_to, SUBSCRIPTIONS_IN_SPACES_ONLY_MSG)))),
+            };
+            Some(match subscription {
+                Some(subscription) => {
+                    if self.state.unsubscribe(&room_id, &subscription)? {
+                        self.update_subscription_routes()?;
+                        Task::ReplyAndSave(Response::new(
+                            sender.reply_to,
+                            format!("Got it! I will stop posting review events of {} to this space.", subscription),
+                        ))
+                    } else {
+                        Task::Reply(Response::new(
+                            sender.reply_to,
+                            format!("This space is not subscribed to {}.", subscription),
+                        ))
+                    }
+                }
+                None => {
+                    if self.state.unsubscribe_all(&room_id)? > 0 {
+                        self.update_subscription_routes()?;
+                        Task::ReplyAndSave(Response::new(
+                            sender.reply_to,
+                            "Got it! I removed all subscriptions of this space.",
+                        ))
+                    } else {
+                        Task::Reply(Response::new(sender.reply_to, "This space has no subscriptions."))
+                    }
+                }
             })
         }
+        Action::Subscriptions(sender) => {
+            let room_id = match sender.room_id() {
+                Some(room_id) => room_id.clone(),
+                None => return Ok(Some(Task::Reply(Response::new(sender.reply_to, SUBSCRIPTIONS_IN_SPACES_ONLY_MSG)))),
+            };
+            let subscriptions = self.state.subscriptions(&room_id)?;
+            let message = if subscriptions.is_empty() {
+                "This space has no subscriptions.".to_string()
+            } else {
+                subscriptions.iter().fold(
+                    String::from("This space is subscribed to:\n"),
+                    |message, subscription| message + &format!("\n* {}", subscription),
+                )
+            };
+            Some(Task::Reply(Response::new(sender.reply_to, message)))
+        }
+        Action::NotModerator(sender) => Some(Task::Reply(Response::new(
+            sender.reply_to,
+            "Only moderators of this space can change its subscriptions.",
+        ))),
+        Action::Joined(room_id, room_type) => {
+            let message = match room_type {
+                RoomType::Direct => GREETINGS_MSG,
+                RoomType::Group => SPACE_GREETINGS_MSG,
+            };
+            Some(Task::Reply(Response::new(room_id, message)))
+        }
+        Action::Left(room_id) => {
+            if self.state.unsubscribe_all(&room_id)? > 0 {
+                self.update_subscription_routes()?;
+                Some(Task::Save)
+            } else {
+                None
+            }
+        }
+        Action::Mute(sender, change) => Some(self.mute_change(sender, change, true)?),
+        Action::Unmute(sender, change) => Some(self.mute_change(sender, change, false)?),
+        Action::ReviewerAdded(event) => {
+            let thread = event.change.number.to_string();
+            let card = self.get_reviewer_added_card(&event);
+            let mut responses = self.get_room_reviewer_added_msgs(&event);
+            if let Some((user, message)) = self.get_reviewer_added_msg(&event)? {
+                self.deliver(&user, &event.change, "reviewer-added", &*event, &message);
+                responses.push(Response::new(user.user_id.clone(), message));
+            }
+            let responses = responses.into_iter().map(|r| r.in_thread(thread.clone()).with_card(card.clone()));
+            Some(Task::Notify(responses.collect()))
+        }
+    })
     }
+
+    fn mute_change(&mut self, sender: Sender, change: u32, muted: bool) -> Result<Task, BotError> {
+        Ok(
+            match self.state.mute_change(&sender.person_id, change, muted)? {
+                Ok(true) => Task::ReplyAndSave(Response::new(
+                    sender.reply_to,
+                    if muted {
+                        format!(
+                            "Got it! I will not notify you about change {} anymore.",
+                            change
+                        )
+                    } else {
+                        format!("Got it! I will notify you about change {} again.", change)
+                    },
+                )),
+                Ok(false) => Task::Reply(Response::new(
+                    sender.reply_to,
+                    format!(
+                        "Change {} is {}muted.",
+                        change,
+                        if muted { "already " } else { "not " }
+                    ),
+                )),
+                Err(_) => Task::Reply(Response::new(
+                    sender.reply_to,
+                    "Notifications for you are disabled. Please enable notifications first.",
+                )),
+            },
+        )
     }
 
-    fn handle_task(&mut self, task: Task) -> Option<Response> {
+    fn handle_task(&mut self, task: Task) -> Vec<Response> {
         debug!("New task {:#?}", task);
-        let response = match task {
-            Task::Reply(response) => Some(response),
-            Task::ReplyAndSave(response) => {
-                self.save("state.json")
+        let responses = match task {
+            Task::Reply(response) => vec![response],
+            Task::ReplyAndSave(response) => match self.save() {
+                Ok(()) => vec![response],
+                Err(err) => {
+                    error!("Could not save state: {:?}", err);
+                    vec![Response::new(response.target, STATE_ERROR_MSG)]
+                }
+            },
+            Task::Notify(responses) => responses,
+            Task::Save => {
+                self.save()
                     .map_err(|err| {
                         error!("Could not save state: {:?}", err);
                     })
                     .ok();
-                Some(response)
+                Vec::new()
             }
         };
-        return response;
+        responses
+    }
+
+    /// Rebuild the routes of the subscriptions after they changed.
+    fn update_subscription_routes(&mut self) -> Result<(), BotError> {
+        self.subscription_routes = self.state.subscription_routes()?;
+        Ok(())
+    }
+
+    /// Rooms an event of the given type about the change is posted to, either
+    /// due to the configured routes or the subscriptions of the rooms.
+    fn routed_rooms(&self, event_type: &str, change: &gerrit::Change) -> Vec<RoomId> {
+        let mut rooms: Vec<RoomId> = Vec::new();
+        for route in self.routes.iter().chain(self.subscription_routes.iter()) {
+            if route.matches(event_type, change) && !rooms.contains(route.room_id()) {
+                rooms.push(route.room_id().clone());
+            }
+        }
+        rooms
+    }
+
+    fn get_comment_added_card(
+        &self,
+        event: &gerrit::CommentAddedEvent,
+    ) -> Option<serde_json::Value> {
+        self.formatter
+            .format_comment_added_card(event, is_human(&event.author))
+            .unwrap_or_else(|e| {
+                error!("card formatting failed: {}", e);
+                None
+            })
+    }
+
+    fn get_reviewer_added_card(
+        &self,
+        event: &gerrit::ReviewerAddedEvent,
+    ) -> Option<serde_json::Value> {
+        self.formatter
+            .format_reviewer_added_card(event)
+            .unwrap_or_else(|e| {
+                error!("card formatting failed: {}", e);
+                None
+            })
+    }
+
+    fn get_room_approvals_msgs(&self, event: &gerrit::CommentAddedEvent) -> Vec<Response> {
+        let rooms = self.routed_rooms("comment-added", &event.change);
+        if rooms.is_empty() {
+            return Vec::new();
+        }
+
+        match self
+            .formatter
+            .format_room_comment_added(event, is_human(&event.author))
+        {
+            Ok(Some(message)) => rooms
+                .into_iter()
+                .map(|room_id| Response::new(room_id, message.clone()))
+                .collect(),
+            Ok(None) => Vec::new(),
+            Err(e) => {
+                error!("room message formatting failed: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    fn get_room_reviewer_added_msgs(&self, event: &gerrit::ReviewerAddedEvent) -> Vec<Response> {
+        let rooms = self.routed_rooms("reviewer-added", &event.change);
+        if rooms.is_empty() {
+            return Vec::new();
+        }
+
+        match self.formatter.format_room_reviewer_added(event) {
+            Ok(Some(message)) => rooms
+                .into_iter()
+                .map(|room_id| Response::new(room_id, message.clone()))
+                .collect(),
+            Ok(None) => Vec::new(),
+            Err(e) => {
+                error!("room message formatting failed: {}", e);
+                Vec::new()
+            }
+        }
     }
 
     fn get_approvals_msg(
         &mut self,
-        event: Box<gerrit::CommentAddedEvent>,
-    ) -> Option<(&User, String, bool)> {
+        event: &gerrit::CommentAddedEvent,
+    ) -> Result<Option<(User, String, bool)>, BotError> {
         debug!("Incoming approvals: {:#?}", event);
 
         let approvals = &event.approvals;
         let change = &event.change;
-        let approver = event.author.username.as_ref()?;
-        if Some(approver) == change.owner.username.as_ref() {
+        match event.author.username.as_ref() {
             // No need to notify about user's own approvals.
-            return None;
-        }
-        let owner_email = spark::Email::new(change.owner.email.clone());
-
-        // try to find the use and check it is enabled
-        let user_pos = *self.state.email_index.get(&owner_email)?;
-        if !self.state.users[user_pos].enabled {
-            return None;
+            Some(approver) if Some(approver) != change.owner.username.as_ref() => (),
+            _ => return Ok(None),
         }
-
-        let is_human = !approver.to_lowercase().contains("bot");
+        let user = match self.notified_user(&change.owner.email, change)? {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+        let is_human = is_human(&event.author);
 
         // filter all messages that were already sent to the user recently
-        if !approvals.is_empty() && self.rate_limiter.limit(user_pos, &*event) {
+        if !approvals.is_empty() && self.rate_limiter.limit(&user.user_id, event) {
             debug!("Filtered approval due to cache hit.");
-            return None;
+            return Ok(None);
         }
-        let user = &self.state.users[user_pos];
 
-        self.formatter
-            .format_comment_added(&event, is_human)
+        Ok(self
+            .formatter
+            .format_comment_added(event, is_human)
             .unwrap_or_else(|e| {
                 error!("message formatting failed: {}", e);
                 None
             })
             .filter(|msg| {
                 // if user has configured and enabled a filter try to apply it
-                !self.state.is_filtered(user_pos, &msg)
+                !user.is_filtered(msg)
             })
-            .map(|m| (user, m, is_human))
+            .map(|m| (user, m, is_human)))
     }
 
     fn get_reviewer_added_msg(
         &mut self,
         event: &gerrit::ReviewerAddedEvent,
-    ) -> Option<(&User, String)> {
-        let reviewer = event.reviewer.clone();
-        let reviewer_email = spark::Email::new(reviewer.email.clone());
-        let user_pos = *self.state.email_index.get(&reviewer_email)?;
-        if !self.state.users[user_pos].enabled {
-            return None;
-        }
+    ) -> Result<Option<(User, String)>, BotError> {
+        let user = match self.notified_user(&event.reviewer.email, &event.change)? {
+            Some(user) => user,
+            None => return Ok(None),
+        };
 
         // filter all messages that were already sent to the user recently
-        if self.rate_limiter.limit(user_pos, event) {
+        if self.rate_limiter.limit(&user.user_id, event) {
             debug!("Filtered reviewer-added due to cache hit.");
-            return None;
+            return Ok(None);
         }
 
-        let message = self.formatter.format_reviewer_added(event).ok()?;
+        Ok(self
+            .formatter
+            .format_reviewer_added(event)
+            .ok()
+            .map(|message| (user, message)))
+    }
 
-        Some((&self.state.users[user_pos], message))
+    /// The user with the given Gerrit email, if they enabled notifications
+    /// and did not mute the change.
+    fn notified_user(
+        &self,
+        email: &str,
+        change: &gerrit::Change,
+    ) -> Result<Option<User>, BotError> {
+        Ok(self
+            .state
+            .find_user_by_email(EmailRef::new(email))?
+            .filter(|user| user.enabled && !user.is_muted(change.number)))
     }
 
-    pub fn save<P>(&self, filename: P) -> Result<(), BotError>
-    where
-        P: AsRef<Path>,
-    {
-        let f = File::create(filename)?;
-        serde_json::to_writer(f, &self.state)?;
-        Ok(())
+    /// Deliver a notification about the change by email and to the outgoing
+    /// webhook, if the user gets notifications that way.
+    fn deliver<E: Serialize>(
+        &self,
+        user: &User,
+        change: &gerrit::Change,
+        event_type: &str,
+        event: &E,
+        message: &str,
+    ) {
+        let to = user.email.as_str();
+        if let Some(mailer) = self.mailer.as_ref().filter(|m| m.is_recipient(to)) {
+            mailer.send(Mail {
+                to: to.to_string(),
+                change_number: change.number,
+                change_subject: change.subject.clone(),
+                markdown: message.to_string(),
+            });
+        }
+        if let Some(webhook) = self
+            .outgoing_webhook
+            .as_ref()
+            .filter(|w| w.is_recipient(to))
+        {
+            let payload = WebhookPayload {
+                recipient: to,
+                change_number: change.number,
+                event_type,
+                markdown: message,
+                event: serde_json::to_value(event).unwrap_or_default(),
+            };
+            match self.formatter.format_webhook_payload(&payload) {
+                Ok(Some(payload)) => webhook.send(payload),
+                Ok(None) => (),
+                Err(e) => error!("webhook payload formatting failed: {}", e),
+            }
+        }
+    }
+
+    /// Save the state to the store it was loaded from.
+    pub fn save(&mut self) -> Result<(), BotError> {
+        self.state.flush()
     }
 
-    pub fn status_for(&self, person_id: &spark::PersonIdRef) -> String {
-        let user = self.state.find_user(person_id);
+    pub fn status_for(&self, person_id: &ChatUserIdRef) -> Result<String, BotError> {
+        let user = self.state.find_user(person_id)?;
         let enabled = user.map_or(false, |u| u.enabled);
-        let enabled_user_count =
-            self.state.users.iter().filter(|u| u.enabled).count() - if enabled { 1 } else { 0 };
-        format!(
+        let enabled_user_count = self.state.num_enabled_users()? - if enabled { 1 } else { 0 };
+        Ok(format!(
             "Notifications for you are **{}**. I am notifying {}.",
             if enabled { "enabled" } else { "disabled" },
             match (enabled, enabled_user_count) {
@@ -683,77 +1264,187 @@ where
                 (false, _) => format!("{} users", enabled_user_count),
                 (true, _) => format!("another {} users", enabled_user_count),
             }
-        )
+        ))
+    }
+}
+
+/// Sender of a command.
+#[derive(Debug, Clone)]
+pub struct Sender {
+    pub person_id: ChatUserId,
+    pub email: Email,
+    /// where to send the reply to, i.e. the person or the group room
+    pub reply_to: MessageTarget,
+}
+
+impl Sender {
+    /// The group room the command was sent in, if any.
+    pub fn room_id(&self) -> Option<&RoomId> {
+        match &self.reply_to {
+            MessageTarget::RoomId(room_id) => Some(room_id),
+            _ => None,
+        }
     }
 }
 
 #[derive(Debug)]
 pub enum Action {
-    Enable(spark::PersonId, spark::Email),
-    Disable(spark::PersonId, spark::Email),
+    Enable(Sender),
+    Disable(Sender),
     UpdateApprovals(Box<gerrit::CommentAddedEvent>),
-    Help(spark::PersonId),
-    Unknown(spark::PersonId),
-    Status(spark::PersonId),
-    Version(spark::PersonId),
-    FilterStatus(spark::PersonId),
-    FilterAdd(spark::PersonId, String /* filter */),
-    FilterEnable(spark::PersonId),
-    FilterDisable(spark::PersonId),
+    Help(Sender),
+    Unknown(Sender),
+    Status(Sender),
+    Version(Sender),
+    FilterStatus(Sender),
+    FilterAdd(Sender, String /* filter */),
+    FilterEnable(Sender),
+    FilterDisable(Sender),
+    Subscribe(Sender, Subscription),
+    /// remove the given or all subscriptions of the room
+    Unsubscribe(Sender, Option<Subscription>),
+    Subscriptions(Sender),
+    /// a command which is restricted to moderators was sent by somebody else
+    NotModerator(Sender),
+    /// stop notifying the sender about the change with the given number
+    Mute(Sender, u32),
+    Unmute(Sender, u32),
     ReviewerAdded(Box<gerrit::ReviewerAddedEvent>),
+    /// the bot was added to the room
+    Joined(RoomId, RoomType),
+    /// the bot was removed from the room
+    Left(RoomId),
+}
+
+impl Action {
+    /// Sender of the command, if the action is a command.
+    fn sender(&self) -> Option<&Sender> {
+        match self {
+            Action::Enable(sender)
+            | Action::Disable(sender)
+            | Action::Help(sender)
+            | Action::Unknown(sender)
+            | Action::Status(sender)
+            | Action::Version(sender)
+            | Action::FilterStatus(sender)
+            | Action::FilterAdd(sender, _)
+            | Action::FilterEnable(sender)
+            | Action::FilterDisable(sender)
+            | Action::Subscribe(sender, _)
+            | Action::Unsubscribe(sender, _)
+            | Action::Subscriptions(sender)
+            | Action::NotModerator(sender)
+            | Action::Mute(sender, _)
+            | Action::Unmute(sender, _) => Some(sender),
+            Action::UpdateApprovals(_)
+            | Action::ReviewerAdded(_)
+            | Action::Joined(..)
+            | Action::Left(_) => None,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Response {
-    pub person_id: spark::PersonId,
+    pub target: MessageTarget,
     pub message: String,
+    /// key of the thread the message belongs to, e.g. the number of the change
+    /// the message is about
+    pub thread: Option<String>,
+    /// Adaptive Card sent with the message
+    pub card: Option<serde_json::Value>,
 }
 
 impl Response {
-    pub fn new<A>(person_id: spark::PersonId, message: A) -> Response
+    pub fn new<T, A>(target: T, message: A) -> Response
     where
+        T: Into<MessageTarget>,
         A: Into<String>,
     {
         Response {
-            person_id,
+            target: target.into(),
             message: message.into(),
+            thread: None,
+            card: None,
+        }
+    }
+
+    /// Post the message in the thread with the given key.
+    pub fn in_thread<A: Into<String>>(self, thread: A) -> Response {
+        Response {
+            thread: Some(thread.into()),
+            ..self
         }
     }
+
+    /// Send the message with the given Adaptive Card, if any.
+    pub fn with_card(self, card: Option<serde_json::Value>) -> Response {
+        Response { card, ..self }
+    }
 }
 
 #[derive(Debug)]
 pub enum Task {
     Reply(Response),
     ReplyAndSave(Response),
+    /// Notifications about a Gerrit event for users and routed rooms
+    Notify(Vec<Response>),
+    /// Save the state without replying
+    Save,
 }
 
-const GREETINGS_MSG: &str =
-r#"Hi. I am GerritBot. I can watch Gerrit reviews for you, and notify you about new +1/-1's.
+const GREETINGS_MSG: &str = r#"Hi. I am GerritBot. I can watch Gerrit reviews for you, and notify you about new +1/-1's.
 
-To enable notifications, just type in **enable**. A small note: your email in Spark and in Gerrit has to be the same. Otherwise, I can't match your accounts.
+To enable notifications, just type in **enable**. A small note: your email in the chat and in Gerrit has to be the same. Otherwise, I can't match your accounts.
 
 For more information, type in **help**.
 "#;
 
+const SPACE_GREETINGS_MSG: &str = r#"Hi. I am GerritBot. I can post Gerrit review events to this space.
+
+Mention me to send a command, e.g. **@GerritBot subscribe project <name>** to post all review events of a project here.
+
+For more information, mention me with **help**.
+"#;
+
 const HELP_MSG: &str = r#"Commands:
 
 `enable` -- I will start notifying you.
 
 `disable` -- I will stop notifying you.
 
-`filter <regex>` -- Filter all messages by applying the specified regex pattern. If the pattern matches, the message is filtered. The pattern is applied to the full text I send to you. Be aware, to send this command **not** in markdown mode, otherwise, Spark would eat some special characters in the pattern. For regex specification, cf. https://docs.rs/regex/0.2.10/regex/#syntax.
+`filter <regex>` -- Filter all messages by applying the specified regex pattern. If the pattern matches, the message is filtered. The pattern is applied to the full text I send to you. Be aware, to send this command **not** in markdown mode, otherwise, the chat client might eat some special characters in the pattern. For regex specification, cf. https://docs.rs/regex/0.2.10/regex/#syntax.
 
 `filter enable` -- Enable the filtering of messages with the configured filter.
 
 `filter disable` -- Disable the filtering of messages with the configured filter.
 
+`mute <change number>` -- I will not notify you about the change anymore.
+
+`unmute <change number>` -- I will notify you about the change again.
+
 `status` -- Show if I am notifying you, and a little bit more information. 😉
 
 `help` -- This message
 
+In group spaces, mention me to send a command, e.g. `@GerritBot status`. I will answer in the space. The following commands are only available in group spaces:
+
+`subscribe project <name> [branch <glob>]` -- I will post all review events of the project to the space. The project name and the branch may contain the wildcards `*` and `?`.
+
+`unsubscribe project <name> [branch <glob>]` -- I will stop posting review events of the project to the space.
+
+`unsubscribe` -- Remove all subscriptions of the space.
+
+`subscriptions` -- Show the subscriptions of the space.
+
 This project is open source, feel free to help us at: https://github.com/boxdot/gerritbot-rs
 "#;
 
+const STATE_ERROR_MSG: &str =
+    "Sorry, I could not access my settings right now. Please try again later.";
+
+const SUBSCRIPTIONS_IN_SPACES_ONLY_MSG: &str = "Subscriptions are only available in group spaces. Add me to a space and mention me there to subscribe it to a project.";
+
 const VERSION_MSG: &str = concat!(
     env!("CARGO_PKG_NAME"),
     " ",
@@ -781,45 +1472,83 @@ mod test {
     use spectral::prelude::*;
     use speculate::speculate;
 
-    use spark::{EmailRef, PersonId, PersonIdRef};
-
     use super::*;
+    use crate::chat::MessageId;
 
     struct TestGerritCommandRunner;
     impl GerritCommandRunner for TestGerritCommandRunner {}
 
     #[derive(Clone)]
-    struct TestSparkClient;
+    struct TestChatBackend;
+
+    type TestBot = Bot<TestGerritCommandRunner, TestChatBackend>;
+
+    impl ChatBackend for TestChatBackend {
+        type Event = ChatEvent;
+        type ReplyFuture = future::FutureResult<SentMessage, ChatError>;
+        fn id(&self) -> &ChatUserIdRef {
+            ChatUserIdRef::new("bot_person_id")
+        }
+        fn to_chat_event(&self, event: ChatEvent) -> ChatEvent {
+            event
+        }
+        fn send_message(
+            &self,
+            _target: &MessageTarget,
+            _msg: &str,
+            _parent_id: Option<&MessageIdRef>,
+        ) -> Self::ReplyFuture {
+            future::ok(SentMessage {
+                id: MessageId::new("message_id".to_string()),
+                room_id: RoomId::new("room_id".to_string()),
+            })
+        }
+        fn edit_message(
+            &self,
+            message_id: &MessageIdRef,
+            room_id: &RoomIdRef,
+            _msg: &str,
+        ) -> Self::ReplyFuture {
+            future::ok(SentMessage {
+                id: message_id.to_owned(),
+                room_id: room_id.to_owned(),
+            })
+        }
+    }
 
-    type TestBot = Bot<TestGerritCommandRunner, TestSparkClient>;
+    impl State {
+        fn users(&self) -> Vec<User> {
+            self.store.users().unwrap()
+        }
 
-    impl SparkClient for TestSparkClient {
-        type ReplyFuture = future::FutureResult<(), spark::Error>;
-        fn send_message(&self, _person_id: &PersonId, _msg: &str) -> Self::ReplyFuture {
-            future::ok(())
+        fn add_user(&mut self, person_id: &ChatUserIdRef, email: &EmailRef) -> User {
+            let user = User::new(person_id.to_owned(), email.to_owned());
+            self.save_user(&user).unwrap();
+            user
         }
     }
 
     impl TestBot {
         fn add_user(&mut self, person_id: &str, email: &str) {
             self.state
-                .add_user(PersonIdRef::new(person_id), EmailRef::new(email));
+                .add_user(ChatUserIdRef::new(person_id), EmailRef::new(email));
         }
 
         fn enable(&mut self, person_id: &str, email: &str, enabled: bool) {
             self.state
-                .enable(PersonIdRef::new(person_id), EmailRef::new(email), enabled);
+                .enable(ChatUserIdRef::new(person_id), EmailRef::new(email), enabled)
+                .unwrap();
         }
     }
 
     fn new_bot() -> TestBot {
-        Builder::new(State::new()).build(TestGerritCommandRunner, TestSparkClient)
+        Builder::new(State::new()).build(TestGerritCommandRunner, TestChatBackend)
     }
 
     fn new_bot_with_msg_cache(capacity: usize, expiration: Duration) -> TestBot {
         Builder::new(State::new())
             .with_msg_cache(capacity, expiration)
-            .build(TestGerritCommandRunner, TestSparkClient)
+            .build(TestGerritCommandRunner, TestChatBackend)
     }
 
     trait UserAssertions {
@@ -829,10 +1558,10 @@ mod test {
         fn is_not_enabled(&mut self);
     }
 
-    impl<'s> UserAssertions for spectral::Spec<'s, &User> {
+    impl<'s> UserAssertions for spectral::Spec<'s, User> {
         fn has_person_id(&mut self, expected: &str) {
-            let actual = &self.subject.spark_person_id;
-            let expected = PersonIdRef::new(expected);
+            let actual = &self.subject.user_id;
+            let expected = ChatUserIdRef::new(expected);
             if actual != expected {
                 spectral::AssertionFailure::from_spec(self)
                     .with_expected(format!("user with name <{}>", expected))
@@ -911,74 +1640,74 @@ mod test {
             }
 
             before {
-                assert_that!(bot.state.users).has_length(1);
+                assert_that!(bot.state.users()).has_length(1);
             }
 
             it "has the expected attributes" {
-                let user = &bot.state.users[0];
+                let user = bot.state.users().remove(0);
                 assert_that!(user).has_person_id("some_person_id");
                 assert_that!(user).has_email("some@example.com");
                 assert_that!(user).is_enabled();
             }
 
             test "enabled status response" {
-                let resp = bot.status_for(PersonIdRef::new("some_person_id"));
+                let resp = bot.status_for(ChatUserIdRef::new("some_person_id")).unwrap();
                 assert_that!(resp).contains("enabled");
             }
 
             test "disabled status response" {
-                bot.state.users[0].enabled = false;
-                let resp = bot.status_for(PersonIdRef::new("some_person_id"));
+                bot.enable("some_person_id", "some@example.com", false);
+                let resp = bot.status_for(ChatUserIdRef::new("some_person_id")).unwrap();
                 assert_that!(resp).contains("disabled");
             }
 
             test "existing user can be enabled" {
                 bot.enable("some_person_id", "some@example.com", true);
-                assert_that!(bot.state.users)
+                assert_that!(bot.state.users())
                     .has_item_matching(
-                        |u| u.spark_person_id == PersonIdRef::new("some_person_id")
+                        |u| u.user_id == ChatUserIdRef::new("some_person_id")
                             && u.email == EmailRef::new("some@example.com")
                             && u.enabled);
-                assert_that!(bot.state.users).has_length(1);
+                assert_that!(bot.state.users()).has_length(1);
             }
 
             test "existing can be disabled" {
                 bot.enable("some_person_id", "some@example.com", false);
-                assert_that!(bot.state.users)
+                assert_that!(bot.state.users())
                     .has_item_matching(
-                        |u| u.spark_person_id == PersonIdRef::new("some_person_id")
+                        |u| u.user_id == ChatUserIdRef::new("some_person_id")
                             && u.email == EmailRef::new("some@example.com")
                             && !u.enabled);
-                assert_that!(bot.state.users).has_length(1);
+                assert_that!(bot.state.users()).has_length(1);
             }
         }
 
         test "non-existing user is automatically added when enabled" {
-            assert_that!(bot.state.users).has_length(0);
+            assert_that!(bot.state.users()).has_length(0);
             let mut bot = bot;
             bot.enable("some_person_id", "some@example.com", true);
-            assert_that!(bot.state.users)
+            assert_that!(bot.state.users())
                 .has_item_matching(
-                    |u| u.spark_person_id == PersonIdRef::new("some_person_id")
+                    |u| u.user_id == ChatUserIdRef::new("some_person_id")
                         && u.email == EmailRef::new("some@example.com")
                         && u.enabled);
-            assert_that!(bot.state.users).has_length(1);
+            assert_that!(bot.state.users()).has_length(1);
         }
 
         test "non-existing user is automatically added when disabled" {
-            assert_that!(bot.state.users).has_length(0);
+            assert_that!(bot.state.users()).has_length(0);
             let mut bot = bot;
             bot.enable("some_person_id", "some@example.com", false);
-            assert_that!(bot.state.users)
+            assert_that!(bot.state.users())
                 .has_item_matching(
-                    |u| u.spark_person_id == PersonIdRef::new("some_person_id")
+                    |u| u.user_id == ChatUserIdRef::new("some_person_id")
                         && u.email == EmailRef::new("some@example.com")
                         && !u.enabled);
-            assert_that!(bot.state.users).has_length(1);
+            assert_that!(bot.state.users()).has_length(1);
         }
 
         test "unknown user gets disabled status response" {
-            let resp = bot.status_for(PersonIdRef::new("some_non_existent_id"));
+            let resp = bot.status_for(ChatUserIdRef::new("some_non_existent_id")).unwrap();
             assert!(resp.contains("disabled"));
         }
     }
@@ -994,77 +1723,11 @@ mod test {
         }
     }
 
-    #[test]
-    fn test_add_user() {
-        let mut state = State::new();
-        state.add_user(
-            PersonIdRef::new("some_person_id"),
-            EmailRef::new("some@example.com"),
-        );
-        assert_eq!(state.users.len(), 1);
-        assert_eq!(state.person_id_index.len(), 1);
-        assert_eq!(state.email_index.len(), 1);
-        assert_eq!(
-            state.users[0].spark_person_id,
-            PersonIdRef::new("some_person_id")
-        );
-        assert_eq!(state.users[0].email, EmailRef::new("some@example.com"));
-        assert_eq!(
-            state
-                .person_id_index
-                .get(PersonIdRef::new("some_person_id")),
-            Some(&0)
-        );
-        assert_eq!(
-            state.email_index.get(EmailRef::new("some@example.com")),
-            Some(&0)
-        );
-
-        state.add_user(
-            PersonIdRef::new("some_person_id_2"),
-            EmailRef::new("some_2@example.com"),
-        );
-        assert_eq!(state.users.len(), 2);
-        assert_eq!(state.person_id_index.len(), 2);
-        assert_eq!(state.email_index.len(), 2);
-        assert_eq!(
-            state.users[1].spark_person_id,
-            PersonIdRef::new("some_person_id_2")
-        );
-        assert_eq!(state.users[1].email, EmailRef::new("some_2@example.com"));
-        assert_eq!(
-            state
-                .person_id_index
-                .get(PersonIdRef::new("some_person_id_2")),
-            Some(&1)
-        );
-        assert_eq!(
-            state.email_index.get(EmailRef::new("some_2@example.com")),
-            Some(&1)
-        );
-
-        let user = state.find_user(PersonIdRef::new("some_person_id"));
-        assert!(user.is_some());
-        assert_eq!(
-            user.unwrap().spark_person_id,
-            PersonIdRef::new("some_person_id")
-        );
-        assert_eq!(user.unwrap().email, EmailRef::new("some@example.com"));
-
-        let user = state.find_user(PersonIdRef::new("some_person_id_2"));
-        assert!(user.is_some());
-        assert_eq!(
-            user.unwrap().spark_person_id,
-            PersonIdRef::new("some_person_id_2")
-        );
-        assert_eq!(user.unwrap().email, EmailRef::new("some_2@example.com"));
-    }
-
     #[test]
     fn get_approvals_msg_for_empty_bot() {
         // bot does not have the user => no message
         let mut bot = new_bot();
-        let res = bot.get_approvals_msg(Box::new(get_event()));
+        let res = bot.get_approvals_msg(&get_event()).unwrap();
         assert!(res.is_none());
     }
 
@@ -1073,10 +1736,10 @@ mod test {
         // the approval is from the author => no message
         let mut bot = new_bot();
         bot.state.add_user(
-            PersonIdRef::new("approver_spark_id"),
+            ChatUserIdRef::new("approver_spark_id"),
             EmailRef::new("approver@example.com"),
         );
-        let res = bot.get_approvals_msg(Box::new(get_event()));
+        let res = bot.get_approvals_msg(&get_event()).unwrap();
         assert!(res.is_none());
     }
 
@@ -1086,11 +1749,17 @@ mod test {
         // => no message
         let mut bot = new_bot();
         bot.state.add_user(
-            PersonIdRef::new("author_spark_id"),
+            ChatUserIdRef::new("author_spark_id"),
             EmailRef::new("author@example.com"),
         );
-        bot.state.users[0].enabled = false;
-        let res = bot.get_approvals_msg(Box::new(get_event()));
+        bot.state
+            .enable(
+                ChatUserIdRef::new("author_spark_id"),
+                EmailRef::new("author@example.com"),
+                false,
+            )
+            .unwrap();
+        let res = bot.get_approvals_msg(&get_event()).unwrap();
         assert!(res.is_none());
     }
 
@@ -1100,45 +1769,320 @@ mod test {
         // => message
         let mut bot = new_bot();
         bot.state.add_user(
-            PersonIdRef::new("author_spark_id"),
+            ChatUserIdRef::new("author_spark_id"),
             EmailRef::new("author@example.com"),
         );
-        let res = bot.get_approvals_msg(Box::new(get_event()));
+        let res = bot.get_approvals_msg(&get_event()).unwrap();
         assert!(res.is_some());
         let (user, msg, is_human) = res.unwrap();
-        assert_eq!(user.spark_person_id, PersonIdRef::new("author_spark_id"));
+        assert_eq!(user.user_id, ChatUserIdRef::new("author_spark_id"));
         assert_eq!(user.email, EmailRef::new("author@example.com"));
         assert!(msg.contains("Some review."));
         assert!(is_human);
     }
 
+    #[test]
+    fn get_approvals_msg_for_muted_change() {
+        // the approval is about a change muted by the user => no message
+        let mut bot = new_bot();
+        bot.state.add_user(
+            ChatUserIdRef::new("author_spark_id"),
+            EmailRef::new("author@example.com"),
+        );
+        let res = bot
+            .state
+            .mute_change(ChatUserIdRef::new("author_spark_id"), 49, true)
+            .unwrap();
+        assert_eq!(res, Ok(true));
+        let res = bot.get_approvals_msg(&get_event()).unwrap();
+        assert!(res.is_none());
+
+        let res = bot
+            .state
+            .mute_change(ChatUserIdRef::new("author_spark_id"), 49, false)
+            .unwrap();
+        assert_eq!(res, Ok(true));
+        let res = bot.get_approvals_msg(&get_event()).unwrap();
+        assert!(res.is_some());
+    }
+
+    #[test]
+    fn card_action_runs_command() {
+        let card_action = CardAction {
+            user_id: ChatUserId::new("some_person_id".to_string()),
+            user_email: Email::new("some@example.com".to_string()),
+            room_id: RoomId::new("room_id".to_string()),
+            room_type: RoomType::Direct,
+            inputs: serde_json::from_str(r#"{"command": "mute 49"}"#).unwrap(),
+        };
+        match card_action_to_action(card_action.clone()) {
+            Some(Action::Mute(sender, 49)) => {
+                assert_eq!(sender.email, EmailRef::new("some@example.com"));
+                assert_eq!(
+                    sender.reply_to,
+                    MessageTarget::UserId(ChatUserId::new("some_person_id".to_string()))
+                );
+            }
+            action => panic!("unexpected action: {:?}", action),
+        }
+
+        let mut card_action = card_action;
+        card_action.inputs.clear();
+        assert!(card_action_to_action(card_action).is_none());
+    }
+
+    #[test]
+    fn update_approvals_posts_to_routed_rooms() {
+        // the event matches only one of the routes and no user is enabled
+        // => message to the matching room only
+        let mut bot = Builder::new(State::new())
+            .with_routes(vec![
+                Route::new(RoomId::new("demo_room".to_string())).with_project("demo-*"),
+                Route::new(RoomId::new("other_room".to_string())).with_project("other"),
+            ])
+            .build(TestGerritCommandRunner, TestChatBackend);
+        let task = bot.update(Action::UpdateApprovals(Box::new(get_event())));
+        let responses = bot.handle_task(task.expect("no task"));
+        assert_eq!(responses.len(), 1);
+        assert_eq!(
+            responses[0].target,
+            MessageTarget::RoomId(RoomId::new("demo_room".to_string()))
+        );
+        assert!(responses[0].message.contains("Some review."));
+        assert_eq!(responses[0].thread, Some("49".to_string()));
+    }
+
+    #[test]
+    fn update_approvals_sends_mail_to_recipients() {
+        // the owner gets notifications by email => mail to the owner
+        let (address, messages) = email::test::start_smtp_sink();
+        let mut bot = Builder::new(State::new())
+            .with_mailer(email::test::sink_mailer(address))
+            .build(TestGerritCommandRunner, TestChatBackend);
+        bot.add_user("author_spark_id", "author@example.com");
+        bot.update(Action::UpdateApprovals(Box::new(get_event())));
+        let message = messages
+            .recv_timeout(Duration::from_secs(10))
+            .expect("no mail");
+        assert!(message.contains("To: <author@example.com>"));
+        assert!(message.contains("Some review."));
+    }
+
+    #[test]
+    fn update_approvals_sends_no_mail_for_repeated_event() {
+        // same approval 2 times in less than 1 sec => only one mail
+        let (address, messages) = email::test::start_smtp_sink();
+        let mut bot = Builder::new(State::new())
+            .with_msg_cache(10, Duration::from_secs(1))
+            .with_mailer(email::test::sink_mailer(address))
+            .build(TestGerritCommandRunner, TestChatBackend);
+        bot.add_user("author_spark_id", "author@example.com");
+        bot.update(Action::UpdateApprovals(Box::new(get_event())));
+        bot.update(Action::UpdateApprovals(Box::new(get_event())));
+        messages
+            .recv_timeout(Duration::from_secs(10))
+            .expect("no mail");
+        assert!(messages.recv_timeout(Duration::from_millis(500)).is_err());
+    }
+
+    fn get_reviewer_added_event() -> gerrit::ReviewerAddedEvent {
+        gerrit::ReviewerAddedEvent {
+            change: get_event().change,
+            patchset: get_event().patchset,
+            reviewer: gerrit::User {
+                name: Some("Reviewer".to_string()),
+                username: Some("reviewer".to_string()),
+                email: "reviewer@example.com".to_string(),
+            },
+            created_on: 0,
+        }
+    }
+
+    #[test]
+    fn update_reviewer_added_posts_to_outgoing_webhook() {
+        let (url, bodies) = outgoing_webhook::test::start_http_sink();
+        let mut bot = Builder::new(State::new())
+            .with_outgoing_webhook(OutgoingWebhook::new(url))
+            .build(TestGerritCommandRunner, TestChatBackend);
+        bot.add_user("reviewer_spark_id", "reviewer@example.com");
+        let event = get_reviewer_added_event();
+        bot.update(Action::ReviewerAdded(Box::new(event)));
+        let payload = bodies
+            .recv_timeout(Duration::from_secs(10))
+            .expect("no payload");
+        assert_eq!(payload["recipient"], "reviewer@example.com");
+        assert_eq!(payload["change_number"], 49);
+        assert_eq!(payload["event_type"], "reviewer-added");
+        assert!(payload["markdown"]
+            .as_str()
+            .unwrap()
+            .contains("Added as reviewer"));
+        assert_eq!(payload["event"]["reviewer"]["username"], "reviewer");
+    }
+
+    #[test]
+    fn outgoing_webhook_skips_repeated_event() {
+        // same reviewer added 2 times in less than 1 sec => only one payload
+        let (url, bodies) = outgoing_webhook::test::start_http_sink();
+        let mut bot = Builder::new(State::new())
+            .with_msg_cache(10, Duration::from_secs(1))
+            .with_outgoing_webhook(OutgoingWebhook::new(url))
+            .build(TestGerritCommandRunner, TestChatBackend);
+        bot.add_user("reviewer_spark_id", "reviewer@example.com");
+        bot.update(Action::ReviewerAdded(Box::new(get_reviewer_added_event())));
+        bot.update(Action::ReviewerAdded(Box::new(get_reviewer_added_event())));
+        bodies
+            .recv_timeout(Duration::from_secs(10))
+            .expect("no payload");
+        assert!(bodies.recv_timeout(Duration::from_millis(500)).is_err());
+    }
+
+    #[test]
+    fn outgoing_webhook_skips_disabled_users_and_muted_changes() {
+        // the reviewer muted the change and the owner disabled notifications
+        // => no payloads until the owner enables notifications again
+        let (url, bodies) = outgoing_webhook::test::start_http_sink();
+        let mut bot = Builder::new(State::new())
+            .with_outgoing_webhook(OutgoingWebhook::new(url))
+            .build(TestGerritCommandRunner, TestChatBackend);
+        bot.add_user("reviewer_spark_id", "reviewer@example.com");
+        bot.state
+            .mute_change(ChatUserIdRef::new("reviewer_spark_id"), 49, true)
+            .unwrap()
+            .unwrap();
+        bot.enable("author_spark_id", "author@example.com", false);
+        bot.update(Action::ReviewerAdded(Box::new(get_reviewer_added_event())));
+        bot.update(Action::UpdateApprovals(Box::new(get_event())));
+
+        bot.enable("author_spark_id", "author@example.com", true);
+        bot.update(Action::UpdateApprovals(Box::new(get_event())));
+        let payload = bodies
+            .recv_timeout(Duration::from_secs(10))
+            .expect("no payload");
+        assert_eq!(payload["recipient"], "author@example.com");
+        assert_eq!(payload["event_type"], "comment-added");
+        assert!(bodies.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn update_approvals_posts_to_rooms_and_users() {
+        // the event is routed to a room and the owner has enabled notifications
+        // => message to the room and to the owner
+        let mut bot = Builder::new(State::new())
+            .with_routes(vec![Route::new(RoomId::new("demo_room".to_string()))])
+            .build(TestGerritCommandRunner, TestChatBackend);
+        bot.state.add_user(
+            ChatUserIdRef::new("author_spark_id"),
+            EmailRef::new("author@example.com"),
+        );
+        let task = bot.update(Action::UpdateApprovals(Box::new(get_event())));
+        let targets: Vec<_> = bot
+            .handle_task(task.expect("no task"))
+            .into_iter()
+            .map(|response| response.target)
+            .collect();
+        assert_eq!(
+            targets,
+            vec![
+                MessageTarget::RoomId(RoomId::new("demo_room".to_string())),
+                MessageTarget::UserId(ChatUserId::new("author_spark_id".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn update_approvals_posts_to_subscribed_rooms() {
+        // the room is subscribed via the configured route and a subscription
+        // => one message to the room
+        let room_id = RoomId::new("demo_room".to_string());
+        let mut state = State::new();
+        state
+            .subscribe(&room_id, Subscription::new("demo-project", "*"))
+            .unwrap();
+        state
+            .subscribe(
+                RoomIdRef::new("release_room"),
+                Subscription::new("demo-project", "release/*"),
+            )
+            .unwrap();
+        let mut bot = Builder::new(state)
+            .with_routes(vec![Route::new(room_id.clone())])
+            .build(TestGerritCommandRunner, TestChatBackend);
+        let task = bot.update(Action::UpdateApprovals(Box::new(get_event())));
+        let responses = bot.handle_task(task.expect("no task"));
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].target, MessageTarget::RoomId(room_id));
+    }
+
+    #[test]
+    fn update_approvals_follows_subscription_changes() {
+        let room_id = RoomId::new("demo_room".to_string());
+        let sender = Sender {
+            person_id: ChatUserId::new("some_person_id".to_string()),
+            email: Email::new("some@example.com".to_string()),
+            reply_to: MessageTarget::RoomId(room_id.clone()),
+        };
+        let mut bot = new_bot();
+        let subscription = Subscription::new("demo-project", "*");
+        bot.update(Action::Subscribe(sender, subscription));
+        let task = bot.update(Action::UpdateApprovals(Box::new(get_event())));
+        let responses = bot.handle_task(task.expect("no task"));
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].target, MessageTarget::RoomId(room_id.clone()));
+
+        bot.update(Action::Left(room_id));
+        let task = bot.update(Action::UpdateApprovals(Box::new(get_event())));
+        let responses = bot.handle_task(task.expect("no task"));
+        assert!(responses.is_empty());
+    }
+
+    #[test]
+    fn subscribe_and_unsubscribe() {
+        let mut state = State::new();
+        let room_id = RoomIdRef::new("room");
+        let subscription = Subscription::new("demo-project", "*");
+        assert!(state.subscribe(room_id, subscription.clone()).unwrap());
+        assert!(!state.subscribe(room_id, subscription.clone()).unwrap());
+        assert!(state
+            .subscribe(room_id, Subscription::new("other", "master"))
+            .unwrap());
+        assert_eq!(state.subscriptions(room_id).unwrap().len(), 2);
+        assert!(state.unsubscribe(room_id, &subscription).unwrap());
+        assert!(!state.unsubscribe(room_id, &subscription).unwrap());
+        assert_eq!(state.unsubscribe_all(room_id).unwrap(), 1);
+        assert!(state.subscriptions(room_id).unwrap().is_empty());
+        assert_eq!(state.unsubscribe_all(room_id).unwrap(), 0);
+    }
+
     #[test]
     fn get_approvals_msg_for_user_with_enabled_notifications_and_filter() {
         // the approval is for the user with enabled notifications
         // => message
         let mut bot = new_bot();
         bot.state.add_user(
-            PersonIdRef::new("author_spark_id"),
+            ChatUserIdRef::new("author_spark_id"),
             EmailRef::new("author@example.com"),
         );
 
         {
             let res = bot
                 .state
-                .add_filter(PersonIdRef::new("author_spark_id"), ".*Code-Review.*");
+                .add_filter(ChatUserIdRef::new("author_spark_id"), ".*Code-Review.*")
+                .unwrap();
             assert!(res.is_ok());
-            let res = bot.get_approvals_msg(Box::new(get_event()));
+            let res = bot.get_approvals_msg(&get_event()).unwrap();
             assert!(res.is_none());
         }
         {
             let res = bot
                 .state
-                .enable_filter(PersonIdRef::new("author_spark_id"), false);
+                .enable_filter(ChatUserIdRef::new("author_spark_id"), false)
+                .unwrap();
             assert!(res.is_ok());
-            let res = bot.get_approvals_msg(Box::new(get_event()));
+            let res = bot.get_approvals_msg(&get_event()).unwrap();
             assert!(res.is_some());
             let (user, msg, is_human) = res.unwrap();
-            assert_eq!(user.spark_person_id, PersonIdRef::new("author_spark_id"));
+            assert_eq!(user.user_id, ChatUserIdRef::new("author_spark_id"));
             assert_eq!(user.email, EmailRef::new("author@example.com"));
             assert!(msg.contains("Some review."));
             assert!(is_human);
@@ -1146,17 +2090,21 @@ mod test {
         {
             let res = bot
                 .state
-                .enable_filter(PersonIdRef::new("author_spark_id"), true);
+                .enable_filter(ChatUserIdRef::new("author_spark_id"), true)
+                .unwrap();
             assert!(res.is_ok());
-            let res = bot.state.add_filter(
-                PersonIdRef::new("author_spark_id"),
-                "some_non_matching_filter",
-            );
+            let res = bot
+                .state
+                .add_filter(
+                    ChatUserIdRef::new("author_spark_id"),
+                    "some_non_matching_filter",
+                )
+                .unwrap();
             assert!(res.is_ok());
-            let res = bot.get_approvals_msg(Box::new(get_event()));
+            let res = bot.get_approvals_msg(&get_event()).unwrap();
             assert!(res.is_some());
             let (user, msg, is_human) = res.unwrap();
-            assert_eq!(user.spark_person_id, PersonIdRef::new("author_spark_id"));
+            assert_eq!(user.user_id, ChatUserIdRef::new("author_spark_id"));
             assert_eq!(user.email, EmailRef::new("author@example.com"));
             assert!(msg.contains("Some review."));
             assert!(is_human);
@@ -1169,20 +2117,20 @@ mod test {
         // => first time get message, second time nothing
         let mut bot = new_bot_with_msg_cache(10, Duration::from_secs(1));
         bot.state.add_user(
-            PersonIdRef::new("author_spark_id"),
+            ChatUserIdRef::new("author_spark_id"),
             EmailRef::new("author@example.com"),
         );
         {
-            let res = bot.get_approvals_msg(Box::new(get_event()));
+            let res = bot.get_approvals_msg(&get_event()).unwrap();
             assert!(res.is_some());
             let (user, msg, is_human) = res.unwrap();
-            assert_eq!(user.spark_person_id, PersonIdRef::new("author_spark_id"));
+            assert_eq!(user.user_id, ChatUserIdRef::new("author_spark_id"));
             assert_eq!(user.email, EmailRef::new("author@example.com"));
             assert!(msg.contains("Some review."));
             assert!(is_human);
         }
         {
-            let res = bot.get_approvals_msg(Box::new(get_event()));
+            let res = bot.get_approvals_msg(&get_event()).unwrap();
             assert!(res.is_none());
         }
     }
@@ -1193,24 +2141,24 @@ mod test {
         // => get message 2 times
         let mut bot = new_bot_with_msg_cache(10, Duration::from_millis(50));
         bot.state.add_user(
-            PersonIdRef::new("author_spark_id"),
+            ChatUserIdRef::new("author_spark_id"),
             EmailRef::new("author@example.com"),
         );
         {
-            let res = bot.get_approvals_msg(Box::new(get_event()));
+            let res = bot.get_approvals_msg(&get_event()).unwrap();
             assert!(res.is_some());
             let (user, msg, is_human) = res.unwrap();
-            assert_eq!(user.spark_person_id, PersonIdRef::new("author_spark_id"));
+            assert_eq!(user.user_id, ChatUserIdRef::new("author_spark_id"));
             assert_eq!(user.email, EmailRef::new("author@example.com"));
             assert!(msg.contains("Some review."));
             assert!(is_human);
         }
         thread::sleep(Duration::from_millis(200));
         {
-            let res = bot.get_approvals_msg(Box::new(get_event()));
+            let res = bot.get_approvals_msg(&get_event()).unwrap();
             assert!(res.is_some());
             let (user, msg, is_human) = res.unwrap();
-            assert_eq!(user.spark_person_id, PersonIdRef::new("author_spark_id"));
+            assert_eq!(user.user_id, ChatUserIdRef::new("author_spark_id"));
             assert_eq!(user.email, EmailRef::new("author@example.com"));
             assert!(msg.contains("Some review."));
             assert!(is_human);
@@ -1224,25 +2172,25 @@ mod test {
         // => get message 3 times
         let mut bot = new_bot_with_msg_cache(1, Duration::from_secs(1));
         bot.state.add_user(
-            PersonIdRef::new("author_spark_id"),
+            ChatUserIdRef::new("author_spark_id"),
             EmailRef::new("author@example.com"),
         );
         {
             let mut event = get_event();
             event.change.subject = String::from("A");
-            let res = bot.get_approvals_msg(Box::new(event));
+            let res = bot.get_approvals_msg(&event).unwrap();
             assert!(res.is_some());
         }
         {
             let mut event = get_event();
             event.change.subject = String::from("B");
-            let res = bot.get_approvals_msg(Box::new(event));
+            let res = bot.get_approvals_msg(&event).unwrap();
             assert!(res.is_some());
         }
         {
             let mut event = get_event();
             event.change.subject = String::from("A");
-            let res = bot.get_approvals_msg(Box::new(event));
+            let res = bot.get_approvals_msg(&event).unwrap();
             assert!(res.is_some());
         }
     }
@@ -1251,29 +2199,32 @@ mod test {
     fn add_invalid_filter_for_existing_user() {
         let mut bot = new_bot();
         bot.state.add_user(
-            PersonIdRef::new("some_person_id"),
+            ChatUserIdRef::new("some_person_id"),
             EmailRef::new("some@example.com"),
         );
         let res = bot
             .state
-            .add_filter(PersonIdRef::new("some_person_id"), ".some_weard_regex/[");
+            .add_filter(ChatUserIdRef::new("some_person_id"), ".some_weard_regex/[")
+            .unwrap();
         assert_eq!(res, Err(AddFilterResult::InvalidFilter));
         assert!(bot
             .state
-            .users
+            .users()
             .iter()
-            .position(|u| u.spark_person_id == PersonIdRef::new("some_person_id")
+            .position(|u| u.user_id == ChatUserIdRef::new("some_person_id")
                 && u.email == EmailRef::new("some@example.com")
                 && u.filter == None)
             .is_some());
 
         let res = bot
             .state
-            .enable_filter(PersonIdRef::new("some_person_id"), true);
+            .enable_filter(ChatUserIdRef::new("some_person_id"), true)
+            .unwrap();
         assert_eq!(res, Err(AddFilterResult::FilterNotConfigured));
         let res = bot
             .state
-            .enable_filter(PersonIdRef::new("some_person_id"), false);
+            .enable_filter(ChatUserIdRef::new("some_person_id"), false)
+            .unwrap();
         assert_eq!(res, Err(AddFilterResult::FilterNotConfigured));
     }
 
@@ -1281,43 +2232,49 @@ mod test {
     fn add_valid_filter_for_existing_user() {
         let mut bot = new_bot();
         bot.state.add_user(
-            PersonIdRef::new("some_person_id"),
+            ChatUserIdRef::new("some_person_id"),
             EmailRef::new("some@example.com"),
         );
 
         let res = bot
             .state
-            .add_filter(PersonIdRef::new("some_person_id"), ".*some_word.*");
+            .add_filter(ChatUserIdRef::new("some_person_id"), ".*some_word.*")
+            .unwrap();
         assert!(res.is_ok());
         assert!(bot
             .state
-            .users
+            .users()
             .iter()
-            .position(|u| u.spark_person_id == PersonIdRef::new("some_person_id")
+            .position(|u| u.user_id == ChatUserIdRef::new("some_person_id")
                 && u.email == EmailRef::new("some@example.com")
                 && u.filter == Some(Filter::new(".*some_word.*")))
             .is_some());
 
         {
-            let filter = bot.state.get_filter(PersonIdRef::new("some_person_id"));
-            assert_eq!(filter, Ok(Some(&Filter::new(".*some_word.*"))));
+            let filter = bot
+                .state
+                .get_filter(ChatUserIdRef::new("some_person_id"))
+                .unwrap();
+            assert_eq!(filter, Ok(Some(Filter::new(".*some_word.*"))));
         }
         let res = bot
             .state
-            .enable_filter(PersonIdRef::new("some_person_id"), false);
+            .enable_filter(ChatUserIdRef::new("some_person_id"), false)
+            .unwrap();
         assert_eq!(res, Ok(String::from(".*some_word.*")));
         assert!(bot
             .state
-            .users
+            .users()
             .iter()
-            .position(|u| u.spark_person_id == PersonIdRef::new("some_person_id")
+            .position(|u| u.user_id == ChatUserIdRef::new("some_person_id")
                 && u.email == EmailRef::new("some@example.com")
                 && u.filter.as_ref().map(|f| f.enabled) == Some(false))
             .is_some());
         {
             let filter = bot
                 .state
-                .get_filter(PersonIdRef::new("some_person_id"))
+                .get_filter(ChatUserIdRef::new("some_person_id"))
+                .unwrap()
                 .unwrap()
                 .unwrap();
             assert_eq!(filter.regex, ".*some_word.*");
@@ -1325,19 +2282,23 @@ mod test {
         }
         let res = bot
             .state
-            .enable_filter(PersonIdRef::new("some_person_id"), true);
+            .enable_filter(ChatUserIdRef::new("some_person_id"), true)
+            .unwrap();
         assert_eq!(res, Ok(String::from(".*some_word.*")));
         assert!(bot
             .state
-            .users
+            .users()
             .iter()
-            .position(|u| u.spark_person_id == PersonIdRef::new("some_person_id")
+            .position(|u| u.user_id == ChatUserIdRef::new("some_person_id")
                 && u.email == EmailRef::new("some@example.com")
                 && u.filter.as_ref().map(|f| f.enabled) == Some(true))
             .is_some());
         {
-            let filter = bot.state.get_filter(PersonIdRef::new("some_person_id"));
-            assert_eq!(filter, Ok(Some(&Filter::new(".*some_word.*"))));
+            let filter = bot
+                .state
+                .get_filter(ChatUserIdRef::new("some_person_id"))
+                .unwrap();
+            assert_eq!(filter, Ok(Some(Filter::new(".*some_word.*"))));
         }
     }
 
@@ -1346,15 +2307,18 @@ mod test {
         let mut bot = new_bot();
         let res = bot
             .state
-            .add_filter(PersonIdRef::new("some_person_id"), ".*some_word.*");
+            .add_filter(ChatUserIdRef::new("some_person_id"), ".*some_word.*")
+            .unwrap();
         assert_eq!(res, Err(AddFilterResult::UserNotFound));
         let res = bot
             .state
-            .enable_filter(PersonIdRef::new("some_person_id"), true);
+            .enable_filter(ChatUserIdRef::new("some_person_id"), true)
+            .unwrap();
         assert_eq!(res, Err(AddFilterResult::UserNotFound));
         let res = bot
             .state
-            .enable_filter(PersonIdRef::new("some_person_id"), false);
+            .enable_filter(ChatUserIdRef::new("some_person_id"), false)
+            .unwrap();
         assert_eq!(res, Err(AddFilterResult::UserNotFound));
     }
 
@@ -1362,22 +2326,31 @@ mod test {
     fn add_valid_filter_for_disabled_user() {
         let mut bot = new_bot();
         bot.state.add_user(
-            PersonIdRef::new("some_person_id"),
+            ChatUserIdRef::new("some_person_id"),
             EmailRef::new("some@example.com"),
         );
-        bot.state.users[0].enabled = false;
+        bot.state
+            .enable(
+                ChatUserIdRef::new("some_person_id"),
+                EmailRef::new("some@example.com"),
+                false,
+            )
+            .unwrap();
 
         let res = bot
             .state
-            .add_filter(PersonIdRef::new("some_person_id"), ".*some_word.*");
+            .add_filter(ChatUserIdRef::new("some_person_id"), ".*some_word.*")
+            .unwrap();
         assert_eq!(res, Err(AddFilterResult::UserDisabled));
         let res = bot
             .state
-            .enable_filter(PersonIdRef::new("some_person_id"), true);
+            .enable_filter(ChatUserIdRef::new("some_person_id"), true)
+            .unwrap();
         assert_eq!(res, Err(AddFilterResult::UserDisabled));
         let res = bot
             .state
-            .enable_filter(PersonIdRef::new("some_person_id"), false);
+            .enable_filter(ChatUserIdRef::new("some_person_id"), false)
+            .unwrap();
         assert_eq!(res, Err(AddFilterResult::UserDisabled));
     }
 
@@ -1385,39 +2358,80 @@ mod test {
     fn enable_non_configured_filter_for_existing_user() {
         let mut bot = new_bot();
         bot.state.add_user(
-            PersonIdRef::new("some_person_id"),
+            ChatUserIdRef::new("some_person_id"),
             EmailRef::new("some@example.com"),
         );
 
         let res = bot
             .state
-            .enable_filter(PersonIdRef::new("some_person_id"), true);
+            .enable_filter(ChatUserIdRef::new("some_person_id"), true)
+            .unwrap();
         assert_eq!(res, Err(AddFilterResult::FilterNotConfigured));
         let res = bot
             .state
-            .enable_filter(PersonIdRef::new("some_person_id"), false);
+            .enable_filter(ChatUserIdRef::new("some_person_id"), false)
+            .unwrap();
         assert_eq!(res, Err(AddFilterResult::FilterNotConfigured));
     }
 
     #[test]
     fn enable_invalid_filter_for_existing_user() {
         let mut bot = new_bot();
-        bot.state.add_user(
-            PersonIdRef::new("some_person_id"),
+        let mut user = bot.state.add_user(
+            ChatUserIdRef::new("some_person_id"),
             EmailRef::new("some@example.com"),
         );
-        bot.state.users[0].filter = Some(Filter::new("invlide_filter_set_from_outside["));
+        user.filter = Some(Filter::new("invlide_filter_set_from_outside["));
+        bot.state.save_user(&user).unwrap();
 
         let res = bot
             .state
-            .enable_filter(PersonIdRef::new("some_person_id"), true);
+            .enable_filter(ChatUserIdRef::new("some_person_id"), true)
+            .unwrap();
         assert_eq!(res, Err(AddFilterResult::InvalidFilter));
         let res = bot
             .state
-            .enable_filter(PersonIdRef::new("some_person_id"), false);
+            .enable_filter(ChatUserIdRef::new("some_person_id"), false)
+            .unwrap();
         assert_eq!(res, Err(AddFilterResult::InvalidFilter));
     }
 
+    #[test]
+    fn enable_saves_state_to_the_file_of_the_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let filename = dir.path().join("bot-state.json");
+        let mut bot = Builder::new(State::with_store(JsonStateStore::new(&filename)))
+            .build(TestGerritCommandRunner, TestChatBackend);
+        let sender = Sender {
+            person_id: ChatUserId::new("some_person_id".to_string()),
+            email: Email::new("some@example.com".to_string()),
+            reply_to: MessageTarget::UserId(ChatUserId::new("some_person_id".to_string())),
+        };
+        let task = bot.update(Action::Enable(sender));
+        bot.handle_task(task.expect("no task"));
+
+        let state = State::load(&filename).unwrap();
+        assert_eq!(state.num_users().unwrap(), 1);
+    }
+
+    #[test]
+    fn enable_replies_with_error_if_state_cannot_be_saved() {
+        let dir = tempfile::tempdir().unwrap();
+        let filename = dir.path().join("missing").join("bot-state.json");
+        let mut bot = Builder::new(State::with_store(JsonStateStore::new(&filename)))
+            .build(TestGerritCommandRunner, TestChatBackend);
+        let sender = Sender {
+            person_id: ChatUserId::new("some_person_id".to_string()),
+            email: Email::new("some@example.com".to_string()),
+            reply_to: MessageTarget::UserId(ChatUserId::new("some_person_id".to_string())),
+        };
+        let task = bot.update(Action::Enable(sender));
+        let responses = bot.handle_task(task.expect("no task"));
+
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].message, STATE_ERROR_MSG);
+    }
+
     #[test]
     fn test_maybe_has_inline_comments() {
         let mut event = get_event();
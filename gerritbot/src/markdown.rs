@@ -0,0 +1,65 @@
+//! Conversion of the Markdown produced by the format script for services
+//! which do not render Markdown themselves.
+
+use pulldown_cmark::{html, Event, Parser, Tag};
+
+/// Render the Markdown as HTML.
+pub fn to_html(markdown: &str) -> String {
+    let mut html = String::new();
+    html::push_html(&mut html, Parser::new(markdown));
+    html
+}
+
+/// Strip the Markdown syntax, keeping only the text. Links are written as
+/// the link text followed by the url in parentheses.
+pub fn to_text(markdown: &str) -> String {
+    let mut text = String::new();
+    // start of the text of the current link
+    let mut link_start = 0;
+    for event in Parser::new(markdown) {
+        match event {
+            Event::Text(s) | Event::Code(s) | Event::Html(s) => text.push_str(&s),
+            Event::SoftBreak | Event::HardBreak => text.push('\n'),
+            Event::Start(Tag::Link(..)) => link_start = text.len(),
+            Event::End(Tag::Link(_, url, _)) if text[link_start..] != *url => {
+                text.push_str(&format!(" ({})", url));
+            }
+            Event::Start(Tag::Item) => text.push_str("- "),
+            Event::End(Tag::Paragraph)
+            | Event::End(Tag::Heading(_))
+            | Event::End(Tag::CodeBlock(_))
+            | Event::End(Tag::Item)
+                if !text.ends_with('\n') =>
+            {
+                text.push('\n');
+            }
+            _ => (),
+        }
+    }
+    text.trim_end().to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn render_html() {
+        assert_eq!(
+            to_html("[Fix the build](http://gerrit/1) from **Jane**"),
+            "<p><a href=\"http://gerrit/1\">Fix the build</a> from <strong>Jane</strong></p>\n"
+        );
+    }
+
+    #[test]
+    fn strip_markdown() {
+        assert_eq!(
+            to_text("[Fix the build](http://gerrit/1) (project) 👍 +1 (Code-Review) from **Jane**"),
+            "Fix the build (http://gerrit/1) (project) 👍 +1 (Code-Review) from Jane"
+        );
+        assert_eq!(
+            to_text("# Heading\n\n* `one`\n* <http://gerrit/2>\n\ntext\nmore"),
+            "Heading\n- one\n- http://gerrit/2\ntext\nmore"
+        );
+    }
+}
@@ -0,0 +1,355 @@
+//! Matrix as chat service of the bot.
+//!
+//! Matrix is mapped to the bot's chat model: user ids are used as chat user
+//! ids, rooms as rooms and event ids as message ids. Messages to a
+//! person are sent to the direct room with the person, which is created if
+//! there is none yet.
+//!
+//! Matrix accounts have no email the bot could rely on. The emails of users
+//! are either configured, or linked by the users themselves with
+//! `link <email>`, which is only accepted if the identity server confirms that
+//! the email is bound to the user's account.
+
+use std::collections::HashMap;
+use std::convert::identity;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use futures::{future, Future, Stream};
+use lazy_static::lazy_static;
+use log::{error, info};
+use regex::Regex;
+
+use gerritbot_matrix as matrix;
+
+use super::BotError;
+use crate::chat::{
+    ChatError, ChatEvent, ChatMessage, ChatUserId, ChatUserIdRef, Email, MessageId, MessageIdRef,
+    MessageTarget, RoomId, RoomIdRef, RoomType, SentMessage,
+};
+use crate::ChatBackend;
+
+const NOT_LINKED_MSG: &str = "Your Matrix account is not linked to an email yet. Please send me `link <email>` with the email you use in Gerrit. The email has to be bound to your Matrix account at the identity server.";
+
+impl ChatBackend for matrix::Client {
+    /// Events are converted by `matrix_event_stream`, which needs to look up
+    /// the emails of the users.
+    type Event = ChatEvent;
+    type ReplyFuture = Box<dyn Future<Item = SentMessage, Error = ChatError> + Send>;
+    fn id(&self) -> &ChatUserIdRef {
+        ChatUserIdRef::new(matrix::Client::id(self).as_str())
+    }
+    fn to_chat_event(&self, event: ChatEvent) -> ChatEvent {
+        event
+    }
+    fn send_message(
+        &self,
+        target: &MessageTarget,
+        msg: &str,
+        parent_id: Option<&MessageIdRef>,
+    ) -> Self::ReplyFuture {
+        let room_id = match target {
+            MessageTarget::RoomId(room_id) => {
+                future::Either::A(future::ok(matrix::RoomId::new(room_id.to_string())))
+            }
+            MessageTarget::UserId(user_id) => future::Either::B(future::Either::A(
+                self.direct_room(&matrix::UserId::new(user_id.to_string())),
+            )),
+            MessageTarget::UserEmail(email) => {
+                let client = self.clone();
+                let email = email.to_string();
+                future::Either::B(future::Either::B(
+                    self.lookup_email(&email)
+                        .and_then(move |user_id| match user_id {
+                            Some(user_id) => Ok(user_id),
+                            None => Err(matrix::Error::ApiError {
+                                errcode: "M_NOT_FOUND".to_string(),
+                                error: format!("no user with email {}", email),
+                            }),
+                        })
+                        .and_then(move |user_id| client.direct_room(&user_id)),
+                ))
+            }
+        };
+        let client = self.clone();
+        let body = msg.to_string();
+        let html = matrix::markdown_to_html(msg);
+        let reply_to = parent_id.map(|id| matrix::EventId::new(id.to_string()));
+        Box::new(
+            room_id
+                .and_then(move |room_id| {
+                    client
+                        .send_message(&room_id, &body, &html, reply_to.as_ref())
+                        .map(move |event_id| SentMessage {
+                            id: MessageId::new(event_id.into_string()),
+                            room_id: RoomId::new(room_id.into_string()),
+                        })
+                })
+                .map_err(ChatError::new),
+        )
+    }
+    fn edit_message(
+        &self,
+        message_id: &MessageIdRef,
+        room_id: &RoomIdRef,
+        msg: &str,
+    ) -> Self::ReplyFuture {
+        // the edit is a new event, but the message keeps its id
+        let sent = SentMessage {
+            id: message_id.to_owned(),
+            room_id: room_id.to_owned(),
+        };
+        Box::new(
+            self.edit_message(
+                &matrix::RoomId::new(room_id.to_string()),
+                &matrix::EventId::new(message_id.to_string()),
+                msg,
+                &matrix::markdown_to_html(msg),
+            )
+            .map(move |_| sent)
+            .map_err(ChatError::new),
+        )
+    }
+}
+
+/// Emails of Matrix users.
+///
+/// If created with a filename, the emails linked by users are written to disk
+/// on every change. Configured emails are not written.
+#[derive(Debug, Default)]
+pub struct EmailLinks {
+    configured: HashMap<matrix::UserId, String>,
+    linked: HashMap<matrix::UserId, String>,
+    filename: Option<PathBuf>,
+}
+
+impl EmailLinks {
+    /// Create links which are kept in memory only.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Load the links from the given file. A missing file results in no
+    /// links. All later changes are written back to the same file.
+    pub fn load<P>(filename: P) -> Result<Self, BotError>
+    where
+        P: AsRef<Path>,
+    {
+        let filename = filename.as_ref();
+        let linked = match File::open(filename) {
+            Ok(f) => serde_json::from_reader(f)?,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Self {
+            linked,
+            filename: Some(filename.to_path_buf()),
+            ..Default::default()
+        })
+    }
+
+    /// Use the given emails, which take precedence over the ones linked by
+    /// the users.
+    pub fn with_configured<I>(self, configured: I) -> Self
+    where
+        I: IntoIterator<Item = (matrix::UserId, String)>,
+    {
+        Self {
+            configured: configured.into_iter().collect(),
+            ..self
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.configured.len() + self.linked.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.configured.is_empty() && self.linked.is_empty()
+    }
+
+    fn save(&self) {
+        if let Some(filename) = self.filename.as_ref() {
+            let result = File::create(filename)
+                .map_err(BotError::from)
+                .and_then(|f| serde_json::to_writer(f, &self.linked).map_err(BotError::from));
+            if let Err(err) = result {
+                error!("Could not save email links: {:?}", err);
+            }
+        }
+    }
+
+    /// Email of the user.
+    pub fn get(&self, user_id: &matrix::UserId) -> Option<&str> {
+        self.configured
+            .get(user_id)
+            .or_else(|| self.linked.get(user_id))
+            .map(String::as_str)
+    }
+
+    /// Link the user to the email.
+    pub fn link(&mut self, user_id: matrix::UserId, email: String) {
+        info!("Linking {} to {}", user_id, email);
+        self.linked.insert(user_id, email);
+        self.save();
+    }
+}
+
+/// Convert a message to the bot into the event the bot handles. Mentions of
+/// the bot are already removed from the text of messages in group rooms.
+pub fn matrix_message_to_event(
+    message: matrix::Message,
+    email: String,
+    bot_id: &matrix::UserId,
+) -> ChatEvent {
+    let room_type = if message.direct {
+        RoomType::Direct
+    } else {
+        RoomType::Group
+    };
+    ChatEvent::Message(ChatMessage {
+        id: MessageId::new(message.event_id.into_string()),
+        user_email: Email::new(email),
+        user_id: ChatUserId::new(message.sender.into_string()),
+        room_id: RoomId::new(message.room_id.into_string()),
+        room_type,
+        text: message.text,
+        mentioned_users: vec![ChatUserId::new(bot_id.to_string())],
+    })
+}
+
+fn reply(
+    client: &matrix::Client,
+    message: &matrix::Message,
+    text: &str,
+) -> impl Future<Item = (), Error = ()> {
+    client
+        .send_message(
+            &message.room_id,
+            text,
+            &matrix::markdown_to_html(text),
+            None,
+        )
+        .map(|_| ())
+        .map_err(|e| error!("failed to reply: {}", e))
+}
+
+/// Handle a message of a user without email: link the email if the message is
+/// `link <email>` and the email is bound to the user, otherwise explain how to
+/// link it.
+fn link_email(
+    client: &matrix::Client,
+    links: &Arc<Mutex<EmailLinks>>,
+    message: matrix::Message,
+) -> impl Future<Item = (), Error = ()> {
+    lazy_static! {
+        static ref LINK_REGEX: Regex = Regex::new(r"(?i)^link (\S+@\S+)$").unwrap();
+    }
+    let email = match LINK_REGEX.captures(&message.text) {
+        Some(captures) => captures[1].to_string(),
+        None => return future::Either::A(reply(client, &message, NOT_LINKED_MSG)),
+    };
+
+    let client = client.clone();
+    let links = links.clone();
+    future::Either::B(client.lookup_email(&email).then(move |result| {
+        let text = match result {
+            Ok(Some(ref user_id)) if *user_id == message.sender => {
+                links
+                    .lock()
+                    .unwrap()
+                    .link(message.sender.clone(), email.clone());
+                format!("Your Matrix account is linked to {} now.", email)
+            }
+            Ok(_) => format!(
+                "{} is not bound to your Matrix account at the identity server.",
+                email
+            ),
+            Err(e) => {
+                error!("failed to look up email {}: {}", email, e);
+                "Could not check the email. Please try again later.".to_string()
+            }
+        };
+        reply(&client, &message, &text)
+    }))
+}
+
+/// Stream of the events for the bot. Messages of users without email are
+/// answered directly and not passed on, cf. `EmailLinks`.
+pub fn matrix_event_stream(
+    client: matrix::Client,
+    links: EmailLinks,
+) -> impl Stream<Item = ChatEvent, Error = ()> {
+    let links = Arc::new(Mutex::new(links));
+    matrix::sync_stream(client.clone())
+        .and_then(move |message| {
+            let email = links.lock().unwrap().get(&message.sender).map(String::from);
+            match email {
+                Some(email) => future::Either::A(future::ok(Some(matrix_message_to_event(
+                    message,
+                    email,
+                    client.id(),
+                )))),
+                None => future::Either::B(link_email(&client, &links, message).then(|_| Ok(None))),
+            }
+        })
+        .filter_map(identity)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn user_id(id: &str) -> matrix::UserId {
+        matrix::UserId::new(id.to_string())
+    }
+
+    #[test]
+    fn convert_messages_to_events() {
+        let bot_id = user_id("@gerritbot:example.org");
+        for &direct in &[true, false] {
+            let message = matrix::Message {
+                sender: user_id("@alice:example.org"),
+                room_id: matrix::RoomId::new("!room:example.org".to_string()),
+                event_id: matrix::EventId::new("$event:example.org".to_string()),
+                text: "status".to_string(),
+                direct,
+            };
+            let message =
+                matrix_message_to_event(message, "alice@example.com".to_string(), &bot_id)
+                    .into_message()
+                    .unwrap();
+            assert_eq!(message.room_type == RoomType::Direct, direct);
+            assert_eq!(message.user_id.as_str(), "@alice:example.org");
+            assert_eq!(message.user_email.as_str(), "alice@example.com");
+            assert_eq!(message.room_id.as_str(), "!room:example.org");
+            assert_eq!(message.id.as_str(), "$event:example.org");
+            assert!(message.mentions(ChatUserIdRef::new("@gerritbot:example.org")));
+            assert_eq!(message.text, "status");
+        }
+    }
+
+    #[test]
+    fn configured_emails_take_precedence() {
+        let dir = tempfile::tempdir().unwrap();
+        let filename = dir.path().join("links.json");
+        let alice = user_id("@alice:example.org");
+        let bob = user_id("@bob:example.org");
+
+        let mut links = EmailLinks::load(&filename)
+            .unwrap()
+            .with_configured(vec![(alice.clone(), "alice@example.com".to_string())]);
+        links.link(alice.clone(), "alice@example.org".to_string());
+        links.link(bob.clone(), "bob@example.com".to_string());
+        assert_eq!(links.get(&alice), Some("alice@example.com"));
+        assert_eq!(links.get(&bob), Some("bob@example.com"));
+
+        // only linked emails are stored
+        let links = EmailLinks::load(&filename).unwrap();
+        assert_eq!(links.get(&alice), Some("alice@example.org"));
+        assert_eq!(links.get(&bob), Some("bob@example.com"));
+        assert_eq!(links.get(&user_id("@carol:example.org")), None);
+    }
+}
@@ -0,0 +1,138 @@
+//! Mattermost as chat service of the bot.
+//!
+//! Mattermost is mapped to the bot's chat model: user ids are used as chat
+//! user ids, channels as rooms and post ids as message ids. Messages to a
+//! person are posted to the direct message channel with the person. The
+//! Markdown of the format script is posted as is, Mattermost renders it.
+
+use futures::{future, Future};
+
+use gerritbot_mattermost as mattermost;
+
+use crate::chat::{
+    ChatError, ChatEvent, ChatMessage, ChatUserId, ChatUserIdRef, Email, MessageId, MessageIdRef,
+    MessageTarget, RoomId, RoomIdRef, RoomType, SentMessage,
+};
+use crate::ChatBackend;
+
+fn to_sent_message(post: mattermost::Post) -> SentMessage {
+    SentMessage {
+        id: MessageId::new(post.id.into_string()),
+        room_id: RoomId::new(post.channel_id.into_string()),
+    }
+}
+
+impl ChatBackend for mattermost::Client {
+    type Event = mattermost::Message;
+    type ReplyFuture = Box<dyn Future<Item = SentMessage, Error = ChatError> + Send>;
+    fn id(&self) -> &ChatUserIdRef {
+        ChatUserIdRef::new(mattermost::Client::id(self).as_str())
+    }
+    fn to_chat_event(&self, message: mattermost::Message) -> ChatEvent {
+        mattermost_message_to_event(message, mattermost::Client::id(self))
+    }
+    fn send_message(
+        &self,
+        target: &MessageTarget,
+        msg: &str,
+        parent_id: Option<&MessageIdRef>,
+    ) -> Self::ReplyFuture {
+        let channel_id = match target {
+            MessageTarget::RoomId(room_id) => {
+                future::Either::A(future::ok(mattermost::ChannelId::new(room_id.to_string())))
+            }
+            MessageTarget::UserId(user_id) => future::Either::B(future::Either::A(
+                self.direct_channel(&mattermost::UserId::new(user_id.to_string())),
+            )),
+            MessageTarget::UserEmail(email) => {
+                let client = self.clone();
+                future::Either::B(future::Either::B(
+                    self.get_user_by_email(email.as_str())
+                        .and_then(move |user| client.direct_channel(&user.id)),
+                ))
+            }
+        };
+        let client = self.clone();
+        let message = msg.to_string();
+        let root_id = parent_id.map(|id| mattermost::PostId::new(id.to_string()));
+        Box::new(
+            channel_id
+                .and_then(move |channel_id| {
+                    client.create_post(&channel_id, &message, root_id.as_ref())
+                })
+                .map(to_sent_message)
+                .map_err(ChatError::new),
+        )
+    }
+    fn edit_message(
+        &self,
+        message_id: &MessageIdRef,
+        _room_id: &RoomIdRef,
+        msg: &str,
+    ) -> Self::ReplyFuture {
+        Box::new(
+            self.update_post(&mattermost::PostId::new(message_id.to_string()), msg)
+                .map(to_sent_message)
+                .map_err(ChatError::new),
+        )
+    }
+}
+
+/// Convert a message to the bot into the event the bot handles. Mentions of
+/// the bot are already removed from the text of messages in channels.
+pub fn mattermost_message_to_event(
+    message: mattermost::Message,
+    bot_id: &mattermost::UserId,
+) -> ChatEvent {
+    let room_type = match message.channel_type {
+        mattermost::ChannelType::Direct => RoomType::Direct,
+        mattermost::ChannelType::Group => RoomType::Group,
+    };
+    ChatEvent::Message(ChatMessage {
+        id: MessageId::new(message.post_id.into_string()),
+        user_email: Email::new(message.user_email),
+        user_id: ChatUserId::new(message.user_id.into_string()),
+        room_id: RoomId::new(message.channel_id.into_string()),
+        room_type,
+        text: message.text,
+        mentioned_users: vec![ChatUserId::new(bot_id.to_string())],
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn message(channel_type: mattermost::ChannelType) -> mattermost::Message {
+        mattermost::Message {
+            user_id: mattermost::UserId::new("u1".to_string()),
+            user_email: "author@example.com".to_string(),
+            channel_id: mattermost::ChannelId::new("c1".to_string()),
+            channel_type,
+            text: "status".to_string(),
+            post_id: mattermost::PostId::new("p1".to_string()),
+            root_id: None,
+        }
+    }
+
+    #[test]
+    fn convert_messages_to_events() {
+        let bot_id = mattermost::UserId::new("bot".to_string());
+        let bot_person_id = ChatUserIdRef::new("bot");
+        for (channel_type, room_type) in &[
+            (mattermost::ChannelType::Direct, RoomType::Direct),
+            (mattermost::ChannelType::Group, RoomType::Group),
+        ] {
+            let message = mattermost_message_to_event(message(*channel_type), &bot_id)
+                .into_message()
+                .unwrap();
+            assert_eq!(message.room_type, *room_type);
+            assert_eq!(message.user_id.as_str(), "u1");
+            assert_eq!(message.user_email.as_str(), "author@example.com");
+            assert_eq!(message.room_id.as_str(), "c1");
+            assert_eq!(message.id.as_str(), "p1");
+            assert!(message.mentions(bot_person_id));
+            assert_eq!(message.text, "status");
+        }
+    }
+}
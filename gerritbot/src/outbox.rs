@@ -0,0 +1,875 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use futures::{Async, Future, Poll, Stream};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::timer::Delay;
+
+use super::{json_file, BotError, ChatBackend, Response, Threads};
+use crate::chat::{MessageIdRef, MessageTarget, SentMessage};
+
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(5);
+const DEFAULT_MAX_RETRY_DELAY: Duration = Duration::from_secs(10 * 60);
+const DEFAULT_PARALLELISM: usize = 10;
+/// Webex Teams allows to edit a message at most 10 times.
+const MAX_EDITS: u32 = 10;
+
+/// A message waiting to be delivered.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct QueuedMessage {
+    target: MessageTarget,
+    message: String,
+    /// key of the thread the message is posted in, if any
+    #[serde(default)]
+    thread: Option<String>,
+    /// Adaptive Card sent with the message, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    card: Option<serde_json::Value>,
+    /// time the message was put into the queue
+    queued_at: SystemTime,
+    /// number of failed delivery attempts so far
+    attempts: u32,
+    /// earliest time of the next delivery attempt; not persisted, after a
+    /// restart all messages are due immediately
+    #[serde(skip)]
+    next_attempt: Option<Instant>,
+    /// true while a delivery attempt is in flight
+    #[serde(skip)]
+    sending: bool,
+    /// true if the delivery in flight edits the recent message of the thread
+    #[serde(skip)]
+    editing: bool,
+}
+
+impl QueuedMessage {
+    fn new(response: Response) -> Self {
+        Self {
+            target: response.target,
+            message: response.message,
+            thread: response.thread,
+            card: response.card,
+            queued_at: SystemTime::now(),
+            attempts: 0,
+            next_attempt: None,
+            sending: false,
+            editing: false,
+        }
+    }
+
+    /// Messages to the same recipient are delivered in order.
+    fn recipient(&self) -> &MessageTarget {
+        &self.target
+    }
+
+    fn is_due(&self, now: Instant) -> bool {
+        self.next_attempt.map(|t| t <= now).unwrap_or(true)
+    }
+
+    fn age(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(self.queued_at)
+            .unwrap_or_else(|_| Duration::from_secs(0))
+    }
+}
+
+/// Latest message with a thread key sent to a recipient. During the edit window
+/// it is replaced by further messages with the same key by editing it.
+#[derive(Debug)]
+struct RecentMessage {
+    sent: SentMessage,
+    sent_at: Instant,
+    edits: u32,
+}
+
+/// Queue of outgoing messages.
+///
+/// Messages stay in the queue until they were delivered successfully or
+/// became older than the configured maximum age. Failed deliveries are retried
+/// with exponential backoff. If the queue was created with a filename, it is
+/// written to disk after changes so that pending messages survive a restart.
+///
+/// Messages to different recipients are sent concurrently, up to the configured
+/// parallelism. Messages to the same recipient are sent one after another in
+/// the order they were queued.
+///
+/// If the outbox has threads, messages with a thread key are posted as replies
+/// in the thread of the first message with the same key to the same recipient.
+///
+/// If the outbox has an edit window, a message with a thread key is not sent
+/// as new message if the latest message with the same key to the same
+/// recipient is younger than the window. Instead the latest message is edited
+/// and replaced by the new message, e.g. a notification showing the current
+/// votes on a change.
+#[derive(Debug)]
+pub struct Outbox {
+    messages: VecDeque<QueuedMessage>,
+    filename: Option<PathBuf>,
+    /// true if the queue changed since it was last written to disk
+    changed: bool,
+    threads: Option<Threads>,
+    edit_window: Option<Duration>,
+    /// not persisted, the edit window is expected to be short
+    recent: HashMap<(MessageTarget, String), RecentMessage>,
+    max_age: Duration,
+    retry_delay: Duration,
+    max_retry_delay: Duration,
+    parallelism: usize,
+}
+
+impl Default for Outbox {
+    fn default() -> Self {
+        Self {
+            messages: VecDeque::new(),
+            filename: None,
+            changed: false,
+            threads: None,
+            edit_window: None,
+            recent: HashMap::new(),
+            max_age: DEFAULT_MAX_AGE,
+            retry_delay: DEFAULT_RETRY_DELAY,
+            max_retry_delay: DEFAULT_MAX_RETRY_DELAY,
+            parallelism: DEFAULT_PARALLELISM,
+        }
+    }
+}
+
+impl Outbox {
+    /// Create an empty outbox which is kept in memory only.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Load the outbox from the given file. A missing file results in an empty
+    /// outbox. All later changes are written back to the same file.
+    pub fn load<P>(filename: P) -> Result<Self, BotError>
+    where
+        P: AsRef<Path>,
+    {
+        let filename = filename.as_ref();
+        let messages = match File::open(filename) {
+            Ok(f) => serde_json::from_reader(f)?,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => VecDeque::new(),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(Self {
+            messages,
+            filename: Some(filename.to_path_buf()),
+            ..Default::default()
+        })
+    }
+
+    /// Drop messages which could not be delivered for longer than `max_age`.
+    pub fn with_max_age(self, max_age: Duration) -> Self {
+        Self { max_age, ..self }
+    }
+
+    /// Wait `retry_delay` after the first failed delivery, and double the delay
+    /// after each further failure up to `max_retry_delay`.
+    pub fn with_retry_delay(self, retry_delay: Duration, max_retry_delay: Duration) -> Self {
+        Self {
+            retry_delay,
+            max_retry_delay,
+            ..self
+        }
+    }
+
+    /// Send at most `parallelism` messages at the same time.
+    pub fn with_parallelism(self, parallelism: usize) -> Self {
+        Self {
+            parallelism: parallelism.max(1),
+            ..self
+        }
+    }
+
+    /// Post messages with a thread key in threads.
+    pub fn with_threads(self, threads: Threads) -> Self {
+        Self {
+            threads: Some(threads),
+            ..self
+        }
+    }
+
+    /// Edit the latest message with the same thread key instead of sending a
+    /// new one, if the latest message is younger than `edit_window`. A zero
+    /// window disables editing.
+    pub fn with_edit_window(self, edit_window: Duration) -> Self {
+        Self {
+            edit_window: Some(edit_window).filter(|window| *window > Duration::from_secs(0)),
+            ..self
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Write the queue to disk, if it changed since it was last written.
+    fn save(&mut self) {
+        if !self.changed {
+            return;
+        }
+        self.changed = false;
+        if let Some(filename) = self.filename.as_ref() {
+            if let Err(err) = json_file::write(filename, &self.messages, 0) {
+                error!("Could not save outbox: {:?}", err);
+            }
+        }
+    }
+
+    fn push(&mut self, response: Response) {
+        self.messages.push_back(QueuedMessage::new(response));
+        self.changed = true;
+    }
+
+    /// Remove all messages older than the maximum age, which are not being sent
+    /// right now.
+    fn drop_expired(&mut self) {
+        let max_age = self.max_age;
+        let len = self.messages.len();
+        self.messages.retain(|msg| {
+            let age = msg.age();
+            if msg.sending {
+                return true;
+            }
+            if age > max_age {
+                warn!(
+                    "Dropping message to {} after {} failed attempt(s), it is {} sec old: {:?}",
+                    msg.target,
+                    msg.attempts,
+                    age.as_secs(),
+                    msg.message
+                );
+            }
+            age <= max_age
+        });
+        if self.messages.len() != len {
+            self.changed = true;
+        }
+    }
+
+    /// Positions of the messages which are due for delivery. Only the first
+    /// queued message of each recipient is considered and only if there is no
+    /// delivery to the same recipient in flight.
+    fn due(&self, now: Instant, limit: usize) -> Vec<usize> {
+        let mut recipients = HashSet::new();
+        self.messages
+            .iter()
+            .enumerate()
+            .filter(|(_, msg)| recipients.insert(msg.recipient()))
+            .filter(|(_, msg)| !msg.sending && msg.is_due(now))
+            .map(|(index, _)| index)
+            .take(limit)
+            .collect()
+    }
+
+    /// Earliest time after `now` at which a message will become due.
+    fn next_attempt(&self, now: Instant) -> Option<Instant> {
+        self.messages
+            .iter()
+            .filter_map(|msg| msg.next_attempt)
+            .filter(|next_attempt| *next_attempt > now)
+            .min()
+    }
+
+    /// Position of the message currently being sent to the recipient.
+    fn sending_to(&self, recipient: &MessageTarget) -> usize {
+        self.messages
+            .iter()
+            .position(|msg| msg.sending && msg.recipient() == recipient)
+            .expect("no message in flight to recipient")
+    }
+
+    /// Message the given message should be posted as reply to.
+    fn parent_of(&self, msg: &QueuedMessage) -> Option<&MessageIdRef> {
+        let threads = self.threads.as_ref()?;
+        let key = msg.thread.as_ref()?;
+        threads.get(msg.recipient(), key).map(|id| &**id)
+    }
+
+    /// Recent message which should be edited instead of sending the given
+    /// message.
+    fn editable(&self, msg: &QueuedMessage) -> Option<&RecentMessage> {
+        let edit_window = self.edit_window?;
+        if msg.card.is_some() {
+            // cards cannot be replaced by editing a message
+            return None;
+        }
+        let key = msg.thread.as_ref()?;
+        self.recent
+            .get(&(msg.recipient().clone(), key.clone()))
+            .filter(|recent| recent.sent_at.elapsed() <= edit_window && recent.edits < MAX_EDITS)
+    }
+
+    fn delivered(&mut self, recipient: &MessageTarget, sent: SentMessage) {
+        let index = self.sending_to(recipient);
+        let msg = self.messages.remove(index).expect("invalid message index");
+        if let Some(key) = msg.thread {
+            if let Some(threads) = self.threads.as_mut() {
+                threads.start(recipient, &key, sent.id.clone());
+            }
+            let recent_key = (recipient.clone(), key);
+            if msg.editing {
+                if let Some(recent) = self.recent.get_mut(&recent_key) {
+                    recent.edits += 1;
+                }
+            } else if let (Some(edit_window), None) = (self.edit_window, msg.card) {
+                self.recent
+                    .retain(|_, recent| recent.sent_at.elapsed() <= edit_window);
+                self.recent.insert(
+                    recent_key,
+                    RecentMessage {
+                        sent,
+                        sent_at: Instant::now(),
+                        edits: 0,
+                    },
+                );
+            }
+        }
+        self.changed = true;
+    }
+
+    fn failed(&mut self, recipient: &MessageTarget) -> Duration {
+        let index = self.sending_to(recipient);
+        let retry_delay = self.retry_delay;
+        let max_retry_delay = self.max_retry_delay;
+        let msg = &mut self.messages[index];
+        let delay = retry_delay
+            .checked_mul(1 << msg.attempts.min(16))
+            .map_or(max_retry_delay, |delay| delay.min(max_retry_delay));
+        msg.attempts += 1;
+        msg.next_attempt = Some(Instant::now() + delay);
+        msg.sending = false;
+        if msg.editing {
+            // send a new message on retry, the recent one might not be
+            // editable anymore
+            msg.editing = false;
+            if let Some(key) = msg.thread.clone() {
+                self.recent.remove(&(recipient.clone(), key));
+            }
+        }
+        self.changed = true;
+        delay
+    }
+
+    /// Consume the outbox and deliver all messages from the queue and the given
+    /// stream of responses through `chat_client`.
+    ///
+    /// The returned future finishes when the stream of responses ends and no
+    /// more messages are due for delivery. Messages waiting for a retry at that
+    /// point remain in the outbox file.
+    pub(crate) fn deliver<R, S>(self, responses: R, chat_client: S) -> Delivery<R, S>
+    where
+        R: Stream<Item = Response, Error = ()>,
+        S: ChatBackend,
+    {
+        Delivery {
+            outbox: self,
+            responses: Some(responses),
+            chat_client,
+            in_flight: Vec::new(),
+            retry_timer: None,
+        }
+    }
+}
+
+#[must_use = "futures do nothing unless polled"]
+pub(crate) struct Delivery<R, S>
+where
+    S: ChatBackend,
+{
+    outbox: Outbox,
+    /// `None` after the stream has ended
+    responses: Option<R>,
+    chat_client: S,
+    /// recipients and pending reply futures
+    in_flight: Vec<(MessageTarget, S::ReplyFuture)>,
+    retry_timer: Option<Delay>,
+}
+
+impl<R, S> Delivery<R, S>
+where
+    R: Stream<Item = Response, Error = ()>,
+    S: ChatBackend,
+{
+    fn poll_responses(&mut self) {
+        while let Some(responses) = self.responses.as_mut() {
+            match responses.poll() {
+                Ok(Async::Ready(Some(response))) => {
+                    debug!("Queueing reply: {}", response.message);
+                    self.outbox.push(response);
+                }
+                Ok(Async::Ready(None)) | Err(()) => self.responses = None,
+                Ok(Async::NotReady) => break,
+            }
+        }
+    }
+
+    /// Poll the in-flight deliveries. Returns the number of deliveries which
+    /// finished.
+    fn poll_in_flight(&mut self) -> usize {
+        let mut finished = 0;
+        let mut i = 0;
+        while i < self.in_flight.len() {
+            let result = match self.in_flight[i].1.poll() {
+                Ok(Async::NotReady) => {
+                    i += 1;
+                    continue;
+                }
+                Ok(Async::Ready(sent)) => Ok(sent),
+                Err(e) => Err(e),
+            };
+            let (recipient, _) = self.in_flight.swap_remove(i);
+            finished += 1;
+
+            match result {
+                Ok(sent) => self.outbox.delivered(&recipient, sent),
+                Err(e) => {
+                    let delay = self.outbox.failed(&recipient);
+                    error!(
+                        "failed to send chat message to {}: {}; retrying in {} sec",
+                        recipient,
+                        e,
+                        delay.as_secs()
+                    );
+                }
+            }
+        }
+        finished
+    }
+
+    /// Start delivery of due messages. Returns the number of started
+    /// deliveries.
+    fn start_due(&mut self) -> usize {
+        let limit = self.outbox.parallelism.saturating_sub(self.in_flight.len());
+        let due = self.outbox.due(Instant::now(), limit);
+
+        for &index in &due {
+            let msg = &self.outbox.messages[index];
+            let editing;
+            let reply = match self.outbox.editable(msg) {
+                Some(recent) => {
+                    debug!("Editing {} with: {}", recent.sent.id, msg.message);
+                    editing = true;
+                    self.chat_client.edit_message(
+                        &recent.sent.id,
+                        &recent.sent.room_id,
+                        &msg.message,
+                    )
+                }
+                None => {
+                    debug!("Replying with: {}", msg.message);
+                    editing = false;
+                    let parent_id = self.outbox.parent_of(msg);
+                    match &msg.card {
+                        Some(card) => {
+                            self.chat_client
+                                .send_card(&msg.target, &msg.message, card, parent_id)
+                        }
+                        None => self
+                            .chat_client
+                            .send_message(&msg.target, &msg.message, parent_id),
+                    }
+                }
+            };
+            self.in_flight.push((msg.recipient().clone(), reply));
+            let msg = &mut self.outbox.messages[index];
+            msg.sending = true;
+            msg.editing = editing;
+        }
+
+        due.len()
+    }
+}
+
+impl<R, S> Future for Delivery<R, S>
+where
+    R: Stream<Item = Response, Error = ()>,
+    S: ChatBackend,
+{
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            self.poll_responses();
+            let finished = self.poll_in_flight();
+            self.outbox.drop_expired();
+            let started = self.start_due();
+
+            if finished > 0 || started > 0 {
+                // new deliveries have to be polled, finished ones might have
+                // unblocked further messages
+                continue;
+            }
+
+            // write all changes of this round at once
+            self.outbox.save();
+
+            if self.responses.is_none() && self.in_flight.is_empty() {
+                if !self.outbox.is_empty() {
+                    info!(
+                        "{} undelivered message(s) left in the outbox",
+                        self.outbox.len()
+                    );
+                }
+                return Ok(Async::Ready(()));
+            }
+
+            // wait for new responses, finished deliveries or the next retry
+            if let Some(next_attempt) = self.outbox.next_attempt(Instant::now()) {
+                let retry_timer = self
+                    .retry_timer
+                    .get_or_insert_with(|| Delay::new(next_attempt));
+                retry_timer.reset(next_attempt);
+                match retry_timer.poll() {
+                    Ok(Async::NotReady) => (),
+                    Ok(Async::Ready(())) => continue,
+                    Err(e) => {
+                        error!("retry timer failed: {}", e);
+                        return Err(());
+                    }
+                }
+            }
+
+            return Ok(Async::NotReady);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use futures::{future, stream};
+    use tokio::runtime::current_thread::Runtime;
+
+    use super::*;
+    use crate::chat::{
+        ChatError, ChatEvent, ChatUserId, ChatUserIdRef, MessageId, RoomId, RoomIdRef,
+    };
+
+    fn sent_message(id: String) -> SentMessage {
+        SentMessage {
+            id: MessageId::new(id),
+            room_id: RoomId::new("room".to_string()),
+        }
+    }
+
+    /// Chat backend failing the first `failures` attempts.
+    #[derive(Clone)]
+    struct FlakyChatBackend {
+        failures: Arc<Mutex<usize>>,
+        sent: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl FlakyChatBackend {
+        fn new(failures: usize) -> Self {
+            Self {
+                failures: Arc::new(Mutex::new(failures)),
+                sent: Default::default(),
+            }
+        }
+    }
+
+    impl ChatBackend for FlakyChatBackend {
+        type Event = ChatEvent;
+        type ReplyFuture = future::FutureResult<SentMessage, ChatError>;
+        fn id(&self) -> &ChatUserIdRef {
+            ChatUserIdRef::new("bot")
+        }
+        fn to_chat_event(&self, event: ChatEvent) -> ChatEvent {
+            event
+        }
+        fn send_message(
+            &self,
+            _target: &MessageTarget,
+            msg: &str,
+            parent_id: Option<&MessageIdRef>,
+        ) -> Self::ReplyFuture {
+            let mut failures = self.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                future::err(ChatError::new("flaky"))
+            } else {
+                let mut sent = self.sent.lock().unwrap();
+                sent.push(match parent_id {
+                    Some(parent_id) => format!("{} (reply to {})", msg, parent_id),
+                    None => msg.to_string(),
+                });
+                future::ok(sent_message(format!("message-{}", sent.len())))
+            }
+        }
+        fn edit_message(
+            &self,
+            message_id: &MessageIdRef,
+            _room_id: &RoomIdRef,
+            msg: &str,
+        ) -> Self::ReplyFuture {
+            let mut sent = self.sent.lock().unwrap();
+            sent.push(format!("{} (edit of {})", msg, message_id));
+            future::ok(sent_message(message_id.to_string()))
+        }
+    }
+
+    fn responses(messages: &[&str]) -> impl Stream<Item = Response, Error = ()> {
+        let responses: Vec<_> = messages
+            .iter()
+            .map(|msg| Response::new(ChatUserId::new("person".to_string()), *msg))
+            .collect();
+        stream::iter_ok(responses)
+    }
+
+    #[test]
+    fn delivers_all_messages() {
+        let client = FlakyChatBackend::new(0);
+        Outbox::new()
+            .deliver(responses(&["a", "b"]), client.clone())
+            .wait()
+            .unwrap();
+        assert_eq!(*client.sent.lock().unwrap(), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn retries_failed_messages() {
+        let client = FlakyChatBackend::new(2);
+        // keep the stream of responses open long enough for the retries
+        let keep_open = Delay::new(Instant::now() + Duration::from_millis(200))
+            .into_stream()
+            .filter_map(|()| None)
+            .map_err(|e| panic!("timer failed: {}", e));
+        let outbox =
+            Outbox::new().with_retry_delay(Duration::from_millis(10), Duration::from_millis(20));
+        Runtime::new()
+            .unwrap()
+            .block_on(outbox.deliver(responses(&["a"]).chain(keep_open), client.clone()))
+            .unwrap();
+        assert_eq!(*client.sent.lock().unwrap(), vec!["a"]);
+    }
+
+    #[test]
+    fn drops_expired_messages() {
+        let client = FlakyChatBackend::new(1);
+        let mut outbox = Outbox::new().with_max_age(Duration::from_millis(0));
+        outbox.push(Response::new(ChatUserId::new("person".to_string()), "a"));
+        outbox.messages[0].queued_at -= Duration::from_secs(1);
+        outbox
+            .deliver(stream::empty(), client.clone())
+            .wait()
+            .unwrap();
+        assert!(client.sent.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn persists_pending_messages() {
+        let dir = tempfile::tempdir().unwrap();
+        let filename = dir.path().join("outbox.json");
+
+        let client = FlakyChatBackend::new(1);
+        Outbox::load(&filename)
+            .unwrap()
+            .deliver(responses(&["a"]), client.clone())
+            .wait()
+            .unwrap();
+        assert!(client.sent.lock().unwrap().is_empty());
+
+        let outbox = Outbox::load(&filename).unwrap();
+        assert_eq!(outbox.len(), 1);
+        outbox
+            .deliver(stream::empty(), client.clone())
+            .wait()
+            .unwrap();
+        assert_eq!(*client.sent.lock().unwrap(), vec!["a"]);
+        assert!(Outbox::load(&filename).unwrap().is_empty());
+    }
+
+    #[test]
+    fn posts_messages_in_threads() {
+        let client = FlakyChatBackend::new(0);
+        let person = ChatUserId::new("person".to_string());
+        let responses = stream::iter_ok(vec![
+            Response::new(person.clone(), "a").in_thread("1"),
+            Response::new(person.clone(), "b").in_thread("2"),
+            Response::new(person.clone(), "c").in_thread("1"),
+            Response::new(person.clone(), "d"),
+        ]);
+        Outbox::new()
+            .with_threads(Threads::new())
+            .deliver(responses, client.clone())
+            .wait()
+            .unwrap();
+        assert_eq!(
+            *client.sent.lock().unwrap(),
+            vec!["a", "b", "c (reply to message-1)", "d"]
+        );
+    }
+
+    #[test]
+    fn edits_recent_messages() {
+        let client = FlakyChatBackend::new(0);
+        let person = ChatUserId::new("person".to_string());
+        let responses = stream::iter_ok(vec![
+            Response::new(person.clone(), "a").in_thread("1"),
+            Response::new(person.clone(), "b").in_thread("2"),
+            Response::new(person.clone(), "c").in_thread("1"),
+            Response::new(person.clone(), "d").in_thread("1"),
+            Response::new(person.clone(), "e"),
+        ]);
+        Outbox::new()
+            .with_edit_window(Duration::from_secs(60))
+            .deliver(responses, client.clone())
+            .wait()
+            .unwrap();
+        assert_eq!(
+            *client.sent.lock().unwrap(),
+            vec![
+                "a",
+                "b",
+                "c (edit of message-1)",
+                "d (edit of message-1)",
+                "e"
+            ]
+        );
+    }
+
+    #[test]
+    fn sends_new_message_after_edit_window() {
+        let client = FlakyChatBackend::new(0);
+        let person = ChatUserId::new("person".to_string());
+        let mut outbox = Outbox::new().with_edit_window(Duration::from_secs(60));
+        outbox.recent.insert(
+            (MessageTarget::UserId(person.clone()), "1".to_string()),
+            RecentMessage {
+                sent: sent_message("message-0".to_string()),
+                sent_at: Instant::now() - Duration::from_secs(61),
+                edits: 0,
+            },
+        );
+        outbox
+            .deliver(
+                stream::iter_ok(vec![Response::new(person.clone(), "b").in_thread("1")]),
+                client.clone(),
+            )
+            .wait()
+            .unwrap();
+        assert_eq!(*client.sent.lock().unwrap(), vec!["b"]);
+    }
+
+    #[test]
+    fn ignores_threads_without_threads() {
+        let client = FlakyChatBackend::new(0);
+        let person = ChatUserId::new("person".to_string());
+        let responses = stream::iter_ok(vec![
+            Response::new(person.clone(), "a").in_thread("1"),
+            Response::new(person.clone(), "b").in_thread("1"),
+        ]);
+        Outbox::new()
+            .deliver(responses, client.clone())
+            .wait()
+            .unwrap();
+        assert_eq!(*client.sent.lock().unwrap(), vec!["a", "b"]);
+    }
+
+    /// Chat backend which answers slowly for person "slow" and records when
+    /// deliveries start and finish.
+    #[derive(Clone, Default)]
+    struct SlowChatBackend {
+        events: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl ChatBackend for SlowChatBackend {
+        type Event = ChatEvent;
+        type ReplyFuture = Box<dyn Future<Item = SentMessage, Error = ChatError> + Send>;
+        fn id(&self) -> &ChatUserIdRef {
+            ChatUserIdRef::new("bot")
+        }
+        fn to_chat_event(&self, event: ChatEvent) -> ChatEvent {
+            event
+        }
+        fn send_message(
+            &self,
+            target: &MessageTarget,
+            msg: &str,
+            _parent_id: Option<&MessageIdRef>,
+        ) -> Self::ReplyFuture {
+            self.events.lock().unwrap().push(format!("start {}", msg));
+            let slow = MessageTarget::UserId(ChatUserId::new("slow".to_string()));
+            let delay = if *target == slow { 50 } else { 0 };
+            let events = self.events.clone();
+            let msg = msg.to_string();
+            Box::new(
+                Delay::new(Instant::now() + Duration::from_millis(delay))
+                    .map_err(|e| panic!("timer failed: {}", e))
+                    .map(move |()| {
+                        events.lock().unwrap().push(format!("done {}", msg));
+                        sent_message(msg)
+                    }),
+            )
+        }
+        fn edit_message(
+            &self,
+            message_id: &MessageIdRef,
+            room_id: &RoomIdRef,
+            _msg: &str,
+        ) -> Self::ReplyFuture {
+            Box::new(future::ok(SentMessage {
+                id: message_id.to_owned(),
+                room_id: room_id.to_owned(),
+            }))
+        }
+    }
+
+    #[test]
+    fn keeps_order_per_recipient() {
+        let client = SlowChatBackend::default();
+        let responses = stream::iter_ok(vec![
+            Response::new(ChatUserId::new("slow".to_string()), "slow 1"),
+            Response::new(ChatUserId::new("fast".to_string()), "fast 1"),
+            Response::new(ChatUserId::new("slow".to_string()), "slow 2"),
+        ]);
+        Runtime::new()
+            .unwrap()
+            .block_on(Outbox::new().deliver(responses, client.clone()))
+            .unwrap();
+
+        let events = client.events.lock().unwrap();
+        let position = |event: &str| events.iter().position(|e| e == event).unwrap();
+        // the fast recipient does not wait for the slow one
+        assert!(position("done fast 1") < position("done slow 1"));
+        // the second message to the slow recipient waits for the first one
+        assert!(position("done slow 1") < position("start slow 2"));
+        assert_eq!(events.len(), 6);
+    }
+
+    #[test]
+    fn limits_parallelism() {
+        let client = SlowChatBackend::default();
+        let responses = stream::iter_ok(vec![
+            Response::new(ChatUserId::new("slow".to_string()), "slow 1"),
+            Response::new(ChatUserId::new("fast".to_string()), "fast 1"),
+        ]);
+        Runtime::new()
+            .unwrap()
+            .block_on(
+                Outbox::new()
+                    .with_parallelism(1)
+                    .deliver(responses, client.clone()),
+            )
+            .unwrap();
+
+        let events = client.events.lock().unwrap();
+        assert_eq!(
+            *events,
+            vec!["start slow 1", "done slow 1", "start fast 1", "done fast 1"]
+        );
+    }
+}
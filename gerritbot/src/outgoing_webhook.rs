@@ -0,0 +1,147 @@
+//! Notifications posted as JSON to an outgoing webhook, e.g. to bridge them to
+//! chat services without a backend of their own.
+//!
+//! The payload contains the recipient, the change, the type of the event, the
+//! formatted notification and the event as received from Gerrit. The format
+//! script can reshape it with `format_webhook_payload(payload)`.
+
+use std::collections::HashSet;
+use std::sync::mpsc;
+use std::thread;
+
+use log::{debug, error};
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+
+/// The default payload of a notification.
+#[derive(Debug, Serialize)]
+pub struct WebhookPayload<'a> {
+    /// email of the user the notification is for
+    pub recipient: &'a str,
+    pub change_number: u32,
+    /// type of the Gerrit event, e.g. `comment-added`
+    pub event_type: &'a str,
+    /// the notification as formatted by the format script
+    pub markdown: &'a str,
+    /// the Gerrit event
+    pub event: JsonValue,
+}
+
+/// Posts notifications to a webhook.
+pub struct OutgoingWebhook {
+    recipients: HashSet<String>,
+    queue: mpsc::Sender<JsonValue>,
+}
+
+impl OutgoingWebhook {
+    /// Create a webhook posting to the given url. The requests are sent by a
+    /// separate thread.
+    pub fn new(url: String) -> Self {
+        let (queue, payloads) = mpsc::channel::<JsonValue>();
+        thread::spawn(move || {
+            let client = reqwest::Client::new();
+            for payload in payloads {
+                match client.post(&url).json(&payload).send() {
+                    Ok(ref response) if !response.status().is_success() => {
+                        error!("webhook {} responded with {}", url, response.status())
+                    }
+                    Ok(_) => (),
+                    Err(e) => error!("failed to post to webhook {}: {}", url, e),
+                }
+            }
+        });
+        Self {
+            recipients: HashSet::new(),
+            queue,
+        }
+    }
+
+    /// Gerrit emails of the users whose notifications are posted. If there
+    /// are none, the notifications of all users are posted.
+    pub fn with_recipients<I>(self, recipients: I) -> Self
+    where
+        I: IntoIterator<Item = String>,
+    {
+        Self {
+            recipients: recipients.into_iter().collect(),
+            ..self
+        }
+    }
+
+    pub fn is_recipient(&self, email: &str) -> bool {
+        self.recipients.is_empty() || self.recipients.contains(email)
+    }
+
+    /// Queue the payload for posting.
+    pub fn send(&self, payload: JsonValue) {
+        debug!("post to webhook: {}", payload);
+        if self.queue.send(payload).is_err() {
+            error!("failed to queue webhook payload, the webhook thread is gone");
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::Receiver;
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use super::*;
+
+    /// Start an HTTP server on a free local port, which answers all requests
+    /// with `204 No Content`. Returns its url and the received JSON bodies.
+    pub fn start_http_sink() -> (String, Receiver<JsonValue>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (received, bodies) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut content_length = 0;
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap_or(0) > 2 {
+                    let lowercase = line.to_lowercase();
+                    if let Some(value) = lowercase.strip_prefix("content-length:") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                    line.clear();
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                received
+                    .send(serde_json::from_slice(&body).unwrap())
+                    .unwrap();
+                stream
+                    .write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n")
+                    .unwrap();
+            }
+        });
+        (url, bodies)
+    }
+
+    #[test]
+    fn post_payloads() {
+        let (url, bodies) = start_http_sink();
+        let webhook = OutgoingWebhook::new(url);
+        webhook.send(json!({ "text": "first" }));
+        webhook.send(json!({ "text": "second" }));
+
+        let timeout = Duration::from_secs(10);
+        assert_eq!(bodies.recv_timeout(timeout).unwrap()["text"], "first");
+        assert_eq!(bodies.recv_timeout(timeout).unwrap()["text"], "second");
+    }
+
+    #[test]
+    fn post_for_recipients_only() {
+        let webhook = OutgoingWebhook::new("http://localhost/hook".to_string());
+        assert!(webhook.is_recipient("author@example.com"));
+        let webhook = webhook.with_recipients(vec!["author@example.com".to_string()]);
+        assert!(webhook.is_recipient("author@example.com"));
+        assert!(!webhook.is_recipient("other@example.com"));
+    }
+}
@@ -4,6 +4,8 @@ use lru_time_cache::LruCache;
 
 use gerritbot_gerrit as gerrit;
 
+use crate::chat::{ChatUserId, ChatUserIdRef};
+
 #[derive(Clone, Default)]
 pub struct RateLimiter {
     cache: Option<LruCache<MsgCacheLine, ()>>,
@@ -18,13 +20,13 @@ impl RateLimiter {
         }
     }
 
-    pub fn limit<E>(&mut self, user_index: usize, event: E) -> bool
+    pub fn limit<E>(&mut self, user_id: &ChatUserIdRef, event: E) -> bool
     where
         E: IntoCacheLine,
     {
         self.cache
             .as_mut()
-            .and_then(|cache| cache.insert(IntoCacheLine::into_cache_line(user_index, &event), ()))
+            .and_then(|cache| cache.insert(IntoCacheLine::into_cache_line(user_id, &event), ()))
             .is_some()
     }
 }
@@ -55,24 +57,24 @@ pub struct Approval {
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum MsgCacheLine {
     Approvals {
-        /// position of the user in bots.user vector
-        user_ref: usize,
+        /// user the message is sent to
+        user_id: ChatUserId,
         subject: Subject,
         approver: String,
         approvals: Vec<Approval>,
     },
     ReviewerAdded {
-        user_ref: usize,
+        user_id: ChatUserId,
         subject: Subject,
     },
 }
 
 pub trait IntoCacheLine {
-    fn into_cache_line(user_index: usize, event: &Self) -> MsgCacheLine;
+    fn into_cache_line(user_id: &ChatUserIdRef, event: &Self) -> MsgCacheLine;
 }
 
 impl IntoCacheLine for &gerrit::CommentAddedEvent {
-    fn into_cache_line(user_index: usize, event: &Self) -> MsgCacheLine {
+    fn into_cache_line(user_id: &ChatUserIdRef, event: &Self) -> MsgCacheLine {
         let mut approvals: Vec<_> = event
             .approvals
             .iter()
@@ -92,7 +94,7 @@ impl IntoCacheLine for &gerrit::CommentAddedEvent {
         approvals.sort_unstable();
 
         MsgCacheLine::Approvals {
-            user_ref: user_index,
+            user_id: user_id.to_owned(),
             subject: Subject::from_change(&event.change),
             approver: event.author.email.clone(),
             approvals,
@@ -101,9 +103,9 @@ impl IntoCacheLine for &gerrit::CommentAddedEvent {
 }
 
 impl IntoCacheLine for &gerrit::ReviewerAddedEvent {
-    fn into_cache_line(user_index: usize, event: &Self) -> MsgCacheLine {
+    fn into_cache_line(user_id: &ChatUserIdRef, event: &Self) -> MsgCacheLine {
         MsgCacheLine::ReviewerAdded {
-            user_ref: user_index,
+            user_id: user_id.to_owned(),
             subject: Subject::from_change(&event.change),
         }
     }
@@ -0,0 +1,150 @@
+use regex::Regex;
+
+use gerritbot_gerrit as gerrit;
+
+use crate::chat::RoomId;
+
+/// Translate a glob pattern into an anchored regex. `*` matches any sequence
+/// of characters, `?` matches a single character.
+fn glob_to_regex(glob: &str) -> Regex {
+    let mut pattern = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+    Regex::new(&pattern).expect("escaped glob is a valid regex")
+}
+
+/// Glob pattern matching a Gerrit property like a project name.
+#[derive(Debug, Clone)]
+struct Pattern(Regex);
+
+impl Pattern {
+    fn new<A: AsRef<str>>(glob: A) -> Self {
+        Pattern(glob_to_regex(glob.as_ref()))
+    }
+
+    fn is_match(&self, s: &str) -> bool {
+        self.0.is_match(s)
+    }
+}
+
+impl Default for Pattern {
+    fn default() -> Self {
+        Self::new("*")
+    }
+}
+
+/// Route of Gerrit events to a group room.
+#[derive(Debug, Clone)]
+pub struct Route {
+    room_id: RoomId,
+    project: Pattern,
+    branch: Pattern,
+    event_type: Pattern,
+}
+
+impl Route {
+    /// Create a route posting all events to the given room.
+    pub fn new(room_id: RoomId) -> Self {
+        Self {
+            room_id,
+            project: Default::default(),
+            branch: Default::default(),
+            event_type: Default::default(),
+        }
+    }
+
+    /// Only route events of projects matching the given glob.
+    pub fn with_project<A: AsRef<str>>(self, project: A) -> Self {
+        Self {
+            project: Pattern::new(project),
+            ..self
+        }
+    }
+
+    /// Only route events of branches matching the given glob.
+    pub fn with_branch<A: AsRef<str>>(self, branch: A) -> Self {
+        Self {
+            branch: Pattern::new(branch),
+            ..self
+        }
+    }
+
+    /// Only route events whose type, e.g. `comment-added`, matches the given
+    /// glob.
+    pub fn with_event_type<A: AsRef<str>>(self, event_type: A) -> Self {
+        Self {
+            event_type: Pattern::new(event_type),
+            ..self
+        }
+    }
+
+    pub fn room_id(&self) -> &RoomId {
+        &self.room_id
+    }
+
+    /// Check if an event of the given type about the given change is routed.
+    pub fn matches(&self, event_type: &str, change: &gerrit::Change) -> bool {
+        self.project.is_match(&change.project)
+            && self.branch.is_match(&change.branch)
+            && self.event_type.is_match(event_type)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const EVENT_JSON: &str = r#"
+{"author":{"name":"Approver","username":"approver","email":"approver@approvers.com"},"approvals":[{"type":"Code-Review","description":"Code-Review","value":"2","oldValue":"-1"}],"comment":"Patch Set 1: Code-Review+2","patchSet":{"number":1,"revision":"49a65998c02eda928559f2d0b586c20bc8e37b10","parents":["fb1909b4eda306985d2bbce769310e5a50a98cf5"],"ref":"refs/changes/42/42/1","uploader":{"name":"Author","email":"author@example.com","username":"Author"},"createdOn":1494165142,"author":{"name":"Author","email":"author@example.com","username":"Author"},"isDraft":false,"kind":"REWORK","sizeInsertions":0,"sizeDeletions":0},"change":{"project":"demo-project","branch":"release/1.0","id":"Ic160fa37fca005fec17a2434aadf0d9dcfbb7b14","number":49,"subject":"Some review.","owner":{"name":"Author","email":"author@example.com","username":"author"},"url":"http://localhost/42","commitMessage":"Some review.\n\nChange-Id: Ic160fa37fca005fec17a2434aadf0d9dcfbb7b14\n","status":"NEW"},"project":"demo-project","refName":"refs/heads/release/1.0","changeKey":{"id":"Ic160fa37fca005fec17a2434aadf0d9dcfbb7b14"},"type":"comment-added","eventCreatedOn":1499190282}"#;
+
+    fn get_change() -> gerrit::Change {
+        let event: gerrit::Event =
+            serde_json::from_str(EVENT_JSON).expect("failed to decode event");
+        event.change().clone()
+    }
+
+    fn route() -> Route {
+        Route::new(RoomId::new("room".to_string()))
+    }
+
+    #[test]
+    fn test_glob_to_regex() {
+        assert!(glob_to_regex("*").is_match(""));
+        assert!(glob_to_regex("*").is_match("any/thing"));
+        assert!(glob_to_regex("release/*").is_match("release/1.0"));
+        assert!(!glob_to_regex("release/*").is_match("master"));
+        assert!(glob_to_regex("v?.0").is_match("v1.0"));
+        assert!(!glob_to_regex("v?.0").is_match("v10.0"));
+        assert!(!glob_to_regex("a.c").is_match("abc"));
+        assert!(!glob_to_regex("demo").is_match("demo-project"));
+    }
+
+    #[test]
+    fn default_route_matches_everything() {
+        assert!(route().matches("comment-added", &get_change()));
+    }
+
+    #[test]
+    fn route_matches_patterns() {
+        let change = get_change();
+        let matches = |route: Route| route.matches("comment-added", &change);
+        assert!(matches(route().with_project("demo-*")));
+        assert!(!matches(route().with_project("other")));
+        assert!(matches(route().with_branch("release/*")));
+        assert!(!matches(route().with_branch("master")));
+        assert!(matches(route().with_event_type("comment-added")));
+        assert!(!matches(route().with_event_type("reviewer-added")));
+        assert!(matches(
+            route()
+                .with_project("demo-project")
+                .with_branch("release/*")
+                .with_event_type("comment-*")
+        ));
+    }
+}
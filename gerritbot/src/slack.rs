@@ -0,0 +1,129 @@
+//! Slack as chat service of the bot.
+//!
+//! Slack is mapped to the bot's chat model: user ids are used as chat user
+//! ids, channels as rooms and the timestamps of messages as message
+//! ids. Posting to a user id sends a direct message to the user.
+
+use futures::{future, Future};
+
+use gerritbot_slack as slack;
+
+use crate::chat::{
+    ChatError, ChatEvent, ChatMessage, ChatUserId, ChatUserIdRef, Email, MessageId, MessageIdRef,
+    MessageTarget, RoomId, RoomIdRef, RoomType, SentMessage,
+};
+use crate::ChatBackend;
+
+fn to_sent_message(sent: slack::SentMessage) -> SentMessage {
+    SentMessage {
+        id: MessageId::new(sent.ts.into_string()),
+        room_id: RoomId::new(sent.channel.into_string()),
+    }
+}
+
+impl ChatBackend for slack::Client {
+    type Event = slack::Message;
+    type ReplyFuture = Box<dyn Future<Item = SentMessage, Error = ChatError> + Send>;
+    fn id(&self) -> &ChatUserIdRef {
+        ChatUserIdRef::new(slack::Client::id(self).as_str())
+    }
+    fn to_chat_event(&self, message: slack::Message) -> ChatEvent {
+        slack_message_to_event(message, slack::Client::id(self))
+    }
+    fn send_message(
+        &self,
+        target: &MessageTarget,
+        msg: &str,
+        parent_id: Option<&MessageIdRef>,
+    ) -> Self::ReplyFuture {
+        let channel = match target {
+            MessageTarget::RoomId(room_id) => future::Either::A(future::ok(room_id.to_string())),
+            MessageTarget::UserId(user_id) => future::Either::A(future::ok(user_id.to_string())),
+            MessageTarget::UserEmail(email) => future::Either::B(
+                self.lookup_user_by_email(email.as_str())
+                    .map(|user| user.id.into_string()),
+            ),
+        };
+        let client = self.clone();
+        let text = slack::markdown_to_mrkdwn(msg);
+        let thread_ts = parent_id.map(|id| slack::MessageTs::new(id.to_string()));
+        Box::new(
+            channel
+                .and_then(move |channel| client.post_message(&channel, &text, thread_ts.as_ref()))
+                .map(to_sent_message)
+                .map_err(ChatError::new),
+        )
+    }
+    fn edit_message(
+        &self,
+        message_id: &MessageIdRef,
+        room_id: &RoomIdRef,
+        msg: &str,
+    ) -> Self::ReplyFuture {
+        Box::new(
+            self.update_message(
+                &slack::ChannelId::new(room_id.to_string()),
+                &slack::MessageTs::new(message_id.to_string()),
+                &slack::markdown_to_mrkdwn(msg),
+            )
+            .map(to_sent_message)
+            .map_err(ChatError::new),
+        )
+    }
+}
+
+/// Convert a message to the bot into the event the bot handles. Mentions of
+/// the bot are already removed from the text of messages in channels.
+pub fn slack_message_to_event(message: slack::Message, bot_id: &slack::UserId) -> ChatEvent {
+    let room_type = match message.channel_type {
+        slack::ChannelType::Direct => RoomType::Direct,
+        slack::ChannelType::Group => RoomType::Group,
+    };
+    ChatEvent::Message(ChatMessage {
+        id: MessageId::new(message.ts.into_string()),
+        user_email: Email::new(message.user_email),
+        user_id: ChatUserId::new(message.user.into_string()),
+        room_id: RoomId::new(message.channel.into_string()),
+        room_type,
+        text: message.text,
+        mentioned_users: vec![ChatUserId::new(bot_id.to_string())],
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn message(channel_type: slack::ChannelType) -> slack::Message {
+        slack::Message {
+            user: slack::UserId::new("U1".to_string()),
+            user_email: "author@example.com".to_string(),
+            channel: slack::ChannelId::new("C1".to_string()),
+            channel_type,
+            text: "status".to_string(),
+            ts: slack::MessageTs::new("1.1".to_string()),
+            thread_ts: None,
+        }
+    }
+
+    #[test]
+    fn convert_messages_to_events() {
+        let bot_id = slack::UserId::new("UBOT".to_string());
+        let bot_person_id = ChatUserIdRef::new("UBOT");
+        for (channel_type, room_type) in &[
+            (slack::ChannelType::Direct, RoomType::Direct),
+            (slack::ChannelType::Group, RoomType::Group),
+        ] {
+            let message = slack_message_to_event(message(*channel_type), &bot_id)
+                .into_message()
+                .unwrap();
+            assert_eq!(message.room_type, *room_type);
+            assert_eq!(message.user_id.as_str(), "U1");
+            assert_eq!(message.user_email.as_str(), "author@example.com");
+            assert_eq!(message.room_id.as_str(), "C1");
+            assert_eq!(message.id.as_str(), "1.1");
+            assert!(message.mentions(bot_person_id));
+            assert_eq!(message.text, "status");
+        }
+    }
+}
@@ -0,0 +1,267 @@
+//! Webex Teams (formerly Spark) as chat service of the bot.
+//!
+//! Person ids are used as chat user ids. Webex Teams has rooms, threads and
+//! Adaptive Cards, so the bot's chat model maps directly onto it.
+
+use futures::Future;
+use rusoto_core::Region;
+use serde::Deserialize;
+
+use gerritbot_spark as spark;
+
+use crate::chat::{
+    CardAction, ChatError, ChatEvent, ChatMessage, ChatUserId, ChatUserIdRef, Email, MessageId,
+    MessageIdRef, MessageTarget, RoomId, RoomIdRef, RoomType, SentMessage,
+};
+use crate::ChatBackend;
+
+/// How the bot receives the messages from Webex Teams.
+#[derive(Debug, Deserialize, Clone)]
+pub enum SparkModeConfig {
+    Direct {
+        endpoint: std::net::SocketAddr,
+    },
+    /// Poll the messages from an SQS queue. Messages are deleted only after
+    /// they were handled.
+    Sqs {
+        uri: String,
+        region: Region,
+        /// Endpoint used instead of the one of the region, e.g. of a local
+        /// ElasticMQ.
+        #[serde(default)]
+        endpoint: Option<String>,
+        #[serde(default)]
+        credentials: spark::SqsCredentials,
+        /// Seconds a poll waits for messages (at most 20).
+        #[serde(default)]
+        wait_time: Option<u64>,
+        /// Maximum number of messages received by a poll (1 to 10).
+        #[serde(default)]
+        batch_size: Option<usize>,
+        /// Move messages to this queue after `max_receive_count` failed
+        /// attempts to handle them. Without it, the redrive policy of the
+        /// queue applies.
+        #[serde(default)]
+        dead_letter_queue: Option<String>,
+        #[serde(default = "default_max_receive_count")]
+        max_receive_count: u32,
+    },
+    /// Receive messages over the websocket of a Webex device. No webhook is
+    /// registered in this mode.
+    Websocket {
+        #[serde(default = "default_devices_url")]
+        devices_url: String,
+        /// Connect to this websocket instead of registering a device.
+        #[serde(default)]
+        websocket_url: Option<String>,
+    },
+}
+
+fn default_max_receive_count() -> u32 {
+    5
+}
+
+fn default_devices_url() -> String {
+    spark::DEFAULT_DEVICES_URL.to_string()
+}
+
+fn to_spark_target(target: &MessageTarget) -> spark::MessageTarget {
+    match target {
+        MessageTarget::RoomId(room_id) => {
+            spark::MessageTarget::RoomId(spark::RoomId::new(room_id.to_string()))
+        }
+        MessageTarget::UserId(user_id) => {
+            spark::MessageTarget::PersonId(spark::PersonId::new(user_id.to_string()))
+        }
+        MessageTarget::UserEmail(email) => {
+            spark::MessageTarget::PersonEmail(spark::Email::new(email.to_string()))
+        }
+    }
+}
+
+fn to_room_type(room_type: spark::RoomType) -> RoomType {
+    match room_type {
+        spark::RoomType::Direct => RoomType::Direct,
+        spark::RoomType::Group => RoomType::Group,
+    }
+}
+
+fn to_sent_message(sent: spark::SentMessage) -> SentMessage {
+    SentMessage {
+        id: MessageId::new(sent.id.into_string()),
+        room_id: RoomId::new(sent.room_id.into_string()),
+    }
+}
+
+type ReplyFuture = Box<dyn Future<Item = SentMessage, Error = ChatError> + Send>;
+
+fn reply_future<F>(future: F) -> ReplyFuture
+where
+    F: Future<Item = spark::SentMessage, Error = spark::Error> + Send + 'static,
+{
+    Box::new(future.map(to_sent_message).map_err(ChatError::new))
+}
+
+impl ChatBackend for spark::Client {
+    type Event = spark::Event;
+    type ReplyFuture = ReplyFuture;
+    fn id(&self) -> &ChatUserIdRef {
+        ChatUserIdRef::new(spark::Client::id(self).as_str())
+    }
+    fn to_chat_event(&self, event: spark::Event) -> ChatEvent {
+        spark_event_to_event(event)
+    }
+    fn send_message(
+        &self,
+        target: &MessageTarget,
+        msg: &str,
+        parent_id: Option<&MessageIdRef>,
+    ) -> Self::ReplyFuture {
+        let target = to_spark_target(target);
+        match parent_id {
+            Some(parent_id) => reply_future(self.send_thread_reply(
+                &target,
+                spark::MessageIdRef::new(parent_id.as_str()),
+                msg,
+            )),
+            None => reply_future(spark::Client::send_message(self, &target, msg)),
+        }
+    }
+    fn edit_message(
+        &self,
+        message_id: &MessageIdRef,
+        room_id: &RoomIdRef,
+        msg: &str,
+    ) -> Self::ReplyFuture {
+        reply_future(spark::Client::edit_message(
+            self,
+            spark::MessageIdRef::new(message_id.as_str()),
+            spark::RoomIdRef::new(room_id.as_str()),
+            msg,
+        ))
+    }
+    fn send_card(
+        &self,
+        target: &MessageTarget,
+        msg: &str,
+        card: &serde_json::Value,
+        parent_id: Option<&MessageIdRef>,
+    ) -> Self::ReplyFuture {
+        reply_future(spark::Client::send_card(
+            self,
+            &to_spark_target(target),
+            msg,
+            card,
+            parent_id.map(|id| spark::MessageIdRef::new(id.as_str())),
+        ))
+    }
+    fn is_moderator(
+        &self,
+        room_id: &RoomIdRef,
+        user_id: &ChatUserIdRef,
+    ) -> Box<dyn Future<Item = bool, Error = ChatError> + Send> {
+        Box::new(
+            spark::Client::is_moderator(
+                self,
+                spark::RoomIdRef::new(room_id.as_str()),
+                spark::PersonIdRef::new(user_id.as_str()),
+            )
+            .map_err(ChatError::new),
+        )
+    }
+}
+
+/// Convert a message, card action or membership change into the event the
+/// bot handles. Leading @mentions are removed from the text of messages in
+/// group rooms.
+fn spark_event_to_event(event: spark::Event) -> ChatEvent {
+    match event {
+        spark::Event::Message(message) => {
+            let text = match message.room_type {
+                spark::RoomType::Direct => message.text.clone(),
+                spark::RoomType::Group => message.text_without_mentions().to_string(),
+            };
+            ChatEvent::Message(ChatMessage {
+                id: MessageId::new(message.id.into_string()),
+                user_id: ChatUserId::new(message.person_id.into_string()),
+                user_email: Email::new(message.person_email.into_string()),
+                room_id: RoomId::new(message.room_id.into_string()),
+                room_type: to_room_type(message.room_type),
+                text,
+                mentioned_users: message
+                    .mentioned_people
+                    .into_iter()
+                    .map(|person_id| ChatUserId::new(person_id.into_string()))
+                    .collect(),
+            })
+        }
+        spark::Event::CardAction(card_action) => ChatEvent::CardAction(CardAction {
+            user_id: ChatUserId::new(card_action.action.person_id.into_string()),
+            user_email: Email::new(card_action.person_email.into_string()),
+            room_id: RoomId::new(card_action.action.room_id.into_string()),
+            room_type: to_room_type(card_action.room_type),
+            inputs: card_action.action.inputs,
+        }),
+        spark::Event::BotAdded(membership) => ChatEvent::BotAdded(
+            RoomId::new(membership.room_id.into_string()),
+            membership.room_type.map_or(RoomType::Group, to_room_type),
+        ),
+        spark::Event::BotRemoved(membership) => {
+            ChatEvent::BotRemoved(RoomId::new(membership.room_id.into_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn group_message(text: &str, html: &str) -> spark::Event {
+        spark::Event::Message(spark::Message {
+            person_email: spark::Email::new("author@example.com".to_string()),
+            person_id: spark::PersonId::new("author".to_string()),
+            room_id: spark::RoomId::new("room".to_string()),
+            room_type: spark::RoomType::Group,
+            text: text.to_string(),
+            html: Some(html.to_string()),
+            mentioned_people: vec![spark::PersonId::new("bot".to_string())],
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn convert_group_messages_to_events() {
+        let message = spark_event_to_event(group_message(
+            "GerritBot status",
+            r#"<p><spark-mention data-object-type="person" data-object-id="bot">GerritBot</spark-mention> status</p>"#,
+        ))
+        .into_message()
+        .unwrap();
+        assert_eq!(message.room_type, RoomType::Group);
+        assert_eq!(message.user_id.as_str(), "author");
+        assert_eq!(message.user_email.as_str(), "author@example.com");
+        assert_eq!(message.room_id.as_str(), "room");
+        assert!(message.mentions(ChatUserIdRef::new("bot")));
+        assert_eq!(message.text, "status");
+    }
+
+    #[test]
+    fn convert_memberships_to_events() {
+        let membership = spark::Membership {
+            id: "membership".to_string(),
+            room_id: spark::RoomId::new("room".to_string()),
+            person_id: spark::PersonId::new("bot".to_string()),
+            person_email: spark::Email::new("bot@example.com".to_string()),
+            is_moderator: false,
+            room_type: None,
+        };
+        match spark_event_to_event(spark::Event::BotAdded(membership.clone())) {
+            ChatEvent::BotAdded(room_id, RoomType::Group) => assert_eq!(room_id.as_str(), "room"),
+            event => panic!("unexpected event: {:?}", event),
+        }
+        match spark_event_to_event(spark::Event::BotRemoved(membership)) {
+            ChatEvent::BotRemoved(room_id) => assert_eq!(room_id.as_str(), "room"),
+            event => panic!("unexpected event: {:?}", event),
+        }
+    }
+}
@@ -0,0 +1,351 @@
+//! State store in an SQLite database.
+//!
+//! Every change is committed immediately in its own transaction, so that the
+//! database is always consistent and no full rewrite is necessary on save.
+
+use std::path::Path;
+
+use rusqlite::{params, Connection, Row, NO_PARAMS};
+
+use super::{BotError, Filter, StateStore, Subscription, User};
+use crate::chat::{ChatUserId, ChatUserIdRef, Email, EmailRef, RoomId, RoomIdRef};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS users (
+        id INTEGER PRIMARY KEY,
+        user_id TEXT NOT NULL UNIQUE,
+        email TEXT NOT NULL,
+        enabled INTEGER NOT NULL,
+        filter_regex TEXT,
+        filter_enabled INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE INDEX IF NOT EXISTS users_email ON users (email);
+    CREATE TABLE IF NOT EXISTS muted_changes (
+        user_id TEXT NOT NULL,
+        change INTEGER NOT NULL,
+        PRIMARY KEY (user_id, change)
+    );
+    CREATE TABLE IF NOT EXISTS subscriptions (
+        id INTEGER PRIMARY KEY,
+        room_id TEXT NOT NULL,
+        project TEXT NOT NULL,
+        branch TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS subscriptions_room_id ON subscriptions (room_id);
+";
+
+const SELECT_USERS: &str =
+    "SELECT id, user_id, email, enabled, filter_regex, filter_enabled FROM users";
+
+/// Store of the users and subscriptions in an SQLite database.
+pub struct SqliteStateStore {
+    connection: Connection,
+}
+
+impl SqliteStateStore {
+    /// Open the database, creating it and its tables if missing.
+    pub fn open<P: AsRef<Path>>(filename: P) -> Result<Self, BotError> {
+        Self::with_connection(Connection::open(filename)?)
+    }
+
+    /// Store which is lost when dropped.
+    pub fn open_in_memory() -> Result<Self, BotError> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(connection: Connection) -> Result<Self, BotError> {
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self { connection })
+    }
+
+    /// Query users and fetch their muted changes.
+    fn query_users<P>(&self, condition: &str, params: P) -> Result<Vec<User>, BotError>
+    where
+        P: IntoIterator,
+        P::Item: rusqlite::ToSql,
+    {
+        let mut statement = self
+            .connection
+            .prepare(&format!("{} {}", SELECT_USERS, condition))?;
+        let users = statement
+            .query_map(params, user_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        users
+            .into_iter()
+            .map(|mut user| {
+                user.muted_changes = self.muted_changes(&user.user_id)?;
+                Ok(user)
+            })
+            .collect()
+    }
+
+    fn muted_changes(&self, user_id: &ChatUserIdRef) -> Result<Vec<u32>, BotError> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT change FROM muted_changes WHERE user_id = ?1 ORDER BY rowid")?;
+        let changes = statement
+            .query_map(params![user_id.as_str()], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(changes)
+    }
+}
+
+fn user_from_row(row: &Row) -> rusqlite::Result<User> {
+    let filter_regex: Option<String> = row.get(4)?;
+    Ok(User {
+        user_id: ChatUserId::new(row.get(1)?),
+        email: Email::new(row.get(2)?),
+        enabled: row.get(3)?,
+        filter: match filter_regex {
+            Some(regex) => Some(Filter {
+                regex,
+                enabled: row.get(5)?,
+            }),
+            None => None,
+        },
+        muted_changes: Vec::new(),
+    })
+}
+
+impl StateStore for SqliteStateStore {
+    fn num_users(&self) -> Result<usize, BotError> {
+        let count: i64 =
+            self.connection
+                .query_row("SELECT COUNT(*) FROM users", NO_PARAMS, |row| row.get(0))?;
+        Ok(count as usize)
+    }
+
+    fn num_enabled_users(&self) -> Result<usize, BotError> {
+        let count: i64 = self.connection.query_row(
+            "SELECT COUNT(*) FROM users WHERE enabled",
+            NO_PARAMS,
+            |row| row.get(0),
+        )?;
+        Ok(count as usize)
+    }
+
+    fn users(&self) -> Result<Vec<User>, BotError> {
+        self.query_users("ORDER BY id", NO_PARAMS)
+    }
+
+    fn find_user(&self, user_id: &ChatUserIdRef) -> Result<Option<User>, BotError> {
+        Ok(self
+            .query_users("WHERE user_id = ?1", params![user_id.as_str()])?
+            .pop())
+    }
+
+    fn find_user_by_email(&self, email: &EmailRef) -> Result<Option<User>, BotError> {
+        Ok(self
+            .query_users(
+                "WHERE email = ?1 ORDER BY id DESC LIMIT 1",
+                params![email.as_str()],
+            )?
+            .pop())
+    }
+
+    fn save_user(&mut self, user: &User) -> Result<(), BotError> {
+        let transaction = self.connection.transaction()?;
+        transaction.execute(
+            "INSERT INTO users (user_id, email, enabled, filter_regex, filter_enabled)
+             VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT (user_id) DO UPDATE SET
+                 email = excluded.email,
+                 enabled = excluded.enabled,
+                 filter_regex = excluded.filter_regex,
+                 filter_enabled = excluded.filter_enabled",
+            params![
+                user.user_id.as_str(),
+                user.email.as_str(),
+                user.enabled,
+                user.filter.as_ref().map(|f| f.regex.as_str()),
+                user.filter.as_ref().map_or(false, |f| f.enabled),
+            ],
+        )?;
+        transaction.execute(
+            "DELETE FROM muted_changes WHERE user_id = ?1",
+            params![user.user_id.as_str()],
+        )?;
+        for change in &user.muted_changes {
+            transaction.execute(
+                "INSERT OR IGNORE INTO muted_changes (user_id, change) VALUES (?1, ?2)",
+                params![user.user_id.as_str(), change],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn subscriptions(&self, room_id: &RoomIdRef) -> Result<Vec<Subscription>, BotError> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT project, branch FROM subscriptions WHERE room_id = ?1 ORDER BY id")?;
+        let subscriptions = statement
+            .query_map(params![room_id.as_str()], |row| {
+                Ok(Subscription::new(
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                ))
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(subscriptions)
+    }
+
+    fn all_subscriptions(&self) -> Result<Vec<(RoomId, Subscription)>, BotError> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT room_id, project, branch FROM subscriptions ORDER BY id")?;
+        let subscriptions = statement
+            .query_map(NO_PARAMS, |row| {
+                Ok((
+                    RoomId::new(row.get(0)?),
+                    Subscription::new(row.get::<_, String>(1)?, row.get::<_, String>(2)?),
+                ))
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(subscriptions)
+    }
+
+    fn set_subscriptions(
+        &mut self,
+        room_id: &RoomIdRef,
+        subscriptions: &[Subscription],
+    ) -> Result<(), BotError> {
+        let transaction = self.connection.transaction()?;
+        transaction.execute(
+            "DELETE FROM subscriptions WHERE room_id = ?1",
+            params![room_id.as_str()],
+        )?;
+        for subscription in subscriptions {
+            transaction.execute(
+                "INSERT INTO subscriptions (room_id, project, branch) VALUES (?1, ?2, ?3)",
+                params![room_id.as_str(), subscription.project, subscription.branch],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), BotError> {
+        // all changes are already committed
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn user(user_id: &str, email: &str) -> User {
+        User::new(
+            ChatUserId::new(user_id.to_string()),
+            Email::new(email.to_string()),
+        )
+    }
+
+    #[test]
+    fn save_and_find_users() {
+        let mut store = SqliteStateStore::open_in_memory().unwrap();
+        let mut some_user = user("some_person_id", "some@example.com");
+        store.save_user(&some_user).unwrap();
+        store
+            .save_user(&user("other_person_id", "other@example.com"))
+            .unwrap();
+        assert_eq!(store.num_users().unwrap(), 2);
+        assert_eq!(store.num_enabled_users().unwrap(), 2);
+
+        some_user.enabled = false;
+        some_user.filter = Some(Filter::new(".*some_word.*"));
+        some_user.muted_changes = vec![49, 42];
+        store.save_user(&some_user).unwrap();
+        assert_eq!(store.num_users().unwrap(), 2);
+        assert_eq!(store.num_enabled_users().unwrap(), 1);
+        assert_eq!(
+            store
+                .find_user(ChatUserIdRef::new("some_person_id"))
+                .unwrap(),
+            Some(some_user.clone())
+        );
+        assert_eq!(
+            store
+                .find_user_by_email(EmailRef::new("some@example.com"))
+                .unwrap(),
+            Some(some_user.clone())
+        );
+        assert_eq!(
+            store.find_user(ChatUserIdRef::new("unknown_id")).unwrap(),
+            None
+        );
+        assert_eq!(
+            store.users().unwrap(),
+            vec![some_user, user("other_person_id", "other@example.com")]
+        );
+    }
+
+    #[test]
+    fn find_last_added_user_by_email() {
+        let mut store = SqliteStateStore::open_in_memory().unwrap();
+        store
+            .save_user(&user("some_person_id", "some@example.com"))
+            .unwrap();
+        store
+            .save_user(&user("other_person_id", "some@example.com"))
+            .unwrap();
+        let user = store
+            .find_user_by_email(EmailRef::new("some@example.com"))
+            .unwrap();
+        assert_eq!(user.unwrap().user_id, ChatUserIdRef::new("other_person_id"));
+    }
+
+    #[test]
+    fn set_subscriptions() {
+        let mut store = SqliteStateStore::open_in_memory().unwrap();
+        let room_id = RoomIdRef::new("room");
+        let subscriptions = vec![
+            Subscription::new("demo-project", "*"),
+            Subscription::new("other", "master"),
+        ];
+        store.set_subscriptions(room_id, &subscriptions).unwrap();
+        store
+            .set_subscriptions(
+                RoomIdRef::new("other_room"),
+                &[Subscription::new("demo-project", "release/*")],
+            )
+            .unwrap();
+        assert_eq!(store.subscriptions(room_id).unwrap(), subscriptions);
+        assert_eq!(store.all_subscriptions().unwrap().len(), 3);
+
+        store.set_subscriptions(room_id, &[]).unwrap();
+        assert!(store.subscriptions(room_id).unwrap().is_empty());
+        assert_eq!(
+            store.all_subscriptions().unwrap(),
+            vec![(
+                RoomId::new("other_room".to_string()),
+                Subscription::new("demo-project", "release/*")
+            )]
+        );
+    }
+
+    #[test]
+    fn keep_state_after_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let filename = dir.path().join("state.db");
+        {
+            let mut store = SqliteStateStore::open(&filename).unwrap();
+            store
+                .save_user(&user("some_person_id", "some@example.com"))
+                .unwrap();
+            store
+                .set_subscriptions(
+                    RoomIdRef::new("room"),
+                    &[Subscription::new("demo-project", "*")],
+                )
+                .unwrap();
+        }
+        let store = SqliteStateStore::open(&filename).unwrap();
+        assert_eq!(store.num_users().unwrap(), 1);
+        assert_eq!(
+            store.subscriptions(RoomIdRef::new("room")).unwrap().len(),
+            1
+        );
+    }
+}
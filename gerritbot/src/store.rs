@@ -0,0 +1,478 @@
+//! Persistence of the users and the subscriptions of the bot.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use super::{json_file, BotError, Filter, Subscription};
+use crate::chat::{ChatUserId, ChatUserIdRef, Email, EmailRef, RoomId, RoomIdRef};
+
+/// User of the chat service, who gets notifications about the changes in
+/// Gerrit.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct User {
+    pub user_id: ChatUserId,
+    /// email of the user; assumed to be the same in the chat service and Gerrit
+    pub email: Email,
+    pub enabled: bool,
+    pub filter: Option<Filter>,
+    /// numbers of changes the user does not want to be notified about
+    #[serde(default)]
+    pub muted_changes: Vec<u32>,
+}
+
+impl User {
+    pub fn new(user_id: ChatUserId, email: Email) -> Self {
+        Self {
+            user_id,
+            email,
+            filter: None,
+            enabled: true,
+            muted_changes: Vec::new(),
+        }
+    }
+
+    pub(crate) fn is_muted(&self, change: u32) -> bool {
+        self.muted_changes.contains(&change)
+    }
+
+    /// Check if the message is filtered out by the enabled filter of the
+    /// user.
+    pub(crate) fn is_filtered(&self, msg: &str) -> bool {
+        if let Some(filter) = self.filter.as_ref() {
+            if filter.enabled {
+                if let Ok(re) = Regex::new(&filter.regex) {
+                    return re.is_match(msg);
+                } else {
+                    warn!(
+                        "User {} has configured invalid filter regex: {}",
+                        self.user_id, filter.regex
+                    );
+                }
+            }
+        }
+        false
+    }
+}
+
+/// Storage of the users and the subscriptions of group rooms.
+///
+/// A store may apply changes immediately or only on `flush`, which is called
+/// whenever the bot saves its state.
+pub trait StateStore: Send {
+    fn num_users(&self) -> Result<usize, BotError>;
+    fn num_enabled_users(&self) -> Result<usize, BotError>;
+    /// All users, e.g. to copy them into another store.
+    fn users(&self) -> Result<Vec<User>, BotError>;
+    fn find_user(&self, user_id: &ChatUserIdRef) -> Result<Option<User>, BotError>;
+    /// Find the user with the email. If several users have the same email, the
+    /// last added one is returned.
+    fn find_user_by_email(&self, email: &EmailRef) -> Result<Option<User>, BotError>;
+    /// Add the user, or replace the user with the same id.
+    fn save_user(&mut self, user: &User) -> Result<(), BotError>;
+    /// Subscriptions of the room in the order they were added.
+    fn subscriptions(&self, room_id: &RoomIdRef) -> Result<Vec<Subscription>, BotError>;
+    /// Subscriptions of all rooms.
+    fn all_subscriptions(&self) -> Result<Vec<(RoomId, Subscription)>, BotError>;
+    /// Replace the subscriptions of the room. An empty list removes the room.
+    fn set_subscriptions(
+        &mut self,
+        room_id: &RoomIdRef,
+        subscriptions: &[Subscription],
+    ) -> Result<(), BotError>;
+    /// Persist all changes made so far.
+    fn flush(&mut self) -> Result<(), BotError>;
+}
+
+/// Version of the state file written by this version of the bot. Files
+/// without version are of version 1.
+const VERSION: u64 = 2;
+
+/// Migrations of the state file to the next version, starting with the one
+/// from version 1 to 2.
+const MIGRATIONS: [fn(&mut serde_json::Map<String, serde_json::Value>); 1] =
+    [rename_spark_person_ids];
+
+/// Version 2: the chat user id of a user is stored as `user_id` instead of
+/// `spark_person_id`, since it is not necessarily a Webex Teams person id.
+fn rename_spark_person_ids(state: &mut serde_json::Map<String, serde_json::Value>) {
+    let users = state
+        .get_mut("users")
+        .and_then(|users| users.as_array_mut());
+    for user in users
+        .into_iter()
+        .flatten()
+        .filter_map(|u| u.as_object_mut())
+    {
+        if let Some(user_id) = user.remove("spark_person_id") {
+            user.insert("user_id".to_string(), user_id);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct JsonState {
+    version: u64,
+    users: Vec<User>,
+    /// subscriptions of group rooms
+    #[serde(default)]
+    subscriptions: HashMap<RoomId, Vec<Subscription>>,
+}
+
+impl Default for JsonState {
+    fn default() -> Self {
+        Self {
+            version: VERSION,
+            users: Vec::new(),
+            subscriptions: HashMap::new(),
+        }
+    }
+}
+
+impl JsonState {
+    /// Parse the state, migrating it from older versions.
+    fn from_json(json: serde_json::Value) -> Result<Self, BotError> {
+        let mut state = match json {
+            serde_json::Value::Object(state) => state,
+            json => return Ok(serde_json::from_value(json)?),
+        };
+        let version = match state.get("version") {
+            Some(version) => version
+                .as_u64()
+                .filter(|&version| version > 0)
+                .ok_or_else(|| {
+                    <serde_json::Error as serde::de::Error>::custom("invalid version")
+                })?,
+            None => 1,
+        };
+        if version > VERSION {
+            return Err(BotError::StateVersion(version));
+        }
+        for migration in &MIGRATIONS[version as usize - 1..] {
+            migration(&mut state);
+        }
+        state.insert("version".to_string(), VERSION.into());
+        Ok(serde_json::from_value(serde_json::Value::Object(state))?)
+    }
+}
+
+/// Store keeping the whole state in memory, and writing it to a JSON file on
+/// `flush`.
+#[derive(Debug, Clone, Default)]
+pub struct JsonStateStore {
+    state: JsonState,
+    user_id_index: HashMap<ChatUserId, usize>,
+    email_index: HashMap<Email, usize>,
+    /// file the state is written to; in-memory only if `None`
+    filename: Option<PathBuf>,
+    /// number of previous versions of the file kept as `<file>.1`,
+    /// `<file>.2`, ...
+    backups: usize,
+}
+
+impl JsonStateStore {
+    /// Create an empty store, which is written to the file.
+    pub fn new<P: Into<PathBuf>>(filename: P) -> Self {
+        Self {
+            filename: Some(filename.into()),
+            ..Default::default()
+        }
+    }
+
+    /// Load the store from the file. A missing file results in an empty
+    /// store. Files of older versions are migrated.
+    pub fn load<P>(filename: P) -> Result<Self, BotError>
+    where
+        P: AsRef<Path>,
+    {
+        let filename = filename.as_ref();
+        let state = match File::open(filename) {
+            Ok(f) => JsonState::from_json(serde_json::from_reader(io::BufReader::new(f))?)?,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => JsonState::default(),
+            Err(err) => return Err(err.into()),
+        };
+        let mut store = Self::new(filename);
+        store.state = state;
+        store.index_users();
+        Ok(store)
+    }
+
+    /// Keep the given number of previous versions of the file.
+    pub fn with_backups(self, backups: usize) -> Self {
+        Self { backups, ..self }
+    }
+
+    /// Index the users by id and email. Of several users with the same email,
+    /// the last added one is found.
+    fn index_users(&mut self) {
+        self.user_id_index.clear();
+        self.email_index.clear();
+        for (user_pos, user) in self.state.users.iter().enumerate() {
+            self.user_id_index.insert(user.user_id.clone(), user_pos);
+            self.email_index.insert(user.email.clone(), user_pos);
+        }
+    }
+}
+
+impl StateStore for JsonStateStore {
+    fn num_users(&self) -> Result<usize, BotError> {
+        Ok(self.state.users.len())
+    }
+
+    fn num_enabled_users(&self) -> Result<usize, BotError> {
+        Ok(self.state.users.iter().filter(|u| u.enabled).count())
+    }
+
+    fn users(&self) -> Result<Vec<User>, BotError> {
+        Ok(self.state.users.clone())
+    }
+
+    fn find_user(&self, user_id: &ChatUserIdRef) -> Result<Option<User>, BotError> {
+        Ok(self
+            .user_id_index
+            .get(user_id)
+            .map(|&pos| self.state.users[pos].clone()))
+    }
+
+    fn find_user_by_email(&self, email: &EmailRef) -> Result<Option<User>, BotError> {
+        Ok(self
+            .email_index
+            .get(email)
+            .map(|&pos| self.state.users[pos].clone()))
+    }
+
+    fn save_user(&mut self, user: &User) -> Result<(), BotError> {
+        match self.user_id_index.get(&user.user_id) {
+            Some(&pos) => {
+                let previous = std::mem::replace(&mut self.state.users[pos], user.clone());
+                if previous.email != user.email {
+                    self.index_users();
+                }
+            }
+            None => {
+                let pos = self.state.users.len();
+                self.state.users.push(user.clone());
+                self.user_id_index.insert(user.user_id.clone(), pos);
+                self.email_index.insert(user.email.clone(), pos);
+            }
+        }
+        Ok(())
+    }
+
+    fn subscriptions(&self, room_id: &RoomIdRef) -> Result<Vec<Subscription>, BotError> {
+        Ok(self
+            .state
+            .subscriptions
+            .get(room_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    fn all_subscriptions(&self) -> Result<Vec<(RoomId, Subscription)>, BotError> {
+        Ok(self
+            .state
+            .subscriptions
+            .iter()
+            .flat_map(|(room_id, subscriptions)| {
+                subscriptions
+                    .iter()
+                    .map(move |subscription| (room_id.clone(), subscription.clone()))
+            })
+            .collect())
+    }
+
+    fn set_subscriptions(
+        &mut self,
+        room_id: &RoomIdRef,
+        subscriptions: &[Subscription],
+    ) -> Result<(), BotError> {
+        if subscriptions.is_empty() {
+            self.state.subscriptions.remove(room_id);
+        } else {
+            self.state
+                .subscriptions
+                .insert(room_id.to_owned(), subscriptions.to_vec());
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), BotError> {
+        match self.filename.as_ref() {
+            Some(filename) => json_file::write(filename, &self.state, self.backups),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn user(user_id: &str, email: &str) -> User {
+        User::new(
+            ChatUserId::new(user_id.to_string()),
+            Email::new(email.to_string()),
+        )
+    }
+
+    #[test]
+    fn load_state_of_older_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let filename = dir.path().join("state.json");
+        std::fs::write(
+            &filename,
+            r#"{"users": [{"spark_person_id": "some_person_id", "email": "some@example.com", "enabled": true, "filter": null}]}"#,
+        )
+        .unwrap();
+        let mut store = JsonStateStore::load(&filename).unwrap();
+        let user = store
+            .find_user(ChatUserIdRef::new("some_person_id"))
+            .unwrap();
+        assert_eq!(user.unwrap().email, EmailRef::new("some@example.com"));
+
+        store.flush().unwrap();
+        let json = std::fs::read_to_string(&filename).unwrap();
+        assert!(json.contains(r#""version":2"#));
+        assert!(json.contains(r#""user_id":"some_person_id""#));
+        assert!(!json.contains("spark_person_id"));
+    }
+
+    #[test]
+    fn refuse_unknown_versions_and_corrupt_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let filename = dir.path().join("state.json");
+        std::fs::write(&filename, r#"{"version": 3, "users": []}"#).unwrap();
+        match JsonStateStore::load(&filename) {
+            Err(BotError::StateVersion(3)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        std::fs::write(&filename, r#"{"version": "2", "users": []}"#).unwrap();
+        match JsonStateStore::load(&filename) {
+            Err(BotError::Serialization(_)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        std::fs::write(&filename, r#"{"version": 2, "users": [{"user_"#).unwrap();
+        match JsonStateStore::load(&filename) {
+            Err(BotError::Serialization(_)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn missing_file_is_empty_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = JsonStateStore::load(dir.path().join("state.json")).unwrap();
+        assert_eq!(store.num_users().unwrap(), 0);
+    }
+
+    #[test]
+    fn keep_backups_of_previous_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let filename = dir.path().join("state.json");
+        let mut store = JsonStateStore::new(&filename).with_backups(2);
+        for i in 0..4 {
+            store
+                .save_user(&user(&format!("person_{}", i), "some@example.com"))
+                .unwrap();
+            store.flush().unwrap();
+        }
+        let num_users = |filename: PathBuf| JsonStateStore::load(filename).unwrap().num_users();
+        assert_eq!(num_users(filename.clone()).unwrap(), 4);
+        assert_eq!(num_users(dir.path().join("state.json.1")).unwrap(), 3);
+        assert_eq!(num_users(dir.path().join("state.json.2")).unwrap(), 2);
+        assert!(!dir.path().join("state.json.3").exists());
+        assert!(!dir.path().join("state.json.tmp").exists());
+    }
+
+    #[test]
+    fn save_users() {
+        let mut store = JsonStateStore::default();
+        store
+            .save_user(&user("some_person_id", "some@example.com"))
+            .unwrap();
+        store
+            .save_user(&user("some_person_id_2", "some_2@example.com"))
+            .unwrap();
+        assert_eq!(store.num_users().unwrap(), 2);
+        assert_eq!(store.user_id_index.len(), 2);
+        assert_eq!(store.email_index.len(), 2);
+        assert_eq!(
+            store.email_index.get(EmailRef::new("some_2@example.com")),
+            Some(&1)
+        );
+
+        let mut updated = user("some_person_id", "some@example.com");
+        updated.enabled = false;
+        store.save_user(&updated).unwrap();
+        assert_eq!(store.num_users().unwrap(), 2);
+        assert_eq!(store.num_enabled_users().unwrap(), 1);
+        assert_eq!(
+            store
+                .find_user(ChatUserIdRef::new("some_person_id"))
+                .unwrap(),
+            Some(updated)
+        );
+        assert_eq!(
+            store
+                .find_user_by_email(EmailRef::new("some_2@example.com"))
+                .unwrap(),
+            Some(user("some_person_id_2", "some_2@example.com"))
+        );
+        assert_eq!(
+            store
+                .find_user(ChatUserIdRef::new("some_person_id_3"))
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn change_email_of_user() {
+        let mut store = JsonStateStore::default();
+        store
+            .save_user(&user("some_person_id", "some@example.com"))
+            .unwrap();
+        store
+            .save_user(&user("other_person_id", "other@example.com"))
+            .unwrap();
+
+        let updated = user("some_person_id", "new@example.com");
+        store.save_user(&updated).unwrap();
+        assert_eq!(
+            store
+                .find_user_by_email(EmailRef::new("some@example.com"))
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            store
+                .find_user_by_email(EmailRef::new("new@example.com"))
+                .unwrap(),
+            Some(updated)
+        );
+        assert_eq!(store.email_index.len(), 2);
+
+        // the other user takes over the email
+        store
+            .save_user(&user("other_person_id", "new@example.com"))
+            .unwrap();
+        let found = store
+            .find_user_by_email(EmailRef::new("new@example.com"))
+            .unwrap();
+        assert_eq!(
+            found.unwrap().user_id,
+            ChatUserIdRef::new("other_person_id")
+        );
+        assert_eq!(
+            store
+                .find_user_by_email(EmailRef::new("other@example.com"))
+                .unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+
+use super::{json_file, BotError};
+use crate::chat::{MessageId, MessageTarget};
+
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Thread started by the first message with a thread key to a recipient.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Thread {
+    recipient: MessageTarget,
+    key: String,
+    message_id: MessageId,
+    started_at: SystemTime,
+}
+
+impl Thread {
+    fn is_older_than(&self, max_age: Duration) -> bool {
+        SystemTime::now()
+            .duration_since(self.started_at)
+            .map(|age| age > max_age)
+            .unwrap_or(false)
+    }
+}
+
+/// Message threads of recipients.
+///
+/// Messages about the same subject, e.g. the same change, share a thread key.
+/// The first message with a key starts a thread, later messages with the same
+/// key are posted as replies in this thread. Threads older than the configured
+/// maximum age are forgotten, so that the next message starts a new thread.
+///
+/// If created with a filename, the threads are written to disk on every change.
+#[derive(Debug)]
+pub struct Threads {
+    threads: HashMap<(MessageTarget, String), Thread>,
+    filename: Option<PathBuf>,
+    max_age: Duration,
+}
+
+impl Default for Threads {
+    fn default() -> Self {
+        Self {
+            threads: HashMap::new(),
+            filename: None,
+            max_age: DEFAULT_MAX_AGE,
+        }
+    }
+}
+
+impl Threads {
+    /// Create threads which are kept in memory only.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Load the threads from the given file. A missing or corrupt file results
+    /// in no threads, so that only new threads are started. All later changes
+    /// are written back to the same file.
+    pub fn load<P>(filename: P) -> Result<Self, BotError>
+    where
+        P: AsRef<Path>,
+    {
+        let filename = filename.as_ref();
+        let threads: Vec<Thread> = match File::open(filename) {
+            Ok(f) => serde_json::from_reader(io::BufReader::new(f)).unwrap_or_else(|err| {
+                warn!("Ignoring corrupt threads file {:?}: {}", filename, err);
+                Vec::new()
+            }),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(Self {
+            threads: threads
+                .into_iter()
+                .map(|thread| ((thread.recipient.clone(), thread.key.clone()), thread))
+                .collect(),
+            filename: Some(filename.to_path_buf()),
+            ..Default::default()
+        })
+    }
+
+    /// Start a new thread for messages sent more than `max_age` after the
+    /// first message of the thread.
+    pub fn with_max_age(self, max_age: Duration) -> Self {
+        Self { max_age, ..self }
+    }
+
+    pub fn len(&self) -> usize {
+        self.threads.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.threads.is_empty()
+    }
+
+    fn save(&self) {
+        if let Some(filename) = self.filename.as_ref() {
+            let threads: Vec<&Thread> = self.threads.values().collect();
+            if let Err(err) = json_file::write(filename, &threads, 0) {
+                error!("Could not save threads: {:?}", err);
+            }
+        }
+    }
+
+    /// Message starting the thread with the given key, if it is not expired.
+    pub fn get(&self, recipient: &MessageTarget, key: &str) -> Option<&MessageId> {
+        self.threads
+            .get(&(recipient.clone(), key.to_string()))
+            .filter(|thread| !thread.is_older_than(self.max_age))
+            .map(|thread| &thread.message_id)
+    }
+
+    /// Remember the message as start of the thread with the given key, unless
+    /// there is already such a thread. Expired threads are removed.
+    pub fn start(&mut self, recipient: &MessageTarget, key: &str, message_id: MessageId) {
+        if self.get(recipient, key).is_some() {
+            return;
+        }
+
+        let max_age = self.max_age;
+        self.threads
+            .retain(|_, thread| !thread.is_older_than(max_age));
+        self.threads.insert(
+            (recipient.clone(), key.to_string()),
+            Thread {
+                recipient: recipient.clone(),
+                key: key.to_string(),
+                message_id,
+                started_at: SystemTime::now(),
+            },
+        );
+        self.save();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::chat::ChatUserId;
+
+    fn recipient() -> MessageTarget {
+        MessageTarget::UserId(ChatUserId::new("person".to_string()))
+    }
+
+    fn message_id(id: &str) -> MessageId {
+        MessageId::new(id.to_string())
+    }
+
+    #[test]
+    fn first_message_starts_thread() {
+        let mut threads = Threads::new();
+        assert_eq!(threads.get(&recipient(), "42"), None);
+        threads.start(&recipient(), "42", message_id("first"));
+        threads.start(&recipient(), "42", message_id("second"));
+        assert_eq!(threads.get(&recipient(), "42"), Some(&message_id("first")));
+        assert_eq!(threads.get(&recipient(), "43"), None);
+    }
+
+    #[test]
+    fn expired_thread_is_replaced() {
+        let mut threads = Threads::new().with_max_age(Duration::from_secs(60));
+        threads.start(&recipient(), "42", message_id("first"));
+        for thread in threads.threads.values_mut() {
+            thread.started_at -= Duration::from_secs(61);
+        }
+        assert_eq!(threads.get(&recipient(), "42"), None);
+        threads.start(&recipient(), "42", message_id("second"));
+        assert_eq!(threads.get(&recipient(), "42"), Some(&message_id("second")));
+        assert_eq!(threads.len(), 1);
+    }
+
+    #[test]
+    fn persists_threads() {
+        let dir = tempfile::tempdir().unwrap();
+        let filename = dir.path().join("threads.json");
+
+        let mut threads = Threads::load(&filename).unwrap();
+        assert!(threads.is_empty());
+        threads.start(&recipient(), "42", message_id("first"));
+
+        let threads = Threads::load(&filename).unwrap();
+        assert_eq!(threads.get(&recipient(), "42"), Some(&message_id("first")));
+    }
+
+    #[test]
+    fn ignores_corrupt_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let filename = dir.path().join("threads.json");
+        std::fs::write(&filename, r#"[{"recipient":"#).unwrap();
+
+        let mut threads = Threads::load(&filename).unwrap();
+        assert!(threads.is_empty());
+        threads.start(&recipient(), "42", message_id("first"));
+        assert_eq!(Threads::load(&filename).unwrap().len(), 1);
+    }
+}
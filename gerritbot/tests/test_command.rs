@@ -7,9 +7,7 @@ use lazy_static::lazy_static;
 use spectral::prelude::*;
 use speculate::speculate;
 
-use gerritbot_spark as spark;
-use spark::{EmailRef, PersonId, PersonIdRef};
-
+use gerritbot::chat::*;
 use gerritbot::*;
 
 #[derive(Debug, Clone, Default)]
@@ -18,39 +16,101 @@ impl GerritCommandRunner for TestGerritCommandRunner {}
 
 #[derive(Debug, Clone)]
 struct Reply {
-    person_id: PersonId,
+    target: MessageTarget,
     message: String,
 }
 
 type Replies = Rc<RefCell<Vec<Reply>>>;
 
 #[derive(Debug, Clone, Default)]
-struct TestSparkClient {
+struct TestChatBackend {
     replies: Replies,
+    is_moderator: bool,
 }
 
-impl SparkClient for TestSparkClient {
-    type ReplyFuture = future::FutureResult<(), spark::Error>;
-    fn send_message(&self, person_id: &PersonId, msg: &str) -> Self::ReplyFuture {
+impl ChatBackend for TestChatBackend {
+    type Event = ChatEvent;
+    type ReplyFuture = future::FutureResult<SentMessage, ChatError>;
+    fn id(&self) -> &ChatUserIdRef {
+        &TEST_BOT_ID
+    }
+    fn to_chat_event(&self, event: ChatEvent) -> ChatEvent {
+        event
+    }
+    fn send_message(
+        &self,
+        target: &MessageTarget,
+        msg: &str,
+        _parent_id: Option<&MessageIdRef>,
+    ) -> Self::ReplyFuture {
+        let mut replies = self.replies.borrow_mut();
+        replies.push(Reply {
+            target: target.clone(),
+            message: msg.to_string(),
+        });
+        future::ok(SentMessage {
+            id: MessageId::new(format!("message-{}", replies.len())),
+            room_id: RoomId::new(format!("room-{}", target)),
+        })
+    }
+    fn edit_message(
+        &self,
+        message_id: &MessageIdRef,
+        room_id: &RoomIdRef,
+        msg: &str,
+    ) -> Self::ReplyFuture {
         self.replies.borrow_mut().push(Reply {
-            person_id: person_id.to_owned(),
+            target: MessageTarget::RoomId(room_id.to_owned()),
             message: msg.to_string(),
         });
-        future::ok(())
+        future::ok(SentMessage {
+            id: message_id.to_owned(),
+            room_id: room_id.to_owned(),
+        })
+    }
+    fn is_moderator(
+        &self,
+        _room_id: &RoomIdRef,
+        _user_id: &ChatUserIdRef,
+    ) -> Box<dyn Future<Item = bool, Error = ChatError> + Send> {
+        Box::new(future::ok(self.is_moderator))
     }
 }
 
-type TestBot = Bot<TestGerritCommandRunner, TestSparkClient>;
+type TestBot = Bot<TestGerritCommandRunner, TestChatBackend>;
 
 lazy_static! {
-    static ref TEST_PERSON_ID: &'static PersonIdRef = PersonIdRef::new("test_person_id");
+    static ref TEST_BOT_ID: &'static ChatUserIdRef = ChatUserIdRef::new("test_bot_id");
+    static ref TEST_PERSON_ID: &'static ChatUserIdRef = ChatUserIdRef::new("test_person_id");
     static ref TEST_PERSON_EMAIL: &'static EmailRef = EmailRef::new("test@person.test");
+    static ref TEST_ROOM_ID: &'static RoomIdRef = RoomIdRef::new("test_room_id");
+    static ref TEST_PERSON_TARGET: MessageTarget = MessageTarget::UserId(TEST_PERSON_ID.to_owned());
+    static ref TEST_ROOM_TARGET: MessageTarget = MessageTarget::RoomId(TEST_ROOM_ID.to_owned());
 }
 
 trait TestBotTrait: Sized {
     fn new() -> (Self, Replies);
+    fn new_moderators_only(is_moderator: bool) -> (Self, Replies);
     fn send_message(self, message: &str);
     fn send_messages(self, messages: &[&str]);
+    fn send_group_messages(self, messages: &[ChatMessage]);
+}
+
+/// Create a message to the bot in a group room.
+fn group_message(text: &str, mentions_bot: bool) -> ChatMessage {
+    ChatMessage {
+        user_email: TEST_PERSON_EMAIL.to_owned(),
+        user_id: TEST_PERSON_ID.to_owned(),
+        room_id: TEST_ROOM_ID.to_owned(),
+        room_type: RoomType::Group,
+        text: text.to_string(),
+        mentioned_users: if mentions_bot {
+            vec![TEST_BOT_ID.to_owned()]
+        } else {
+            Vec::new()
+        },
+        ..Default::default()
+    }
 }
 
 impl TestBotTrait for TestBot {
@@ -58,27 +118,50 @@ impl TestBotTrait for TestBot {
         let replies = Replies::default();
         let bot = Builder::new(State::new()).build(
             Default::default(),
-            TestSparkClient {
+            TestChatBackend {
                 replies: replies.clone(),
+                is_moderator: true,
             },
         );
         (bot, replies)
     }
 
+    fn new_moderators_only(is_moderator: bool) -> (Self, Replies) {
+        let replies = Replies::default();
+        let bot = Builder::new(State::new())
+            .with_subscriptions_moderators_only(true)
+            .build(
+                Default::default(),
+                TestChatBackend {
+                    replies: replies.clone(),
+                    is_moderator,
+                },
+            );
+        (bot, replies)
+    }
+
     fn send_messages(self, messages: &[&str]) {
-        let spark_messages = stream::iter_ok(messages.iter().map(|msg| spark::Message {
-            person_email: TEST_PERSON_EMAIL.to_owned(),
-            person_id: TEST_PERSON_ID.to_owned(),
-            text: msg.to_string(),
-            ..Default::default()
+        let chat_events = stream::iter_ok(messages.iter().map(|msg| {
+            ChatEvent::Message(ChatMessage {
+                user_email: TEST_PERSON_EMAIL.to_owned(),
+                user_id: TEST_PERSON_ID.to_owned(),
+                text: msg.to_string(),
+                ..Default::default()
+            })
         }));
         let gerrit_events = stream::empty();
-        self.run(gerrit_events, spark_messages).wait().unwrap();
+        self.run(gerrit_events, chat_events).wait().unwrap();
     }
 
     fn send_message(self, message: &str) {
         self.send_messages(&[message][..]);
     }
+
+    fn send_group_messages(self, messages: &[ChatMessage]) {
+        let chat_events = stream::iter_ok(messages.iter().cloned().map(ChatEvent::Message));
+        let gerrit_events = stream::empty();
+        self.run(gerrit_events, chat_events).wait().unwrap();
+    }
 }
 
 speculate! {
@@ -91,7 +174,7 @@ speculate! {
             bot.send_message("status");
             let replies = Rc::try_unwrap(replies).unwrap().into_inner();
             assert_that!(replies).has_length(1);
-            assert_that!(replies[0].person_id).is_equal_to(TEST_PERSON_ID.to_owned());
+            assert_that!(replies[0].target).is_equal_to(&*TEST_PERSON_TARGET);
             assert_that!(replies[0].message).contains("disabled");
         }
 
@@ -99,9 +182,9 @@ speculate! {
             bot.send_messages(&["enable", "status"][..]);
             let replies = Rc::try_unwrap(replies).unwrap().into_inner();
             assert_that!(replies).has_length(2);
-            assert_that!(replies[0].person_id).is_equal_to(TEST_PERSON_ID.to_owned());
+            assert_that!(replies[0].target).is_equal_to(&*TEST_PERSON_TARGET);
             assert_that!(replies[0].message).contains("Happy reviewing!");
-            assert_that!(replies[1].person_id).is_equal_to(TEST_PERSON_ID.to_owned());
+            assert_that!(replies[1].target).is_equal_to(&*TEST_PERSON_TARGET);
             assert_that!(replies[1].message).contains("enabled");
         }
 
@@ -109,18 +192,192 @@ speculate! {
             bot.send_message("this is not a known command");
             let replies = Rc::try_unwrap(replies).unwrap().into_inner();
             assert_that!(replies).has_length(1);
-            assert_that!(replies[0].person_id).is_equal_to(TEST_PERSON_ID.to_owned());
+            assert_that!(replies[0].target).is_equal_to(&*TEST_PERSON_TARGET);
             assert_that!(replies[0].message).contains("I am GerritBot");
         }
 
+        test "mute requires enabled notifications" {
+            bot.send_message("mute 42");
+            let replies = Rc::try_unwrap(replies).unwrap().into_inner();
+            assert_that!(replies).has_length(1);
+            assert_that!(replies[0].message).contains("enable notifications first");
+        }
+
         test "version" {
             bot.send_message("version");
             let replies = Rc::try_unwrap(replies).unwrap().into_inner();
             assert_that!(replies).has_length(1);
-            assert_that!(replies[0].person_id).is_equal_to(TEST_PERSON_ID.to_owned());
+            assert_that!(replies[0].target).is_equal_to(&*TEST_PERSON_TARGET);
             assert_that!(replies[0].message).contains(env!("CARGO_PKG_NAME"));
             assert_that!(replies[0].message).contains(env!("CARGO_PKG_VERSION"));
             assert_that!(replies[0].message).contains(env!("VERGEN_SHA"));
         }
     }
+
+    describe "group room tests" {
+        test "mentioned command is answered in the room" {
+            bot.send_group_messages(&[group_message("status", true)]);
+            let replies = Rc::try_unwrap(replies).unwrap().into_inner();
+            assert_that!(replies).has_length(1);
+            assert_that!(replies[0].target).is_equal_to(&*TEST_ROOM_TARGET);
+            assert_that!(replies[0].message).contains("disabled");
+        }
+
+        test "enable then status" {
+            bot.send_group_messages(&[
+                group_message("enable", true),
+                group_message("status", true),
+            ]);
+            let replies = Rc::try_unwrap(replies).unwrap().into_inner();
+            assert_that!(replies).has_length(2);
+            assert_that!(replies[0].target).is_equal_to(&*TEST_ROOM_TARGET);
+            assert_that!(replies[0].message).contains("Happy reviewing!");
+            assert_that!(replies[1].target).is_equal_to(&*TEST_ROOM_TARGET);
+            assert_that!(replies[1].message).contains("enabled");
+        }
+
+        test "message without mention is ignored" {
+            bot.send_group_messages(&[group_message("status", false)]);
+            let replies = Rc::try_unwrap(replies).unwrap().into_inner();
+            assert_that!(replies).is_empty();
+        }
+    }
+
+    describe "subscription tests" {
+        test "subscribe then list subscriptions" {
+            bot.send_group_messages(&[
+                group_message("subscribe project gerritbot-rs", true),
+                group_message("subscribe project gerritbot-rs branch release/*", true),
+                group_message("subscriptions", true),
+            ]);
+            let replies = Rc::try_unwrap(replies).unwrap().into_inner();
+            assert_that!(replies).has_length(3);
+            assert_that!(replies[0].target).is_equal_to(&*TEST_ROOM_TARGET);
+            assert_that!(replies[0].message).contains("project `gerritbot-rs`");
+            assert_that!(replies[1].message).contains("on branch `release/*`");
+            assert_that!(replies[2].target).is_equal_to(&*TEST_ROOM_TARGET);
+            assert_that!(replies[2].message).contains("* project `gerritbot-rs`\n");
+            assert_that!(replies[2].message)
+                .contains("* project `gerritbot-rs` on branch `release/*`");
+        }
+
+        test "unsubscribe" {
+            bot.send_group_messages(&[
+                group_message("subscribe project gerritbot-rs", true),
+                group_message("unsubscribe project gerritbot-rs", true),
+                group_message("unsubscribe project gerritbot-rs", true),
+                group_message("subscriptions", true),
+            ]);
+            let replies = Rc::try_unwrap(replies).unwrap().into_inner();
+            assert_that!(replies).has_length(4);
+            assert_that!(replies[1].message).contains("stop posting");
+            assert_that!(replies[2].message).contains("not subscribed");
+            assert_that!(replies[3].message).contains("no subscriptions");
+        }
+
+        test "subscriptions are only available in spaces" {
+            bot.send_message("subscribe project gerritbot-rs");
+            let replies = Rc::try_unwrap(replies).unwrap().into_inner();
+            assert_that!(replies).has_length(1);
+            assert_that!(replies[0].target).is_equal_to(&*TEST_PERSON_TARGET);
+            assert_that!(replies[0].message).contains("only available in group spaces");
+        }
+    }
+}
+
+#[test]
+fn moderator_can_subscribe() {
+    let (bot, replies) = TestBot::new_moderators_only(true);
+    bot.send_group_messages(&[group_message("subscribe project gerritbot-rs", true)]);
+    let replies = Rc::try_unwrap(replies).unwrap().into_inner();
+    assert_that!(replies).has_length(1);
+    assert_that!(replies[0].message).contains("Got it!");
+}
+
+#[test]
+fn only_moderators_can_subscribe() {
+    let (bot, replies) = TestBot::new_moderators_only(false);
+    bot.send_group_messages(&[
+        group_message("subscribe project gerritbot-rs", true),
+        group_message("subscriptions", true),
+    ]);
+    let replies = Rc::try_unwrap(replies).unwrap().into_inner();
+    assert_that!(replies).has_length(2);
+    assert_that!(replies[0].message).contains("Only moderators");
+    assert_that!(replies[1].message).contains("no subscriptions");
+}
+
+/// Create a click on a card button in the direct room with the bot.
+fn card_action(data: serde_json::Value) -> ChatEvent {
+    ChatEvent::CardAction(CardAction {
+        user_id: TEST_PERSON_ID.to_owned(),
+        user_email: TEST_PERSON_EMAIL.to_owned(),
+        room_id: TEST_ROOM_ID.to_owned(),
+        room_type: RoomType::Direct,
+        inputs: match data {
+            serde_json::Value::Object(inputs) => inputs,
+            _ => panic!("card data must be an object"),
+        },
+    })
+}
+
+#[test]
+fn card_actions_run_commands() {
+    let (bot, replies) = TestBot::new();
+    let events = vec![
+        ChatEvent::Message(ChatMessage {
+            user_email: TEST_PERSON_EMAIL.to_owned(),
+            user_id: TEST_PERSON_ID.to_owned(),
+            text: "enable".to_string(),
+            ..Default::default()
+        }),
+        card_action(serde_json::json!({"command": "mute 42"})),
+        card_action(serde_json::json!({"command": "mute 42"})),
+        card_action(serde_json::json!({"unknown": "input"})),
+        card_action(serde_json::json!({"command": "unmute 42"})),
+    ];
+    bot.run(stream::empty(), stream::iter_ok(events))
+        .wait()
+        .unwrap();
+    let replies = Rc::try_unwrap(replies).unwrap().into_inner();
+    assert_that!(replies).has_length(4);
+    assert_that!(replies[1].target).is_equal_to(&*TEST_PERSON_TARGET);
+    assert_that!(replies[1].message).contains("not notify you about change 42");
+    assert_that!(replies[2].message).contains("already muted");
+    assert_that!(replies[3].message).contains("notify you about change 42 again");
+}
+
+#[test]
+fn bot_greets_when_added() {
+    let (bot, replies) = TestBot::new();
+    let events = vec![
+        ChatEvent::BotAdded(TEST_ROOM_ID.to_owned(), RoomType::Direct),
+        ChatEvent::BotAdded(TEST_ROOM_ID.to_owned(), RoomType::Group),
+    ];
+    bot.run(stream::empty(), stream::iter_ok(events))
+        .wait()
+        .unwrap();
+    let replies = Rc::try_unwrap(replies).unwrap().into_inner();
+    assert_that!(replies).has_length(2);
+    assert_that!(replies[0].target).is_equal_to(&*TEST_ROOM_TARGET);
+    assert_that!(replies[0].message).contains("type in **enable**");
+    assert_that!(replies[1].target).is_equal_to(&*TEST_ROOM_TARGET);
+    assert_that!(replies[1].message).contains("subscribe project");
+}
+
+#[test]
+fn bot_removed_from_space_drops_subscriptions() {
+    let (bot, replies) = TestBot::new();
+    let events = vec![
+        group_message("subscribe project gerritbot-rs", true).into(),
+        ChatEvent::BotRemoved(TEST_ROOM_ID.to_owned()),
+        group_message("subscriptions", true).into(),
+    ];
+    bot.run(stream::empty(), stream::iter_ok::<_, ()>(events))
+        .wait()
+        .unwrap();
+    let replies = Rc::try_unwrap(replies).unwrap().into_inner();
+    assert_that!(replies).has_length(2);
+    assert_that!(replies[0].message).contains("Got it!");
+    assert_that!(replies[1].message).contains("no subscriptions");
 }
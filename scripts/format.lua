@@ -243,3 +243,22 @@ function format_reviewer_added(event)
         format_user(base_url, change.owner, "owner")
     )
 end
+
+-- Formatting of messages posted to group rooms configured in the routes. The
+-- direct message functions above are used if these functions are missing.
+function format_room_comment_added(event, is_human)
+    return format_comment_added(event, is_human)
+end
+
+function format_room_reviewer_added(event)
+    local change = event.change
+    local base_url = get_gerrit_base_url(change.url)
+
+    return string.format(
+        "%s (%s) by %s 👓 %s added as reviewer",
+        format_change_subject(change),
+        format_change_project(base_url, change),
+        format_user(base_url, change.owner, "owner"),
+        format_user(base_url, event.reviewer, "reviewer")
+    )
+end